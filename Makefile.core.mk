@@ -13,6 +13,8 @@ build:
 # Test that all important features build
 check-features:
 	cargo check --features console
+	cargo check --features io-uring
+	cargo check --no-default-features --features dns-over-tls
 	(cd fuzz; cargo check)
 
 # target in common/Makefile.common.mk doesn't handle our third party vendored files; only check golang and rust codes
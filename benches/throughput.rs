@@ -35,7 +35,7 @@ use ztunnel::test_helpers::TEST_WORKLOAD_HBONE;
 use ztunnel::test_helpers::TEST_WORKLOAD_SOURCE;
 use ztunnel::test_helpers::TEST_WORKLOAD_TCP;
 use ztunnel::test_helpers::{helpers, tcp};
-use ztunnel::{app, identity, metrics, proxy, test_helpers};
+use ztunnel::{app, config, identity, metrics, proxy, test_helpers};
 
 const KB: usize = 1024;
 const MB: usize = 1024 * KB;
@@ -98,6 +98,16 @@ fn create_test_policies() -> Vec<Authorization> {
 fn initialize_environment(
     mode: Mode,
     policies: Vec<Authorization>,
+) -> (Arc<Mutex<TestEnv>>, Runtime) {
+    initialize_environment_with(mode, policies, |_| {})
+}
+
+/// initialize_environment_with is initialize_environment, with `configure` adjusting the ztunnel
+/// config first.
+fn initialize_environment_with(
+    mode: Mode,
+    policies: Vec<Authorization>,
+    configure: impl FnOnce(&mut config::Config) + Send + 'static,
 ) -> (Arc<Mutex<TestEnv>>, Runtime) {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "error")
@@ -114,12 +124,13 @@ fn initialize_environment(
         let config_source = Some(ztunnel::config::ConfigSource::Static(
             test_helpers::local_xds_config(port, None, policies).unwrap(),
         ));
-        let config = test_helpers::test_config_with_port_xds_addr_and_root_cert(
+        let mut config = test_helpers::test_config_with_port_xds_addr_and_root_cert(
             port,
             None,
             None,
             config_source,
         );
+        configure(&mut config);
         let app = app::build_with_cert(config, cert_manager.clone())
             .await
             .unwrap();
@@ -271,6 +282,27 @@ pub fn rbac_throughput(c: &mut Criterion) {
     });
 }
 
+/// buffer_sizes measures HBONE throughput depending on the size of the buffers it is copied through.
+pub fn buffer_sizes(c: &mut Criterion) {
+    let mut c = c.benchmark_group("buffer_sizes");
+
+    let size: usize = 10 * MB;
+    c.throughput(Throughput::Bytes(size as u64));
+    c.sample_size(10);
+    c.sampling_mode(SamplingMode::Flat);
+    c.measurement_time(Duration::from_secs(5));
+    for buffer_size in [4 * KB, 16 * KB - 64, 64 * KB, 256 * KB] {
+        let (env, rt) = initialize_environment_with(Mode::Read, vec![], move |cfg| {
+            cfg.hbone_buffer_size = buffer_size
+        });
+        c.bench_with_input(BenchmarkId::new("hbone", buffer_size), &size, |b, size| {
+            b.to_async(&rt).iter(|| async {
+                tcp::run_client(&mut env.lock().await.hbone, *size, Mode::Write).await
+            })
+        });
+    }
+}
+
 pub fn connections(c: &mut Criterion) {
     let (env, rt) = initialize_environment(Mode::ReadWrite, vec![]);
     let mut c = c.benchmark_group("connections");
@@ -294,8 +326,7 @@ pub fn connections(c: &mut Criterion) {
             tcp::run_client(&mut s, 1, Mode::ReadWrite).await
         })
     });
-    // TODO(https://github.com/istio/ztunnel/issues/15): when we have pooling, split this into "new hbone connection"
-    // and "new connection on existing HBONE connection"
+    // A new connection on the pooled HBONE connection set up during initialization.
     c.bench_function("hbone", |b| {
         b.to_async(&rt).iter(|| async {
             let e = env.lock().await;
@@ -308,6 +339,21 @@ pub fn connections(c: &mut Criterion) {
             tcp::run_client(&mut s, 1, Mode::ReadWrite).await
         })
     });
+    // A new connection that sets up an HBONE connection of its own.
+    let (env, rt) =
+        initialize_environment_with(Mode::ReadWrite, vec![], |cfg| cfg.hbone_pooling = false);
+    c.bench_function("hbone_unpooled", |b| {
+        b.to_async(&rt).iter(|| async {
+            let e = env.lock().await;
+            let mut s =
+                e.ta.socks5_connect(helpers::with_ip(
+                    e.echo_addr,
+                    TEST_WORKLOAD_HBONE.parse().unwrap(),
+                ))
+                .await;
+            tcp::run_client(&mut s, 1, Mode::ReadWrite).await
+        })
+    });
 }
 
 pub fn rbac_connections(c: &mut Criterion) {
@@ -360,9 +406,12 @@ pub fn metrics(c: &mut Criterion) {
                 reporter: Default::default(),
                 source: Some(test_helpers::test_default_workload()),
                 derived_source: None,
+                derived_destination: None,
                 destination: None,
                 destination_service: None,
                 connection_security_policy: Default::default(),
+                trace_id: None,
+                connection_id: proxy::ConnectionId::new(),
             })
         })
     });
@@ -379,7 +428,7 @@ criterion_group! {
     config = Criterion::default()
         .with_profiler(PProfProfiler::new(100, Output::Protobuf))
         .warm_up_time(Duration::from_millis(1));
-    targets = latency, throughput, connections, rbac_latency, rbac_throughput, rbac_connections,
+    targets = latency, throughput, buffer_sizes, connections, rbac_latency, rbac_throughput, rbac_connections,
 }
 
 criterion_main!(benches);
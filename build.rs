@@ -22,6 +22,11 @@ fn main() -> Result<(), anyhow::Error> {
         "proto/workload.proto",
         "proto/authorization.proto",
         "proto/citadel.proto",
+        "proto/secret.proto",
+        "proto/sds.proto",
+        "proto/workload_api.proto",
+        "proto/nds.proto",
+        "proto/traffic.proto",
     ]
     .iter()
     .map(|name| std::env::current_dir().unwrap().join(name))
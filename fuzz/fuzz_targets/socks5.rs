@@ -0,0 +1,35 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+use ztunnel::proxy::read_socks5_request;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = run_socks5_decoder(data);
+});
+
+fn run_socks5_decoder(data: &[u8]) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread().build()?;
+    rt.block_on(async {
+        // Leave room for the method selection reply too.
+        let (mut client, mut server) = tokio::io::duplex(data.len() + 2);
+        client.write_all(data).await?;
+        client.shutdown().await?;
+        read_socks5_request(&mut server).await?;
+        Ok(())
+    })
+}
@@ -12,11 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::config::Config;
-use crate::hyper_util::{empty_response, plaintext_response, Server};
-use crate::identity::SecretManager;
+use crate::config::{AdminAuth, Config, LiveConfig};
+use crate::hyper_util::{
+    empty_response, plaintext_response, EarlyData, LocalPeer, PeerIdentity, Server,
+};
+use crate::identity::{Identity, SecretManager};
+use crate::proxy::{ConnectionTracker, TrafficPairs};
+use crate::rbac;
+use crate::state::workload::{NetworkAddress, Workload};
 use crate::state::DemandProxyState;
-use crate::tls::asn1_time_to_system_time;
+use crate::tls::{asn1_time_to_system_time, CertProvider, TlsError};
 use crate::version::BuildInfo;
 use crate::xds::LocalConfig;
 use crate::{signal, telemetry};
@@ -30,9 +35,11 @@ use hyper::{header::HeaderValue, header::CONTENT_TYPE, Request, Response};
 use pprof::protos::Message;
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpStream;
 use tokio::time;
 use tracing::{error, info, warn};
 
@@ -47,13 +54,16 @@ use tokio::io::AsyncReadExt;
 
 struct State {
     proxy_state: DemandProxyState,
-    config: Config,
+    config: LiveConfig,
     shutdown_trigger: signal::ShutdownTrigger,
     cert_manager: Arc<SecretManager>,
+    connections: Vec<ConnectionTracker>,
+    traffic_pairs: Option<TrafficPairs>,
 }
 
 pub struct Service {
     s: Server<State>,
+    mtls: Option<AdminCertProvider>,
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -71,6 +81,8 @@ pub struct CertDump {
     // Not available via Envoy, but still useful.
     pem: String,
     serial_number: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    subject_alt_names: Vec<String>,
     valid_from: String,
     expiration_time: String,
 }
@@ -85,25 +97,42 @@ pub struct CertsDump {
 
 impl Service {
     pub async fn new(
-        config: Config,
+        config: LiveConfig,
         proxy_state: DemandProxyState,
         shutdown_trigger: signal::ShutdownTrigger,
         drain_rx: Watch,
         cert_manager: Arc<SecretManager>,
+        connections: Vec<ConnectionTracker>,
+        traffic_pairs: Option<TrafficPairs>,
     ) -> anyhow::Result<Self> {
-        Server::<State>::bind(
+        let current = config.current();
+        let mtls = current
+            .admin_auth
+            .mtls_identity
+            .clone()
+            .map(|identity| AdminCertProvider::new(cert_manager.clone(), identity));
+        let s = Server::<State>::bind(
             "admin",
-            config.admin_addr,
+            current.admin_addr,
             drain_rx,
             State {
                 config,
                 proxy_state,
                 shutdown_trigger,
                 cert_manager,
+                connections,
+                traffic_pairs,
             },
         )
-        .await
-        .map(|s| Service { s })
+        .await?;
+        let s = match &current.admin_uds_path {
+            #[cfg(unix)]
+            Some(path) => s.with_unix_socket(path)?,
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("the admin API can only be served on a Unix socket on Unix"),
+            None => s,
+        };
+        Ok(Service { s, mtls })
     }
 
     pub fn address(&self) -> SocketAddr {
@@ -111,33 +140,120 @@ impl Service {
     }
 
     pub fn spawn(self) {
-        self.s.spawn(|state, req| async move {
-            match req.uri().path() {
-                "/debug/pprof/profile" => Ok(handle_pprof(req).await),
-                "/debug/gprof/profile" => Ok(handle_gprof(req).await),
-                "/debug/gprof/heap" => Ok(handle_gprof_heap(req).await),
-                "/quitquitquit" => Ok(handle_server_shutdown(
-                    state.shutdown_trigger.clone(),
-                    req,
-                    state.config.self_termination_deadline,
-                )
-                .await),
-                "/config_dump" => Ok(handle_config_dump(
-                    ConfigDump {
-                        proxy_state: state.proxy_state.clone(),
-                        static_config: Default::default(),
-                        version: BuildInfo::new(),
-                        config: state.config.clone(),
-                        certificates: dump_certs(state.cert_manager.borrow()).await,
-                    },
-                    // req, // bring this back if we start using it
-                )
-                .await),
-                "/logging" => Ok(handle_logging(req).await),
-                "/" => Ok(handle_dashboard(req).await),
-                _ => Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
-            }
-        })
+        match self.mtls {
+            Some(acceptor) => self.s.spawn_mtls(acceptor, handle),
+            None => self.s.spawn(handle),
+        }
+    }
+}
+
+/// The endpoints that change ztunnel's state, which only the allowed identities can call.
+const MUTATING_PATHS: &[&str] = &["/quitquitquit", "/logging", "/debug/certs/refresh"];
+
+async fn handle(
+    state: Arc<State>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    // An attacker can replay early data, so changes must wait for the handshake to complete.
+    if MUTATING_PATHS.contains(&req.uri().path()) && req.extensions().get::<EarlyData>().is_some() {
+        return Ok(plaintext_response(
+            hyper::StatusCode::from_u16(425).expect("valid status code"),
+            "retry once the TLS handshake completes\n".to_string(),
+        ));
+    }
+    // Clients on the Unix socket run as our own user, so they may call anything.
+    if MUTATING_PATHS.contains(&req.uri().path()) && req.extensions().get::<LocalPeer>().is_none() {
+        if let Err(resp) = authorize(&state.config.current().admin_auth, req.extensions().get()) {
+            return Ok(resp);
+        }
+    }
+    match req.uri().path() {
+        "/debug/pprof/profile" => Ok(handle_pprof(req).await),
+        "/debug/pprof/heap" => Ok(handle_pprof_heap(req).await),
+        "/debug/gprof/profile" => Ok(handle_gprof(req).await),
+        "/debug/gprof/heap" => Ok(handle_gprof_heap(req).await),
+        "/quitquitquit" => Ok(handle_server_shutdown(
+            state.shutdown_trigger.clone(),
+            req,
+            state.config.current().self_termination_deadline,
+        )
+        .await),
+        "/config_dump" => Ok(handle_config_dump(
+            ConfigDump {
+                proxy_state: state.proxy_state.clone(),
+                static_config: Default::default(),
+                version: BuildInfo::new(),
+                config: state.config.current().as_ref().clone(),
+                certificates: dump_certs(state.cert_manager.borrow()).await,
+            },
+            // req, // bring this back if we start using it
+        )
+        .await),
+        "/certs" => Ok(handle_certs(state.cert_manager.borrow()).await),
+        "/debug/certs/refresh" => Ok(handle_certs_refresh(state.cert_manager.borrow(), req).await),
+        "/debug/workload" => Ok(lookup_workload(
+            &state.proxy_state,
+            &state.config.current(),
+            req.uri().query(),
+        )
+        .await),
+        "/debug/policy" => Ok(lookup_policy(
+            &state.proxy_state,
+            &state.config.current(),
+            req.uri().query(),
+        )
+        .await),
+        "/connections" => Ok(handle_connections(&state.connections)),
+        "/hbone_peers" => Ok(handle_hbone_peers(&state.connections)),
+        "/traffic_pairs" => Ok(handle_traffic_pairs(state.traffic_pairs.as_ref())),
+        "/logging" => Ok(handle_logging(req).await),
+        "/" => Ok(handle_dashboard(req).await),
+        _ => Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
+    }
+}
+
+/// authorize rejects clients of a mutating endpoint that are not allowed to call it. Without mTLS
+/// the admin API is only reachable over localhost, so there is no identity to check. With mTLS it
+/// is reachable from the whole mesh, so only the allowed identities may call it.
+fn authorize(auth: &AdminAuth, peer: Option<&PeerIdentity>) -> Result<(), Response<Full<Bytes>>> {
+    if auth.mtls_identity.is_none() {
+        return Ok(());
+    }
+    match peer {
+        Some(PeerIdentity(id)) if auth.allowed_identities.contains(id) => Ok(()),
+        _ => {
+            let caller = peer.map_or("unknown".to_string(), |PeerIdentity(id)| id.to_string());
+            warn!(%caller, "rejected admin request");
+            Err(plaintext_response(
+                hyper::StatusCode::FORBIDDEN,
+                format!("{caller} is not allowed to call this endpoint\n"),
+            ))
+        }
+    }
+}
+
+/// AdminCertProvider serves the certificate of the admin API's identity, and requires clients to
+/// present one from the same trust domain. The metrics server uses it too.
+#[derive(Clone)]
+pub(crate) struct AdminCertProvider {
+    cert_manager: Arc<SecretManager>,
+    identity: Identity,
+}
+
+impl AdminCertProvider {
+    pub(crate) fn new(cert_manager: Arc<SecretManager>, identity: Identity) -> Self {
+        AdminCertProvider {
+            cert_manager,
+            identity,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl CertProvider for AdminCertProvider {
+    async fn fetch_cert(&mut self, _: &TcpStream) -> Result<boring::ssl::SslAcceptor, TlsError> {
+        let cert = self.cert_manager.fetch_certificate(&self.identity).await?;
+        Ok(cert.mtls_acceptor(Some(&self.identity))?)
     }
 }
 
@@ -147,6 +263,10 @@ async fn handle_dashboard(_req: Request<Incoming>) -> Response<Full<Bytes>> {
             "debug/pprof/profile",
             "build profile using the pprof profiler (if supported)",
         ),
+        (
+            "debug/pprof/heap",
+            "dump a heap profile from jemalloc (if supported)",
+        ),
         (
             "debug/gprof/profile",
             "build profile using the gperftools profiler (if supported)",
@@ -157,6 +277,31 @@ async fn handle_dashboard(_req: Request<Incoming>) -> Response<Full<Bytes>> {
         ),
         ("quitquitquit", "shut down the server"),
         ("config_dump", "dump the current Ztunnel configuration"),
+        ("certs", "dump the current workload certificates"),
+        (
+            "debug/certs/refresh",
+            "fetch the certificate of an identity again now",
+        ),
+        (
+            "debug/workload",
+            "look up the workload with an IP (?ip=<ip>)",
+        ),
+        (
+            "debug/policy",
+            "evaluate the authorization policies for a connection (?src=<ip>&dst=<ip>&port=<port>)",
+        ),
+        (
+            "connections",
+            "dump the connections being proxied over HBONE",
+        ),
+        (
+            "hbone_peers",
+            "dump the inbound HBONE connections and their CONNECT streams",
+        ),
+        (
+            "traffic_pairs",
+            "dump the traffic counted between pairs of identities (if enabled)",
+        ),
         ("logging", "query/changing logging levels"),
     ];
 
@@ -200,9 +345,17 @@ fn dump_cert(x509: &X509) -> CertDump {
         dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
     }
 
+    let subject_alt_names = x509
+        .subject_alt_names()
+        .iter()
+        .flat_map(|sans| sans.iter())
+        .filter_map(|san| san.uri().or_else(|| san.dnsname()))
+        .map(str::to_string)
+        .collect();
     CertDump {
         pem: x509_to_pem(x509),
         serial_number: x509.serial_number().to_bn().unwrap().to_string(),
+        subject_alt_names,
         valid_from: rfc3339(x509.not_before()),
         expiration_time: rfc3339(x509.not_after()),
     }
@@ -233,14 +386,168 @@ async fn dump_certs(cert_manager: &SecretManager) -> Vec<CertsDump> {
     dump
 }
 
-async fn handle_pprof(_req: Request<Incoming>) -> Response<Full<Bytes>> {
+async fn handle_certs(cert_manager: &SecretManager) -> Response<Full<Bytes>> {
+    let vec = serde_json::to_vec(&dump_certs(cert_manager).await).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(vec.into())
+        .unwrap()
+}
+
+async fn handle_certs_refresh(
+    cert_manager: &SecretManager,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    match *req.method() {
+        hyper::Method::POST => refresh_cert(cert_manager, req.uri().query()).await,
+        _ => empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+async fn refresh_cert(cert_manager: &SecretManager, query: Option<&str>) -> Response<Full<Bytes>> {
+    let identity = query.and_then(|q| {
+        url::form_urlencoded::parse(q.as_bytes())
+            .find(|(k, _)| k == "identity")
+            .map(|(_, v)| v.into_owned())
+    });
+    let Some(identity) = identity else {
+        return plaintext_response(
+            hyper::StatusCode::BAD_REQUEST,
+            "usage: POST /debug/certs/refresh?identity=<SPIFFE ID>\n".into(),
+        );
+    };
+    let id = match Identity::from_str(&identity) {
+        Ok(id) => id,
+        Err(e) => return plaintext_response(hyper::StatusCode::BAD_REQUEST, format!("{e}\n")),
+    };
+    if !cert_manager.refresh(&id).await {
+        return plaintext_response(
+            hyper::StatusCode::NOT_FOUND,
+            format!("no certificate for {id}\n"),
+        );
+    }
+    info!(%id, "certificate refresh requested");
+    plaintext_response(
+        hyper::StatusCode::OK,
+        format!("refreshing certificate for {id}\n"),
+    )
+}
+
+fn query_param(query: Option<&str>, key: &str) -> Option<String> {
+    query.and_then(|q| {
+        url::form_urlencoded::parse(q.as_bytes())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    })
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(value).unwrap().into())
+        .unwrap()
+}
+
+async fn lookup_workload(
+    proxy_state: &DemandProxyState,
+    config: &Config,
+    query: Option<&str>,
+) -> Response<Full<Bytes>> {
+    let Some(ip) = query_param(query, "ip").and_then(|ip| ip.parse::<IpAddr>().ok()) else {
+        return plaintext_response(
+            hyper::StatusCode::BAD_REQUEST,
+            "usage: GET /debug/workload?ip=<IP>\n".into(),
+        );
+    };
+    let addr = NetworkAddress {
+        network: config.network.clone(),
+        address: ip,
+    };
+    match proxy_state.fetch_workload(&addr).await {
+        Some(wl) => json_response(&wl),
+        None => plaintext_response(
+            hyper::StatusCode::NOT_FOUND,
+            format!("no workload for {addr}\n"),
+        ),
+    }
+}
+
+/// PolicyLookup is the outcome of evaluating the authorization policies for a hypothetical
+/// connection, as returned by /debug/policy.
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PolicyLookup {
+    /// The identity the source workload would present, if ztunnel knows of it.
+    src_identity: Option<String>,
+    destination: Option<Workload>,
+    #[serde(flatten)]
+    decision: rbac::Decision,
+}
+
+/// lookup_policy runs the same policy evaluation inbound connections go through, without making
+/// a connection. The source is assumed to present the identity of the workload with its IP.
+async fn lookup_policy(
+    proxy_state: &DemandProxyState,
+    config: &Config,
+    query: Option<&str>,
+) -> Response<Full<Bytes>> {
+    let ip = |key| query_param(query, key).and_then(|ip| ip.parse::<IpAddr>().ok());
+    let port = query_param(query, "port").and_then(|p| p.parse::<u16>().ok());
+    let (Some(src), Some(dst), Some(port)) = (ip("src"), ip("dst"), port) else {
+        return plaintext_response(
+            hyper::StatusCode::BAD_REQUEST,
+            "usage: GET /debug/policy?src=<IP>&dst=<IP>&port=<port>\n".into(),
+        );
+    };
+    let network_addr = |address| NetworkAddress {
+        network: config.network.clone(),
+        address,
+    };
+    let src_identity = proxy_state
+        .fetch_workload(&network_addr(src))
+        .await
+        .map(|wl| wl.identity());
+    let destination = proxy_state.fetch_workload(&network_addr(dst)).await;
+    let conn = rbac::Connection {
+        src_identity: src_identity.clone(),
+        src_ip: src,
+        dst_network: config.network.clone(),
+        dst: SocketAddr::new(dst, port),
+    };
+    let decision = proxy_state.assert_rbac(&conn, config.rbac_audit).await;
+    json_response(&PolicyLookup {
+        src_identity: src_identity.map(|id| id.to_string()),
+        destination,
+        decision,
+    })
+}
+
+/// How long a CPU profile runs for, unless the `seconds` query parameter says otherwise.
+const DEFAULT_PROFILE_DURATION: Duration = Duration::from_secs(10);
+/// The longest a CPU profile runs for, whatever `seconds` asks for, so a request can't keep the
+/// profiler running indefinitely.
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(300);
+
+async fn handle_pprof(req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let duration = req
+        .uri()
+        .query()
+        .and_then(|q| {
+            url::form_urlencoded::parse(q.as_bytes())
+                .find(|(k, _)| k == "seconds")
+                .and_then(|(_, v)| v.parse().ok())
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PROFILE_DURATION)
+        .min(MAX_PROFILE_DURATION);
     let guard = pprof::ProfilerGuardBuilder::default()
         .frequency(1000)
         // .blocklist(&["libc", "libgcc", "pthread", "vdso"])
         .build()
         .unwrap();
 
-    tokio::time::sleep(Duration::from_secs(10)).await;
+    tokio::time::sleep(duration).await;
     match guard.report().build() {
         Ok(report) => {
             let profile = report.pprof().unwrap();
@@ -306,6 +613,36 @@ async fn handle_config_dump(
         .unwrap()
 }
 
+fn handle_connections(connections: &[ConnectionTracker]) -> Response<Full<Bytes>> {
+    let dump: Vec<_> = connections.iter().flat_map(|c| c.dump()).collect();
+    let vec = serde_json::to_vec(&dump).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(vec.into())
+        .unwrap()
+}
+
+fn handle_hbone_peers(connections: &[ConnectionTracker]) -> Response<Full<Bytes>> {
+    let dump: Vec<_> = connections.iter().flat_map(|c| c.dump_peers()).collect();
+    let vec = serde_json::to_vec(&dump).unwrap();
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(vec.into())
+        .unwrap()
+}
+
+fn handle_traffic_pairs(pairs: Option<&TrafficPairs>) -> Response<Full<Bytes>> {
+    let Some(pairs) = pairs else {
+        return plaintext_response(
+            hyper::StatusCode::NOT_FOUND,
+            "traffic pairs are not counted; set TRAFFIC_PAIRS_WINDOW to count them\n".to_string(),
+        );
+    };
+    json_response(&pairs.dump())
+}
+
 //mirror envoy's behavior: https://www.envoyproxy.io/docs/envoy/latest/operations/admin#post--logging
 //NOTE: multiple query parameters is not supported, for example
 //curl -X POST http://127.0.0.1:15000/logging?"tap=debug&router=debug"
@@ -313,6 +650,7 @@ static HELP_STRING: &str = "
 usage: POST /logging\t\t\t\t\t\t(To list current level)
 usage: POST /logging?level=<level>\t\t\t\t(To change global levels)
 usage: POST /logging?level={mod1}:{level1},{mod2}:{level2}\t(To change specific mods' logging level)
+usage: POST /logging?level=<level>&reset\t\t\t(To discard previous changes first)
 
 hint: loglevel:\terror|warn|info|debug|trace|off
 hint: mod_name:\tthe module name, i.e. ztunnel::proxy
@@ -358,11 +696,11 @@ fn list_loggers() -> Response<Full<Bytes>> {
 }
 
 fn change_log_level(reset: bool, level: &str) -> Response<Full<Bytes>> {
-    match tracing::level_filters::LevelFilter::from_str(level) {
-        Ok(level_filter) => {
+    match telemetry::parse_level(level) {
+        Ok(directives) => {
             // Valid level, continue processing
-            tracing::info!("Parsed level: {:?}", level_filter);
-            match telemetry::set_level(reset, level) {
+            tracing::info!("Parsed level: {}", directives);
+            match telemetry::set_level(reset, &directives) {
                 Ok(_) => list_loggers(),
                 Err(e) => plaintext_response(
                     hyper::StatusCode::BAD_REQUEST,
@@ -438,15 +776,64 @@ async fn handle_gprof_heap(_req: Request<Incoming>) -> Response<Full<Bytes>> {
         .unwrap()
 }
 
+/// handle_pprof_heap dumps jemalloc's heap profile. It is in the heap_v2 format, which
+/// `go tool pprof` and `jeprof` read given the ztunnel binary.
+#[cfg(feature = "jemalloc")]
+async fn handle_pprof_heap(_req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let res = tokio::task::spawn_blocking(dump_heap_profile)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r);
+    match res {
+        Ok(buffer) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .body(buffer.into())
+            .unwrap(),
+        Err(err) => plaintext_response(
+            hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to dump heap profile: {err}\n"),
+        ),
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+fn dump_heap_profile() -> anyhow::Result<Vec<u8>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Created exclusively and readable only by us, so no one else can plant or read the dump.
+    let file = tempfile::Builder::new()
+        .prefix("ztunnel-heap-")
+        .suffix(".prof")
+        .tempfile()?;
+    let c_path = CString::new(file.path().as_os_str().as_bytes())?;
+    // Safety: prof.dump takes the path to write to as a C string, which outlives the call.
+    unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", c_path.as_ptr()) }
+        .map_err(|e| anyhow::anyhow!("{e} (is profiling enabled?)"))?;
+    // The file is removed once dropped.
+    Ok(std::fs::read(file.path())?)
+}
+
+#[cfg(not(feature = "jemalloc"))]
+async fn handle_pprof_heap(_req: Request<Incoming>) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+        .body("jemalloc not enabled".into())
+        .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::authorize;
     use super::change_log_level;
     use super::dump_certs;
     use super::handle_config_dump;
     use super::ConfigDump;
     use crate::admin::HELP_STRING;
     use crate::config::construct_config;
+    use crate::config::AdminAuth;
     use crate::config::ProxyConfig;
+    use crate::hyper_util::PeerIdentity;
     use crate::identity;
     use crate::test_helpers::{get_response_str, helpers, new_proxy_state};
     use crate::xds::istio::security::string_match::MatchType as XdsMatchType;
@@ -457,11 +844,13 @@ mod tests {
     use crate::xds::istio::security::Rule as XdsRule;
     use crate::xds::istio::security::StringMatch as XdsStringMatch;
     use crate::xds::istio::workload::gateway_address::Destination as XdsDestination;
+    use crate::xds::istio::workload::ConnectPolicy as XdsConnectPolicy;
     use crate::xds::istio::workload::GatewayAddress as XdsGatewayAddress;
     use crate::xds::istio::workload::NetworkAddress as XdsNetworkAddress;
     use crate::xds::istio::workload::Port as XdsPort;
     use crate::xds::istio::workload::PortList as XdsPortList;
     use crate::xds::istio::workload::Service as XdsService;
+    use crate::xds::istio::workload::SessionAffinity as XdsSessionAffinity;
     use crate::xds::istio::workload::Workload as XdsWorkload;
     use crate::xds::istio::workload::WorkloadType as XdsWorkloadType;
     use bytes::Bytes;
@@ -566,6 +955,7 @@ mod tests {
                       AJauM4JePEb8Fqw=\n\
                       -----END CERTIFICATE-----\n",
               "serial_number": "588850990443535479077311695632745359443207891470",
+              "subject_alt_names": ["spiffe://trust_domain/ns/namespace/sa/sa-0"],
               "valid_from": "2023-03-11T05:57:26Z"
             }],
             "cert_chain": [{
@@ -615,6 +1005,7 @@ mod tests {
                       hozHmFldMalh6Ss=\n\
                       -----END CERTIFICATE-----\n",
               "serial_number": "528170730419860468572163268563070820131458817969",
+              "subject_alt_names": ["spiffe://trust_domain/ns/namespace/sa/sa-1"],
               "valid_from": "2023-03-11T06:57:26Z"
             }],
             "cert_chain": [{
@@ -654,6 +1045,28 @@ mod tests {
         pending_fetch.await.unwrap().unwrap();
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_refresh_cert() {
+        let manager = identity::mock::new_secret_manager(Duration::from_secs(60 * 60));
+        let id = identity::Identity::default();
+        let before = manager.fetch_certificate(&id).await.unwrap();
+
+        let resp = refresh_cert(&manager, None).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = refresh_cert(&manager, Some("identity=not-spiffe")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+        let resp = refresh_cert(&manager, Some("identity=spiffe://td/ns/ns/sa/unknown")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let resp = refresh_cert(&manager, Some(&format!("identity={id}"))).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let after = manager.fetch_certificate(&id).await.unwrap();
+        // The certificate was issued again, a second later.
+        assert_ne!(before, after);
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_dump_config() {
         let manager = identity::mock::new_secret_manager_cfg(identity::mock::SecretManagerConfig {
@@ -699,6 +1112,9 @@ mod tests {
             cluster_id: "Kubernetes".to_string(),
             authorization_policies: Vec::new(),
             native_tunnel: false,
+            excluded_outbound_ports: vec![7000],
+            included_outbound_ports: vec![80, 7000],
+            ports: vec![8080],
             workload_type: XdsWorkloadType::Deployment.into(),
             services: HashMap::from([(
                 "ns/svc1.ns.svc.cluster.local".to_string(),
@@ -725,6 +1141,11 @@ mod tests {
                 target_port: 80,
             }],
             subject_alt_names: vec!["SAN1".to_string(), "SAN2".to_string()],
+            session_affinity: XdsSessionAffinity::ClientIp.into(),
+            connect_policy: Some(XdsConnectPolicy {
+                timeout_ms: 500,
+                max_retries: 2,
+            }),
             // ..Default::default() // intentionally don't default. we want all fields populated
         };
 
@@ -773,6 +1194,7 @@ mod tests {
                     }],
                 }],
             }],
+            audit: false,
             // ..Default::default() // intentionally don't default. we want all fields populated
         };
 
@@ -817,6 +1239,118 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_lookup_workload_and_policy() {
+        let workload = |ip: u8, name: &str| XdsWorkload {
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, ip])],
+            uid: name.to_string(),
+            name: name.to_string(),
+            namespace: "ns".to_string(),
+            trust_domain: "cluster.local".to_string(),
+            service_account: name.to_string(),
+            network: "defaultnw".to_string(),
+            ..Default::default()
+        };
+        let port_rule = |port| XdsRule {
+            clauses: vec![XdsClause {
+                matches: vec![XdsMatch {
+                    destination_ports: vec![port],
+                    ..Default::default()
+                }],
+            }],
+        };
+        let deny = XdsAuthorization {
+            name: "deny".to_string(),
+            namespace: "ns".to_string(),
+            scope: 1,
+            action: 1,
+            rules: vec![port_rule(9090), port_rule(8080)],
+            ..Default::default()
+        };
+        let proxy_state = new_proxy_state(
+            &[workload(1, "client"), workload(2, "server")],
+            &[],
+            &[deny],
+        );
+        let mut config = construct_config(ProxyConfig::default()).unwrap();
+        config.network = "defaultnw".to_string();
+
+        async fn body(resp: Response<Full<Bytes>>) -> serde_json::Value {
+            let data = resp.into_body().frame().await.unwrap().unwrap();
+            serde_json::from_slice(&data.into_data().unwrap()).unwrap()
+        }
+
+        let resp = lookup_workload(&proxy_state, &config, Some("ip=127.0.0.2")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        assert_eq!(body(resp).await["name"], "server");
+        let resp = lookup_workload(&proxy_state, &config, Some("ip=127.0.0.9")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        let resp = lookup_workload(&proxy_state, &config, Some("ip=server")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+
+        let resp = lookup_policy(
+            &proxy_state,
+            &config,
+            Some("src=127.0.0.1&dst=127.0.0.2&port=8080"),
+        )
+        .await;
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let decision = body(resp).await;
+        assert_eq!(decision["allowed"], false);
+        assert_eq!(
+            decision["srcIdentity"],
+            "spiffe://cluster.local/ns/ns/sa/client"
+        );
+        assert_eq!(decision["destination"]["name"], "server");
+        assert_eq!(
+            decision["matched"],
+            serde_json::json!({"policy": "ns/deny", "rule": 1})
+        );
+
+        let resp = lookup_policy(
+            &proxy_state,
+            &config,
+            Some("src=127.0.0.1&dst=127.0.0.2&port=80"),
+        )
+        .await;
+        let decision = body(resp).await;
+        assert_eq!(decision["allowed"], true);
+        assert!(decision.get("matched").is_none());
+
+        let resp = lookup_policy(&proxy_state, &config, Some("src=127.0.0.1&dst=127.0.0.2")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let id = |sa: &str| identity::Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "istio-system".to_string(),
+            service_account: sa.to_string(),
+        };
+        let operator = PeerIdentity(id("operator"));
+        let other = PeerIdentity(id("other"));
+
+        // Without mTLS, there is no identity to check.
+        let mut auth = AdminAuth {
+            mtls_identity: None,
+            allowed_identities: vec![id("operator")],
+            metrics: false,
+        };
+        assert!(authorize(&auth, None).is_ok());
+
+        auth.mtls_identity = Some(id("ztunnel"));
+        assert!(authorize(&auth, Some(&operator)).is_ok());
+        let resp = authorize(&auth, Some(&other)).unwrap_err();
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        assert!(authorize(&auth, None).is_err());
+
+        // With no allow list, no mTLS client is allowed.
+        auth.allowed_identities.clear();
+        assert!(authorize(&auth, Some(&operator)).is_err());
+        assert!(authorize(&auth, Some(&other)).is_err());
+    }
+
     // each of these tests assert that we can change the log level and the
     // appropriate response string is returned.
     //
@@ -858,5 +1392,28 @@ mod tests {
         let resp = change_log_level(true, "off");
         let resp_str = get_response_str(resp).await;
         assert!(resp_str.contains("current log level is off\n"));
+
+        let resp = change_log_level(true, "ztunnel::proxy:debug");
+        let resp_str = get_response_str(resp).await;
+        assert!(resp_str.contains("ztunnel::proxy=debug"));
+
+        // without reset, overrides accumulate
+        let resp = change_log_level(false, "ztunnel::dns=trace");
+        let resp_str = get_response_str(resp).await;
+        assert!(resp_str.contains("ztunnel::proxy=debug"));
+        assert!(resp_str.contains("ztunnel::dns=trace"));
+
+        let resp = change_log_level(true, "warn,ztunnel::proxy::outbound:trace");
+        let resp_str = get_response_str(resp).await;
+        assert!(resp_str.contains("ztunnel::proxy::outbound=trace"));
+        assert!(!resp_str.contains("ztunnel::dns=trace"));
+
+        let resp = change_log_level(true, "ztunnel::proxy");
+        let resp_str = get_response_str(resp).await;
+        assert!(resp_str.contains(HELP_STRING));
+
+        let resp = change_log_level(true, "=debug");
+        let resp_str = get_response_str(resp).await;
+        assert!(resp_str.contains(HELP_STRING));
     }
 }
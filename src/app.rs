@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::borrow::Cow;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::pin::Pin;
@@ -21,23 +22,64 @@ use std::thread;
 use std::time::Duration;
 
 use anyhow::Context;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
+use tokio::sync::oneshot;
 use tokio::task::JoinSet;
-use tracing::{warn, Instrument};
+use tracing::{info, warn, Instrument};
 
+use crate::config::{CaProvider, RuntimeMode};
 use crate::identity::SecretManager;
-use crate::state::ProxyStateManager;
-use crate::{admin, config, identity, metrics, proxy, readiness, signal};
-use crate::{dns, xds};
+use crate::proxy::{
+    ConnectionEvents, ConnectionHooks, ConnectionTracker, FairQueues, FlowExporter, TrafficPairs,
+};
+use crate::state::{DemandProxyState, ProxyStateManager};
+use crate::{admin, config, handoff, identity, metrics, proxy, readiness, signal, socket, tls};
+use crate::{cert_fetcher, dns, xds};
 
 pub async fn build_with_cert(
     config: config::Config,
     cert_manager: Arc<SecretManager>,
 ) -> anyhow::Result<Bound> {
-    // Start the data plane worker pool.
-    let data_plane_pool = new_data_plane_pool(config.num_worker_threads);
+    build_with_hooks(config, cert_manager, ConnectionHooks::default()).await
+}
+
+/// build_with_hooks is [build_with_cert], with `hooks` called as the proxy's connections open and
+/// close.
+pub async fn build_with_hooks(
+    config: config::Config,
+    cert_manager: Arc<SecretManager>,
+    hooks: ConnectionHooks,
+) -> anyhow::Result<Bound> {
+    // Start the data plane worker pool. In per-core mode there is one pool per worker thread; the
+    // first one also runs the tasks that do not need to be spread out.
+    let data_plane_pools = match config.runtime_mode {
+        RuntimeMode::Shared => vec![new_data_plane_pool(config.num_worker_threads)],
+        RuntimeMode::PerCore => (0..config.num_worker_threads)
+            .map(new_worker_runtime)
+            .collect(),
+    };
+    let data_plane_pool = data_plane_pools[0].clone();
+
+    if config.io_uring {
+        socket::enable_io_uring();
+    }
+    proxy::fault::configure(&config.fault_injection);
+    tls::groups::configure(&config.tls_key_exchange_groups);
+    tls::secret::configure(config.hardened_key_storage);
+    if let Some(path) = &config.tls_key_log {
+        tls::keylog::configure(path)
+            .with_context(|| format!("failed to open TLS key log {}", path.display()))?;
+    }
 
     let shutdown = signal::Shutdown::new();
+    // Take over the listeners of a previous ztunnel before anything binds, so we do not race it.
+    if let Some(path) = &config.listener_handoff_path {
+        match handoff::inherit(path).await {
+            Ok(count) => info!(count, "inherited listeners from previous process"),
+            Err(e) => warn!("failed to inherit listeners, binding new ones: {e}"),
+        }
+    }
     // Setup a drain channel. drain_tx is used to trigger a drain, which will complete
     // once all drain_rx handlers are dropped.
     // Any component which wants time to gracefully exit should take in a drain_rx clone,
@@ -59,11 +101,26 @@ pub async fn build_with_cert(
         None
     };
 
+    // Liveness is driven by heartbeats from each runtime; if a runtime stops making progress, so
+    // do its heartbeats.
+    let liveness = readiness::Liveness::new();
+    let main_heartbeat = liveness.register_heartbeat("main");
+    let data_plane_heartbeats: Vec<_> = match config.runtime_mode {
+        RuntimeMode::Shared => vec![liveness.register_heartbeat("data plane")],
+        RuntimeMode::PerCore => (0..data_plane_pools.len())
+            .map(|i| liveness.register_heartbeat(&format!("data plane worker {i}")))
+            .collect(),
+    };
+
     // Create and start the readiness server.
-    let readiness_server = readiness::Server::new(config.clone(), drain_rx.clone(), ready.clone())
-        .await
-        .context("readiness server starts")?;
+    let readiness_server =
+        readiness::Server::new(config.clone(), drain_rx.clone(), ready.clone(), liveness)
+            .await
+            .context("readiness server starts")?;
     let readiness_address = readiness_server.address();
+    if let Some(path) = config.readiness_file.clone() {
+        tokio::spawn(readiness::publish(ready.clone(), path, drain_rx.clone()));
+    }
     // Run the readiness server in the data plane worker pool.
     data_plane_pool.send(DataPlaneTask {
         block_shutdown: false,
@@ -72,17 +129,116 @@ pub async fn build_with_cert(
             Ok(())
         }),
     })?;
+    for (pool, heartbeat) in data_plane_pools.iter().zip(data_plane_heartbeats) {
+        pool.send(DataPlaneTask {
+            block_shutdown: false,
+            fut: Box::pin(async move {
+                heartbeat.run().await;
+                Ok(())
+            }),
+        })?;
+    }
+    tokio::spawn(main_heartbeat.run());
 
     // Register metrics.
     let mut registry = Registry::default();
     let istio_registry = metrics::sub_registry(&mut registry);
     let _ = metrics::meta::Metrics::new(istio_registry);
     let xds_metrics = xds::Metrics::new(istio_registry);
-    let proxy_metrics = if config.proxy {
-        Some(proxy::Metrics::new(istio_registry))
-    } else {
-        None
+    // The clients of remote clusters record to their own metrics, partitioned by a `cluster` label.
+    let remote_xds_metrics = config
+        .remote_xds_clusters
+        .iter()
+        .map(|remote| {
+            xds::Metrics::new(istio_registry.sub_registry_with_label((
+                Cow::Borrowed("cluster"),
+                Cow::Owned(remote.cluster_id.clone()),
+            )))
+        })
+        .collect();
+    let cert_metrics = cert_fetcher::Metrics::new(istio_registry);
+    // Counted from the proxy's connections, for the next restart to prefetch in order.
+    let (prefetch_history, hooks) = match config.cert_prefetch_history.clone() {
+        Some(path) => {
+            let history = cert_fetcher::History::load(&path);
+            tokio::spawn(history.clone().persist(path));
+            (Some(history.clone()), hooks.with(history))
+        }
+        None => (None, hooks),
     };
+    if let Some(path) = &config.crl_path {
+        tls::crl::configure(path, config.crl_refresh_interval, istio_registry)?;
+    }
+    if config.hbone_early_data {
+        tls::early_data::configure(istio_registry)?;
+    }
+    let fair_queues = config.fair_queueing.clone().map(FairQueues::new);
+    let connection_events = match &config.connection_events_socket {
+        Some(path) => {
+            let events = ConnectionEvents::default();
+            events
+                .serve(path, drain_rx.clone())
+                .with_context(|| format!("connection events serve on {}", path.display()))?;
+            Some(events)
+        }
+        None => None,
+    };
+    let flow_exporter = match &config.ipfix {
+        Some(ipfix) => {
+            Some(FlowExporter::export(ipfix, drain_rx.clone()).context("ipfix export starts")?)
+        }
+        None => None,
+    };
+    let traffic_pairs = match &config.traffic_pairs {
+        Some(cfg) => {
+            let pairs = TrafficPairs::new(cfg.window);
+            pairs
+                .report(
+                    cfg,
+                    config.xds_root_cert.clone(),
+                    config.auth.clone(),
+                    config.local_node.clone().unwrap_or_default(),
+                    drain_rx.clone(),
+                )
+                .context("traffic pair reports start")?;
+            Some(pairs)
+        }
+        None => None,
+    };
+    // Per-core workers each record to their own metrics, partitioned by a `worker` label.
+    let proxy_metrics: Vec<proxy::Metrics> =
+        match (config.proxy, config.runtime_mode) {
+            (false, _) => Vec::new(),
+            (true, RuntimeMode::Shared) => vec![proxy::Metrics::new(istio_registry)
+                .with_namespace_series_limit(config.metrics_namespace_series_limit)
+                .with_fair_queues(fair_queues)
+                .with_connection_events(connection_events)
+                .with_flow_exporter(flow_exporter)
+                .with_traffic_pairs(traffic_pairs.clone())],
+            (true, RuntimeMode::PerCore) => (0..data_plane_pools.len())
+                .map(|i| {
+                    proxy::Metrics::new(istio_registry.sub_registry_with_label((
+                        Cow::Borrowed("worker"),
+                        Cow::Owned(i.to_string()),
+                    )))
+                    .with_namespace_series_limit(config.metrics_namespace_series_limit)
+                    .with_fair_queues(fair_queues.clone())
+                    .with_connection_events(connection_events.clone())
+                    .with_flow_exporter(flow_exporter.clone())
+                    .with_traffic_pairs(traffic_pairs.clone())
+                })
+                .collect(),
+        };
+    let draining_connections = Gauge::default();
+    istio_registry.register(
+        "draining_connections",
+        "The number of connections still open while shutting down",
+        draining_connections.clone(),
+    );
+    let connections: Vec<_> = proxy_metrics
+        .iter()
+        .map(|m| m.connections.clone())
+        .collect();
     let dns_metrics = if config.dns_proxy {
         Some(dns::Metrics::new(istio_registry))
     } else {
@@ -90,9 +246,14 @@ pub async fn build_with_cert(
     };
 
     // Create and start the metrics server.
-    let metrics_server = metrics::Server::new(config.clone(), drain_rx.clone(), registry)
-        .await
-        .context("stats server starts")?;
+    let metrics_server = metrics::Server::new(
+        config.clone(),
+        drain_rx.clone(),
+        registry,
+        cert_manager.clone(),
+    )
+    .await
+    .context("stats server starts")?;
     let metrics_address = metrics_server.address();
     // Run the metrics sever in the current tokio worker pool.
     metrics_server.spawn();
@@ -101,19 +262,31 @@ pub async fn build_with_cert(
     let state_mgr = ProxyStateManager::new(
         config.clone(),
         xds_metrics,
+        remote_xds_metrics,
+        cert_metrics,
+        prefetch_history,
         state_mgr_task,
         cert_manager.clone(),
     )
     .await?;
     let state = state_mgr.state();
+    if let Some(policy_sync) = state_mgr.policy_sync() {
+        tokio::spawn(policy_sync.gate(ready.clone()));
+    }
+
+    // Watch for configuration changes that can be applied without a restart.
+    let (config_watcher, live_config) = config::reload::Watcher::new(config.clone());
+    tokio::spawn(config_watcher.run());
 
     // Create and start the admin server.
     let admin_server = admin::Service::new(
-        config.clone(),
+        live_config.clone(),
         state.clone(),
         shutdown.trigger(),
         drain_rx.clone(),
         cert_manager.clone(),
+        connections.clone(),
+        traffic_pairs,
     )
     .await
     .context("admin server starts")?;
@@ -126,27 +299,24 @@ pub async fn build_with_cert(
 
     // Optionally create the HBONE proxy.
     let proxy_addresses = if config.proxy {
-        let proxy = proxy::Proxy::new(
-            config.clone(),
-            state.clone(),
-            cert_manager.clone(),
-            proxy_metrics.unwrap(),
-            drain_rx.clone(),
-        )
-        .await?;
-        let addresses = proxy.addresses();
-
-        // Run the HBONE proxy in the data plane worker pool.
-        data_plane_pool.send(DataPlaneTask {
-            block_shutdown: true,
-            fut: Box::pin(async move {
-                proxy.run().in_current_span().await;
-                Ok(())
-            }),
-        })?;
+        // Each pool gets its own proxy, with its own listeners and connection pool.
+        let mut addresses = None;
+        for (pool, metrics) in data_plane_pools.iter().zip(proxy_metrics) {
+            let bound = start_proxy(
+                pool,
+                live_config.clone(),
+                state.clone(),
+                cert_manager.clone(),
+                metrics,
+                hooks.clone(),
+                drain_rx.clone(),
+            )
+            .await?;
+            addresses.get_or_insert(bound);
+        }
 
         drop(proxy_task);
-        Some(addresses)
+        addresses
     } else {
         None
     };
@@ -158,7 +328,7 @@ pub async fn build_with_cert(
             config.dns_proxy_addr,
             config.network,
             state.clone(),
-            dns::forwarder_for_mode(config.proxy_mode)?,
+            dns::forwarder_for_mode(config.proxy_mode, &config.dns_upstreams)?,
             dns_metrics.unwrap(),
         )
         .await?;
@@ -179,8 +349,16 @@ pub async fn build_with_cert(
         None
     };
 
+    // All listeners are bound; offer them to our eventual replacement.
+    if let Some(path) = config.listener_handoff_path.clone() {
+        handoff::serve(path, shutdown.trigger()).context("listener handoff starts")?;
+    }
+
     Ok(Bound {
         drain_tx,
+        drain_duration: config.termination_drain_duration,
+        connections,
+        draining_connections,
         shutdown,
         readiness_address,
         admin_address,
@@ -192,25 +370,75 @@ pub async fn build_with_cert(
 
 struct DataPlaneTask {
     block_shutdown: bool,
-    fut: Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + Sync + 'static>>,
+    fut: Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'static>>,
+}
+
+/// start_proxy creates a proxy from within `pool`, so its listeners are driven by that pool's
+/// runtime, and runs it there. It returns once the proxy's listeners are bound.
+async fn start_proxy(
+    pool: &mpsc::Sender<DataPlaneTask>,
+    cfg: config::LiveConfig,
+    state: DemandProxyState,
+    cert_manager: Arc<SecretManager>,
+    metrics: proxy::Metrics,
+    hooks: ConnectionHooks,
+    drain_rx: drain::Watch,
+) -> anyhow::Result<proxy::Addresses> {
+    let (tx, rx) = oneshot::channel();
+    pool.send(DataPlaneTask {
+        block_shutdown: true,
+        fut: Box::pin(
+            async move {
+                let proxy =
+                    match proxy::Proxy::new(cfg, state, cert_manager, metrics, hooks, drain_rx)
+                        .await
+                    {
+                        Ok(proxy) => proxy,
+                        Err(e) => {
+                            let _ = tx.send(Err(e));
+                            return Ok(());
+                        }
+                    };
+                let _ = tx.send(Ok(proxy.addresses()));
+                proxy.run().in_current_span().await;
+                Ok(())
+            }
+            .in_current_span(),
+        ),
+    })?;
+    let addresses = rx
+        .await
+        .context("data plane stopped before the proxy started")??;
+    Ok(addresses)
 }
 
 fn new_data_plane_pool(num_worker_threads: usize) -> mpsc::Sender<DataPlaneTask> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder
+        .worker_threads(num_worker_threads)
+        .thread_name_fn(|| {
+            static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
+            let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
+            format!("ztunnel-proxy-{id}")
+        });
+    spawn_data_plane(builder)
+}
+
+/// new_worker_runtime starts a single-threaded data plane for [RuntimeMode::PerCore]. Tasks sent to
+/// it never leave its thread.
+fn new_worker_runtime(id: usize) -> mpsc::Sender<DataPlaneTask> {
+    let mut builder = tokio::runtime::Builder::new_current_thread();
+    builder.thread_name(format!("ztunnel-worker-{id}"));
+    spawn_data_plane(builder)
+}
+
+fn spawn_data_plane(mut builder: tokio::runtime::Builder) -> mpsc::Sender<DataPlaneTask> {
     let (tx, rx) = mpsc::channel();
 
     let span = tracing::span::Span::current();
     thread::spawn(move || {
         let _span = span.enter();
-        let runtime = tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(num_worker_threads)
-            .thread_name_fn(|| {
-                static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
-                let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
-                format!("ztunnel-proxy-{id}")
-            })
-            .enable_all()
-            .build()
-            .unwrap();
+        let runtime = builder.enable_all().build().unwrap();
         runtime.block_on(
             async move {
                 let mut join_set = JoinSet::new();
@@ -246,12 +474,64 @@ fn new_data_plane_pool(num_worker_threads: usize) -> mpsc::Sender<DataPlaneTask>
 }
 
 pub async fn build(config: config::Config) -> anyhow::Result<Bound> {
+    let cert_manager = new_cert_manager(&config, None)?;
+    build_with_cert(config, cert_manager).await
+}
+
+/// build_with_key_provider is [build] for builds that embed ztunnel with a key held elsewhere. The
+/// certificates are read from the files of the file CA provider, all but `key.pem`, and signed for
+/// by `provider`.
+pub async fn build_with_key_provider(
+    config: config::Config,
+    provider: Arc<dyn tls::KeyProvider>,
+) -> anyhow::Result<Bound> {
+    let cert_manager = new_cert_manager(&config, Some(provider))?;
+    build_with_cert(config, cert_manager).await
+}
+
+fn new_cert_manager(
+    config: &config::Config,
+    provider: Option<Arc<dyn tls::KeyProvider>>,
+) -> anyhow::Result<Arc<SecretManager>> {
+    if provider.is_some() && (config.fake_ca || !matches!(config.ca_provider, CaProvider::File(_)))
+    {
+        anyhow::bail!("a key provider requires the file CA provider");
+    }
     let cert_manager = if config.fake_ca {
         identity::mock::new_secret_manager(Duration::from_secs(86400))
     } else {
-        Arc::new(SecretManager::new(config.clone())?)
+        match &config.ca_provider {
+            CaProvider::Istiod => Arc::new(SecretManager::new(config.clone())?),
+            CaProvider::SelfSigned => Arc::new(SecretManager::new_with_client(
+                identity::SelfSignedCaClient::new(&config.cluster_domain)?,
+            )),
+            #[cfg(unix)]
+            CaProvider::Sds(path) => Arc::new(SecretManager::new_with_client(
+                identity::SdsClient::new(path),
+            )),
+            #[cfg(unix)]
+            CaProvider::WorkloadApi(path) => {
+                let client = identity::WorkloadApiClient::new(path);
+                let cert_manager = Arc::new(SecretManager::new_with_client(client.clone()));
+                tokio::spawn(client.watch(Arc::downgrade(&cert_manager)));
+                cert_manager
+            }
+            #[cfg(not(unix))]
+            CaProvider::Sds(_) | CaProvider::WorkloadApi(_) => {
+                anyhow::bail!("the sds and workload-api CA providers require Unix domain sockets")
+            }
+            CaProvider::File(dir) => {
+                let client = match provider {
+                    Some(provider) => identity::FileCaClient::keyless(dir, provider)?,
+                    None => identity::FileCaClient::new(dir)?,
+                };
+                let cert_manager = Arc::new(SecretManager::new_with_client(client.clone()));
+                tokio::spawn(client.watch(Arc::downgrade(&cert_manager)));
+                cert_manager
+            }
+        }
     };
-    build_with_cert(config, cert_manager).await
+    Ok(cert_manager)
 }
 
 pub struct Bound {
@@ -264,8 +544,14 @@ pub struct Bound {
 
     pub shutdown: signal::Shutdown,
     drain_tx: drain::Signal,
+    drain_duration: Duration,
+    connections: Vec<ConnectionTracker>,
+    draining_connections: Gauge,
 }
 
+/// How often the connections left open are reported while draining.
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
 impl Bound {
     pub async fn wait_termination(self) -> anyhow::Result<()> {
         // Wait for a signal to shutdown from explicit admin shutdown or signal
@@ -273,8 +559,39 @@ impl Bound {
 
         // Start a drain; this will attempt to end all connections
         // or itself be interrupted by a stronger TERM signal, whichever comes first.
-        self.drain_tx.drain().await;
+        // Connections already open get until the drain duration to close; we exit as soon as
+        // they all have, so a preStop hook or terminationGracePeriodSeconds need not be guessed.
+        let drain = self.drain_tx.drain();
+        tokio::pin!(drain);
+        let deadline = tokio::time::sleep(self.drain_duration);
+        tokio::pin!(deadline);
+        let mut report = tokio::time::interval(DRAIN_REPORT_INTERVAL);
+        let mut drained = false;
+        loop {
+            tokio::select! {
+                _ = &mut drain, if !drained => drained = true,
+                _ = report.tick() => {}
+                _ = &mut deadline => {
+                    let remaining = self.active_connections();
+                    if remaining > 0 {
+                        warn!(remaining, "drain duration elapsed, closing remaining connections");
+                    }
+                    break;
+                }
+            }
+            let remaining = self.active_connections();
+            self.draining_connections.set(remaining as i64);
+            if remaining == 0 && drained {
+                info!("all connections closed, shutting down");
+                break;
+            }
+            info!(remaining, "draining connections");
+        }
 
         Ok(())
     }
+
+    fn active_connections(&self) -> usize {
+        self.connections.iter().map(ConnectionTracker::active).sum()
+    }
 }
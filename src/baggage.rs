@@ -12,46 +12,99 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! Peer metadata, exchanged in the W3C baggage header of HBONE CONNECT requests and responses, so
+//! that each end can label its telemetry with the other even when the peer workload is not in its
+//! own view of the mesh, such as across clusters.
+
 use hyper::{
     header::{GetAll, ToStrError},
     http::HeaderValue,
 };
 
+use crate::state::workload::Workload;
+
+/// The most baggage read from a peer, in bytes. Anything past it is ignored.
+const MAX_BAGGAGE_LEN: usize = 1024;
+/// The longest value accepted, that of a Kubernetes DNS subdomain name.
+const MAX_VALUE_LEN: usize = 253;
+
 #[derive(Default)]
 pub struct Baggage {
     pub cluster_id: Option<String>,
     pub namespace: Option<String>,
     pub workload_name: Option<String>,
+    pub service_account: Option<String>,
     pub service_name: Option<String>,
     pub revision: Option<String>,
 }
 
+/// valid returns whether `v` can be carried as a baggage value, and used as a metric label,
+/// without escaping.
+fn valid(v: &str) -> bool {
+    !v.is_empty()
+        && v.len() <= MAX_VALUE_LEN
+        && v.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._/:".contains(&b))
+}
+
+/// or_empty returns `v` if it is valid, so that a peer never has to guess where a value ends.
+fn or_empty(v: &str) -> &str {
+    if valid(v) {
+        v
+    } else {
+        ""
+    }
+}
+
+/// encode returns the baggage describing `w`.
+pub fn encode(w: &Workload, cluster_id: &str) -> String {
+    let workload_type = match w.workload_type.as_str() {
+        t @ ("deployment" | "cronjob" | "pod" | "job") => t,
+        _ => "deployment",
+    };
+    format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},k8s.serviceaccount.name={service_account},service.name={name},service.version={version}",
+            cluster = or_empty(cluster_id),
+            namespace = or_empty(&w.namespace),
+            workload_name = or_empty(&w.workload_name),
+            service_account = or_empty(&w.service_account),
+            name = or_empty(&w.canonical_name),
+            version = or_empty(&w.canonical_revision),
+    )
+}
+
+/// parse_baggage_header reads the peer metadata out of baggage headers. Entries that are not
+/// valid, or that are past [MAX_BAGGAGE_LEN], are ignored.
 pub fn parse_baggage_header(headers: GetAll<HeaderValue>) -> Result<Baggage, ToStrError> {
     let mut baggage = Baggage {
         ..Default::default()
     };
+    let mut read = 0;
     for hv in headers.iter() {
         let v = hv.to_str()?;
-        v.split(',').for_each(|s| {
-            let parts: Vec<&str> = s.split('=').collect();
-            if parts.len() > 1 {
-                let val = match parts[1] {
-                    "" => None,
-                    s => Some(s.to_string()),
-                };
-                match parts[0] {
-                    "k8s.cluster.name" => baggage.cluster_id = val,
-                    "k8s.namespace.name" => baggage.namespace = val,
-                    "k8s.deployment.name"
-                    | "k8s.cronjob.name"
-                    | "k8s.pod.name"
-                    | "k8s.job.name" => baggage.workload_name = val,
-                    "service.name" => baggage.service_name = val,
-                    "service.version" => baggage.revision = val,
-                    _ => {}
+        for s in v.split(',') {
+            read += s.len() + 1;
+            if read > MAX_BAGGAGE_LEN {
+                return Ok(baggage);
+            }
+            // Drop the properties of the entry, if it has any.
+            let entry = s.split(';').next().unwrap_or_default();
+            let Some((key, value)) = entry.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let val = valid(value).then(|| value.to_string());
+            match key.trim() {
+                "k8s.cluster.name" => baggage.cluster_id = val,
+                "k8s.namespace.name" => baggage.namespace = val,
+                "k8s.deployment.name" | "k8s.cronjob.name" | "k8s.pod.name" | "k8s.job.name" => {
+                    baggage.workload_name = val
                 }
+                "k8s.serviceaccount.name" => baggage.service_account = val,
+                "service.name" => baggage.service_name = val,
+                "service.version" => baggage.revision = val,
+                _ => {}
             }
-        });
+        }
     }
     Ok(baggage)
 }
@@ -62,18 +115,20 @@ pub mod tests {
 
     use crate::proxy::BAGGAGE_HEADER;
 
-    use super::parse_baggage_header;
+    use super::{encode, parse_baggage_header, MAX_BAGGAGE_LEN, MAX_VALUE_LEN};
+    use crate::state::workload::Workload;
 
     #[test]
     fn baggage_parser() -> anyhow::Result<()> {
         let mut hm = HeaderMap::new();
-        let baggage_str = "k8s.cluster.name=K1,k8s.namespace.name=NS1,k8s.deployment.name=N1,service.name=N2,service.version=V1";
+        let baggage_str = "k8s.cluster.name=K1,k8s.namespace.name=NS1,k8s.deployment.name=N1,k8s.serviceaccount.name=SA1,service.name=N2,service.version=V1";
         let header_value = HeaderValue::from_str(baggage_str)?;
         hm.append(BAGGAGE_HEADER, header_value);
         let baggage = parse_baggage_header(hm.get_all(BAGGAGE_HEADER))?;
         assert_eq!(baggage.cluster_id, Some("K1".to_string()));
         assert_eq!(baggage.namespace, Some("NS1".to_string()));
         assert_eq!(baggage.workload_name, Some("N1".to_string()));
+        assert_eq!(baggage.service_account, Some("SA1".to_string()));
         assert_eq!(baggage.service_name, Some("N2".to_string()));
         assert_eq!(baggage.revision, Some("V1".to_string()));
         Ok(())
@@ -130,4 +185,57 @@ pub mod tests {
         assert_eq!(baggage.revision, None);
         Ok(())
     }
+
+    #[test]
+    fn baggage_parser_validation() -> anyhow::Result<()> {
+        let mut hm = HeaderMap::new();
+        hm.append(
+            BAGGAGE_HEADER,
+            HeaderValue::from_str(&format!(
+                "k8s.cluster.name=K1;prop=x,k8s.namespace.name=a b,service.name={}",
+                "a".repeat(MAX_VALUE_LEN + 1)
+            ))?,
+        );
+        let baggage = parse_baggage_header(hm.get_all(BAGGAGE_HEADER))?;
+        assert_eq!(baggage.cluster_id, Some("K1".to_string()));
+        assert_eq!(baggage.namespace, None);
+        assert_eq!(baggage.service_name, None);
+
+        // Entries past the size limit are ignored.
+        let mut hm = HeaderMap::new();
+        let padding = format!("padding={}", "a".repeat(MAX_BAGGAGE_LEN));
+        hm.append(
+            BAGGAGE_HEADER,
+            HeaderValue::from_str("k8s.cluster.name=K1")?,
+        );
+        hm.append(BAGGAGE_HEADER, HeaderValue::from_str(&padding)?);
+        hm.append(
+            BAGGAGE_HEADER,
+            HeaderValue::from_str("k8s.namespace.name=NS1")?,
+        );
+        let baggage = parse_baggage_header(hm.get_all(BAGGAGE_HEADER))?;
+        assert_eq!(baggage.cluster_id, Some("K1".to_string()));
+        assert_eq!(baggage.namespace, None);
+        Ok(())
+    }
+
+    #[test]
+    fn encode_round_trip() -> anyhow::Result<()> {
+        let w = Workload {
+            namespace: "ns".to_string(),
+            workload_name: "reviews-v1".to_string(),
+            workload_type: "pod".to_string(),
+            canonical_name: "reviews".to_string(),
+            canonical_revision: "not valid".to_string(),
+            ..crate::test_helpers::test_default_workload()
+        };
+        let mut hm = HeaderMap::new();
+        hm.append(BAGGAGE_HEADER, HeaderValue::from_str(&encode(&w, "K1"))?);
+        let baggage = parse_baggage_header(hm.get_all(BAGGAGE_HEADER))?;
+        assert_eq!(baggage.cluster_id, Some("K1".to_string()));
+        assert_eq!(baggage.workload_name, Some("reviews-v1".to_string()));
+        assert_eq!(baggage.service_name, Some("reviews".to_string()));
+        assert_eq!(baggage.revision, None);
+        Ok(())
+    }
 }
@@ -0,0 +1,237 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! loadgen runs an in-process ztunnel for every combination of HBONE buffer size and connection
+//! pooling, and reports how HBONE and passthrough connections through it compare in throughput,
+//! connection setup latency and memory per open connection. See benches/README.md.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use tokio::net::TcpStream;
+
+use ztunnel::test_helpers::app::TestApp;
+use ztunnel::test_helpers::tcp::{self, Mode};
+use ztunnel::test_helpers::{
+    helpers, TEST_WORKLOAD_HBONE, TEST_WORKLOAD_SOURCE, TEST_WORKLOAD_TCP,
+};
+use ztunnel::{app, config, identity, test_helpers};
+
+const MB: usize = 1024 * 1024;
+
+struct Options {
+    /// Bytes sent on one connection to measure throughput.
+    size: usize,
+    /// Connections opened one after another to measure setup latency.
+    connects: usize,
+    /// Connections held open at once to measure memory.
+    connections: usize,
+    buffer_sizes: Vec<usize>,
+}
+
+impl Options {
+    fn parse() -> anyhow::Result<Options> {
+        let mut opts = Options {
+            size: 100 * MB,
+            connects: 1000,
+            connections: 1000,
+            buffer_sizes: vec![4 * 1024, 16 * 1024 - 64, 64 * 1024],
+        };
+        for arg in std::env::args().skip(1) {
+            let (flag, value) = arg
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected --flag=value, got {arg}"))?;
+            match flag {
+                "--size-mb" => opts.size = value.parse::<usize>()? * MB,
+                "--connects" => opts.connects = value.parse()?,
+                "--connections" => opts.connections = value.parse()?,
+                "--buffer-sizes" => {
+                    opts.buffer_sizes =
+                        value.split(',').map(str::parse).collect::<Result<_, _>>()?
+                }
+                _ => return Err(anyhow!("unknown flag {flag}")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Passthrough,
+    Hbone,
+}
+
+impl Target {
+    fn name(self) -> &'static str {
+        match self {
+            Target::Passthrough => "passthrough",
+            Target::Hbone => "hbone",
+        }
+    }
+
+    fn workload(self) -> &'static str {
+        match self {
+            Target::Passthrough => TEST_WORKLOAD_TCP,
+            Target::Hbone => TEST_WORKLOAD_HBONE,
+        }
+    }
+}
+
+struct Env {
+    ta: TestApp,
+    /// Reads everything sent to it.
+    sink: SocketAddr,
+    /// Echoes everything sent to it.
+    echo: SocketAddr,
+}
+
+impl Env {
+    async fn new(buffer_size: usize, pooling: bool) -> anyhow::Result<Env> {
+        let cert_manager = identity::mock::new_secret_manager(Duration::from_secs(10));
+        let config_source = Some(config::ConfigSource::Static(
+            test_helpers::local_xds_config(80, None, vec![])?,
+        ));
+        let mut cfg = test_helpers::test_config_with_port_xds_addr_and_root_cert(
+            80,
+            None,
+            None,
+            config_source,
+        );
+        cfg.hbone_buffer_size = buffer_size;
+        cfg.hbone_pooling = pooling;
+        let app = app::build_with_cert(cfg, cert_manager.clone()).await?;
+        let ta = TestApp::from((&app, cert_manager));
+        ta.ready().await;
+
+        let source = TEST_WORKLOAD_SOURCE.parse()?;
+        let sink = tcp::TestServer::new(Mode::Read, 0).await;
+        let echo = tcp::TestServer::new(Mode::ReadWrite, 0).await;
+        let env = Env {
+            ta,
+            sink: helpers::with_ip(sink.address(), source),
+            echo: helpers::with_ip(echo.address(), source),
+        };
+        tokio::spawn(async move {
+            let _ = tokio::join!(app.wait_termination(), sink.run(), echo.run());
+        });
+        Ok(env)
+    }
+
+    async fn connect(&self, target: Target, server: SocketAddr) -> anyhow::Result<TcpStream> {
+        let mut s = self
+            .ta
+            .socks5_connect(helpers::with_ip(server, target.workload().parse()?))
+            .await;
+        // Only count the connection once it has made it all the way to the server.
+        tcp::run_client(&mut s, 1, Mode::ReadWrite).await?;
+        Ok(s)
+    }
+
+    /// throughput returns the rate, in MB/s, of sending `size` bytes on a single connection.
+    async fn throughput(&self, target: Target, size: usize) -> anyhow::Result<f64> {
+        let mut s = self
+            .ta
+            .socks5_connect(helpers::with_ip(self.sink, target.workload().parse()?))
+            .await;
+        let start = Instant::now();
+        tcp::run_client(&mut s, size, Mode::Write).await?;
+        Ok(size as f64 / MB as f64 / start.elapsed().as_secs_f64())
+    }
+
+    /// setup_latency returns the median time to set up a connection and receive its first byte.
+    async fn setup_latency(&self, target: Target, connects: usize) -> anyhow::Result<Duration> {
+        let mut samples = Vec::with_capacity(connects);
+        for _ in 0..connects {
+            let start = Instant::now();
+            let s = self.connect(target, self.echo).await?;
+            samples.push(start.elapsed());
+            drop(s);
+        }
+        samples.sort();
+        samples
+            .get(samples.len() / 2)
+            .copied()
+            .ok_or_else(|| anyhow!("no connections made"))
+    }
+
+    /// memory_per_connection returns the growth in the process' resident memory, in bytes, per
+    /// held open connection. This includes the client and server ends along with ztunnel's.
+    async fn memory_per_connection(
+        &self,
+        target: Target,
+        connections: usize,
+    ) -> anyhow::Result<Option<u64>> {
+        let Some(before) = resident_bytes() else {
+            return Ok(None);
+        };
+        let mut held = Vec::with_capacity(connections);
+        for _ in 0..connections {
+            held.push(self.connect(target, self.echo).await?);
+        }
+        let after = resident_bytes().unwrap_or(before);
+        Ok(Some(
+            after.saturating_sub(before) / connections.max(1) as u64,
+        ))
+    }
+}
+
+/// resident_bytes returns the process' resident set size, where the platform exposes it.
+fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts = Options::parse().context(
+        "usage: loadgen [--size-mb=N] [--connects=N] [--connections=N] [--buffer-sizes=A,B,...]",
+    )?;
+    helpers::initialize_telemetry();
+
+    println!(
+        "{:<12} {:>11} {:>8} {:>12} {:>14} {:>14}",
+        "target", "buffer_size", "pooling", "throughput", "setup_latency", "mem_per_conn"
+    );
+    for &buffer_size in &opts.buffer_sizes {
+        for pooling in [true, false] {
+            let env = Env::new(buffer_size, pooling).await?;
+            for target in [Target::Passthrough, Target::Hbone] {
+                let throughput = env.throughput(target, opts.size).await?;
+                let latency = env.setup_latency(target, opts.connects).await?;
+                let memory = env.memory_per_connection(target, opts.connections).await?;
+                println!(
+                    "{:<12} {:>11} {:>8} {:>9.1}MB/s {:>14?} {:>14}",
+                    target.name(),
+                    buffer_size,
+                    pooling,
+                    throughput,
+                    latency,
+                    memory.map_or("n/a".to_string(), |m| format!("{m}B")),
+                );
+            }
+            env.ta.shutdown.shutdown_now().await;
+        }
+    }
+    Ok(())
+}
@@ -16,14 +16,44 @@ use crate::config;
 use crate::config::ProxyMode;
 use crate::identity::Priority::Warmup;
 use crate::identity::{Identity, SecretManager};
+use crate::proxy::{ConnectionHook, ConnectionOpen, Reporter};
+use crate::readiness::BlockReady;
 use crate::state::workload::{Protocol, Workload};
-use std::sync::Arc;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::Registry;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+/// How long a certificate is kept after the last local workload with its identity is removed. This
+/// avoids fetching it again when a pod is only being replaced.
+const EVICTION_DELAY: Duration = Duration::from_secs(60);
+/// How often certificates of removed workloads are checked for eviction.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the connection counts of [History] are written to disk.
+const HISTORY_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// The number of locks the counts of [History] are split across, so that connections of different
+/// identities rarely contend for one.
+const HISTORY_SHARDS: usize = 16;
 
 /// Responsible for pre-fetching certs for workloads.
 pub trait CertFetcher: Send + Sync {
     fn prefetch_cert(&self, w: &Workload);
+
+    /// Called when a workload is removed, so its certificate can be dropped once no other local
+    /// workload has the same identity.
+    fn clear_cert(&self, _w: &Workload) {}
 }
 
 /// A no-op implementation of [CertFetcher].
@@ -33,11 +63,242 @@ impl CertFetcher for NoCertFetcher {
     fn prefetch_cert(&self, _: &Workload) {}
 }
 
+/// History counts the connections of each local identity, so that after a restart the
+/// certificates of the most used identities are prefetched first. Installed as a
+/// [ConnectionHook], it counts the connections of the local side: the source of outbound
+/// connections, and the destination of inbound ones. Only identities of local workloads are
+/// counted, and they are forgotten along with their certificates.
+#[derive(Clone, Default)]
+pub struct History(Arc<[Mutex<HashMap<Identity, u64>>; HISTORY_SHARDS]>);
+
+impl History {
+    /// load reads the counts persisted at `path`, starting afresh if there are none.
+    pub fn load(path: &Path) -> History {
+        let counts = match std::fs::read(path) {
+            Ok(data) => match serde_json::from_slice::<HashMap<String, u64>>(&data) {
+                Ok(counts) => counts
+                    .into_iter()
+                    .filter_map(|(id, count)| Some((Identity::from_str(&id).ok()?, count)))
+                    .collect(),
+                Err(e) => {
+                    warn!(path = %path.display(), "ignoring invalid prefetch history: {e}");
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!(path = %path.display(), "failed to read prefetch history: {e}");
+                HashMap::new()
+            }
+        };
+        let history = History::default();
+        for (id, count) in counts {
+            history.shard(&id).insert(id, count);
+        }
+        history
+    }
+
+    fn shard(&self, id: &Identity) -> MutexGuard<'_, HashMap<Identity, u64>> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        self.0[hasher.finish() as usize % HISTORY_SHARDS]
+            .lock()
+            .unwrap()
+    }
+
+    /// track starts counting the connections of `id`, the identity of a local workload.
+    fn track(&self, id: &Identity) {
+        self.shard(id).entry(id.clone()).or_default();
+    }
+
+    /// forget stops counting the connections of `id`, which is no longer local.
+    fn forget(&self, id: &Identity) {
+        self.shard(id).remove(id);
+    }
+
+    /// retain forgets every identity that is not `local`, such as those loaded from a previous run
+    /// whose workloads are gone.
+    fn retain(&self, local: impl Fn(&Identity) -> bool) {
+        for shard in self.0.iter() {
+            shard.lock().unwrap().retain(|id, _| local(id));
+        }
+    }
+
+    /// persist writes the counts to `path` periodically, for the lifetime of the process.
+    pub async fn persist(self, path: PathBuf) {
+        let mut interval = tokio::time::interval(HISTORY_SAVE_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let (history, to) = (self.clone(), path.clone());
+            let saved = tokio::task::spawn_blocking(move || history.save(&to))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+            if let Err(e) = saved {
+                warn!(path = %path.display(), "failed to save prefetch history: {e}");
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let counts: HashMap<String, u64> = self
+            .0
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock().unwrap();
+                shard
+                    .iter()
+                    .map(|(id, count)| (id.to_string(), *count))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&counts)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn count(&self, id: &Identity) -> u64 {
+        self.shard(id).get(id).copied().unwrap_or_default()
+    }
+
+    /// rank orders `requests` by the connection counts of their identities, most used first.
+    fn rank(&self, requests: &mut [(String, Identity)]) {
+        requests.sort_by_cached_key(|(_, id)| std::cmp::Reverse(self.count(id)));
+    }
+}
+
+impl ConnectionHook for History {
+    fn on_open(&self, conn: &ConnectionOpen) {
+        let local = match conn.reporter {
+            Reporter::source => conn.source.as_ref(),
+            Reporter::destination => conn.destination.as_ref(),
+        };
+        if let Some(wl) = local {
+            let id = wl.identity();
+            if let Some(count) = self.shard(&id).get_mut(&id) {
+                *count += 1;
+            }
+        }
+    }
+}
+
+pub struct Metrics {
+    workload_identities: Gauge,
+    prefetch_duration: Histogram,
+    evictions: Counter,
+}
+
+impl Metrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let workload_identities = Gauge::default();
+        registry.register(
+            "workload_identities",
+            "The number of distinct identities of workloads on this node",
+            workload_identities.clone(),
+        );
+        let prefetch_duration =
+            Histogram::new(vec![0.005f64, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0].into_iter());
+        registry.register(
+            "certificate_prefetch_duration",
+            "Time taken to prefetch the certificate of a new workload (unit: seconds)",
+            prefetch_duration.clone(),
+        );
+        let evictions = Counter::default();
+        registry.register(
+            "certificate_evictions",
+            "The total number of certificates dropped after their workloads were removed",
+            evictions.clone(),
+        );
+        Self {
+            workload_identities,
+            prefetch_duration,
+            evictions,
+        }
+    }
+}
+
 /// Constructs an appropriate [CertFetcher] for the proxy config.
-pub fn new(cfg: &config::Config, cert_manager: Arc<SecretManager>) -> Arc<dyn CertFetcher> {
+///
+/// `block_ready` is held until every certificate requested before `synced` completes has been
+/// fetched, so that readiness is not reported while certificates for local workloads are missing.
+/// With a `history`, those certificates are fetched once `synced` completes, in its order;
+/// otherwise they are fetched as they are requested.
+pub fn new(
+    cfg: &config::Config,
+    cert_manager: Arc<SecretManager>,
+    metrics: Metrics,
+    history: Option<History>,
+    block_ready: BlockReady,
+    synced: impl Future<Output = ()> + Send + 'static,
+) -> Arc<dyn CertFetcher> {
     match cfg.proxy_mode {
         ProxyMode::Dedicated => Arc::new(NoCertFetcher()),
-        ProxyMode::Shared => Arc::new(CertFetcherImpl::new(cfg, cert_manager)),
+        ProxyMode::Shared => Arc::new(CertFetcherImpl::new(
+            cfg,
+            cert_manager,
+            metrics,
+            history,
+            block_ready,
+            synced,
+        )),
+    }
+}
+
+enum Request {
+    // Prefetch the certificate for the workload with the given UID.
+    Prefetch(String, Identity),
+    // The workload with the given UID was removed.
+    Clear(String),
+}
+
+/// Tracks the identities of local workloads, to know which certificates are no longer needed.
+#[derive(Default)]
+struct Identities {
+    // The identity of each workload, keyed by UID.
+    workloads: HashMap<String, Identity>,
+    // The number of workloads with each identity.
+    refs: HashMap<Identity, usize>,
+    // Identities without any workloads left, and since when.
+    idle: HashMap<Identity, Instant>,
+}
+
+impl Identities {
+    fn add(&mut self, uid: String, id: Identity) {
+        if let Some(prev) = self.workloads.insert(uid, id.clone()) {
+            self.release(prev);
+        }
+        self.idle.remove(&id);
+        *self.refs.entry(id).or_default() += 1;
+    }
+
+    fn remove(&mut self, uid: &str) {
+        if let Some(id) = self.workloads.remove(uid) {
+            self.release(id);
+        }
+    }
+
+    fn release(&mut self, id: Identity) {
+        if let Entry::Occupied(mut e) = self.refs.entry(id) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                let (id, _) = e.remove_entry();
+                self.idle.insert(id, Instant::now());
+            }
+        }
+    }
+
+    /// Returns the identities that have been idle for at least [EVICTION_DELAY], and stops
+    /// tracking them.
+    fn evict(&mut self, now: Instant) -> Vec<Identity> {
+        let mut evicted = Vec::new();
+        self.idle.retain(|id, since| {
+            let expired = now.duration_since(*since) >= EVICTION_DELAY;
+            if expired {
+                evicted.push(id.clone());
+            }
+            !expired
+        });
+        evicted
     }
 }
 
@@ -45,26 +306,104 @@ pub fn new(cfg: &config::Config, cert_manager: Arc<SecretManager>) -> Arc<dyn Ce
 struct CertFetcherImpl {
     proxy_mode: ProxyMode,
     local_node: Option<String>,
-    tx: mpsc::Sender<Identity>,
+    tx: mpsc::Sender<Request>,
+    // Number of prefetch requests sent but not yet completed.
+    queued: Arc<AtomicUsize>,
 }
 
 impl CertFetcherImpl {
-    fn new(cfg: &config::Config, cert_manager: Arc<SecretManager>) -> Self {
-        let (tx, mut rx) = mpsc::channel::<Identity>(256);
+    fn new(
+        cfg: &config::Config,
+        cert_manager: Arc<SecretManager>,
+        metrics: Metrics,
+        history: Option<History>,
+        block_ready: BlockReady,
+        synced: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Request>(256);
+        let queued = Arc::new(AtomicUsize::new(0));
 
         // Spawn a task for handling the pre-fetch requests asynchronously.
+        let pending = queued.clone();
         tokio::spawn(async move {
-            while let Some(workload_identity) = rx.recv().await {
-                match cert_manager
-                    .fetch_certificate_pri(&workload_identity, Warmup)
-                    .await
-                {
-                    Ok(_) => debug!("prefetched cert for {:?}", workload_identity.to_string()),
-                    Err(e) => error!(
-                        "unable to prefetch cert for {:?}, skipping, {:?}",
-                        workload_identity.to_string(),
-                        e
-                    ),
+            let mut block_ready = Some(block_ready);
+            let mut synced = Box::pin(synced);
+            let mut is_synced = false;
+            let mut identities = Identities::default();
+            // The prefetches of the initial sync, held until it completes to be ranked by the
+            // history, if there is one.
+            let mut initial = Vec::new();
+            let mut eviction = tokio::time::interval(EVICTION_INTERVAL);
+            let prefetch = |workload_identity: Identity| {
+                let cert_manager = cert_manager.clone();
+                let metrics = &metrics;
+                let pending = &pending;
+                async move {
+                    let start = Instant::now();
+                    match cert_manager
+                        .fetch_certificate_pri(&workload_identity, Warmup)
+                        .await
+                    {
+                        Ok(_) => {
+                            metrics
+                                .prefetch_duration
+                                .observe(start.elapsed().as_secs_f64());
+                            debug!("prefetched cert for {:?}", workload_identity.to_string())
+                        }
+                        Err(e) => error!(
+                            "unable to prefetch cert for {:?}, skipping, {:?}",
+                            workload_identity.to_string(),
+                            e
+                        ),
+                    }
+                    pending.fetch_sub(1, Ordering::SeqCst);
+                }
+            };
+            loop {
+                tokio::select! {
+                    req = rx.recv() => match req {
+                        Some(Request::Prefetch(uid, workload_identity)) => {
+                            identities.add(uid.clone(), workload_identity.clone());
+                            if let Some(history) = &history {
+                                history.track(&workload_identity);
+                            }
+                            if is_synced || history.is_none() {
+                                prefetch(workload_identity).await;
+                            } else {
+                                initial.push((uid, workload_identity));
+                            }
+                        }
+                        Some(Request::Clear(uid)) => identities.remove(&uid),
+                        None => break,
+                    },
+                    _ = eviction.tick() => {
+                        for id in identities.evict(Instant::now()) {
+                            debug!("evicting cert for {:?}", id.to_string());
+                            cert_manager.forget_certificate(&id).await;
+                            if let Some(history) = &history {
+                                history.forget(&id);
+                            }
+                            metrics.evictions.inc();
+                        }
+                    }
+                    _ = &mut synced, if !is_synced => {
+                        is_synced = true;
+                        if let Some(history) = &history {
+                            history.retain(|id| identities.refs.contains_key(id));
+                            history.rank(&mut initial);
+                        }
+                        for (_, workload_identity) in initial.drain(..) {
+                            prefetch(workload_identity).await;
+                        }
+                    }
+                }
+                metrics
+                    .workload_identities
+                    .set(identities.refs.len() as i64);
+                // Prefetches for the initial sync are all queued before it completes, so once it
+                // has completed and the queue is drained we have every initial certificate.
+                if is_synced && pending.load(Ordering::SeqCst) == 0 {
+                    block_ready.take();
                 }
             }
         });
@@ -73,6 +412,7 @@ impl CertFetcherImpl {
             proxy_mode: cfg.proxy_mode.clone(),
             local_node: cfg.local_node.clone(),
             tx,
+            queued,
         }
     }
 
@@ -92,9 +432,88 @@ impl CertFetcherImpl {
 impl CertFetcher for CertFetcherImpl {
     fn prefetch_cert(&self, w: &Workload) {
         if self.should_prefetch_certificate(w) {
-            if let Err(e) = self.tx.try_send(w.identity()) {
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = self
+                .tx
+                .try_send(Request::Prefetch(w.uid.clone(), w.identity()))
+            {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
                 info!("couldn't prefetch: {:?}", e)
             }
         }
     }
+
+    fn clear_cert(&self, w: &Workload) {
+        if self.should_prefetch_certificate(w) {
+            if let Err(e) = self.tx.try_send(Request::Clear(w.uid.clone())) {
+                info!("couldn't release cert: {:?}", e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_idle_identities() {
+        let id = |sa: &str| Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: sa.to_string(),
+        };
+        let mut identities = Identities::default();
+        identities.add("a".to_string(), id("shared"));
+        identities.add("b".to_string(), id("shared"));
+        identities.add("c".to_string(), id("single"));
+
+        // Another workload still has the identity.
+        identities.remove("a");
+        // Replaced right away, as on a workload update.
+        identities.remove("c");
+        identities.add("c".to_string(), id("single"));
+        tokio::time::advance(EVICTION_DELAY).await;
+        assert!(identities.evict(Instant::now()).is_empty());
+
+        identities.remove("b");
+        tokio::time::advance(EVICTION_DELAY / 2).await;
+        assert!(identities.evict(Instant::now()).is_empty());
+        tokio::time::advance(EVICTION_DELAY / 2).await;
+        assert_eq!(identities.evict(Instant::now()), vec![id("shared")]);
+        assert_eq!(identities.refs.len(), 1);
+    }
+
+    #[test]
+    fn history() {
+        let id = |sa: &str| Identity::Spiffe {
+            trust_domain: "cluster.local".to_string(),
+            namespace: "default".to_string(),
+            service_account: sa.to_string(),
+        };
+        let path = std::env::temp_dir().join(format!("prefetch-{}", rand::random::<u64>()));
+        let history = History::load(&path);
+        history.shard(&id("busy")).insert(id("busy"), 10);
+        history.shard(&id("quiet")).insert(id("quiet"), 1);
+        history.save(&path).unwrap();
+
+        let history = History::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut requests = vec![
+            ("a".to_string(), id("unknown")),
+            ("b".to_string(), id("quiet")),
+            ("c".to_string(), id("busy")),
+        ];
+        history.rank(&mut requests);
+        let order: Vec<_> = requests.iter().map(|(uid, _)| uid.as_str()).collect();
+        assert_eq!(order, vec!["c", "b", "a"]);
+
+        // Identities that are no longer local are forgotten.
+        history.retain(|i| *i != id("quiet"));
+        assert_eq!(history.count(&id("quiet")), 0);
+        history.forget(&id("busy"));
+        assert_eq!(history.count(&id("busy")), 0);
+        history.track(&id("new"));
+        assert!(history.shard(&id("new")).contains_key(&id("new")));
+    }
 }
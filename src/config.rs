@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
@@ -24,10 +24,16 @@ use anyhow::anyhow;
 use bytes::Bytes;
 use hyper::http::uri::InvalidUri;
 use hyper::Uri;
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use ipnet::IpNet;
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
 
 use crate::identity;
 
+pub mod reload;
+pub mod validate;
+
+pub use reload::LiveConfig;
+
 const ENABLE_PROXY: &str = "ENABLE_PROXY";
 const KUBERNETES_SERVICE_HOST: &str = "KUBERNETES_SERVICE_HOST";
 const NETWORK: &str = "NETWORK";
@@ -37,13 +43,102 @@ const INSTANCE_IP: &str = "INSTANCE_IP";
 const CLUSTER_ID: &str = "CLUSTER_ID";
 const CLUSTER_DOMAIN: &str = "CLUSTER_DOMAIN";
 const LOCAL_XDS_PATH: &str = "LOCAL_XDS_PATH";
+const XDS_RECORD_PATH: &str = "XDS_RECORD_PATH";
+const XDS_REPLAY_PATH: &str = "XDS_REPLAY_PATH";
 const XDS_ON_DEMAND: &str = "XDS_ON_DEMAND";
+const XDS_ON_DEMAND_TIMEOUT: &str = "XDS_ON_DEMAND_TIMEOUT";
 const XDS_ADDRESS: &str = "XDS_ADDRESS";
+const REMOTE_XDS_CLUSTERS: &str = "REMOTE_XDS_CLUSTERS";
+const CONTROL_PLANE_RERESOLVE_INTERVAL: &str = "CONTROL_PLANE_RERESOLVE_INTERVAL";
 const CA_ADDRESS: &str = "CA_ADDRESS";
+const CA_TOKEN_AUDIENCE: &str = "CA_TOKEN_AUDIENCE";
 const FAKE_CA: &str = "FAKE_CA";
+const CA_PROVIDER: &str = "CA_PROVIDER";
+const CA_CERT_DIR: &str = "CA_CERT_DIR";
+const SDS_SOCKET_PATH: &str = "SDS_SOCKET_PATH";
+const SPIFFE_ENDPOINT_SOCKET: &str = "SPIFFE_ENDPOINT_SOCKET";
 const ZTUNNEL_WORKER_THREADS: &str = "ZTUNNEL_WORKER_THREADS";
 const ENABLE_ORIG_SRC: &str = "ENABLE_ORIG_SRC";
+const PRESERVE_SOURCE_PORT: &str = "PRESERVE_SOURCE_PORT";
+const PRESERVE_TOS: &str = "PRESERVE_TOS";
 const PROXY_CONFIG: &str = "PROXY_CONFIG";
+const RUNTIME_CONFIG_PATH: &str = "RUNTIME_CONFIG_PATH";
+const OUTBOUND_TRAFFIC_POLICY: &str = "OUTBOUND_TRAFFIC_POLICY";
+const GRPC_HEALTH_SIGNALS: &str = "GRPC_HEALTH_SIGNALS";
+const INBOUND_PORT_POLICY: &str = "INBOUND_PORT_POLICY";
+const INBOUND_SOURCE_POLICY: &str = "INBOUND_SOURCE_POLICY";
+const SERVICE_HAIRPIN_POLICY: &str = "SERVICE_HAIRPIN_POLICY";
+const INBOUND_ALLOWED_SOURCE_CIDRS: &str = "INBOUND_ALLOWED_SOURCE_CIDRS";
+const RBAC_AUDIT: &str = "RBAC_AUDIT";
+const POLICY_FAIL_CLOSED: &str = "POLICY_FAIL_CLOSED";
+const POLICY_STALE_AFTER: &str = "POLICY_STALE_AFTER";
+const OUTBOUND_BYPASS_CIDRS: &str = "OUTBOUND_BYPASS_CIDRS";
+const OUTBOUND_BYPASS_PORTS: &str = "OUTBOUND_BYPASS_PORTS";
+const OUTBOUND_BYPASS_WORKLOADS: &str = "OUTBOUND_BYPASS_WORKLOADS";
+const EGRESS_GATEWAY_ADDRESS: &str = "EGRESS_GATEWAY_ADDRESS";
+const EGRESS_GATEWAY_CIDRS: &str = "EGRESS_GATEWAY_CIDRS";
+const EGRESS_GATEWAY_HOSTNAMES: &str = "EGRESS_GATEWAY_HOSTNAMES";
+const TRACE_SAMPLING_PERCENTAGE: &str = "TRACE_SAMPLING_PERCENTAGE";
+const METRICS_NAMESPACE_SERIES_LIMIT: &str = "METRICS_NAMESPACE_SERIES_LIMIT";
+const LISTENER_HANDOFF_PATH: &str = "LISTENER_HANDOFF_PATH";
+const READINESS_FILE: &str = "READINESS_FILE";
+const CERT_PREFETCH_HISTORY_PATH: &str = "CERT_PREFETCH_HISTORY_PATH";
+const LISTENER_SHARDS: &str = "LISTENER_SHARDS";
+const RUNTIME_MODE: &str = "RUNTIME_MODE";
+const IO_URING: &str = "IO_URING";
+const CONNECT_TIMEOUT: &str = "CONNECT_TIMEOUT";
+const CONNECT_RESPONSE_TIMEOUT: &str = "CONNECT_RESPONSE_TIMEOUT";
+const HANDSHAKE_TIMEOUT: &str = "HANDSHAKE_TIMEOUT";
+const WORKLOAD_DRAIN_DURATION: &str = "WORKLOAD_DRAIN_DURATION";
+const TERMINATION_DRAIN_DURATION: &str = "TERMINATION_DRAIN_DURATION";
+const HBONE_MAX_CONCURRENT_STREAMS: &str = "HBONE_MAX_CONCURRENT_STREAMS";
+const HBONE_BUFFER_SIZE: &str = "HBONE_BUFFER_SIZE";
+const HBONE_POOLING: &str = "HBONE_POOLING";
+const HBONE_POOL_IDLE_TIMEOUT: &str = "HBONE_POOL_IDLE_TIMEOUT";
+const HBONE_POOL_MAX_IDLE: &str = "HBONE_POOL_MAX_IDLE";
+const PROTOCOL_DETECTION_TIMEOUT: &str = "PROTOCOL_DETECTION_TIMEOUT";
+const SERVER_FIRST_PORTS: &str = "SERVER_FIRST_PORTS";
+const LATENCY_SENSITIVE_PORTS: &str = "LATENCY_SENSITIVE_PORTS";
+const LISTENER_BACKLOG: &str = "LISTENER_BACKLOG";
+const LISTENER_RECV_BUFFER_SIZE: &str = "LISTENER_RECV_BUFFER_SIZE";
+const LISTENER_SEND_BUFFER_SIZE: &str = "LISTENER_SEND_BUFFER_SIZE";
+const LISTENER_NODELAY: &str = "LISTENER_NODELAY";
+const LISTENER_FAST_OPEN_BACKLOG: &str = "LISTENER_FAST_OPEN_BACKLOG";
+const HBONE_FAST_OPEN: &str = "HBONE_FAST_OPEN";
+const SOCKET_MARK: &str = "SOCKET_MARK";
+const INBOUND_SOCKET_MARK: &str = "INBOUND_SOCKET_MARK";
+const OUTBOUND_SOCKET_MARK: &str = "OUTBOUND_SOCKET_MARK";
+const DNS_UPSTREAMS: &str = "DNS_UPSTREAMS";
+const FAULT_INJECTION_DIRECTIONS: &str = "FAULT_INJECTION_DIRECTIONS";
+const FAULT_CONNECT_DELAY: &str = "FAULT_CONNECT_DELAY";
+const FAULT_CONNECT_DELAY_PERCENTAGE: &str = "FAULT_CONNECT_DELAY_PERCENTAGE";
+const FAULT_HANDSHAKE_FAILURE_PERCENTAGE: &str = "FAULT_HANDSHAKE_FAILURE_PERCENTAGE";
+const FAULT_CORRUPTION_PERCENTAGE: &str = "FAULT_CORRUPTION_PERCENTAGE";
+const FAULT_RESET_PERCENTAGE: &str = "FAULT_RESET_PERCENTAGE";
+const ADMIN_MTLS_IDENTITY: &str = "ADMIN_MTLS_IDENTITY";
+const ADMIN_ALLOWED_IDENTITIES: &str = "ADMIN_ALLOWED_IDENTITIES";
+const CONNECTION_EVENTS_SOCKET: &str = "CONNECTION_EVENTS_SOCKET";
+const FAIR_QUEUEING_BANDWIDTH: &str = "FAIR_QUEUEING_BANDWIDTH";
+const FAIR_QUEUEING_WEIGHTS: &str = "FAIR_QUEUEING_WEIGHTS";
+const OUTLIER_DETECTION_CONSECUTIVE_ERRORS: &str = "OUTLIER_DETECTION_CONSECUTIVE_ERRORS";
+const OUTLIER_DETECTION_BASE_EJECTION_TIME: &str = "OUTLIER_DETECTION_BASE_EJECTION_TIME";
+const OUTLIER_DETECTION_MAX_EJECTION_PERCENT: &str = "OUTLIER_DETECTION_MAX_EJECTION_PERCENT";
+const IPFIX_COLLECTOR_ADDRESS: &str = "IPFIX_COLLECTOR_ADDRESS";
+const IPFIX_EXPORT_INTERVAL: &str = "IPFIX_EXPORT_INTERVAL";
+const IPFIX_OBSERVATION_DOMAIN_ID: &str = "IPFIX_OBSERVATION_DOMAIN_ID";
+const TRAFFIC_PAIRS_WINDOW: &str = "TRAFFIC_PAIRS_WINDOW";
+const TRAFFIC_PAIRS_REPORT_ADDRESS: &str = "TRAFFIC_PAIRS_REPORT_ADDRESS";
+const TRAFFIC_PAIRS_REPORT_INTERVAL: &str = "TRAFFIC_PAIRS_REPORT_INTERVAL";
+const L4_FILTERS: &str = "L4_FILTERS";
+const SSLKEYLOGFILE: &str = "SSLKEYLOGFILE";
+const CRL_PATH: &str = "CRL_PATH";
+const TLS_KEY_EXCHANGE_GROUPS: &str = "TLS_KEY_EXCHANGE_GROUPS";
+const CRL_REFRESH_INTERVAL: &str = "CRL_REFRESH_INTERVAL";
+const UNSAFE_ENABLE_TLS_KEY_LOG: &str = "UNSAFE_ENABLE_TLS_KEY_LOG";
+const HARDENED_KEY_STORAGE: &str = "HARDENED_KEY_STORAGE";
+const HBONE_EARLY_DATA: &str = "HBONE_EARLY_DATA";
+const ADMIN_UDS_PATH: &str = "ADMIN_UDS_PATH";
+const METRICS_MTLS: &str = "METRICS_MTLS";
 
 const DEFAULT_WORKER_THREADS: u16 = 2;
 const DEFAULT_ADMIN_PORT: u16 = 15000;
@@ -51,9 +146,33 @@ const DEFAULT_READINESS_PORT: u16 = 15021;
 const DEFAULT_STATS_PORT: u16 = 15020;
 const DEFAULT_DNS_PORT: u16 = 15053;
 const DEFAULT_SELFTERM_DEADLINE: Duration = Duration::from_secs(5);
+const DEFAULT_ON_DEMAND_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_OUTLIER_BASE_EJECTION_TIME: Duration = Duration::from_secs(30);
+const DEFAULT_OUTLIER_MAX_EJECTION_PERCENT: u8 = 10;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CONNECT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_HBONE_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const DEFAULT_WORKLOAD_DRAIN_DURATION: Duration = Duration::from_secs(5);
+const DEFAULT_TERMINATION_DRAIN_DURATION: Duration = Duration::from_secs(5);
+// TLS record size max is 16k. But we also have a H2 frame header, so leave a bit of room for that.
+const DEFAULT_HBONE_BUFFER_SIZE: usize = 16_384 - 64;
+const DEFAULT_IPFIX_EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_TRAFFIC_PAIRS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_CRL_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_LISTENER_BACKLOG: u32 = 1024;
+/// FTP, SSH, SMTP, POP3, IMAP, SMTP submission and MySQL, in which the server speaks first.
+const DEFAULT_SERVER_FIRST_PORTS: &[u16] = &[21, 22, 25, 110, 143, 587, 3306];
+/// DNS, MySQL, PostgreSQL, Redis, Cassandra and memcached, which exchange small requests and
+/// responses.
+const DEFAULT_LATENCY_SENSITIVE_PORTS: &[u16] = &[53, 3306, 5432, 6379, 9042, 11211];
 const DEFAULT_CLUSTER_ID: &str = "Kubernetes";
 const DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+const DEFAULT_CA_CERT_DIR: &str = "./etc/certs";
+const DEFAULT_SDS_SOCKET_PATH: &str = "./var/run/secrets/workload-spiffe-uds/socket";
+const DEFAULT_SPIFFE_ENDPOINT_SOCKET: &str = "./run/spire/sockets/agent.sock";
 
+const MESH_CONFIG_PATH: &str = "./etc/istio/config/mesh";
 const ISTIO_META_PREFIX: &str = "ISTIO_META_";
 const DNS_CAPTURE_METADATA: &str = "DNS_CAPTURE";
 
@@ -66,6 +185,33 @@ const CERT_SYSTEM: &str = "SYSTEM";
 const PROXY_MODE_DEDICATED: &str = "dedicated";
 const PROXY_MODE_SHARED: &str = "shared";
 
+const RUNTIME_MODE_SHARED: &str = "shared";
+const RUNTIME_MODE_PER_CORE: &str = "per_core";
+
+const CA_PROVIDER_ISTIOD: &str = "istiod";
+const CA_PROVIDER_SELF_SIGNED: &str = "self_signed";
+const CA_PROVIDER_FILE: &str = "file";
+const CA_PROVIDER_SDS: &str = "sds";
+const CA_PROVIDER_SPIFFE: &str = "spiffe";
+
+const OUTBOUND_TRAFFIC_POLICY_STRICT: &str = "strict";
+const OUTBOUND_TRAFFIC_POLICY_PERMISSIVE: &str = "permissive";
+
+const INBOUND_PORT_POLICY_ENFORCE: &str = "enforce";
+const INBOUND_PORT_POLICY_PERMISSIVE: &str = "permissive";
+const INBOUND_SOURCE_POLICY_STRICT: &str = "strict";
+const INBOUND_SOURCE_POLICY_PERMISSIVE: &str = "permissive";
+const SERVICE_HAIRPIN_POLICY_ALLOW: &str = "allow";
+const SERVICE_HAIRPIN_POLICY_PREFER: &str = "prefer";
+const SERVICE_HAIRPIN_POLICY_AVOID: &str = "avoid";
+const FILTER_LISTENER_INBOUND: &str = "inbound";
+const FILTER_LISTENER_INBOUND_PASSTHROUGH: &str = "inbound_passthrough";
+const FILTER_LISTENER_OUTBOUND: &str = "outbound";
+const FILTER_DENY: &str = "deny";
+const FILTER_DELAY: &str = "delay";
+const FILTER_ANNOTATE: &str = "annotate";
+const FILTER_LIBRARY: &str = "library";
+
 #[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum RootCert {
     File(PathBuf),
@@ -95,6 +241,421 @@ pub enum ProxyMode {
     Dedicated,
 }
 
+/// RuntimeMode controls how the data plane is spread across threads.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeMode {
+    /// A single multi-threaded runtime runs all proxy listeners and connections.
+    #[default]
+    Shared,
+    /// Each worker thread runs its own single-threaded runtime, with its own SO_REUSEPORT
+    /// listeners, connection pool and metrics, so connections never move between cores.
+    PerCore,
+}
+
+/// CaProvider selects where workload certificates come from.
+#[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub enum CaProvider {
+    /// Certificates are signed by the CA at `ca_address`.
+    #[default]
+    Istiod,
+    /// Certificates are signed by a root generated when ztunnel starts. Only useful for tests and
+    /// standalone deployments, since no other proxy trusts that root.
+    SelfSigned,
+    /// Certificates are read from `cert-chain.pem`, `key.pem` and `root-cert.pem` in the given
+    /// directory, and reloaded when they change.
+    File(PathBuf),
+    /// Certificates are fetched from an Envoy SDS server (such as SPIRE) on the given Unix socket.
+    Sds(PathBuf),
+    /// Certificates are streamed from the SPIFFE Workload API (such as the SPIRE agent) on the
+    /// given Unix socket.
+    WorkloadApi(PathBuf),
+}
+
+/// OutboundBypass selects outbound traffic that is sent straight to its original destination,
+/// without HBONE, authorization policy or telemetry.
+#[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct OutboundBypass {
+    pub cidrs: Vec<IpNet>,
+    pub ports: Vec<u16>,
+    /// Source workloads, as `namespace/workload_name`. `namespace/*` matches every workload in the
+    /// namespace.
+    pub workloads: Vec<String>,
+}
+
+impl OutboundBypass {
+    pub fn matches_destination(&self, dst: SocketAddr) -> bool {
+        self.ports.contains(&dst.port()) || self.cidrs.iter().any(|c| c.contains(&dst.ip()))
+    }
+
+    pub fn matches_source(&self, namespace: &str, workload_name: &str) -> bool {
+        self.workloads.iter().any(|w| match w.split_once('/') {
+            Some((ns, name)) => ns == namespace && (name == "*" || name == workload_name),
+            None => false,
+        })
+    }
+}
+
+/// EgressGateway routes outbound traffic for external destinations over HBONE through a gateway
+/// workload, which connects to them on the client's behalf.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct EgressGateway {
+    /// An address of the gateway workload; its identity is looked up like any other workload.
+    pub address: IpAddr,
+    pub cidrs: Vec<IpNet>,
+    /// Hostnames are matched by the addresses they resolve to with the system resolver.
+    pub hostnames: Vec<String>,
+}
+
+/// ProtocolDetection controls peeking at the first bytes of inbound plaintext connections.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProtocolDetection {
+    /// How long to wait for the client to send something. Server-first protocols send nothing
+    /// until the server does, so they are held up for this long. Zero, the default, disables
+    /// detection; the detected protocol is only logged for now, so it is not worth the delay.
+    pub timeout: Duration,
+    /// Destination ports of server-first protocols, which are never peeked at.
+    pub server_first_ports: Vec<u16>,
+}
+
+/// SocketMarks are the SO_MARK values set on the sockets ztunnel connects from, so that node
+/// routing rules can tell its own traffic apart, for example to not capture it again. Sockets
+/// without a mark configured are left unmarked. Marks are only supported on Linux.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketMarks {
+    /// The mark of connections to local workloads, for inbound traffic.
+    pub inbound: Option<u32>,
+    /// The mark of connections to anything else (HBONE peers, gateways and plaintext
+    /// destinations), for outbound traffic.
+    pub outbound: Option<u32>,
+}
+
+/// PreserveTos selects the listeners whose connections pass the TOS byte, or IPv6 traffic class,
+/// their client set on to the upstream connection, so QoS markings survive the proxy. Pooled HBONE
+/// connections are shared by many clients, so they keep our own marking.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PreserveTos {
+    /// HBONE connections, whose marking was passed on by the peer.
+    pub inbound: bool,
+    pub inbound_passthrough: bool,
+    pub outbound: bool,
+}
+
+/// ListenerTuning are the socket options of each proxy listener, and of the connections it
+/// accepts. Each option of a listener is read from its variable prefixed with the listener's name,
+/// such as `INBOUND_LISTENER_BACKLOG`, and otherwise from the unprefixed variable shared by all.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerTuning {
+    pub inbound: ListenerOptions,
+    pub inbound_passthrough: ListenerOptions,
+    pub outbound: ListenerOptions,
+    pub socks5: ListenerOptions,
+    pub http_connect: ListenerOptions,
+    /// Whether pooled HBONE connections send their TLS ClientHello in the SYN with TCP Fast Open,
+    /// once the kernel holds a cookie of the peer. No SYN is sent until a Fast Open client writes,
+    /// so this is not used for plaintext connections, whose server may speak first. For the same
+    /// reason CONNECT_TIMEOUT does not apply: a peer that can't be reached fails the TLS handshake
+    /// instead, within HANDSHAKE_TIMEOUT, and is counted as a handshake failure.
+    pub hbone_fast_open: bool,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerOptions {
+    /// The most connections waiting to be accepted, before the kernel drops new ones.
+    pub backlog: u32,
+    /// SO_RCVBUF of accepted connections, instead of the kernel's autotuning.
+    pub recv_buffer_size: Option<u32>,
+    /// SO_SNDBUF of accepted connections, instead of the kernel's autotuning.
+    pub send_buffer_size: Option<u32>,
+    /// Whether accepted connections disable Nagle's algorithm.
+    pub nodelay: bool,
+    /// If set, clients may send data in their SYN with TCP Fast Open, and at most this many such
+    /// connections wait to be accepted.
+    pub fast_open_backlog: Option<u32>,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        ListenerOptions {
+            backlog: DEFAULT_LISTENER_BACKLOG,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            nodelay: false,
+            fast_open_backlog: None,
+        }
+    }
+}
+
+/// FaultInjection deliberately degrades proxied connections, so that the way applications cope
+/// with an unreliable network can be tested through the mesh. Each fault hits the given percentage
+/// of connections, or for corruption, of the writes on a connection. Faults are only injected by
+/// builds with the `fault-injection` feature.
+#[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct FaultInjection {
+    /// Whether faults are injected into inbound traffic.
+    pub inbound: bool,
+    /// Whether faults are injected into outbound traffic.
+    pub outbound: bool,
+    /// How long connecting to the upstream is delayed by.
+    pub connect_delay: Duration,
+    pub connect_delay_percentage: u8,
+    /// Outbound HBONE connections fail before their TLS handshake, and inbound ones are closed
+    /// right after it.
+    pub handshake_failure_percentage: u8,
+    /// A byte of the data written is flipped.
+    pub corruption_percentage: u8,
+    /// The connection to the local workload is reset partway through.
+    pub reset_percentage: u8,
+}
+
+impl FaultInjection {
+    pub fn is_enabled(&self) -> bool {
+        (self.inbound || self.outbound)
+            && [
+                self.connect_delay_percentage,
+                self.handshake_failure_percentage,
+                self.corruption_percentage,
+                self.reset_percentage,
+            ]
+            .iter()
+            .any(|&p| p > 0)
+    }
+}
+
+/// AdminAuth controls who may use the admin API. By default it is only reachable over localhost,
+/// and anything running on the node can use all of it.
+#[derive(serde::Serialize, Default, Clone, Debug, PartialEq, Eq)]
+pub struct AdminAuth {
+    /// If set, clients must present a certificate from the mesh CA, and the admin server presents
+    /// the certificate of this identity. The admin API then listens on every address rather than
+    /// only localhost, so it can be reached from off the node.
+    pub mtls_identity: Option<identity::Identity>,
+    /// The clients allowed to call endpoints that change ztunnel's state, such as shutting down,
+    /// changing log levels or refreshing certificates. If empty, none can over mTLS, leaving them
+    /// to clients on the Unix socket.
+    pub allowed_identities: Vec<identity::Identity>,
+    /// If set along with mtls_identity, the metrics server requires mTLS too, so it can only be
+    /// scraped with a certificate from the mesh CA.
+    pub metrics: bool,
+}
+
+/// FairQueueing paces the data each source workload sends through ztunnel, in each direction, to
+/// a bandwidth shared between the workload's connections in proportion to their weights. One bulk
+/// transfer then cannot starve the other connections from the same pod.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FairQueueing {
+    /// Bytes per second each source workload may send in each direction.
+    pub bandwidth: u64,
+    /// The weight of connections to each destination service hostname. Connections to other
+    /// destinations have a weight of 1.
+    pub weights: HashMap<String, u32>,
+}
+
+/// OutlierDetection ejects service endpoints that our connections keep failing to from load
+/// balancing for a while.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct OutlierDetection {
+    /// How many connections in a row must fail to connect or to complete their handshake before
+    /// the endpoint is ejected. Connections that fail once established do not count.
+    pub consecutive_errors: u32,
+    /// How long an endpoint is ejected for the first time. Each ejection after lasts longer.
+    pub base_ejection_time: Duration,
+    /// The most of a service's endpoints that may be ejected at once, in percent. One endpoint
+    /// may always be ejected, unless it is the only one. Past the cap, the endpoints whose
+    /// ejections end last are avoided.
+    pub max_ejection_percent: u8,
+}
+
+/// FairQueueingWeight parses a `hostname=weight` entry of FAIR_QUEUEING_WEIGHTS.
+struct FairQueueingWeight(String, u32);
+
+impl FromStr for FairQueueingWeight {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, weight) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected hostname=weight"))?;
+        let weight: u32 = weight.parse()?;
+        if host.is_empty() || weight == 0 {
+            return Err(anyhow!("expected a hostname and a positive weight"));
+        }
+        Ok(FairQueueingWeight(host.to_string(), weight))
+    }
+}
+
+/// RemoteXdsCluster is the control plane of another cluster, parsed from a
+/// `cluster-id=address` entry of REMOTE_XDS_CLUSTERS.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct RemoteXdsCluster {
+    /// The cluster the control plane serves, which qualifies the names of its workloads so they
+    /// cannot collide with those of other clusters.
+    pub cluster_id: String,
+    pub address: String,
+}
+
+impl FromStr for RemoteXdsCluster {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::EnvVar(REMOTE_XDS_CLUSTERS.to_string(), s.to_string());
+        let (cluster_id, address) = s.split_once('=').ok_or_else(invalid)?;
+        if cluster_id.is_empty() || cluster_id.contains('/') {
+            return Err(invalid());
+        }
+        let address =
+            validate_uri(empty_to_none(Some(address.to_string())))?.ok_or_else(invalid)?;
+        Ok(RemoteXdsCluster {
+            cluster_id: cluster_id.to_string(),
+            address,
+        })
+    }
+}
+
+/// IpfixExport ships a flow record of each connection ztunnel proxies to an IPFIX collector.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct IpfixExport {
+    /// The UDP address of the collector.
+    pub collector: SocketAddr,
+    /// How often the records of the connections that finished are exported.
+    pub interval: Duration,
+    /// The observation domain the records are exported under, to tell exporters apart.
+    pub observation_domain_id: u32,
+}
+
+/// TrafficPairs keeps rolling counts of the traffic proxied between each pair of source and
+/// destination identities, for audits of which service accounts talk to each other.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct TrafficPairs {
+    /// How far back the counts go.
+    pub window: Duration,
+    /// If set, the counts are also reported to this control plane address, with the root cert
+    /// and credentials of xds_address.
+    pub report_address: Option<String>,
+    /// How often the counts are reported.
+    pub report_interval: Duration,
+}
+
+/// FilterSpec is a built-in L4 filter applied to the connections a listener accepts, parsed from a
+/// `listener:filter=argument` entry of L4_FILTERS, such as `inbound:deny=10.0.0.0/8`.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct FilterSpec {
+    pub listener: FilterListener,
+    pub filter: BuiltinFilter,
+}
+
+/// FilterListener is a listener that L4 filters can be applied to.
+#[derive(serde::Serialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum FilterListener {
+    /// Inbound HBONE connections, before their TLS handshake.
+    Inbound,
+    /// Inbound plaintext connections.
+    InboundPassthrough,
+    /// Connections captured from local workloads, including those of socks5 and HTTP CONNECT
+    /// clients.
+    Outbound,
+}
+
+impl FilterListener {
+    /// as_str returns the name of the listener in L4_FILTERS.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterListener::Inbound => FILTER_LISTENER_INBOUND,
+            FilterListener::InboundPassthrough => FILTER_LISTENER_INBOUND_PASSTHROUGH,
+            FilterListener::Outbound => FILTER_LISTENER_OUTBOUND,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum BuiltinFilter {
+    /// Deny connections from sources in the CIDR, such as `deny=10.0.0.0/8`.
+    Deny(IpNet),
+    /// Hold up connections for a while before proxying them, such as `delay=50ms`.
+    Delay(Duration),
+    /// Annotate the logs of connections with a key and value, such as `annotate=tier=gold`.
+    Annotate(String, String),
+    /// Run the `ztunnel_l4_filter` function of a shared library, such as
+    /// `library=/opt/filters/libgeo.so`. It is loaded at startup, and called on the proxy's
+    /// threads, so it must return promptly.
+    Library(PathBuf),
+}
+
+impl FromStr for FilterSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::EnvVar(L4_FILTERS.to_string(), s.to_string());
+        let (listener, filter) = s.split_once(':').ok_or_else(invalid)?;
+        let listener = match listener.to_lowercase().as_str() {
+            FILTER_LISTENER_INBOUND => FilterListener::Inbound,
+            FILTER_LISTENER_INBOUND_PASSTHROUGH => FilterListener::InboundPassthrough,
+            FILTER_LISTENER_OUTBOUND => FilterListener::Outbound,
+            _ => return Err(invalid()),
+        };
+        let (name, arg) = filter.split_once('=').ok_or_else(invalid)?;
+        let filter = match name.to_lowercase().as_str() {
+            FILTER_DENY => BuiltinFilter::Deny(arg.parse().map_err(|_| invalid())?),
+            FILTER_DELAY => {
+                BuiltinFilter::Delay(arg.parse::<GoDuration>().map_err(|_| invalid())?.0)
+            }
+            FILTER_ANNOTATE => match arg.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    BuiltinFilter::Annotate(key.to_string(), value.to_string())
+                }
+                _ => return Err(invalid()),
+            },
+            FILTER_LIBRARY if !arg.is_empty() => BuiltinFilter::Library(PathBuf::from(arg)),
+            _ => return Err(invalid()),
+        };
+        Ok(FilterSpec { listener, filter })
+    }
+}
+
+/// OutboundTrafficPolicy controls which transports are acceptable for outbound traffic to
+/// destinations known to the mesh.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutboundTrafficPolicy {
+    /// Permissive sends plaintext TCP to destinations that are not HBONE capable.
+    #[default]
+    Permissive,
+    /// Strict requires mTLS (HBONE) for every mesh destination; plaintext is rejected.
+    Strict,
+}
+
+/// InboundPortPolicy controls plaintext inbound connections to ports a workload does not declare.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundPortPolicy {
+    /// Permissive allows such connections, logging them.
+    #[default]
+    Permissive,
+    /// Enforce rejects such connections.
+    Enforce,
+}
+
+/// HairpinPolicy controls whether a workload connecting to a service it is an endpoint of is
+/// load balanced back to itself.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HairpinPolicy {
+    /// Allow picks the workload like any other endpoint.
+    #[default]
+    Allow,
+    /// Prefer always picks the workload itself, keeping the connection within the pod.
+    Prefer,
+    /// Avoid picks other endpoints, unless the workload is the only one.
+    Avoid,
+}
+
+/// InboundSourcePolicy controls plaintext inbound connections from sources that are not workloads
+/// known to the mesh.
+#[derive(serde::Serialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundSourcePolicy {
+    /// Permissive allows such connections, attributing them to an unknown source.
+    #[default]
+    Permissive,
+    /// Strict rejects such connections, unless they come from an allowed CIDR.
+    Strict,
+}
+
 #[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
 pub struct Config {
     /// If true, the HBONE proxy will be used.
@@ -105,11 +666,39 @@ pub struct Config {
     pub window_size: u32,
     pub connection_window_size: u32,
     pub frame_size: u32,
+    /// How many CONNECT streams a client may have open at once on each inbound HBONE connection,
+    /// if limited. Further streams wait until one closes. Our own pool keeps a single connection
+    /// per peer, so a limit also caps how many connections a ztunnel can proxy to another.
+    pub hbone_max_concurrent_streams: Option<u32>,
+    /// Size of the buffer each direction of an HBONE connection is copied through.
+    pub hbone_buffer_size: usize,
+    /// If false, every outbound HBONE connection gets an HTTP/2 connection of its own, rather than
+    /// sharing one with other connections to the same peer.
+    pub hbone_pooling: bool,
+    /// How long a pooled HBONE connection may sit without streams before it is closed.
+    pub hbone_pool_idle_timeout: Duration,
+    /// How many idle HBONE connections the pool keeps to each peer.
+    pub hbone_pool_max_idle: usize,
 
     pub socks5_addr: SocketAddr,
+    /// Address of the explicit HTTP proxy, which tunnels CONNECT requests like socks5_addr does.
+    pub http_connect_addr: SocketAddr,
     pub admin_addr: SocketAddr,
+    pub admin_auth: AdminAuth,
+    /// If set, a Unix socket at this path streams connection open and close events, one JSON
+    /// object per line, to every client connected to it that runs as ztunnel's user.
+    pub connection_events_socket: Option<PathBuf>,
+    /// If set, the admin API is also served on a Unix socket at this path. Only processes running
+    /// as ztunnel's user can connect to it, and they can use all of it.
+    pub admin_uds_path: Option<PathBuf>,
     pub stats_addr: SocketAddr,
     pub readiness_addr: SocketAddr,
+    /// If set, a file at this path exists only while ztunnel is ready, so the CNI plugin can hold
+    /// workload traffic until ztunnel can serve it.
+    pub readiness_file: Option<PathBuf>,
+    /// If set, the connection counts of each local identity are persisted in this file, so that
+    /// after a restart the certificates of the most used identities are prefetched first.
+    pub cert_prefetch_history: Option<PathBuf>,
     pub inbound_addr: SocketAddr,
     pub inbound_plaintext_addr: SocketAddr,
     pub outbound_addr: SocketAddr,
@@ -122,6 +711,50 @@ pub struct Config {
     pub local_node: Option<String>,
     /// The proxy mode of ztunnel, Shared or Dedicated, default to Shared.
     pub proxy_mode: ProxyMode,
+    /// Whether plaintext is allowed to mesh destinations that cannot accept HBONE.
+    pub outbound_traffic_policy: OutboundTrafficPolicy,
+    /// If true, waypoints that report an unhealthy gRPC status of their upstream in the response
+    /// to a CONNECT are logged and counted, and are errors of the destination to outlier
+    /// detection.
+    pub grpc_health_signals: bool,
+    /// Whether plaintext inbound connections to ports the destination workload does not declare
+    /// are rejected.
+    pub inbound_port_policy: InboundPortPolicy,
+    pub inbound_source_policy: InboundSourcePolicy,
+    pub service_hairpin_policy: HairpinPolicy,
+    /// Sources outside the mesh that plaintext inbound connections are still allowed from, under
+    /// [InboundSourcePolicy::Strict].
+    pub inbound_allowed_source_cidrs: Vec<IpNet>,
+    /// If true, every authorization policy is evaluated in audit mode: connections it would deny
+    /// are logged and counted, but still allowed.
+    pub rbac_audit: bool,
+    /// If true, inbound connections are denied, and ztunnel is not ready, until authorization
+    /// policies have been synced from xDS.
+    pub policy_fail_closed: bool,
+    /// With policy_fail_closed, policies are also treated as unsynced once the xDS stream has been
+    /// down for this long, as they may have changed since. If unset, only the initial sync counts.
+    pub policy_stale_after: Option<Duration>,
+    /// Outbound traffic that is not proxied at all.
+    pub outbound_bypass: OutboundBypass,
+    /// If set, traffic to the gateway's destinations that is not otherwise known to the mesh is
+    /// sent through it rather than directly.
+    pub egress_gateway: Option<EgressGateway>,
+    /// Percentage (0-100) of the traces ztunnel starts that are marked as sampled.
+    pub trace_sampling_percentage: u8,
+    /// The most traffic metric series each destination namespace may have, after which new ones
+    /// are recorded without workload labels. Unlimited if unset.
+    pub metrics_namespace_series_limit: Option<usize>,
+    /// If set, the connections of each source workload share a bandwidth budget fairly. Only
+    /// connections copied in userspace are paced, not those spliced or copied with io_uring.
+    pub fair_queueing: Option<FairQueueing>,
+    /// If set, endpoints that connections keep failing to are avoided for a while.
+    pub outlier_detection: Option<OutlierDetection>,
+    /// If set, flow records of the connections proxied are exported to an IPFIX collector.
+    pub ipfix: Option<IpfixExport>,
+    /// If set, the traffic proxied is counted by the pair of identities at its ends.
+    pub traffic_pairs: Option<TrafficPairs>,
+    /// Built-in L4 filters, applied in order to the connections of their listener.
+    pub l4_filters: Vec<FilterSpec>,
     /// The local_ip we are running at.
     pub local_ip: Option<IpAddr>,
     /// The Cluster ID of the cluster that his ztunnel belongs to
@@ -136,33 +769,120 @@ pub struct Config {
     pub ca_root_cert: RootCert,
     /// XDS address to use. If unset, XDS will not be used.
     pub xds_address: Option<String>,
+    /// The control planes of other clusters, whose workloads are discovered alongside those of
+    /// our own. They are reached with the same root cert and credentials as xds_address. Our own
+    /// workloads take precedence over theirs, and they don't hold up readiness.
+    pub remote_xds_clusters: Vec<RemoteXdsCluster>,
     /// Root cert for XDS TLS verification.
     pub xds_root_cert: RootCert,
     /// YAML config for local XDS workloads
     #[serde(skip_serializing)]
     pub local_xds_config: Option<ConfigSource>,
+    /// If set, every XDS response received is appended to this file, for debugging.
+    pub xds_record_path: Option<PathBuf>,
+    /// If set, the XDS responses recorded in this file are applied in place of a control plane,
+    /// and xds_address is ignored.
+    pub xds_replay_path: Option<PathBuf>,
     /// If true, on-demand XDS will be used
     pub xds_on_demand: bool,
+    /// How long a lookup waits for an on-demand XDS response before treating the resource as unknown.
+    pub xds_on_demand_timeout: Duration,
+    /// About how often the XDS and CA addresses are resolved again, so ztunnel follows the
+    /// control plane once the address it is connected to is no longer behind their names. If
+    /// None, the default, they are resolved only when connecting.
+    pub control_plane_reresolve_interval: Option<Duration>,
 
     /// If true, then use builtin fake CA with self-signed certificates.
     pub fake_ca: bool,
+    /// Where workload certificates come from, unless `fake_ca` is set.
+    pub ca_provider: CaProvider,
     #[serde(skip_serializing)]
     pub auth: identity::AuthSource,
     // How long ztunnel should wait for in-flight requesthandlers to finish processing
     // before giving up when ztunnel is self-terminating (when instructed via the Admin API)
     pub self_termination_deadline: Duration,
+    /// How long an outbound TCP connect to an upstream may take.
+    pub connect_timeout: Duration,
+    /// How long to wait for the upstream to respond to an HBONE CONNECT request, once connected.
+    pub connect_response_timeout: Duration,
+    /// How long the TLS and HTTP/2 handshakes of a new HBONE connection may take. For inbound
+    /// connections, the client must also have sent its first request by then.
+    pub handshake_timeout: Duration,
+    /// How long inbound connections to a local workload may continue once it is removed, before
+    /// they are reset.
+    pub workload_drain_duration: Duration,
+    /// How long open connections may continue once shutdown starts, on SIGTERM or from a preStop
+    /// hook calling /quitquitquit, before ztunnel exits and closes them. It exits sooner once they
+    /// are all closed, so this should stay below the pod's terminationGracePeriodSeconds.
+    pub termination_drain_duration: Duration,
+    /// Unix socket used to hand listening sockets over to a replacement ztunnel during an in-place
+    /// upgrade. If unset, every process binds its own listeners.
+    pub listener_handoff_path: Option<PathBuf>,
+    pub protocol_detection: ProtocolDetection,
+    /// Destination ports of request/response protocols, whose small writes are sent right away on
+    /// every leg of their connections, and which are copied through small buffers. Other flows are
+    /// treated as bulk transfers, whose writes are batched by Nagle's algorithm and which are
+    /// copied through buffers of hbone_buffer_size.
+    pub latency_sensitive_ports: Vec<u16>,
+    pub socket_marks: SocketMarks,
+    pub fault_injection: FaultInjection,
+    /// If set, the session keys of every TLS connection are appended to this file, so captured
+    /// traffic can be decrypted. Only for debugging: it defeats the encryption of all traffic.
+    pub tls_key_log: Option<PathBuf>,
+    /// If set, the certificates of mTLS peers are checked against the revocation lists in this
+    /// file, and peers whose issuer has none are refused.
+    pub crl_path: Option<PathBuf>,
+    /// How often the revocation lists are re-read.
+    pub crl_refresh_interval: Duration,
+    /// The key exchange groups offered in mTLS handshakes, in order of preference, such as
+    /// `X25519Kyber768Draft00,X25519`. If empty, or not all supported, the defaults are used.
+    pub tls_key_exchange_groups: Vec<String>,
+    /// If set, the encoded private keys ztunnel reads or generates are locked into memory while it
+    /// holds them, so they are never swapped to disk, and left out of core dumps. Keys stay
+    /// unlocked, with a warning, if RLIMIT_MEMLOCK is too low. The parsed keys that TLS contexts
+    /// use are held by BoringSSL, and are not protected.
+    pub hardened_key_storage: bool,
+    /// If true, outbound HBONE connections to peers with a resumable session send their CONNECT
+    /// preamble as TLS 1.3 early data, saving a round trip, and inbound ones accept it. Early
+    /// data can be replayed, so the client's own bytes are never part of it. Resumed sessions skip
+    /// certificate verification, the CRL check included, so a peer can keep connecting with a
+    /// session issued for up to ten minutes after its certificate is revoked.
+    pub hbone_early_data: bool,
 
     pub proxy_metadata: HashMap<String, String>,
+    /// A file of proxy metadata entries, typically mounted from a ConfigMap, that override those of
+    /// the mesh config and are reloaded when it changes, to tune ztunnel without a restart.
+    pub runtime_config_path: Option<PathBuf>,
 
     /// Specify the number of worker threads the Tokio Runtime will use.
     pub num_worker_threads: usize,
 
+    /// Number of sockets, each with its own accept loop, that proxy listeners are sharded across.
+    /// In [RuntimeMode::PerCore], this applies to each worker.
+    pub listener_shards: usize,
+
+    pub runtime_mode: RuntimeMode,
+
+    /// If true, proxied connections are copied through io_uring where the build and kernel
+    /// support it, rather than epoll.
+    pub io_uring: bool,
+
     // If true, then use original source proxying
     pub enable_original_source: Option<bool>,
+    /// If true, passthrough connections that keep the client's address also keep its source port,
+    /// falling back to an ephemeral port when it is already in use.
+    pub preserve_source_port: bool,
+    /// The listeners that pass the QoS marking of their clients on to upstream connections.
+    pub preserve_tos: PreserveTos,
+    pub listener_tuning: ListenerTuning,
 
     // CLI args passed to ztunnel at runtime
     pub proxy_args: String,
 
+    /// Resolvers to send DNS queries to instead of those in /etc/resolv.conf, both for queries the
+    /// DNS proxy forwards and for ztunnel's own lookups.
+    pub dns_upstreams: Vec<DnsUpstream>,
+
     // System dns resolver config used for on-demand ztunnel dns resolution
     pub dns_resolver_cfg: ResolverConfig,
 
@@ -178,6 +898,10 @@ pub enum Error {
     ProxyConfig(anyhow::Error),
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
+    #[error("{SSLKEYLOGFILE} is set, but TLS key logging exposes all traffic and also requires {UNSAFE_ENABLE_TLS_KEY_LOG}=true")]
+    UnsafeTlsKeyLog,
+    #[error("{SSLKEYLOGFILE} writes TLS secrets to disk, and cannot be used with {HARDENED_KEY_STORAGE}")]
+    HardenedTlsKeyLog,
 }
 
 impl From<InvalidUri> for Error {
@@ -186,6 +910,109 @@ impl From<InvalidUri> for Error {
     }
 }
 
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+    /// DNS-over-TLS (RFC 7858).
+    #[cfg(feature = "dns-over-tls")]
+    Tls,
+    /// DNS-over-HTTPS (RFC 8484).
+    #[cfg(feature = "dns-over-tls")]
+    Https,
+}
+
+/// DnsUpstream is a resolver to send DNS queries to, given as
+/// `<udp|tcp|tls|https>://<ip>[:<port>][#<tls name>]`, for example `tls://1.1.1.1#one.one.one.one`.
+/// TLS and HTTPS resolvers must name the identity their certificate is validated against, and can
+/// only be used in builds with the `dns-over-tls` feature.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct DnsUpstream {
+    pub protocol: DnsProtocol,
+    pub address: SocketAddr,
+    pub tls_name: Option<String>,
+}
+
+impl DnsUpstream {
+    pub fn name_server(&self) -> NameServerConfig {
+        NameServerConfig {
+            socket_addr: self.address,
+            protocol: match self.protocol {
+                DnsProtocol::Udp => Protocol::Udp,
+                DnsProtocol::Tcp => Protocol::Tcp,
+                #[cfg(feature = "dns-over-tls")]
+                DnsProtocol::Tls => Protocol::Tls,
+                #[cfg(feature = "dns-over-tls")]
+                DnsProtocol::Https => Protocol::Https,
+            },
+            tls_dns_name: self.tls_name.clone(),
+            trust_nx_responses: false,
+            // Validated against the system's trusted roots.
+            #[cfg(feature = "dns-over-tls")]
+            tls_config: None,
+            bind_addr: None,
+        }
+    }
+}
+
+impl FromStr for DnsUpstream {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::EnvVar(DNS_UPSTREAMS.to_string(), s.to_string());
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (protocol, default_port) = match scheme {
+            "udp" => (DnsProtocol::Udp, 53),
+            "tcp" => (DnsProtocol::Tcp, 53),
+            #[cfg(feature = "dns-over-tls")]
+            "tls" => (DnsProtocol::Tls, 853),
+            #[cfg(feature = "dns-over-tls")]
+            "https" => (DnsProtocol::Https, 443),
+            _ => return Err(invalid()),
+        };
+        let (address, tls_name) = match rest.split_once('#') {
+            Some((address, name)) if !name.is_empty() => (address, Some(name.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (rest, None),
+        };
+        let address = address
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                address
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, default_port))
+            })
+            .map_err(|_| invalid())?;
+        let encrypted = !matches!(protocol, DnsProtocol::Udp | DnsProtocol::Tcp);
+        if encrypted != tls_name.is_some() {
+            return Err(invalid());
+        }
+        Ok(DnsUpstream {
+            protocol,
+            address,
+            tls_name,
+        })
+    }
+}
+
+/// SocketMark parses an SO_MARK value, given either in decimal or in hex with a `0x` prefix, as
+/// in iptables rules.
+struct SocketMark(u32);
+
+impl FromStr for SocketMark {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse(),
+        }
+        .map(SocketMark)
+    }
+}
+
 /// GoDuration wraps a Duration to implement golang Duration parsing semantics
 struct GoDuration(Duration);
 
@@ -211,6 +1038,89 @@ fn parse_default<T: FromStr>(env: &str, default: T) -> Result<T, Error> {
     parse(env).map(|v| v.unwrap_or(default))
 }
 
+/// parse_runtime reads `env`, or else the proxy metadata, which can be updated in the mesh config or
+/// runtime config at runtime.
+fn parse_runtime<T: FromStr>(
+    env: &str,
+    proxy_metadata: &HashMap<String, String>,
+) -> Result<Option<T>, Error> {
+    if let Some(v) = parse(env)? {
+        return Ok(Some(v));
+    }
+    match proxy_metadata.get(env) {
+        Some(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::EnvVar(env.to_string(), val.clone())),
+        None => Ok(None),
+    }
+}
+
+/// parse_list reads a comma separated list from `env`, or else from the proxy metadata, which can
+/// be updated in the mesh config at runtime.
+fn parse_list<T: FromStr>(
+    env: &str,
+    proxy_metadata: &HashMap<String, String>,
+) -> Result<Vec<T>, Error> {
+    let Some(val) = parse::<String>(env)?.or_else(|| proxy_metadata.get(env).cloned()) else {
+        return Ok(Vec::new());
+    };
+    val.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse()
+                .map_err(|_| Error::EnvVar(env.to_string(), val.clone()))
+        })
+        .collect()
+}
+
+/// parse_listener_options reads the options of the listener whose variables start with `prefix`,
+/// as looked up by `lookup`, falling back to `defaults` for those that are not set.
+fn parse_listener_options(
+    prefix: &str,
+    defaults: &ListenerOptions,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<ListenerOptions, Error> {
+    fn get<T: FromStr>(
+        lookup: &impl Fn(&str) -> Option<String>,
+        var: String,
+    ) -> Result<Option<T>, Error> {
+        match lookup(&var) {
+            Some(val) => val.parse().map(Some).map_err(|_| Error::EnvVar(var, val)),
+            None => Ok(None),
+        }
+    }
+    let var = |name: &str| format!("{prefix}{name}");
+    let backlog = get(&lookup, var(LISTENER_BACKLOG))?.unwrap_or(defaults.backlog);
+    if backlog == 0 {
+        return Err(Error::EnvVar(var(LISTENER_BACKLOG), "0".to_string()));
+    }
+    Ok(ListenerOptions {
+        backlog,
+        recv_buffer_size: get(&lookup, var(LISTENER_RECV_BUFFER_SIZE))?
+            .or(defaults.recv_buffer_size),
+        send_buffer_size: get(&lookup, var(LISTENER_SEND_BUFFER_SIZE))?
+            .or(defaults.send_buffer_size),
+        nodelay: get(&lookup, var(LISTENER_NODELAY))?.unwrap_or(defaults.nodelay),
+        fast_open_backlog: get(&lookup, var(LISTENER_FAST_OPEN_BACKLOG))?
+            .or(defaults.fast_open_backlog),
+    })
+}
+
+/// parse_list_or is [parse_list], but `default` if neither `env` nor the proxy metadata set the
+/// list. Setting it empty clears it.
+fn parse_list_or<T: FromStr + Clone>(
+    env: &str,
+    proxy_metadata: &HashMap<String, String>,
+    default: &[T],
+) -> Result<Vec<T>, Error> {
+    if env::var(env).is_err() && !proxy_metadata.contains_key(env) {
+        return Ok(default.to_vec());
+    }
+    parse_list(env, proxy_metadata)
+}
+
 fn parse_args() -> String {
     let cli_args: Vec<String> = env::args().collect();
     cli_args[1..].join(" ")
@@ -222,10 +1132,53 @@ pub fn parse_config() -> Result<Config, Error> {
 }
 
 fn parse_proxy_config() -> Result<ProxyConfig, Error> {
-    let mesh_config_path = "./etc/istio/config/mesh";
     let pc_env = parse::<String>(PROXY_CONFIG)?;
     let pc_env = pc_env.as_deref();
-    construct_proxy_config(mesh_config_path, pc_env).map_err(Error::ProxyConfig)
+    let mut pc = construct_proxy_config(MESH_CONFIG_PATH, pc_env).map_err(Error::ProxyConfig)?;
+    if let Some(path) = parse::<PathBuf>(RUNTIME_CONFIG_PATH)? {
+        let runtime = read_runtime_config(&path).map_err(Error::ProxyConfig)?;
+        // ISTIO_META_ env vars are set for this ztunnel alone, so they still take precedence.
+        pc.proxy_metadata.extend(
+            runtime
+                .into_iter()
+                .filter(|(k, _)| env::var(format!("{ISTIO_META_PREFIX}{k}")).is_err()),
+        );
+    }
+    Ok(pc)
+}
+
+/// read_runtime_config reads a YAML map of proxy metadata entries. A missing file is empty, as the
+/// ConfigMap it is mounted from may not exist yet.
+fn read_runtime_config(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => {
+            return Err(anyhow!(
+                "failed reading runtime config {}: {e}",
+                path.display()
+            ))
+        }
+    };
+    let entries: Option<HashMap<String, serde_yaml::Value>> = serde_yaml::from_str(&data)
+        .map_err(|e| anyhow!("failed parsing runtime config {}: {e}", path.display()))?;
+    entries
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                serde_yaml::Value::String(s) => s,
+                serde_yaml::Value::Bool(b) => b.to_string(),
+                serde_yaml::Value::Number(n) => n.to_string(),
+                _ => {
+                    return Err(anyhow!(
+                        "runtime config {k} must be a string, bool or number"
+                    ))
+                }
+            };
+            Ok((k, v))
+        })
+        .collect()
 }
 
 pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
@@ -234,21 +1187,78 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
     } else {
         "https://localhost:15012".to_string()
     };
-    let xds_address = validate_uri(empty_to_none(
-        parse(XDS_ADDRESS)?
-            .or(pc.discovery_address)
-            .or_else(|| Some(default_istiod_address.clone())),
-    ))?;
+    let xds_replay_path = parse::<PathBuf>(XDS_REPLAY_PATH)?;
+    // A replayed recording stands in for the control plane.
+    let xds_address = match xds_replay_path {
+        Some(_) => None,
+        None => validate_uri(empty_to_none(
+            parse(XDS_ADDRESS)?
+                .or(pc.discovery_address)
+                .or_else(|| Some(default_istiod_address.clone())),
+        ))?,
+    };
+
+    let tls_key_log = parse::<PathBuf>(SSLKEYLOGFILE)?.filter(|p| !p.as_os_str().is_empty());
+    if tls_key_log.is_some() && !parse_default(UNSAFE_ENABLE_TLS_KEY_LOG, false)? {
+        return Err(Error::UnsafeTlsKeyLog);
+    }
+    let hardened_key_storage = parse_default(HARDENED_KEY_STORAGE, false)?;
+    if tls_key_log.is_some() && hardened_key_storage {
+        return Err(Error::HardenedTlsKeyLog);
+    }
 
     let cluster_id = parse_default(CLUSTER_ID, DEFAULT_CLUSTER_ID.to_string())?;
     let cluster_domain = parse_default(CLUSTER_DOMAIN, DEFAULT_CLUSTER_DOMAIN.to_string())?;
 
+    let remote_xds_clusters: Vec<RemoteXdsCluster> = match xds_address {
+        Some(_) => parse_list(REMOTE_XDS_CLUSTERS, &pc.proxy_metadata)?,
+        None => Vec::new(),
+    };
+    // Each cluster, including our own, may only be discovered once.
+    let mut remote_cluster_ids = HashSet::from([&cluster_id]);
+    for remote in &remote_xds_clusters {
+        if !remote_cluster_ids.insert(&remote.cluster_id) {
+            return Err(Error::EnvVar(
+                REMOTE_XDS_CLUSTERS.to_string(),
+                remote.cluster_id.clone(),
+            ));
+        }
+    }
+
     let fake_ca = parse_default(FAKE_CA, false)?;
-    let ca_address = validate_uri(empty_to_none(if fake_ca {
-        None
-    } else {
-        Some(parse_default(CA_ADDRESS, default_istiod_address)?)
-    }))?;
+    let ca_provider = match parse::<String>(CA_PROVIDER)? {
+        Some(provider) => match provider.as_str() {
+            CA_PROVIDER_ISTIOD => CaProvider::Istiod,
+            CA_PROVIDER_SELF_SIGNED => CaProvider::SelfSigned,
+            CA_PROVIDER_FILE => CaProvider::File(parse_default(
+                CA_CERT_DIR,
+                PathBuf::from(DEFAULT_CA_CERT_DIR),
+            )?),
+            CA_PROVIDER_SDS => CaProvider::Sds(parse_default(
+                SDS_SOCKET_PATH,
+                PathBuf::from(DEFAULT_SDS_SOCKET_PATH),
+            )?),
+            CA_PROVIDER_SPIFFE => {
+                let socket = parse_default(
+                    SPIFFE_ENDPOINT_SOCKET,
+                    DEFAULT_SPIFFE_ENDPOINT_SOCKET.to_string(),
+                )?;
+                // The SPIFFE specification writes the address as a unix:// URI.
+                CaProvider::WorkloadApi(PathBuf::from(
+                    socket.strip_prefix("unix://").unwrap_or(&socket),
+                ))
+            }
+            _ => return Err(Error::EnvVar(CA_PROVIDER.to_string(), provider)),
+        },
+        None => CaProvider::Istiod,
+    };
+    let ca_address = validate_uri(empty_to_none(
+        if fake_ca || ca_provider != CaProvider::Istiod {
+            None
+        } else {
+            Some(parse_default(CA_ADDRESS, default_istiod_address)?)
+        },
+    ))?;
 
     let xds_root_cert_provider =
         parse_default(XDS_ROOT_CA_ENV, DEFAULT_ROOT_CERT_PROVIDER.to_string())?;
@@ -273,7 +1283,24 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
     };
 
     use trust_dns_resolver::system_conf::read_system_conf;
-    let (dns_resolver_cfg, dns_resolver_opts) = read_system_conf().unwrap();
+    let (mut dns_resolver_cfg, dns_resolver_opts) = read_system_conf().unwrap();
+    let dns_upstreams: Vec<DnsUpstream> = parse_list(DNS_UPSTREAMS, &pc.proxy_metadata)?;
+    if !dns_upstreams.is_empty() {
+        dns_resolver_cfg = ResolverConfig::from_parts(
+            dns_resolver_cfg.domain().cloned(),
+            dns_resolver_cfg.search().to_vec(),
+            dns_upstreams
+                .iter()
+                .map(DnsUpstream::name_server)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    let admin_auth = AdminAuth {
+        mtls_identity: parse(ADMIN_MTLS_IDENTITY)?,
+        allowed_identities: parse_list(ADMIN_ALLOWED_IDENTITIES, &pc.proxy_metadata)?,
+        metrics: parse_default(METRICS_MTLS, false)?,
+    };
 
     validate_config(Config {
         proxy: parse_default(ENABLE_PROXY, true)?,
@@ -285,15 +1312,113 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
         window_size: 4 * 1024 * 1024,
         connection_window_size: 4 * 1024 * 1024,
         frame_size: 1024 * 1024,
+        hbone_max_concurrent_streams: parse_runtime(
+            HBONE_MAX_CONCURRENT_STREAMS,
+            &pc.proxy_metadata,
+        )?,
+        hbone_buffer_size: parse_default(HBONE_BUFFER_SIZE, DEFAULT_HBONE_BUFFER_SIZE)?,
+        hbone_pooling: parse_default(HBONE_POOLING, true)?,
+        hbone_pool_idle_timeout: parse_runtime::<GoDuration>(
+            HBONE_POOL_IDLE_TIMEOUT,
+            &pc.proxy_metadata,
+        )?
+        .map(|d| d.0)
+        .unwrap_or(DEFAULT_HBONE_POOL_IDLE_TIMEOUT),
+        hbone_pool_max_idle: parse_runtime(HBONE_POOL_MAX_IDLE, &pc.proxy_metadata)?
+            .unwrap_or(usize::MAX),
 
         self_termination_deadline: DEFAULT_SELFTERM_DEADLINE,
-
-        // admin API should only be accessible over localhost
+        connect_timeout: parse_runtime::<GoDuration>(CONNECT_TIMEOUT, &pc.proxy_metadata)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        connect_response_timeout: parse_runtime::<GoDuration>(
+            CONNECT_RESPONSE_TIMEOUT,
+            &pc.proxy_metadata,
+        )?
+        .map(|d| d.0)
+        .unwrap_or(DEFAULT_CONNECT_RESPONSE_TIMEOUT),
+        handshake_timeout: parse_runtime::<GoDuration>(HANDSHAKE_TIMEOUT, &pc.proxy_metadata)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT),
+        workload_drain_duration: parse::<GoDuration>(WORKLOAD_DRAIN_DURATION)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_WORKLOAD_DRAIN_DURATION),
+        termination_drain_duration: parse::<GoDuration>(TERMINATION_DRAIN_DURATION)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_TERMINATION_DRAIN_DURATION),
+        listener_handoff_path: parse(LISTENER_HANDOFF_PATH)?,
+        readiness_file: parse::<PathBuf>(READINESS_FILE)?.filter(|p| !p.as_os_str().is_empty()),
+        cert_prefetch_history: parse::<PathBuf>(CERT_PREFETCH_HISTORY_PATH)?
+            .filter(|p| !p.as_os_str().is_empty()),
+        protocol_detection: ProtocolDetection {
+            timeout: parse::<GoDuration>(PROTOCOL_DETECTION_TIMEOUT)?
+                .map(|d| d.0)
+                .unwrap_or_default(),
+            server_first_ports: parse_list_or(
+                SERVER_FIRST_PORTS,
+                &pc.proxy_metadata,
+                DEFAULT_SERVER_FIRST_PORTS,
+            )?,
+        },
+        latency_sensitive_ports: parse_list_or(
+            LATENCY_SENSITIVE_PORTS,
+            &pc.proxy_metadata,
+            DEFAULT_LATENCY_SENSITIVE_PORTS,
+        )?,
+        socket_marks: {
+            let mark = parse::<SocketMark>(SOCKET_MARK)?.map(|m| m.0);
+            SocketMarks {
+                inbound: parse::<SocketMark>(INBOUND_SOCKET_MARK)?.map_or(mark, |m| Some(m.0)),
+                outbound: parse::<SocketMark>(OUTBOUND_SOCKET_MARK)?.map_or(mark, |m| Some(m.0)),
+            }
+        },
+        fault_injection: {
+            let directions = parse_list::<String>(FAULT_INJECTION_DIRECTIONS, &pc.proxy_metadata)?;
+            for direction in &directions {
+                if direction != "inbound" && direction != "outbound" {
+                    return Err(Error::EnvVar(
+                        FAULT_INJECTION_DIRECTIONS.to_string(),
+                        direction.to_string(),
+                    ));
+                }
+            }
+            // Both directions, unless restricted.
+            let injects = |d: &str| directions.is_empty() || directions.iter().any(|x| x == d);
+            FaultInjection {
+                inbound: injects("inbound"),
+                outbound: injects("outbound"),
+                connect_delay: parse::<GoDuration>(FAULT_CONNECT_DELAY)?
+                    .map(|d| d.0)
+                    .unwrap_or_default(),
+                connect_delay_percentage: parse_default(FAULT_CONNECT_DELAY_PERCENTAGE, 0)?,
+                handshake_failure_percentage: parse_default(FAULT_HANDSHAKE_FAILURE_PERCENTAGE, 0)?,
+                corruption_percentage: parse_default(FAULT_CORRUPTION_PERCENTAGE, 0)?,
+                reset_percentage: parse_default(FAULT_RESET_PERCENTAGE, 0)?,
+            }
+        },
+        tls_key_log,
+        crl_path: parse::<PathBuf>(CRL_PATH)?.filter(|p| !p.as_os_str().is_empty()),
+        crl_refresh_interval: parse::<GoDuration>(CRL_REFRESH_INTERVAL)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_CRL_REFRESH_INTERVAL),
+        tls_key_exchange_groups: parse_list(TLS_KEY_EXCHANGE_GROUPS, &pc.proxy_metadata)?,
+        hardened_key_storage,
+        hbone_early_data: parse_default(HBONE_EARLY_DATA, false)?,
+
+        // admin API should only be accessible over localhost, unless it requires mTLS
         // todo: bind to both v4 localhost and v6
         admin_addr: SocketAddr::new(
-            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            if admin_auth.mtls_identity.is_some() {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::LOCALHOST)
+            },
             pc.proxy_admin_port.unwrap_or(DEFAULT_ADMIN_PORT),
         ),
+        admin_auth,
+        admin_uds_path: parse::<PathBuf>(ADMIN_UDS_PATH)?.filter(|p| !p.as_os_str().is_empty()),
+        connection_events_socket: parse::<PathBuf>(CONNECTION_EVENTS_SOCKET)?
+            .filter(|p| !p.as_os_str().is_empty()),
         stats_addr: SocketAddr::new(
             IpAddr::V6(Ipv6Addr::UNSPECIFIED),
             pc.status_port.unwrap_or(DEFAULT_STATS_PORT),
@@ -304,6 +1429,7 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
         ),
 
         socks5_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 15080),
+        http_connect_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 15081),
         inbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15008),
         inbound_plaintext_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15006),
         outbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 15001),
@@ -319,22 +1445,145 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
             },
             None => ProxyMode::Shared,
         },
+        // Unlike the env var, proxy metadata can be updated in the mesh config at runtime.
+        outbound_traffic_policy: match parse::<String>(OUTBOUND_TRAFFIC_POLICY)?
+            .or_else(|| pc.proxy_metadata.get(OUTBOUND_TRAFFIC_POLICY).cloned())
+        {
+            Some(policy) => match policy.to_lowercase().as_str() {
+                OUTBOUND_TRAFFIC_POLICY_STRICT => OutboundTrafficPolicy::Strict,
+                OUTBOUND_TRAFFIC_POLICY_PERMISSIVE => OutboundTrafficPolicy::Permissive,
+                _ => return Err(Error::EnvVar(OUTBOUND_TRAFFIC_POLICY.to_string(), policy)),
+            },
+            None => OutboundTrafficPolicy::Permissive,
+        },
+        grpc_health_signals: parse_default(GRPC_HEALTH_SIGNALS, false)?,
+        inbound_port_policy: match parse::<String>(INBOUND_PORT_POLICY)? {
+            Some(policy) => match policy.to_lowercase().as_str() {
+                INBOUND_PORT_POLICY_ENFORCE => InboundPortPolicy::Enforce,
+                INBOUND_PORT_POLICY_PERMISSIVE => InboundPortPolicy::Permissive,
+                _ => return Err(Error::EnvVar(INBOUND_PORT_POLICY.to_string(), policy)),
+            },
+            None => InboundPortPolicy::Permissive,
+        },
+        inbound_source_policy: match parse::<String>(INBOUND_SOURCE_POLICY)? {
+            Some(policy) => match policy.to_lowercase().as_str() {
+                INBOUND_SOURCE_POLICY_STRICT => InboundSourcePolicy::Strict,
+                INBOUND_SOURCE_POLICY_PERMISSIVE => InboundSourcePolicy::Permissive,
+                _ => return Err(Error::EnvVar(INBOUND_SOURCE_POLICY.to_string(), policy)),
+            },
+            None => InboundSourcePolicy::Permissive,
+        },
+        service_hairpin_policy: match parse::<String>(SERVICE_HAIRPIN_POLICY)? {
+            Some(policy) => match policy.to_lowercase().as_str() {
+                SERVICE_HAIRPIN_POLICY_ALLOW => HairpinPolicy::Allow,
+                SERVICE_HAIRPIN_POLICY_PREFER => HairpinPolicy::Prefer,
+                SERVICE_HAIRPIN_POLICY_AVOID => HairpinPolicy::Avoid,
+                _ => return Err(Error::EnvVar(SERVICE_HAIRPIN_POLICY.to_string(), policy)),
+            },
+            None => HairpinPolicy::Allow,
+        },
+        inbound_allowed_source_cidrs: parse_list(INBOUND_ALLOWED_SOURCE_CIDRS, &pc.proxy_metadata)?,
+        rbac_audit: match parse::<String>(RBAC_AUDIT)?
+            .or_else(|| pc.proxy_metadata.get(RBAC_AUDIT).cloned())
+        {
+            Some(audit) => audit
+                .parse()
+                .map_err(|_| Error::EnvVar(RBAC_AUDIT.to_string(), audit))?,
+            None => false,
+        },
+        policy_fail_closed: parse_default(POLICY_FAIL_CLOSED, false)?,
+        policy_stale_after: parse::<GoDuration>(POLICY_STALE_AFTER)?.map(|d| d.0),
+        outbound_bypass: OutboundBypass {
+            cidrs: parse_list(OUTBOUND_BYPASS_CIDRS, &pc.proxy_metadata)?,
+            ports: parse_list(OUTBOUND_BYPASS_PORTS, &pc.proxy_metadata)?,
+            workloads: parse_list(OUTBOUND_BYPASS_WORKLOADS, &pc.proxy_metadata)?,
+        },
+        egress_gateway: match parse(EGRESS_GATEWAY_ADDRESS)? {
+            Some(address) => Some(EgressGateway {
+                address,
+                cidrs: parse_list(EGRESS_GATEWAY_CIDRS, &pc.proxy_metadata)?,
+                hostnames: parse_list(EGRESS_GATEWAY_HOSTNAMES, &pc.proxy_metadata)?,
+            }),
+            None => None,
+        },
+        trace_sampling_percentage: parse_default(TRACE_SAMPLING_PERCENTAGE, 0)?,
+        metrics_namespace_series_limit: parse(METRICS_NAMESPACE_SERIES_LIMIT)?,
+        fair_queueing: match parse::<u64>(FAIR_QUEUEING_BANDWIDTH)? {
+            Some(bandwidth) => Some(FairQueueing {
+                bandwidth,
+                weights: parse_list::<FairQueueingWeight>(
+                    FAIR_QUEUEING_WEIGHTS,
+                    &pc.proxy_metadata,
+                )?
+                .into_iter()
+                .map(|w| (w.0, w.1))
+                .collect(),
+            }),
+            None => None,
+        },
+        outlier_detection: match parse::<u32>(OUTLIER_DETECTION_CONSECUTIVE_ERRORS)? {
+            Some(consecutive_errors) => Some(OutlierDetection {
+                consecutive_errors,
+                base_ejection_time: parse::<GoDuration>(OUTLIER_DETECTION_BASE_EJECTION_TIME)?
+                    .map(|d| d.0)
+                    .unwrap_or(DEFAULT_OUTLIER_BASE_EJECTION_TIME),
+                max_ejection_percent: parse_default(
+                    OUTLIER_DETECTION_MAX_EJECTION_PERCENT,
+                    DEFAULT_OUTLIER_MAX_EJECTION_PERCENT,
+                )?,
+            }),
+            None => None,
+        },
+        ipfix: match parse::<SocketAddr>(IPFIX_COLLECTOR_ADDRESS)? {
+            Some(collector) => Some(IpfixExport {
+                collector,
+                interval: parse::<GoDuration>(IPFIX_EXPORT_INTERVAL)?
+                    .map(|d| d.0)
+                    .unwrap_or(DEFAULT_IPFIX_EXPORT_INTERVAL),
+                observation_domain_id: parse_default(IPFIX_OBSERVATION_DOMAIN_ID, 0)?,
+            }),
+            None => None,
+        },
+        traffic_pairs: match parse::<GoDuration>(TRAFFIC_PAIRS_WINDOW)? {
+            Some(window) => Some(TrafficPairs {
+                window: window.0,
+                report_address: parse(TRAFFIC_PAIRS_REPORT_ADDRESS)?,
+                report_interval: parse::<GoDuration>(TRAFFIC_PAIRS_REPORT_INTERVAL)?
+                    .map(|d| d.0)
+                    .unwrap_or(DEFAULT_TRAFFIC_PAIRS_REPORT_INTERVAL),
+            }),
+            None => None,
+        },
+        l4_filters: parse_list(L4_FILTERS, &pc.proxy_metadata)?,
         local_ip: parse(INSTANCE_IP)?,
         cluster_id: cluster_id.clone(),
         cluster_domain,
 
         xds_address,
+        remote_xds_clusters,
         xds_root_cert,
         ca_address,
         ca_root_cert,
         local_xds_config: parse::<PathBuf>(LOCAL_XDS_PATH)?.map(ConfigSource::File),
+        xds_record_path: parse(XDS_RECORD_PATH)?,
+        xds_replay_path,
         xds_on_demand: parse_default(XDS_ON_DEMAND, false)?,
+        xds_on_demand_timeout: parse::<GoDuration>(XDS_ON_DEMAND_TIMEOUT)?
+            .map(|d| d.0)
+            .unwrap_or(DEFAULT_ON_DEMAND_TIMEOUT),
+        // Zero disables re-resolution, like leaving it unset.
+        control_plane_reresolve_interval: parse::<GoDuration>(CONTROL_PLANE_RERESOLVE_INTERVAL)?
+            .map(|d| d.0)
+            .filter(|d| !d.is_zero()),
+        runtime_config_path: parse(RUNTIME_CONFIG_PATH)?,
         proxy_metadata: pc.proxy_metadata,
 
         fake_ca,
+        ca_provider,
         auth: identity::AuthSource::Token(
             PathBuf::from(r"./var/run/secrets/tokens/istio-token"),
             cluster_id,
+            parse(CA_TOKEN_AUDIENCE)?,
         ),
 
         num_worker_threads: parse_default(
@@ -344,9 +1593,53 @@ pub fn construct_config(pc: ProxyConfig) -> Result<Config, Error> {
                 .try_into()
                 .expect("concurrency cannot be negative"),
         )?,
+        listener_shards: parse_default(
+            LISTENER_SHARDS,
+            std::thread::available_parallelism().map_or(1, |n| n.get()),
+        )?,
+        runtime_mode: match parse::<String>(RUNTIME_MODE)? {
+            Some(mode) => match mode.as_str() {
+                RUNTIME_MODE_SHARED => RuntimeMode::Shared,
+                RUNTIME_MODE_PER_CORE => RuntimeMode::PerCore,
+                _ => return Err(Error::EnvVar(RUNTIME_MODE.to_string(), mode)),
+            },
+            None => RuntimeMode::Shared,
+        },
+        io_uring: parse_default(IO_URING, false)?,
 
         enable_original_source: parse(ENABLE_ORIG_SRC)?,
+        preserve_source_port: parse_default(PRESERVE_SOURCE_PORT, false)?,
+        preserve_tos: {
+            let listeners = parse_list::<String>(PRESERVE_TOS, &pc.proxy_metadata)?;
+            for listener in &listeners {
+                if !["inbound", "inbound_passthrough", "outbound"].contains(&listener.as_str()) {
+                    return Err(Error::EnvVar(
+                        PRESERVE_TOS.to_string(),
+                        listener.to_string(),
+                    ));
+                }
+            }
+            let preserves = |l: &str| listeners.iter().any(|x| x == l);
+            PreserveTos {
+                inbound: preserves("inbound"),
+                inbound_passthrough: preserves("inbound_passthrough"),
+                outbound: preserves("outbound"),
+            }
+        },
+        listener_tuning: {
+            let env = |name: &str| env::var(name).ok();
+            let all = parse_listener_options("", &ListenerOptions::default(), env)?;
+            ListenerTuning {
+                inbound: parse_listener_options("INBOUND_", &all, env)?,
+                inbound_passthrough: parse_listener_options("INBOUND_PASSTHROUGH_", &all, env)?,
+                outbound: parse_listener_options("OUTBOUND_", &all, env)?,
+                socks5: parse_listener_options("SOCKS5_", &all, env)?,
+                http_connect: parse_listener_options("HTTP_CONNECT_", &all, env)?,
+                hbone_fast_open: parse_default(HBONE_FAST_OPEN, false)?,
+            }
+        },
         proxy_args: parse_args(),
+        dns_upstreams,
         dns_resolver_cfg,
         dns_resolver_opts,
     })
@@ -359,6 +1652,123 @@ fn validate_config(cfg: Config) -> Result<Config, Error> {
         )));
     }
 
+    if cfg.trace_sampling_percentage > 100 {
+        return Err(Error::EnvVar(
+            TRACE_SAMPLING_PERCENTAGE.to_string(),
+            cfg.trace_sampling_percentage.to_string(),
+        ));
+    }
+
+    let faults = &cfg.fault_injection;
+    for (env, percentage) in [
+        (
+            FAULT_CONNECT_DELAY_PERCENTAGE,
+            faults.connect_delay_percentage,
+        ),
+        (
+            FAULT_HANDSHAKE_FAILURE_PERCENTAGE,
+            faults.handshake_failure_percentage,
+        ),
+        (FAULT_CORRUPTION_PERCENTAGE, faults.corruption_percentage),
+        (FAULT_RESET_PERCENTAGE, faults.reset_percentage),
+    ] {
+        if percentage > 100 {
+            return Err(Error::EnvVar(env.to_string(), percentage.to_string()));
+        }
+    }
+
+    if cfg.fake_ca && cfg.ca_provider != CaProvider::Istiod {
+        return Err(Error::ProxyConfig(anyhow!(
+            "FAKE_CA cannot be combined with CA_PROVIDER"
+        )));
+    }
+
+    if cfg.listener_shards == 0 {
+        return Err(Error::EnvVar(LISTENER_SHARDS.to_string(), "0".to_string()));
+    }
+
+    if cfg.hbone_max_concurrent_streams == Some(0) {
+        return Err(Error::EnvVar(
+            HBONE_MAX_CONCURRENT_STREAMS.to_string(),
+            "0".to_string(),
+        ));
+    }
+
+    if cfg.hbone_buffer_size == 0 {
+        return Err(Error::EnvVar(
+            HBONE_BUFFER_SIZE.to_string(),
+            "0".to_string(),
+        ));
+    }
+
+    if let Some(od) = &cfg.outlier_detection {
+        if od.consecutive_errors == 0 {
+            return Err(Error::EnvVar(
+                OUTLIER_DETECTION_CONSECUTIVE_ERRORS.to_string(),
+                "0".to_string(),
+            ));
+        }
+        if od.max_ejection_percent > 100 {
+            return Err(Error::EnvVar(
+                OUTLIER_DETECTION_MAX_EJECTION_PERCENT.to_string(),
+                od.max_ejection_percent.to_string(),
+            ));
+        }
+    }
+
+    if matches!(&cfg.fair_queueing, Some(fq) if fq.bandwidth == 0) {
+        return Err(Error::EnvVar(
+            FAIR_QUEUEING_BANDWIDTH.to_string(),
+            "0".to_string(),
+        ));
+    }
+
+    if matches!(&cfg.ipfix, Some(ipfix) if ipfix.interval.is_zero()) {
+        return Err(Error::EnvVar(
+            IPFIX_EXPORT_INTERVAL.to_string(),
+            "0s".to_string(),
+        ));
+    }
+
+    if let Some(pairs) = &cfg.traffic_pairs {
+        if pairs.window.is_zero() {
+            return Err(Error::EnvVar(
+                TRAFFIC_PAIRS_WINDOW.to_string(),
+                "0s".to_string(),
+            ));
+        }
+        if pairs.report_interval.is_zero() {
+            return Err(Error::EnvVar(
+                TRAFFIC_PAIRS_REPORT_INTERVAL.to_string(),
+                "0s".to_string(),
+            ));
+        }
+    }
+
+    if cfg.crl_path.is_some() && cfg.crl_refresh_interval.is_zero() {
+        return Err(Error::EnvVar(
+            CRL_REFRESH_INTERVAL.to_string(),
+            "0s".to_string(),
+        ));
+    }
+
+    // Every worker binds its own listeners, which only share connections if they agree on a port.
+    if cfg.runtime_mode == RuntimeMode::PerCore
+        && [
+            cfg.inbound_addr,
+            cfg.inbound_plaintext_addr,
+            cfg.outbound_addr,
+            cfg.socks5_addr,
+            cfg.http_connect_addr,
+        ]
+        .iter()
+        .any(|a| a.port() == 0)
+    {
+        return Err(Error::ProxyConfig(anyhow!(
+            "per-core runtime mode requires fixed proxy listener ports"
+        )));
+    }
+
     if !cfg.proxy && !cfg.dns_proxy {
         return Err(Error::ProxyConfig(anyhow!(
             "ztunnel run without any servers enabled"
@@ -539,4 +1949,309 @@ pub mod tests {
         assert_eq!(cfg.proxy_metadata["NO_PREFIX"], "no-prefix");
         assert_eq!(cfg.proxy_metadata["INCLUDE_THIS"], "foobar-env");
     }
+
+    #[test]
+    fn socket_mark() {
+        assert_eq!("1337".parse::<SocketMark>().unwrap().0, 1337);
+        assert_eq!("0x539".parse::<SocketMark>().unwrap().0, 0x539);
+        assert_eq!("0XFF".parse::<SocketMark>().unwrap().0, 0xff);
+        assert!("0x".parse::<SocketMark>().is_err());
+        assert!("-1".parse::<SocketMark>().is_err());
+        assert!("mark".parse::<SocketMark>().is_err());
+    }
+
+    #[test]
+    fn dns_upstream() {
+        let upstream = "udp://[::1]".parse::<DnsUpstream>().unwrap();
+        assert_eq!(upstream.address, "[::1]:53".parse().unwrap());
+        assert_eq!(upstream.name_server().protocol, Protocol::Udp);
+
+        // Encrypted upstreams must name their certificate's identity, and only they can.
+        assert!("tls://1.1.1.1".parse::<DnsUpstream>().is_err());
+        assert!("tls://1.1.1.1#".parse::<DnsUpstream>().is_err());
+        assert!("tcp://1.1.1.1#one.one.one.one"
+            .parse::<DnsUpstream>()
+            .is_err());
+        assert!("quic://1.1.1.1#one.one.one.one"
+            .parse::<DnsUpstream>()
+            .is_err());
+        assert!("1.1.1.1".parse::<DnsUpstream>().is_err());
+        assert!("tls://dns.google#dns.google"
+            .parse::<DnsUpstream>()
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "dns-over-tls")]
+    fn encrypted_dns_upstream() {
+        let upstream = "tls://1.1.1.1#one.one.one.one"
+            .parse::<DnsUpstream>()
+            .unwrap();
+        assert_eq!(
+            upstream,
+            DnsUpstream {
+                protocol: DnsProtocol::Tls,
+                address: "1.1.1.1:853".parse().unwrap(),
+                tls_name: Some("one.one.one.one".to_string()),
+            }
+        );
+        let upstream = "https://[2606:4700:4700::1111]:8443#cloudflare-dns.com"
+            .parse::<DnsUpstream>()
+            .unwrap();
+        assert_eq!(
+            upstream.address,
+            "[2606:4700:4700::1111]:8443".parse().unwrap()
+        );
+        assert_eq!(upstream.name_server().protocol, Protocol::Https);
+    }
+
+    #[test]
+    #[cfg(not(feature = "dns-over-tls"))]
+    fn encrypted_dns_upstream_unsupported() {
+        assert!("tls://1.1.1.1#one.one.one.one"
+            .parse::<DnsUpstream>()
+            .is_err());
+        assert!("https://1.1.1.1#cloudflare-dns.com"
+            .parse::<DnsUpstream>()
+            .is_err());
+    }
+
+    #[test]
+    fn outbound_traffic_policy_from_metadata() {
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([(
+                OUTBOUND_TRAFFIC_POLICY.to_string(),
+                "STRICT".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let cfg = construct_config(pc).unwrap();
+        assert_eq!(cfg.outbound_traffic_policy, OutboundTrafficPolicy::Strict);
+    }
+
+    #[test]
+    fn outbound_bypass_from_metadata() {
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([
+                (
+                    OUTBOUND_BYPASS_CIDRS.to_string(),
+                    "10.0.0.0/8, fd00::/8".to_string(),
+                ),
+                (OUTBOUND_BYPASS_PORTS.to_string(), "5432".to_string()),
+                (
+                    OUTBOUND_BYPASS_WORKLOADS.to_string(),
+                    "db/*,ns/app".to_string(),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let bypass = construct_config(pc).unwrap().outbound_bypass;
+        assert!(bypass.matches_destination("10.1.2.3:80".parse().unwrap()));
+        assert!(bypass.matches_destination("[fd00::1]:80".parse().unwrap()));
+        assert!(bypass.matches_destination("192.168.0.1:5432".parse().unwrap()));
+        assert!(!bypass.matches_destination("192.168.0.1:80".parse().unwrap()));
+        assert!(bypass.matches_source("db", "postgres"));
+        assert!(bypass.matches_source("ns", "app"));
+        assert!(!bypass.matches_source("ns", "other"));
+
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([(
+                OUTBOUND_BYPASS_PORTS.to_string(),
+                "http".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert!(matches!(construct_config(pc), Err(Error::EnvVar(_, _))));
+    }
+
+    #[test]
+    fn preserve_tos_from_metadata() {
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([(
+                PRESERVE_TOS.to_string(),
+                "outbound,inbound_passthrough".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let preserve = construct_config(pc).unwrap().preserve_tos;
+        assert_eq!(
+            preserve,
+            PreserveTos {
+                inbound: false,
+                inbound_passthrough: true,
+                outbound: true,
+            }
+        );
+
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([(PRESERVE_TOS.to_string(), "socks5".to_string())]),
+            ..Default::default()
+        };
+        assert!(matches!(construct_config(pc), Err(Error::EnvVar(_, _))));
+    }
+
+    #[test]
+    fn listener_options_override() {
+        let vars = HashMap::from([
+            ("LISTENER_BACKLOG", "4096"),
+            ("LISTENER_NODELAY", "true"),
+            ("OUTBOUND_LISTENER_BACKLOG", "128"),
+        ]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+        let all = parse_listener_options("", &ListenerOptions::default(), lookup).unwrap();
+        let outbound = parse_listener_options("OUTBOUND_", &all, lookup).unwrap();
+
+        assert_eq!(all.backlog, 4096);
+        assert_eq!(
+            outbound,
+            ListenerOptions {
+                backlog: 128,
+                nodelay: true,
+                ..Default::default()
+            }
+        );
+
+        let zero = |name: &str| (name == "LISTENER_BACKLOG").then(|| "0".to_string());
+        assert!(parse_listener_options("", &ListenerOptions::default(), zero).is_err());
+    }
+
+    #[test]
+    fn runtime_config() {
+        let path = std::env::temp_dir().join(format!("runtime-{}.yaml", rand::random::<u64>()));
+        assert!(read_runtime_config(&path).unwrap().is_empty());
+        fs::write(
+            &path,
+            "CONNECT_TIMEOUT: 3s\nHBONE_MAX_CONCURRENT_STREAMS: 250\nHBONE_POOL_MAX_IDLE: 4\n",
+        )
+        .unwrap();
+        let proxy_metadata = read_runtime_config(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let cfg = construct_config(ProxyConfig {
+            proxy_metadata,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.connect_timeout, Duration::from_secs(3));
+        assert_eq!(cfg.hbone_max_concurrent_streams, Some(250));
+        assert_eq!(cfg.hbone_pool_max_idle, 4);
+
+        let pc = ProxyConfig {
+            proxy_metadata: HashMap::from([(HBONE_POOL_MAX_IDLE.to_string(), "maybe".to_string())]),
+            ..Default::default()
+        };
+        assert!(matches!(construct_config(pc), Err(Error::EnvVar(_, _))));
+    }
+
+    #[test]
+    fn remote_xds_clusters() {
+        let remote = |v: &str| ProxyConfig {
+            proxy_metadata: HashMap::from([(REMOTE_XDS_CLUSTERS.to_string(), v.to_string())]),
+            ..Default::default()
+        };
+        let cfg =
+            construct_config(remote("east=istiod.east:15012, west=https://istiod.west")).unwrap();
+        assert_eq!(
+            cfg.remote_xds_clusters,
+            vec![
+                RemoteXdsCluster {
+                    cluster_id: "east".to_string(),
+                    address: "https://istiod.east:15012".to_string(),
+                },
+                RemoteXdsCluster {
+                    cluster_id: "west".to_string(),
+                    address: "https://istiod.west".to_string(),
+                },
+            ]
+        );
+
+        // Clusters must be named, and only once.
+        for invalid in [
+            "istiod.east:15012",
+            "=istiod.east",
+            "east=a,east=b",
+            "Kubernetes=a",
+        ] {
+            assert!(
+                matches!(construct_config(remote(invalid)), Err(Error::EnvVar(_, _))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn l4_filters() {
+        let filters = |v: &str| {
+            construct_config(ProxyConfig {
+                proxy_metadata: HashMap::from([(L4_FILTERS.to_string(), v.to_string())]),
+                ..Default::default()
+            })
+            .map(|cfg| cfg.l4_filters)
+        };
+        assert_eq!(
+            filters("inbound:deny=10.0.0.0/8, outbound:delay=50ms, inbound_passthrough:annotate=tier=gold, outbound:library=/opt/libgeo.so")
+                .unwrap(),
+            vec![
+                FilterSpec {
+                    listener: FilterListener::Inbound,
+                    filter: BuiltinFilter::Deny("10.0.0.0/8".parse().unwrap()),
+                },
+                FilterSpec {
+                    listener: FilterListener::Outbound,
+                    filter: BuiltinFilter::Delay(Duration::from_millis(50)),
+                },
+                FilterSpec {
+                    listener: FilterListener::InboundPassthrough,
+                    filter: BuiltinFilter::Annotate("tier".to_string(), "gold".to_string()),
+                },
+                FilterSpec {
+                    listener: FilterListener::Outbound,
+                    filter: BuiltinFilter::Library(PathBuf::from("/opt/libgeo.so")),
+                },
+            ]
+        );
+        for invalid in [
+            "deny=10.0.0.0/8",
+            "socks5:deny=10.0.0.0/8",
+            "inbound:deny=10.0.0.0",
+            "inbound:wasm=filter.wasm",
+            "inbound:annotate=tier",
+            "inbound:library=",
+        ] {
+            assert!(
+                matches!(filters(invalid), Err(Error::EnvVar(_, _))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn per_core_requires_fixed_ports() {
+        let cfg = construct_config(ProxyConfig::default()).unwrap();
+        let per_core = Config {
+            runtime_mode: RuntimeMode::PerCore,
+            ..cfg.clone()
+        };
+        assert!(validate_config(per_core.clone()).is_ok());
+        let any_port = Config {
+            outbound_addr: SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            ..per_core
+        };
+        assert!(validate_config(any_port).is_err());
+    }
+
+    #[test]
+    fn fake_ca_excludes_provider() {
+        let cfg = construct_config(ProxyConfig::default()).unwrap();
+        let fake = Config {
+            fake_ca: true,
+            ..cfg
+        };
+        assert!(validate_config(fake.clone()).is_ok());
+        let self_signed = Config {
+            ca_provider: CaProvider::SelfSigned,
+            ..fake
+        };
+        assert!(validate_config(self_signed).is_err());
+    }
 }
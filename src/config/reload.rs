@@ -0,0 +1,285 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+use super::{parse_config, Config, MESH_CONFIG_PATH};
+
+/// How often the mesh config and runtime config files are checked for modifications.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// reloadable declares the fields of [Config] that can change at runtime, defining both their
+/// names and [take_reloadable] from the one list, so the two can't drift apart.
+macro_rules! reloadable {
+    ($($field:ident),+ $(,)?) => {
+        /// Fields of [Config] that running components read on every use, and can therefore be
+        /// changed without a restart. Anything else (listener addresses, enabled servers, worker
+        /// threads, ...) is only read at startup.
+        const RELOADABLE: &[&str] = &[$(stringify!($field)),+];
+
+        /// take_reloadable returns `current` with the reloadable fields of `next`.
+        fn take_reloadable(current: &Config, next: Config) -> Config {
+            Config {
+                $($field: next.$field,)+
+                ..current.clone()
+            }
+        }
+    };
+}
+
+reloadable!(
+    window_size,
+    connection_window_size,
+    frame_size,
+    hbone_max_concurrent_streams,
+    self_termination_deadline,
+    outbound_traffic_policy,
+    rbac_audit,
+    outbound_bypass,
+    connect_timeout,
+    connect_response_timeout,
+    handshake_timeout,
+    hbone_pool_idle_timeout,
+    hbone_pool_max_idle,
+);
+
+/// LiveConfig is a handle to the currently active [Config]. Components that support runtime
+/// changes should call [LiveConfig::current] each time they need a reloadable setting, rather
+/// than holding on to a copy.
+#[derive(Clone, Debug)]
+pub struct LiveConfig(watch::Receiver<Arc<Config>>);
+
+impl LiveConfig {
+    /// fixed returns a LiveConfig that will never be updated.
+    pub fn fixed(cfg: Config) -> LiveConfig {
+        let (_tx, rx) = watch::channel(Arc::new(cfg));
+        LiveConfig(rx)
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.0.borrow().clone()
+    }
+}
+
+/// Watcher re-reads the configuration on SIGHUP or when the mesh config or runtime config file
+/// changes, and publishes the reloadable subset of the changes to all [LiveConfig] handles.
+pub struct Watcher {
+    tx: watch::Sender<Arc<Config>>,
+    paths: Vec<PathBuf>,
+}
+
+impl Watcher {
+    pub fn new(cfg: Config) -> (Watcher, LiveConfig) {
+        let paths = std::iter::once(PathBuf::from(MESH_CONFIG_PATH))
+            .chain(cfg.runtime_config_path.clone())
+            .collect();
+        let (tx, rx) = watch::channel(Arc::new(cfg));
+        (Watcher { tx, paths }, LiveConfig(rx))
+    }
+
+    pub async fn run(self) {
+        let mut hangup = imp::Hangup::new();
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_modified = modified(&self.paths).await;
+        loop {
+            tokio::select! {
+                _ = self.tx.closed() => return,
+                _ = hangup.recv() => info!("received signal SIGHUP, reloading configuration"),
+                _ = poll.tick() => {
+                    let m = modified(&self.paths).await;
+                    let Some(changed) = self
+                        .paths
+                        .iter()
+                        .zip(m.iter().zip(&last_modified))
+                        .find_map(|(p, (a, b))| (a != b).then_some(p))
+                    else {
+                        continue;
+                    };
+                    info!(
+                        path = %changed.display(),
+                        "config file changed, reloading configuration"
+                    );
+                    last_modified = m;
+                }
+            }
+            match parse_config() {
+                Ok(next) => self.update(next),
+                Err(e) => warn!("failed to reload configuration, keeping current: {e}"),
+            }
+        }
+    }
+
+    fn update(&self, next: Config) {
+        let current = self.tx.borrow().clone();
+        let (merged, applied, rejected) = merge(&current, next);
+        if !rejected.is_empty() {
+            warn!(
+                fields = ?rejected,
+                "ignoring configuration changes that require a restart to take effect"
+            );
+        }
+        if applied.is_empty() {
+            debug!("no reloadable configuration changes");
+            return;
+        }
+        info!(fields = ?applied, "applied configuration changes");
+        self.tx.send_replace(Arc::new(merged));
+    }
+}
+
+/// merge takes the reloadable fields from `next` on top of `current`. It returns the resulting
+/// config, along with the names of the changed fields that were applied and those that were
+/// rejected because they cannot change at runtime.
+fn merge(current: &Config, next: Config) -> (Config, Vec<String>, Vec<String>) {
+    let (applied, rejected) = changed_fields(current, &next)
+        .into_iter()
+        .partition(|f| RELOADABLE.contains(&f.as_str()));
+    (take_reloadable(current, next), applied, rejected)
+}
+
+/// changed_fields compares the serialized form of two configs, so fields that are not serialized
+/// (such as credentials) are never reported.
+fn changed_fields(a: &Config, b: &Config) -> Vec<String> {
+    let (Ok(serde_json::Value::Object(a)), Ok(serde_json::Value::Object(b))) =
+        (serde_json::to_value(a), serde_json::to_value(b))
+    else {
+        return Vec::new();
+    };
+    let mut changed: Vec<String> = a
+        .into_iter()
+        .filter(|(k, v)| b.get(k) != Some(v))
+        .map(|(k, _)| k)
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// modified returns when each of `paths` was last modified, if it exists.
+async fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(paths.len());
+    for path in paths {
+        let m = tokio::fs::metadata(path).await;
+        times.push(m.and_then(|m| m.modified()).ok());
+    }
+    times
+}
+
+#[cfg(unix)]
+mod imp {
+    use tokio::signal::unix::{signal, Signal, SignalKind};
+    use tracing::warn;
+
+    pub(super) struct Hangup(Option<Signal>);
+
+    impl Hangup {
+        pub(super) fn new() -> Hangup {
+            Hangup(
+                signal(SignalKind::hangup())
+                    .map_err(|e| warn!("failed to register SIGHUP handler: {e}"))
+                    .ok(),
+            )
+        }
+
+        pub(super) async fn recv(&mut self) {
+            if let Some(s) = &mut self.0 {
+                if s.recv().await.is_some() {
+                    return;
+                }
+                // The signal stream is closed, so no further signals will be delivered.
+                self.0 = None;
+            }
+            futures::future::pending::<()>().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub(super) struct Hangup;
+
+    impl Hangup {
+        pub(super) fn new() -> Hangup {
+            Hangup
+        }
+
+        pub(super) async fn recv(&mut self) {
+            futures::future::pending::<()>().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::config::{construct_config, OutboundTrafficPolicy, ProxyConfig};
+
+    #[test]
+    fn merge_reloadable() {
+        let current = construct_config(ProxyConfig::default()).unwrap();
+        let next = Config {
+            outbound_traffic_policy: OutboundTrafficPolicy::Strict,
+            window_size: 1024,
+            ..current.clone()
+        };
+        let (merged, applied, rejected) = merge(&current, next.clone());
+        assert_eq!(merged, next);
+        assert_eq!(applied, vec!["outbound_traffic_policy", "window_size"]);
+        assert!(rejected.is_empty());
+    }
+
+    #[test]
+    fn merge_rejects_listeners() {
+        let current = construct_config(ProxyConfig::default()).unwrap();
+        let next = Config {
+            admin_addr: "127.0.0.1:15999".parse::<SocketAddr>().unwrap(),
+            frame_size: 2048,
+            ..current.clone()
+        };
+        let (merged, applied, rejected) = merge(&current, next);
+        assert_eq!(merged.admin_addr, current.admin_addr);
+        assert_eq!(merged.frame_size, 2048);
+        assert_eq!(applied, vec!["frame_size"]);
+        assert_eq!(rejected, vec!["admin_addr"]);
+    }
+
+    #[test]
+    fn update_publishes() {
+        let initial = construct_config(ProxyConfig::default()).unwrap();
+        let (watcher, live) = Watcher::new(initial.clone());
+        watcher.update(Config {
+            outbound_traffic_policy: OutboundTrafficPolicy::Strict,
+            ..initial.clone()
+        });
+        assert_eq!(
+            live.current().outbound_traffic_policy,
+            OutboundTrafficPolicy::Strict
+        );
+
+        // A change that needs a restart is not published.
+        watcher.update(Config {
+            proxy: false,
+            ..(*live.current()).clone()
+        });
+        assert!(live.current().proxy);
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pre-flight checks of a [Config] against the environment ztunnel is about to run in, so that
+//! problems are reported up front with what to do about them, rather than as a failure deep in
+//! startup or, worse, as traffic that is silently not captured.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use hyper::Uri;
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::time;
+
+use super::{
+    CaProvider, Config, ENABLE_ORIG_SRC, INBOUND_SOCKET_MARK, OUTBOUND_SOCKET_MARK, SOCKET_MARK,
+};
+use crate::socket;
+
+/// How long a control plane address may take to accept a connection.
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// ztunnel would fail to start, or run without doing its job.
+    Error,
+    /// ztunnel can start, but something it depends on looks wrong, and may recover later.
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Report is the outcome of [validate].
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// is_ok returns whether nothing would stop ztunnel from running.
+    pub fn is_ok(&self) -> bool {
+        self.problems.iter().all(|p| p.severity != Severity::Error)
+    }
+
+    fn error(&mut self, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.problems.push(Problem {
+            severity: Severity::Warning,
+            message,
+        });
+    }
+}
+
+/// validate runs every check: the [preflight] checks, whether the listeners of `cfg` can be bound,
+/// and whether the control plane is reachable. Whether a port is free only holds for as long as
+/// nothing else binds it, so this is for `ztunnel validate`, not for startup.
+pub async fn validate(cfg: &Config) -> Report {
+    let mut report = preflight(cfg).await;
+    check_binds(cfg, &mut report).await;
+    report
+        .problems
+        .extend(check_control_plane(cfg).await.problems);
+    report
+}
+
+/// preflight checks what startup would otherwise fail on halfway through: conflicting listeners,
+/// missing certificate and configuration sources, and the privileges the configured features
+/// need. It does not wait on the network, so it can run before every start.
+pub async fn preflight(cfg: &Config) -> Report {
+    let mut report = Report::default();
+    check_listeners(cfg, &mut report);
+    check_privileges(cfg, &mut report).await;
+    check_ca(cfg, &mut report);
+    check_xds(cfg, &mut report);
+    if let Some(path) = &cfg.tls_key_log {
+        report.warning(format!(
+            "TLS session keys will be written to {}, exposing all traffic",
+            path.display()
+        ));
+    }
+    report
+}
+
+/// listeners returns the addresses ztunnel binds, and what for.
+fn listeners(cfg: &Config) -> Vec<(&'static str, SocketAddr)> {
+    let mut listeners = vec![
+        ("admin", cfg.admin_addr),
+        ("stats", cfg.stats_addr),
+        ("readiness", cfg.readiness_addr),
+    ];
+    if cfg.proxy {
+        listeners.extend([
+            ("inbound", cfg.inbound_addr),
+            ("inbound plaintext", cfg.inbound_plaintext_addr),
+            ("outbound", cfg.outbound_addr),
+            ("socks5", cfg.socks5_addr),
+            ("http connect", cfg.http_connect_addr),
+        ]);
+    }
+    if cfg.dns_proxy {
+        listeners.push(("dns proxy", cfg.dns_proxy_addr));
+    }
+    listeners
+}
+
+/// overlaps returns whether binding both addresses would conflict. Port 0 picks a free port, so
+/// never does.
+fn overlaps(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+fn check_listeners(cfg: &Config, report: &mut Report) {
+    let listeners = listeners(cfg);
+    for (i, (name, addr)) in listeners.iter().enumerate() {
+        for (other, other_addr) in &listeners[..i] {
+            if overlaps(*addr, *other_addr) {
+                report.error(format!(
+                    "the {other} ({other_addr}) and {name} ({addr}) listeners both use port {}; configure distinct ports",
+                    addr.port()
+                ));
+            }
+        }
+    }
+}
+
+/// check_binds tries to bind each listener of `cfg`, and releases it again.
+async fn check_binds(cfg: &Config, report: &mut Report) {
+    // Inherited listeners are already bound by the process handing them over.
+    if cfg.listener_handoff_path.is_some() {
+        return;
+    }
+    for (name, addr) in listeners(cfg) {
+        if addr.port() == 0 {
+            continue;
+        }
+        if let Err(e) = TcpListener::bind(addr).await {
+            report.error(format!(
+                "cannot bind the {name} listener on {addr}: {e}; stop whatever is using the port, or configure another one"
+            ));
+        }
+    }
+    if cfg.dns_proxy && cfg.dns_proxy_addr.port() != 0 {
+        if let Err(e) = UdpSocket::bind(cfg.dns_proxy_addr).await {
+            report.error(format!(
+                "cannot bind the dns proxy on udp {}: {e}; stop whatever is using the port, or configure another one",
+                cfg.dns_proxy_addr
+            ));
+        }
+    }
+}
+
+/// check_privileges tries out the socket options that need CAP_NET_ADMIN on Linux.
+async fn check_privileges(cfg: &Config, report: &mut Report) {
+    if cfg.proxy && cfg.enable_original_source != Some(false) {
+        let transparent = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await {
+            Ok(l) => socket::set_transparent(&l),
+            Err(e) => Err(e),
+        };
+        match (transparent, cfg.enable_original_source) {
+            (Ok(()), _) => {}
+            (Err(e), Some(true)) => report.error(format!(
+                "original source is enabled, but transparent sockets are not available: {e}; grant ztunnel CAP_NET_ADMIN, or set {ENABLE_ORIG_SRC}=false"
+            )),
+            (Err(e), _) => report.warning(format!(
+                "transparent sockets are not available, so connections to local workloads will not keep their original source address: {e}; grant ztunnel CAP_NET_ADMIN to enable it"
+            )),
+        }
+    }
+    let marks = [
+        ("inbound", INBOUND_SOCKET_MARK, cfg.socket_marks.inbound),
+        ("outbound", OUTBOUND_SOCKET_MARK, cfg.socket_marks.outbound),
+    ];
+    for (direction, env, mark) in marks {
+        let Some(mark) = mark else {
+            continue;
+        };
+        if let Err(e) = TcpSocket::new_v4().and_then(|s| socket::set_mark(&s, mark)) {
+            report.error(format!(
+                "the {direction} socket mark is set, but sockets cannot be marked: {e}; grant ztunnel CAP_NET_ADMIN, or unset {SOCKET_MARK} and {env}"
+            ));
+        }
+    }
+}
+
+fn check_ca(cfg: &Config, report: &mut Report) {
+    if cfg.fake_ca {
+        return;
+    }
+    match &cfg.ca_provider {
+        CaProvider::Istiod | CaProvider::SelfSigned => {}
+        CaProvider::File(dir) => {
+            for file in ["cert-chain.pem", "key.pem", "root-cert.pem"] {
+                if !dir.join(file).exists() {
+                    report.error(format!(
+                        "the file CA provider needs {}, which does not exist",
+                        dir.join(file).display()
+                    ));
+                }
+            }
+        }
+        CaProvider::Sds(path) => check_socket("SDS server", path, report),
+        CaProvider::WorkloadApi(path) => check_socket("SPIFFE Workload API", path, report),
+    }
+}
+
+fn check_xds(cfg: &Config, report: &mut Report) {
+    if cfg.xds_address.is_none() && cfg.local_xds_config.is_none() {
+        report.warning(
+            "neither an XDS address nor a local XDS config is set, so no workloads will be known"
+                .to_string(),
+        );
+    }
+}
+
+/// check_control_plane probes the CA and the XDS server at once, so an unreachable one costs a
+/// single timeout.
+pub async fn check_control_plane(cfg: &Config) -> Report {
+    async fn probe(what: &str, address: Option<&String>) -> Report {
+        let mut report = Report::default();
+        if let Some(address) = address {
+            check_reachable(what, address, &mut report).await;
+        }
+        report
+    }
+    let ca_address = cfg.ca_address.as_ref().filter(|_| !cfg.fake_ca);
+    let (mut report, xds) = tokio::join!(
+        probe("CA", ca_address),
+        probe("XDS server", cfg.xds_address.as_ref())
+    );
+    report.problems.extend(xds.problems);
+    report
+}
+
+fn check_socket(what: &str, path: &Path, report: &mut Report) {
+    if !path.exists() {
+        report.error(format!(
+            "the {what} socket {} does not exist; check that it is running and its socket is mounted",
+            path.display()
+        ));
+    }
+}
+
+/// check_reachable tries to open a TCP connection to `address`. Failing is only a warning, since
+/// ztunnel keeps retrying the control plane once it runs.
+async fn check_reachable(what: &str, address: &str, report: &mut Report) {
+    let Some((host, port)) = host_port(address) else {
+        report.error(format!("the {what} address {address} has no host"));
+        return;
+    };
+    let connect = match host.parse::<IpAddr>() {
+        Ok(ip) => time::timeout(REACHABILITY_TIMEOUT, TcpStream::connect((ip, port))).await,
+        Err(_) => {
+            time::timeout(
+                REACHABILITY_TIMEOUT,
+                TcpStream::connect((host.as_str(), port)),
+            )
+            .await
+        }
+    };
+    let err = match connect {
+        Ok(Ok(_)) => return,
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("timed out after {REACHABILITY_TIMEOUT:?}"),
+    };
+    report.warning(format!(
+        "the {what} at {address} is not reachable: {err}; check the address, and that network policy allows ztunnel to reach it"
+    ));
+}
+
+/// host_port returns where to connect to for a control plane URI, defaulting the port by scheme.
+fn host_port(address: &str) -> Option<(String, u16)> {
+    let uri: Uri = address.parse().ok()?;
+    let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("http") {
+            80
+        } else {
+            443
+        });
+    Some((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{construct_config, ProxyConfig};
+
+    fn test_config() -> Config {
+        let mut cfg = construct_config(ProxyConfig::default()).unwrap();
+        cfg.xds_address = None;
+        cfg.ca_address = None;
+        cfg.local_xds_config = Some(crate::config::ConfigSource::Static(Default::default()));
+        cfg.enable_original_source = Some(false);
+        cfg
+    }
+
+    #[tokio::test]
+    async fn port_conflicts() {
+        let mut cfg = test_config();
+        cfg.admin_addr = "127.0.0.1:0".parse().unwrap();
+        cfg.stats_addr = "[::]:15020".parse().unwrap();
+        cfg.readiness_addr = "127.0.0.1:15020".parse().unwrap();
+        cfg.listener_handoff_path = Some("/nonexistent".into());
+        cfg.proxy = false;
+        cfg.dns_proxy = false;
+        let report = preflight(&cfg).await;
+        assert!(!report.is_ok());
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0].message.contains("port 15020"));
+    }
+
+    #[tokio::test]
+    async fn port_in_use() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut cfg = test_config();
+        cfg.admin_addr = taken.local_addr().unwrap();
+        cfg.stats_addr = "127.0.0.1:0".parse().unwrap();
+        cfg.readiness_addr = "127.0.0.1:0".parse().unwrap();
+        cfg.proxy = false;
+        cfg.dns_proxy = false;
+        let report = validate(&cfg).await;
+        assert!(!report.is_ok());
+        assert!(report.problems[0].message.contains("cannot bind the admin"));
+    }
+
+    #[tokio::test]
+    async fn unreachable_ca_is_a_warning() {
+        // Nothing listens on a port that was just released.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut report = Report::default();
+        check_reachable("CA", &format!("https://127.0.0.1:{port}"), &mut report).await;
+        assert!(report.is_ok());
+        assert_eq!(report.problems.len(), 1);
+    }
+
+    #[test]
+    fn control_plane_ports() {
+        assert_eq!(
+            host_port("https://istiod.istio-system.svc:15012"),
+            Some(("istiod.istio-system.svc".to_string(), 15012))
+        );
+        assert_eq!(host_port("https://[::1]"), Some(("::1".to_string(), 443)));
+        assert_eq!(
+            host_port("http://localhost"),
+            Some(("localhost".to_string(), 80))
+        );
+    }
+}
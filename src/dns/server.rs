@@ -23,7 +23,6 @@ use itertools::Itertools;
 use once_cell::sync::Lazy;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use tokio::net::{TcpListener, UdpSocket};
 use tracing::{info, warn};
 use trust_dns_proto::error::ProtoErrorKind;
 use trust_dns_proto::op::ResponseCode;
@@ -34,7 +33,7 @@ use trust_dns_server::authority::LookupError;
 use trust_dns_server::server::Request;
 use trust_dns_server::ServerFuture;
 
-use crate::config::ProxyMode;
+use crate::config::{DnsUpstream, ProxyMode};
 use crate::dns;
 use crate::dns::metrics::{
     DnsRequest, ForwardedDuration, ForwardedFailure, ForwardedRequest, Metrics,
@@ -43,7 +42,7 @@ use crate::dns::name_util::{has_domain, trim_domain};
 use crate::dns::resolver::{Answer, Resolver};
 use crate::metrics::{DeferRecorder, IncrementRecorder, Recorder};
 use crate::proxy::Error;
-use crate::socket::to_canonical;
+use crate::socket::{self, to_canonical};
 use crate::state::workload::address::Address;
 use crate::state::workload::{NetworkAddress, Workload};
 use crate::state::DemandProxyState;
@@ -92,7 +91,7 @@ impl Server {
             "starting local DNS server",
         );
         // Bind and register the UDP socket.
-        let udp_socket = UdpSocket::bind(addr)
+        let udp_socket = socket::bind_udp(addr)
             .await
             .map_err(|e| Error::Bind(addr, e))?;
         // Save the bound address.
@@ -100,7 +99,7 @@ impl Server {
         server.register_socket(udp_socket);
 
         // Bind and register the TCP socket.
-        let tcp_listener = TcpListener::bind(addr)
+        let tcp_listener = socket::listen(addr)
             .await
             .map_err(|e| Error::Bind(addr, e))?;
         server.register_listener(
@@ -319,6 +318,9 @@ impl Store {
                         // Should never be empty, since we delete the Vec when it's empty.
                         .unwrap();
 
+                    // A service without addresses is answered with those istiod allocated to
+                    // it, if any, rather than its endpoints.
+                    let service = state.with_allocated_vips(service, &client.network);
                     return Some(ServerMatch {
                         server: Address::Service(Box::new(service)),
                         name: search_name,
@@ -333,6 +335,15 @@ impl Store {
                             alias,
                         });
                     }
+                    // Last, try the name table, which has hosts such as `ServiceEntry`s that
+                    // only exist for DNS.
+                    if let Some(info) = state.names.get(&search_name_str) {
+                        return Some(ServerMatch {
+                            server: Address::Service(Box::new(info.as_service(&client.network))),
+                            name: search_name,
+                            alias,
+                        });
+                    }
                 }
             }
         }
@@ -642,14 +653,18 @@ pub trait Forwarder: Send + Sync {
     ) -> Result<Answer, LookupError>;
 }
 
-/// Creates the appropriate DNS forwarder for the proxy mode.
-pub fn forwarder_for_mode(proxy_mode: ProxyMode) -> Result<Arc<dyn Forwarder>, Error> {
+/// Creates the appropriate DNS forwarder for the proxy mode. Requests are forwarded to `upstreams`
+/// if any are given.
+pub fn forwarder_for_mode(
+    proxy_mode: ProxyMode,
+    upstreams: &[DnsUpstream],
+) -> Result<Arc<dyn Forwarder>, Error> {
     Ok(match proxy_mode {
         ProxyMode::Shared => {
             // TODO(https://github.com/istio/ztunnel/issues/555): Use pod settings if available.
-            Arc::new(SystemForwarder::new()?)
+            Arc::new(SystemForwarder::new(upstreams)?)
         }
-        ProxyMode::Dedicated => Arc::new(SystemForwarder::new()?),
+        ProxyMode::Dedicated => Arc::new(SystemForwarder::new(upstreams)?),
     })
 }
 
@@ -657,20 +672,26 @@ pub fn forwarder_for_mode(proxy_mode: ProxyMode) -> Result<Arc<dyn Forwarder>, E
 /// When running in dedicated (sidecar) proxy mode, this will be the same resolver configuration
 /// that would have been used by the client. For shared proxy mode, this will be the resolver
 /// configuration for the ztunnel DaemonSet (i.e. node-level resolver settings).
+/// Configured upstreams replace the name servers in `/etc/resolv.conf`, which lets requests be
+/// forwarded over TLS or HTTPS. Connections to them are reused across requests.
 struct SystemForwarder {
     search_domains: Vec<Name>,
     resolver: Arc<dyn Resolver>,
 }
 
 impl SystemForwarder {
-    fn new() -> Result<Self, Error> {
+    fn new(upstreams: &[DnsUpstream]) -> Result<Self, Error> {
         // Get the resolver config from /etc/resolv.conf.
         let (cfg, opts) = read_system_conf()?;
 
         // Extract the parts.
         let domain = cfg.domain().cloned();
         let search_domains = cfg.search().to_vec();
-        let name_servers = cfg.name_servers().to_vec();
+        let name_servers = if upstreams.is_empty() {
+            cfg.name_servers().to_vec()
+        } else {
+            upstreams.iter().map(DnsUpstream::name_server).collect()
+        };
 
         // Remove the search list before passing to the resolver. The local resolver that
         // sends the original request will already have search domains applied. We want
@@ -712,16 +733,20 @@ mod tests {
 
     use bytes::Bytes;
     use prometheus_client::registry::Registry;
+    use tokio::net::UdpSocket;
     use trust_dns_server::server::Protocol;
 
     use super::*;
     use crate::metrics;
+    use crate::state::names::NameTableStore;
     use crate::test_helpers::dns::{
         a, aaaa, cname, ip, ipv4, ipv6, n, new_message, new_tcp_client, new_udp_client,
         send_request, server_request, socket_addr,
     };
     use crate::test_helpers::helpers::subscribe;
     use crate::test_helpers::{new_proxy_state, test_default_workload};
+    use crate::xds::istio::networking::nds::v1::name_table::NameInfo as XdsNameInfo;
+    use crate::xds::istio::networking::nds::v1::NameTable as XdsNameTable;
     use crate::xds::istio::workload::NetworkAddress as XdsNetworkAddress;
     use crate::xds::istio::workload::Port as XdsPort;
     use crate::xds::istio::workload::PortList as XdsPortList;
@@ -1031,6 +1056,13 @@ mod tests {
                 query_type: RecordType::AAAA,
                 ..Default::default()
             },
+            Case {
+                name: "success: name table host",
+                host: "api.external.example.",
+                expect_records: vec![
+                    a(n("api.external.example."), ipv4("240.240.0.1"))],
+                ..Default::default()
+            },
             Case {
                 name: "success: wild card returns A record correctly",
                 host: "foo.wildcard.",
@@ -1393,7 +1425,25 @@ mod tests {
             ),
         ];
 
-        new_proxy_state(&workloads, &services, &[])
+        let state = new_proxy_state(&workloads, &services, &[]);
+        // A ServiceEntry host with an address allocated by istiod.
+        let names = XdsNameTable {
+            table: HashMap::from([(
+                "api.external.example".to_string(),
+                XdsNameInfo {
+                    ips: vec!["240.240.0.1".to_string()],
+                    namespace: NS1.to_string(),
+                    ..Default::default()
+                },
+            )]),
+        };
+        state
+            .state
+            .write()
+            .unwrap()
+            .names
+            .replace(NameTableStore::try_from(&names).unwrap());
+        state
     }
 
     fn na<S1: AsRef<str>, S2: AsRef<str>>(network: S1, addr: S2) -> NetworkAddress {
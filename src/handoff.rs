@@ -0,0 +1,391 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handoff of listening sockets between ztunnel processes during an in-place upgrade.
+//!
+//! When enabled, every process serves a Unix socket at the configured path. A new process connects
+//! to it on startup and receives the old process' listening sockets (over `SCM_RIGHTS`) instead of
+//! binding its own. Once the sockets are handed over the old process starts a graceful shutdown,
+//! draining existing connections while the new process accepts new ones on the same sockets.
+//! Sockets that are bound fresh while handoff is enabled also set `SO_REUSEPORT`, so a replacement
+//! can still bind alongside us if the handoff itself fails.
+//!
+//! The handoff socket is only accessible to its owner, and both ends check that their peer runs as
+//! the same user, since whoever connects to it gets our listeners.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use socket2::{SockRef, Socket};
+use tracing::{info, warn};
+
+use crate::signal::ShutdownTrigger;
+
+/// The kind of socket being handed over. TCP and UDP sockets may share an address.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Tcp,
+    Udp,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Entry {
+    kind: Kind,
+    addr: SocketAddr,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Sockets received from the previous process, waiting to be claimed by [take]. An address may
+/// have several sockets when its listener is sharded.
+static INHERITED: Lazy<Mutex<HashMap<(Kind, SocketAddr), Vec<Socket>>>> =
+    Lazy::new(Default::default);
+/// Sockets this process is serving on, which will be handed to our replacement.
+static BOUND: Lazy<Mutex<Vec<(Entry, Socket)>>> = Lazy::new(Default::default);
+
+/// The maximum number of sockets that can be handed over at once: the most file descriptors Linux
+/// passes in a single message (SCM_MAX_FD). Each shard of a listener is a socket of its own.
+pub const MAX_SOCKETS: usize = 253;
+const MAX_PAYLOAD: usize = 64 * 1024;
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// take returns a socket of the given kind inherited for `addr`, if any remain.
+pub fn take(kind: Kind, addr: SocketAddr) -> Option<Socket> {
+    if !enabled() {
+        return None;
+    }
+    INHERITED.lock().unwrap().get_mut(&(kind, addr))?.pop()
+}
+
+/// register records a socket bound for `addr` so it can be handed to our replacement. It fails if
+/// there are more sockets than can be handed over, rather than leave some of them behind.
+pub fn register(kind: Kind, addr: SocketAddr, socket: SockRef) -> io::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let mut bound = BOUND.lock().unwrap();
+    if bound.len() >= MAX_SOCKETS {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "cannot hand over more than {MAX_SOCKETS} sockets, lower LISTENER_SHARDS or disable listener handoff"
+            ),
+        ));
+    }
+    match socket.try_clone() {
+        Ok(s) => bound.push((Entry { kind, addr }, s)),
+        Err(e) => warn!(%addr, "failed to register socket for handoff: {e}"),
+    }
+    Ok(())
+}
+
+/// inherit enables handoff and, if a previous ztunnel is serving `path`, receives its sockets.
+/// It returns the number of sockets received.
+#[cfg(target_os = "linux")]
+pub async fn inherit(path: &Path) -> io::Result<usize> {
+    ENABLED.store(true, Ordering::Relaxed);
+    let stream = match tokio::net::UnixStream::connect(path).await {
+        Ok(s) => s.into_std()?,
+        // Nothing to take over from
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(0)
+        }
+        Err(e) => return Err(e),
+    };
+    imp::check_peer(&stream)?;
+    stream.set_nonblocking(false)?;
+    let received = tokio::task::spawn_blocking(move || imp::recv(&stream))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+    let count = received.len();
+    let mut inherited = INHERITED.lock().unwrap();
+    for (e, s) in received {
+        inherited.entry((e.kind, e.addr)).or_default().push(s);
+    }
+    Ok(count)
+}
+
+/// serve hands our sockets to the first process of our user that connects to `path`, then
+/// triggers a graceful shutdown. Any inherited sockets that were not claimed at this point are closed.
+#[cfg(target_os = "linux")]
+pub fn serve(path: PathBuf, shutdown: ShutdownTrigger) -> io::Result<()> {
+    for ((kind, addr), sockets) in INHERITED.lock().unwrap().drain() {
+        if !sockets.is_empty() {
+            warn!(?kind, %addr, count = sockets.len(), "closing inherited sockets that are no longer used");
+        }
+    }
+    // A previous process may have left the path behind; we only get here once it handed over.
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    tokio::spawn(async move {
+        let res: io::Result<usize> = async {
+            let stream = loop {
+                let (stream, _) = listener.accept().await?;
+                let stream = stream.into_std()?;
+                match imp::check_peer(&stream) {
+                    Ok(()) => break stream,
+                    Err(e) => warn!("refusing listener handoff: {e}"),
+                }
+            };
+            stream.set_nonblocking(false)?;
+            tokio::task::spawn_blocking(move || {
+                let bound = BOUND.lock().unwrap();
+                imp::send(&stream, &bound)
+            })
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        }
+        .await;
+        match res {
+            Ok(count) => {
+                info!(count, "handed listeners over to new process, shutting down");
+                shutdown.shutdown_now().await;
+            }
+            Err(e) => warn!("listener handoff failed: {e}"),
+        }
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::io;
+    use std::mem::{size_of, zeroed};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+
+    use socket2::Socket;
+
+    use super::{Entry, MAX_PAYLOAD, MAX_SOCKETS};
+
+    /// check_peer fails unless the process at the other end of `stream` runs as our user.
+    pub(super) fn check_peer(stream: &UnixStream) -> io::Result<()> {
+        let mut cred: libc::ucred = unsafe { zeroed() };
+        let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+        // Safety: `cred` is a valid buffer of `len` bytes for the duration of the call.
+        let res = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: geteuid cannot fail.
+        let uid = unsafe { libc::geteuid() };
+        if cred.uid != uid {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("peer pid {} runs as uid {}, not {uid}", cred.pid, cred.uid),
+            ));
+        }
+        Ok(())
+    }
+
+    // Backing storage for control messages carrying `space` bytes, aligned for cmsghdr.
+    fn cmsg_buffer(space: u32) -> Vec<u64> {
+        vec![0u64; (space as usize + size_of::<u64>() - 1) / size_of::<u64>()]
+    }
+
+    pub(super) fn send(stream: &UnixStream, bound: &[(Entry, Socket)]) -> io::Result<usize> {
+        if bound.len() > MAX_SOCKETS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("too many sockets to hand over: {}", bound.len()),
+            ));
+        }
+        let entries: Vec<&Entry> = bound.iter().map(|(e, _)| e).collect();
+        let payload = serde_json::to_vec(&entries)?;
+        let fds: Vec<RawFd> = bound.iter().map(|(_, s)| s.as_raw_fd()).collect();
+
+        let mut iov = libc::iovec {
+            iov_base: payload.as_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        // Safety: the message only refers to buffers that outlive the sendmsg call, and the
+        // control buffer is sized by CMSG_SPACE for exactly `fds.len()` descriptors.
+        unsafe {
+            let space = libc::CMSG_SPACE((fds.len() * size_of::<RawFd>()) as u32);
+            let mut control = cmsg_buffer(space);
+            let mut msg: libc::msghdr = zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            if !fds.is_empty() {
+                msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                msg.msg_controllen = space as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN((fds.len() * size_of::<RawFd>()) as u32) as _;
+                std::ptr::copy_nonoverlapping(
+                    fds.as_ptr(),
+                    libc::CMSG_DATA(cmsg) as *mut RawFd,
+                    fds.len(),
+                );
+            }
+            if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(fds.len())
+    }
+
+    pub(super) fn recv(stream: &UnixStream) -> io::Result<Vec<(Entry, Socket)>> {
+        let mut payload = vec![0u8; MAX_PAYLOAD];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        let mut fds = Vec::new();
+        // Safety: the message only refers to buffers that outlive the recvmsg call, and received
+        // descriptors are immediately wrapped in OwnedFd so they are closed if unused.
+        let n = unsafe {
+            let space = libc::CMSG_SPACE((MAX_SOCKETS * size_of::<RawFd>()) as u32);
+            let mut control = cmsg_buffer(space);
+            let mut msg: libc::msghdr = zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = space as _;
+            let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    for i in 0..len / size_of::<RawFd>() {
+                        fds.push(OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            if msg.msg_flags & (libc::MSG_CTRUNC | libc::MSG_TRUNC) != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "handoff message was truncated",
+                ));
+            }
+            n as usize
+        };
+        let entries: Vec<Entry> = serde_json::from_slice(&payload[..n])?;
+        if entries.len() != fds.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "received {} sockets but {} descriptions",
+                    fds.len(),
+                    entries.len()
+                ),
+            ));
+        }
+        Ok(entries
+            .into_iter()
+            .zip(fds.into_iter().map(Socket::from))
+            .collect())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn inherit(_: &Path) -> io::Result<usize> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn serve(_: PathBuf, _: ShutdownTrigger) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "listener handoff is not supported on this operating system",
+    )
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn send_and_receive() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        let bound = vec![
+            (
+                Entry {
+                    kind: Kind::Tcp,
+                    addr: tcp_addr,
+                },
+                Socket::from(tcp),
+            ),
+            (
+                Entry {
+                    kind: Kind::Udp,
+                    addr: udp_addr,
+                },
+                Socket::from(udp),
+            ),
+        ];
+
+        let (old, new) = UnixStream::pair().unwrap();
+        imp::check_peer(&old).unwrap();
+        assert_eq!(imp::send(&old, &bound).unwrap(), 2);
+        let received = imp::recv(&new).unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0.kind, Kind::Tcp);
+        assert_eq!(
+            received[0].1.local_addr().unwrap().as_socket(),
+            Some(tcp_addr)
+        );
+        assert_eq!(received[1].0.kind, Kind::Udp);
+        assert_eq!(
+            received[1].1.local_addr().unwrap().as_socket(),
+            Some(udp_addr)
+        );
+
+        // The inherited listener accepts connections for the original address.
+        let listener = std::net::TcpListener::from(received.into_iter().next().unwrap().1);
+        let _client = std::net::TcpStream::connect(tcp_addr).unwrap();
+        listener.accept().unwrap();
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{
@@ -29,18 +30,36 @@ use hyper::rt::Sleep;
 use hyper::server::conn::{http1, http2};
 use hyper::{Request, Response};
 use hyper_util::client::connect::HttpConnector;
+use prometheus_client::metrics::counter::Counter;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::Stream;
 use tracing::{debug, info, warn};
 
+use crate::config::LiveConfig;
+use crate::identity::Identity;
+use crate::socket;
 use crate::tls::{BoringTlsAcceptor, CertProvider};
 
 pub fn tls_server<T: CertProvider + Clone + 'static>(
     acceptor: T,
     listener: TcpListener,
+) -> impl Stream<Item = tokio_boring::SslStream<TcpStream>> {
+    tls_server_with_timeout(acceptor, listener, None)
+}
+
+/// tls_server_with_timeout is [tls_server], aborting handshakes that take longer than the handshake
+/// timeout of the given config and counting them in the given counter.
+pub fn tls_server_with_timeout<T: CertProvider + Clone + 'static>(
+    acceptor: T,
+    listener: TcpListener,
+    handshake_timeout: Option<(LiveConfig, Counter)>,
 ) -> impl Stream<Item = tokio_boring::SslStream<TcpStream>> {
     use tokio_stream::StreamExt;
-    let boring_acceptor = BoringTlsAcceptor { acceptor };
+    let boring_acceptor = BoringTlsAcceptor {
+        acceptor,
+        handshake_timeout,
+    };
 
     tls_listener::builder(boring_acceptor)
         .listen(listener)
@@ -179,21 +198,33 @@ pub fn plaintext_response(code: hyper::StatusCode, body: String) -> Response<Ful
 pub struct Server<S> {
     name: String,
     bind: TcpListener,
+    #[cfg(unix)]
+    unix: Option<tokio::net::UnixListener>,
     drain_rx: Watch,
     state: Arc<S>,
 }
 
 impl<S> Server<S> {
     pub async fn bind(name: &str, addr: SocketAddr, drain_rx: Watch, s: S) -> anyhow::Result<Self> {
-        let bind = TcpListener::bind(&addr).await?;
+        let bind = socket::listen(addr).await?;
         Ok(Server {
             name: name.to_string(),
             bind,
+            #[cfg(unix)]
+            unix: None,
             drain_rx,
             state: Arc::new(s),
         })
     }
 
+    /// with_unix_socket also serves plaintext on a Unix socket at `path`, which only processes
+    /// running as our user can connect to. Its requests carry a [LocalPeer] extension.
+    #[cfg(unix)]
+    pub fn with_unix_socket(mut self, path: &std::path::Path) -> anyhow::Result<Self> {
+        self.unix = Some(socket::bind_unix(path)?);
+        Ok(self)
+    }
+
     pub fn address(&self) -> SocketAddr {
         self.bind.local_addr().expect("local address must be ready")
     }
@@ -208,10 +239,18 @@ impl<S> Server<S> {
         let address = self.address();
         let drain_stream = self.drain_rx.clone();
         let drain_connections = self.drain_rx;
-        let _name = self.name.clone();
-        // let (tx, rx) = oneshot::channel();
         let state = self.state.clone();
         let f = Arc::new(f);
+        #[cfg(unix)]
+        if let Some(unix) = self.unix {
+            spawn_unix(
+                &self.name,
+                unix,
+                drain_connections.clone(),
+                state.clone(),
+                f.clone(),
+            );
+        }
         info!(
             %address,
             component=self.name,
@@ -222,34 +261,14 @@ impl<S> Server<S> {
             let mut stream = stream.take_until(Box::pin(drain_stream.signaled()));
             while let Some(Ok(socket)) = stream.next().await {
                 socket.set_nodelay(true).unwrap();
-                let drain = drain_connections.clone();
-                let f = f.clone();
-                let state = state.clone();
-                tokio::spawn(async move {
-                    let serve = http1_server()
-                        .half_close(true)
-                        .header_read_timeout(Duration::from_secs(2))
-                        .max_buf_size(8 * 1024)
-                        .serve_connection(
-                            socket,
-                            hyper::service::service_fn(move |req| {
-                                let state = state.clone();
-
-                                f(state, req)
-                            }),
-                        );
-                    // Wait for drain to signal or connection serving to complete
-                    match futures_util::future::select(Box::pin(drain.signaled()), serve).await {
-                        // We got a shutdown request. Start gracful shutdown and wait for the pending requests to complete.
-                        futures_util::future::Either::Left((_shutdown, mut serve)) => {
-                            let drain = std::pin::Pin::new(&mut serve);
-                            drain.graceful_shutdown();
-                            serve.await
-                        }
-                        // Serving finished, just return the result.
-                        futures_util::future::Either::Right((serve, _shutdown)) => serve,
-                    }
-                });
+                tokio::spawn(serve_http1(
+                    socket,
+                    None,
+                    None,
+                    drain_connections.clone(),
+                    state.clone(),
+                    f.clone(),
+                ));
             }
             info!(
                 %address,
@@ -258,4 +277,169 @@ impl<S> Server<S> {
             );
         });
     }
+
+    /// spawn_mtls is [Server::spawn], but clients must first complete an mTLS handshake with
+    /// `acceptor`. The identity a client presented is attached to each of its requests as a
+    /// [PeerIdentity] extension.
+    pub fn spawn_mtls<T, F, R>(self, acceptor: T, f: F)
+    where
+        T: CertProvider + Clone + 'static,
+        S: Send + Sync + 'static,
+        F: Fn(Arc<S>, Request<hyper::body::Incoming>) -> R + Send + Sync + 'static,
+        R: Future<Output = Result<Response<Full<Bytes>>, hyper::Error>> + Send + Sync + 'static,
+    {
+        use futures_util::StreamExt as OtherStreamExt;
+        let address = self.address();
+        let drain_stream = self.drain_rx.clone();
+        let drain_connections = self.drain_rx;
+        let state = self.state.clone();
+        let f = Arc::new(f);
+        #[cfg(unix)]
+        if let Some(unix) = self.unix {
+            spawn_unix(
+                &self.name,
+                unix,
+                drain_connections.clone(),
+                state.clone(),
+                f.clone(),
+            );
+        }
+        info!(
+            %address,
+            component=self.name,
+            "mTLS listener established",
+        );
+        tokio::spawn(async move {
+            let stream = tls_server(acceptor, self.bind);
+            let mut stream = stream.take_until(Box::pin(drain_stream.signaled()));
+            while let Some(socket) = stream.next().await {
+                let socket = crate::tls::early_data::Accepted::new(socket);
+                let identity = socket
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|x| crate::tls::boring::extract_sans(&x).first().cloned());
+                let early = socket.early();
+                tokio::spawn(serve_http1(
+                    socket,
+                    identity,
+                    Some(early),
+                    drain_connections.clone(),
+                    state.clone(),
+                    f.clone(),
+                ));
+            }
+            info!(
+                %address,
+                component=self.name,
+                "listener drained",
+            );
+        });
+    }
+}
+
+/// PeerIdentity is the identity presented by the client of a [Server::spawn_mtls] server.
+#[derive(Clone, Debug)]
+pub struct PeerIdentity(pub Identity);
+
+/// EarlyData marks the requests of a [Server::spawn_mtls] server read from TLS early data, which an
+/// attacker can replay.
+#[derive(Clone, Copy, Debug)]
+pub struct EarlyData;
+
+/// LocalPeer marks the requests of clients connected over a [Server::with_unix_socket] socket,
+/// which run as our own user.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalPeer;
+
+#[cfg(unix)]
+fn spawn_unix<S, F, R>(
+    name: &str,
+    listener: tokio::net::UnixListener,
+    drain: Watch,
+    state: Arc<S>,
+    f: Arc<F>,
+) where
+    S: Send + Sync + 'static,
+    F: Fn(Arc<S>, Request<hyper::body::Incoming>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Response<Full<Bytes>>, hyper::Error>> + Send + Sync + 'static,
+{
+    let name = name.to_string();
+    info!(component = name, "unix socket listener established");
+    tokio::spawn(async move {
+        loop {
+            let socket = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok((socket, _)) => socket,
+                    Err(e) => {
+                        warn!(component = name, "failed to accept: {e}");
+                        continue;
+                    }
+                },
+                _ = drain.clone().signaled() => break,
+            };
+            if let Err(e) = socket::check_unix_peer(&socket) {
+                warn!(component = name, "refusing connection: {e}");
+                continue;
+            }
+            let f = f.clone();
+            tokio::spawn(serve_http1(
+                socket,
+                None,
+                None,
+                drain.clone(),
+                state.clone(),
+                Arc::new(move |state, mut req: Request<hyper::body::Incoming>| {
+                    req.extensions_mut().insert(LocalPeer);
+                    f(state, req)
+                }),
+            ));
+        }
+        info!(component = name, "unix socket listener drained");
+    });
+}
+
+/// serve_http1 serves HTTP/1.1 requests on `socket` with `f` until the client is done, or until
+/// `drain` is signaled and the pending requests complete. `early` tells whether the request being
+/// read is early data.
+async fn serve_http1<IO, S, F, R>(
+    socket: IO,
+    identity: Option<Identity>,
+    early: Option<Arc<AtomicBool>>,
+    drain: Watch,
+    state: Arc<S>,
+    f: Arc<F>,
+) -> hyper::Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + Sync + 'static,
+    F: Fn(Arc<S>, Request<hyper::body::Incoming>) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Response<Full<Bytes>>, hyper::Error>> + Send + Sync + 'static,
+{
+    let serve = http1_server()
+        .half_close(true)
+        .header_read_timeout(Duration::from_secs(2))
+        .max_buf_size(8 * 1024)
+        .serve_connection(
+            socket,
+            hyper::service::service_fn(move |mut req| {
+                if let Some(identity) = &identity {
+                    req.extensions_mut().insert(PeerIdentity(identity.clone()));
+                }
+                if early.as_ref().map_or(false, |e| e.load(Ordering::Relaxed)) {
+                    req.extensions_mut().insert(EarlyData);
+                }
+                f(state.clone(), req)
+            }),
+        );
+    // Wait for drain to signal or connection serving to complete
+    match futures_util::future::select(Box::pin(drain.signaled()), serve).await {
+        // We got a shutdown request. Start gracful shutdown and wait for the pending requests to complete.
+        futures_util::future::Either::Left((_shutdown, mut serve)) => {
+            let drain = std::pin::Pin::new(&mut serve);
+            drain.graceful_shutdown();
+            serve.await
+        }
+        // Serving finished, just return the result.
+        futures_util::future::Either::Right((serve, _shutdown)) => serve,
+    }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::tls;
+use std::path::PathBuf;
 use std::str::Utf8Error;
 
 mod caclient;
@@ -24,6 +25,20 @@ pub use manager::*;
 mod auth;
 pub use auth::*;
 
+mod local;
+pub use local::*;
+
+// The SDS and SPIFFE Workload APIs are served over Unix domain sockets.
+#[cfg(unix)]
+mod sds;
+#[cfg(unix)]
+pub use sds::*;
+
+#[cfg(unix)]
+mod spiffe;
+#[cfg(unix)]
+pub use spiffe::*;
+
 pub mod mock {
     pub use super::caclient::mock::CaClient;
     pub use super::manager::mock::{
@@ -45,6 +60,10 @@ pub enum Error {
     EmptyResponse(Identity),
     #[error("invalid spiffe identity: {0}")]
     Spiffe(String),
+    #[error("invalid SDS response: {0}")]
+    Sds(String),
+    #[error("failed to load certificates from {}: {}", .0.display(), .1)]
+    CertificateFile(PathBuf, String),
     #[error("the identity is no longer needed")]
     Forgotten,
 }
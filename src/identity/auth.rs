@@ -14,21 +14,32 @@
 
 use std::io;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use base64::Engine;
 use tonic::metadata::AsciiMetadataValue;
 use tonic::service::Interceptor;
 use tonic::{Code, Request, Status};
+use tracing::warn;
+
+/// How long before a token expires it is read again. The kubelet rotates projected tokens once 80%
+/// of their lifetime has passed, so by then the file holds a fresh one.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// How long a token without an expiry is used before the file is read again.
+const TOKEN_REREAD_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum AuthSource {
-    // JWT authentication source which contains the token file path and the cluster id.
-    Token(PathBuf, String),
+    // JWT authentication source which contains the token file path, the cluster id and, if set,
+    // the audience the token must have been issued for.
+    Token(PathBuf, String, Option<String>),
 }
 
 impl AuthSource {
     pub fn load(&self) -> io::Result<Vec<u8>> {
         match self {
-            AuthSource::Token(path, _) => {
+            AuthSource::Token(path, _, _) => {
                 let t = std::fs::read(path)?;
 
                 if t.is_empty() {
@@ -41,34 +52,213 @@ impl AuthSource {
             }
         }
     }
+
+    fn insert_metadata(&self, token: Vec<u8>, request: &mut Request<()>) -> Result<(), Status> {
+        let token = bearer(token)?;
+        request.metadata_mut().insert("authorization", token);
+
+        match self {
+            AuthSource::Token(_, cluster_id, _) => {
+                if !cluster_id.is_empty() {
+                    let id = AsciiMetadataValue::try_from(cluster_id.as_bytes().to_vec())
+                        .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+                    request.metadata_mut().insert("clusterid", id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bearer(mut t: Vec<u8>) -> Result<AsciiMetadataValue, Status> {
+    let mut bearer: Vec<u8> = b"Bearer ".to_vec();
+    bearer.append(&mut t);
+    AsciiMetadataValue::try_from(bearer)
+        .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))
 }
 
 impl Interceptor for AuthSource {
     fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
         let token = self
             .load()
-            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))
-            .map(|mut t| {
-                let mut bearer: Vec<u8> = b"Bearer ".to_vec();
-                bearer.append(&mut t);
-                bearer
-            })
-            .and_then(|b| {
-                AsciiMetadataValue::try_from(b)
-                    .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))
-            })?;
+            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+        self.insert_metadata(token, &mut request)?;
+        Ok(request)
+    }
+}
 
-        request.metadata_mut().insert("authorization", token);
+/// CachedToken is an [AuthSource] that keeps the token in memory, rather than reading it for every
+/// request, until shortly before it expires. Kubernetes projected service account tokens are
+/// rotated in place, so the file is then read again to pick up the new one.
+#[derive(Clone)]
+pub struct CachedToken {
+    source: AuthSource,
+    cached: Arc<Mutex<Option<Token>>>,
+}
+
+struct Token {
+    raw: Vec<u8>,
+    expiry: Option<SystemTime>,
+    reread_at: SystemTime,
+}
+
+#[derive(serde::Deserialize)]
+struct Claims {
+    exp: Option<u64>,
+    #[serde(default)]
+    aud: Audiences,
+}
 
+// The aud claim may be either a single string or a list of them.
+#[derive(serde::Deserialize, Default)]
+#[serde(untagged)]
+enum Audiences {
+    #[default]
+    None,
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audiences {
+    fn contains(&self, aud: &str) -> bool {
         match self {
-            AuthSource::Token(_, cluster_id) => {
-                if !cluster_id.is_empty() {
-                    let id = AsciiMetadataValue::try_from(cluster_id.as_bytes().to_vec())
-                        .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
-                    request.metadata_mut().insert("clusterid", id);
+            Audiences::None => false,
+            Audiences::One(a) => a == aud,
+            Audiences::Many(a) => a.iter().any(|a| a == aud),
+        }
+    }
+}
+
+impl CachedToken {
+    pub fn new(source: AuthSource) -> CachedToken {
+        CachedToken {
+            source,
+            cached: Default::default(),
+        }
+    }
+
+    fn token(&self, now: SystemTime) -> Result<Vec<u8>, Status> {
+        let mut cached = self.cached.lock().unwrap();
+        if let Some(t) = cached.as_ref().filter(|t| now < t.reread_at) {
+            return Ok(t.raw.clone());
+        }
+        match self.read(now) {
+            Ok(t) => {
+                let raw = t.raw.clone();
+                *cached = Some(t);
+                Ok(raw)
+            }
+            // Keep using the previous token for as long as it is valid.
+            Err(e) => match cached.as_ref() {
+                Some(t) if t.expiry.map_or(true, |exp| now < exp) => {
+                    warn!("failed to re-read token, using the cached one: {e}");
+                    Ok(t.raw.clone())
                 }
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn read(&self, now: SystemTime) -> Result<Token, Status> {
+        let AuthSource::Token(_, _, audience) = &self.source;
+        let raw = self
+            .source
+            .load()
+            .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
+        let claims = parse_claims(&raw);
+        if let (Some(audience), Some(claims)) = (audience, &claims) {
+            if !claims.aud.contains(audience) {
+                return Err(Status::new(
+                    Code::Unauthenticated,
+                    format!("token was not issued for audience {audience}"),
+                ));
             }
         }
+        let expiry = claims
+            .and_then(|c| c.exp)
+            .map(|exp| UNIX_EPOCH + Duration::from_secs(exp));
+        // Read the file again ahead of the expiry. A token that is about to expire is re-read on
+        // every use, until the rotated one shows up.
+        let reread_at = match expiry {
+            Some(exp) => exp
+                .checked_sub(TOKEN_REFRESH_MARGIN)
+                .filter(|t| *t > now)
+                .unwrap_or(now),
+            None => now + TOKEN_REREAD_INTERVAL,
+        };
+        Ok(Token {
+            raw,
+            expiry,
+            reread_at,
+        })
+    }
+}
+
+/// Returns the claims of a JWT, or None if the token cannot be decoded. The signature is not
+/// verified; that is up to the CA.
+fn parse_claims(token: &[u8]) -> Option<Claims> {
+    let payload = std::str::from_utf8(token).ok()?.trim().split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+impl Interceptor for CachedToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let token = self.token(SystemTime::now())?;
+        self.source.insert_metadata(token, &mut request)?;
         Ok(request)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_token(path: &PathBuf, claims: &str) {
+        let enc = |s: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(s);
+        let token = format!("{}.{}.sig", enc(r#"{"alg":"RS256"}"#), enc(claims));
+        std::fs::write(path, token).unwrap();
+    }
+
+    fn source(name: &str, audience: Option<&str>) -> (PathBuf, CachedToken) {
+        let path = std::env::temp_dir().join(format!("ztunnel-{name}-{}", std::process::id()));
+        let source = AuthSource::Token(path.clone(), String::new(), audience.map(String::from));
+        (path, CachedToken::new(source))
+    }
+
+    #[test]
+    fn cached_until_expiry() {
+        let (path, cache) = source("token-expiry", None);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        write_token(&path, r#"{"exp":1400}"#);
+        let first = cache.token(now).unwrap();
+
+        // Rotated on disk, but the cached token is not due for a refresh yet.
+        write_token(&path, r#"{"exp":2000}"#);
+        assert_eq!(cache.token(now).unwrap(), first);
+        // Within TOKEN_REFRESH_MARGIN of the expiry, the file is read again.
+        let next = cache.token(now + Duration::from_secs(200)).unwrap();
+        assert_ne!(next, first);
+
+        // A failed read falls back to the cached token while it is valid.
+        std::fs::remove_file(&path).unwrap();
+        let later = UNIX_EPOCH + Duration::from_secs(1800);
+        assert_eq!(cache.token(later).unwrap(), next);
+        assert!(cache.token(UNIX_EPOCH + Duration::from_secs(2001)).is_err());
+    }
+
+    #[test]
+    fn audience() {
+        let (path, cache) = source("token-audience", Some("istio-ca"));
+        write_token(&path, r#"{"aud":["istio-ca","other"]}"#);
+        assert!(cache.token(SystemTime::now()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+
+        let (path, cache) = source("token-audience-mismatch", Some("istio-ca"));
+        write_token(&path, r#"{"aud":"other"}"#);
+        assert!(cache.token(SystemTime::now()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
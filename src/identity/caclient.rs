@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use prost_types::value::Kind;
@@ -22,7 +23,7 @@ use tonic::codegen::InterceptedService;
 use tracing::{instrument, warn};
 
 use crate::config::RootCert;
-use crate::identity::auth::AuthSource;
+use crate::identity::auth::{AuthSource, CachedToken};
 use crate::identity::manager::Identity;
 use crate::identity::Error;
 use crate::tls::{self, SanChecker, TlsGrpcChannel};
@@ -30,7 +31,7 @@ use crate::xds::istio::ca::istio_certificate_service_client::IstioCertificateSer
 use crate::xds::istio::ca::IstioCertificateRequest;
 
 pub struct CaClient {
-    pub client: IstioCertificateServiceClient<InterceptedService<TlsGrpcChannel, AuthSource>>,
+    pub client: IstioCertificateServiceClient<InterceptedService<TlsGrpcChannel, CachedToken>>,
     pub enable_impersonated_identity: bool,
 }
 
@@ -40,12 +41,16 @@ impl CaClient {
         root_cert: RootCert,
         auth: AuthSource,
         enable_impersonated_identity: bool,
+        reresolve_interval: Option<Duration>,
     ) -> Result<CaClient, Error> {
-        let svc = tls::grpc_connector(address, root_cert)?;
+        let mut svc = tls::grpc_connector(address, root_cert)?;
+        if let Some(interval) = reresolve_interval {
+            svc = svc.with_reresolution(interval);
+        }
         // let client = IstioCertificateServiceClient::new(svc);
         // let svc =
         //     tower_hyper_http_body_compat::Hyper1HttpServiceAsTowerService03HttpService::new(svc);
-        let client = IstioCertificateServiceClient::with_interceptor(svc, auth);
+        let client = IstioCertificateServiceClient::with_interceptor(svc, CachedToken::new(auth));
         Ok(CaClient {
             client,
             enable_impersonated_identity,
@@ -0,0 +1,249 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Certificate providers that do not talk to a remote CA.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::identity::{CaClientTrait, Error, Identity, SecretManager};
+use crate::tls::secret::SecretBytes;
+use crate::tls::{self, SanChecker, SelfSignedCa};
+
+/// How long the self-signed root is valid for.
+const ROOT_LIFETIME: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+/// How long workload certificates issued by the self-signed root are valid for. This matches the
+/// validity requested from istiod.
+const CERT_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub const CERT_CHAIN_FILE: &str = "cert-chain.pem";
+pub const KEY_FILE: &str = "key.pem";
+pub const ROOT_CERT_FILE: &str = "root-cert.pem";
+
+/// A single change to the files usually shows up as several events (Kubernetes, for example,
+/// swaps a symlink to a new directory); wait for them to settle before reloading.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// SelfSignedCaClient issues certificates for any identity from a root generated at startup.
+pub struct SelfSignedCaClient {
+    ca: SelfSignedCa,
+}
+
+impl SelfSignedCaClient {
+    pub fn new(trust_domain: &str) -> Result<SelfSignedCaClient, Error> {
+        Ok(SelfSignedCaClient {
+            ca: SelfSignedCa::new(trust_domain, ROOT_LIFETIME)?,
+        })
+    }
+}
+
+#[async_trait]
+impl CaClientTrait for SelfSignedCaClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        Ok(self.ca.issue(id, CERT_LIFETIME)?)
+    }
+}
+
+/// FileCaClient serves certificates provisioned out of band into a directory, as
+/// `cert-chain.pem`, `key.pem` and `root-cert.pem`. Since there is only one certificate, only the
+/// identities in its SANs can be served. The files are reloaded whenever they change.
+#[derive(Clone)]
+pub struct FileCaClient {
+    dir: PathBuf,
+    certs: Arc<RwLock<tls::Certs>>,
+    /// If set, the key is held by the provider, and there is no `key.pem`.
+    provider: Option<Arc<dyn tls::KeyProvider>>,
+}
+
+impl FileCaClient {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<FileCaClient, Error> {
+        FileCaClient::build(dir.into(), None)
+    }
+
+    /// keyless serves the certificates of `dir` with the key that `provider` holds.
+    pub fn keyless(
+        dir: impl Into<PathBuf>,
+        provider: Arc<dyn tls::KeyProvider>,
+    ) -> Result<FileCaClient, Error> {
+        FileCaClient::build(dir.into(), Some(provider))
+    }
+
+    fn build(
+        dir: PathBuf,
+        provider: Option<Arc<dyn tls::KeyProvider>>,
+    ) -> Result<FileCaClient, Error> {
+        let certs = load(&dir, provider.as_ref())?;
+        Ok(FileCaClient {
+            dir,
+            certs: Arc::new(RwLock::new(certs)),
+            provider,
+        })
+    }
+
+    /// Returns whether the certificates changed.
+    fn reload(&self) -> Result<bool, Error> {
+        let certs = load(&self.dir, self.provider.as_ref())?;
+        let mut current = self.certs.write().unwrap();
+        if *current == certs {
+            return Ok(false);
+        }
+        *current = certs;
+        Ok(true)
+    }
+
+    /// watch reloads the files whenever they change, and has `manager` pick up the new
+    /// certificates. It returns once `manager` is dropped.
+    pub async fn watch(self, manager: Weak<SecretManager>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            if res.is_ok() {
+                let _ = tx.send(());
+            }
+        })
+        .and_then(|mut w| w.watch(&self.dir, RecursiveMode::NonRecursive).map(|_| w));
+        // The watcher stops delivering events when dropped, so keep it alive for the whole loop.
+        let _watcher = match watcher {
+            Ok(w) => w,
+            Err(e) => {
+                warn!(
+                    dir = %self.dir.display(),
+                    "failed to watch certificate files, they will not be reloaded: {e}"
+                );
+                return;
+            }
+        };
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(RELOAD_DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            match self.reload() {
+                Ok(false) => continue,
+                Ok(true) => info!(dir = %self.dir.display(), "reloaded certificate files"),
+                Err(e) => {
+                    warn!("failed to reload certificate files, keeping current: {e}");
+                    continue;
+                }
+            }
+            let Some(manager) = manager.upgrade() else {
+                return;
+            };
+            manager.refresh_all().await;
+        }
+    }
+}
+
+fn load(dir: &Path, provider: Option<&Arc<dyn tls::KeyProvider>>) -> Result<tls::Certs, Error> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        std::fs::read(&path).map_err(|e| Error::CertificateFile(path, e.to_string()))
+    };
+    let chain = read(CERT_CHAIN_FILE)?;
+    let root = read(ROOT_CERT_FILE)?;
+    let certs = match provider {
+        Some(provider) => tls::load_keyless_certs(provider.clone(), &chain, &root),
+        None => tls::load_certs(&SecretBytes::new(read(KEY_FILE)?), &chain, &root),
+    };
+    certs.map_err(|e| Error::CertificateFile(dir.to_owned(), e.to_string()))
+}
+
+#[async_trait]
+impl CaClientTrait for FileCaClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let certs = self.certs.read().unwrap().clone();
+        certs
+            .verify_san(&[id.clone()])
+            .map_err(|_| Error::SanError(id.to_owned()))?;
+        Ok(certs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const TEST_CERT_CHAIN: &[u8] = include_bytes!("../tls/cert-chain.pem");
+    const TEST_KEY: &[u8] = include_bytes!("../tls/key.pem");
+    const TEST_ROOT: &[u8] = include_bytes!("../tls/root-cert.pem");
+
+    fn cert_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ztunnel-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(CERT_CHAIN_FILE), TEST_CERT_CHAIN).unwrap();
+        std::fs::write(dir.join(KEY_FILE), TEST_KEY).unwrap();
+        std::fs::write(dir.join(ROOT_CERT_FILE), TEST_ROOT).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn self_signed() {
+        let client = SelfSignedCaClient::new("cluster.local").unwrap();
+        let id = Identity::default();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        certs.verify_san(&[id]).unwrap();
+        let root = certs.iter_chain().next().unwrap();
+        assert_eq!(root.to_der().unwrap(), client.ca.root().to_der().unwrap());
+        assert!(certs.x509().verify(&root.public_key().unwrap()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn file() {
+        let dir = cert_dir("file");
+        let client = FileCaClient::new(&dir).unwrap();
+        let id = Identity::from_str("spiffe://cluster.local/ns/default/sa/default").unwrap();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.iter_chain().count(), 1);
+        let other = Identity::from_str("spiffe://cluster.local/ns/default/sa/other").unwrap();
+        assert!(matches!(
+            client.fetch_certificate(&other).await,
+            Err(Error::SanError(_))
+        ));
+
+        // Nothing changed on disk.
+        assert!(!client.reload().unwrap());
+        // A broken update keeps the current certificates.
+        std::fs::write(dir.join(KEY_FILE), b"not a key").unwrap();
+        assert!(client.reload().is_err());
+        assert_eq!(client.fetch_certificate(&id).await.unwrap(), certs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[derive(Debug)]
+    struct Unused;
+
+    impl tls::KeyProvider for Unused {
+        fn sign(&self, _: u16, _: &[u8]) -> Result<Vec<u8>, tls::Error> {
+            unreachable!("no handshake is made")
+        }
+    }
+
+    #[tokio::test]
+    async fn file_keyless() {
+        let dir = cert_dir("file-keyless");
+        std::fs::remove_file(dir.join(KEY_FILE)).unwrap();
+        assert!(FileCaClient::new(&dir).is_err());
+        let client = FileCaClient::keyless(&dir, Arc::new(Unused)).unwrap();
+        let id = Identity::from_str("spiffe://cluster.local/ns/default/sa/default").unwrap();
+        let certs = client.fetch_certificate(&id).await.unwrap();
+        assert_eq!(certs.iter_chain().count(), 1);
+        assert!(!client.reload().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -48,6 +48,12 @@ impl EncodeLabelValue for Identity {
     }
 }
 
+impl serde::Serialize for Identity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl FromStr for Identity {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -302,18 +308,25 @@ impl Worker {
                         Ok(certs) => {
                             let certs: tls::Certs = certs; // Type annotation.
                             let refresh_at = self.time_conv.system_time_to_instant(certs.refresh_at());
-                            let refresh_at = if let Some(t) = refresh_at {
-                                t.into()
-                            } else {
-                                // Malformed certificate (not_after is way too much into the
-                                // past or the future). Queue another refresh soon.
-                                //
-                                // TODO: This is a bit inconsistent since we still return the
-                                // certificate to the caller successfully. Basically the
-                                // behavior is silly, but simple and avoid panics in time math.
-                                // We'll try to get rid of the SystemTime <-> Instant
-                                // conversion here, so for now leaving the code as is.
-                                Instant::now()
+                            let refresh_at = match refresh_at.map(Instant::from) {
+                                // A certificate that is already due for a refresh will not get any
+                                // fresher by asking again right away (for example when it is read
+                                // from files), so back off as if the fetch had failed.
+                                Some(t) if t <= Instant::now() => {
+                                    Instant::now() + CERT_REFRESH_FAILURE_RETRY_DELAY
+                                }
+                                Some(t) => t,
+                                None => {
+                                    // Malformed certificate (not_after is way too much into the
+                                    // past or the future). Queue another refresh soon.
+                                    //
+                                    // TODO: This is a bit inconsistent since we still return the
+                                    // certificate to the caller successfully. Basically the
+                                    // behavior is silly, but simple and avoid panics in time math.
+                                    // We'll try to get rid of the SystemTime <-> Instant
+                                    // conversion here, so for now leaving the code as is.
+                                    Instant::now()
+                                }
                             };
                             (CertState::Available(certs), refresh_at)
                         },
@@ -391,6 +404,7 @@ impl SecretManager {
             cfg.ca_root_cert,
             cfg.auth,
             cfg.proxy_mode == ProxyMode::Shared,
+            cfg.control_plane_reresolve_interval,
         )?;
         Ok(Self::new_with_client(caclient))
     }
@@ -495,6 +509,26 @@ impl SecretManager {
         self.fetch_certificate_pri(id, Priority::RealTime).await
     }
 
+    /// refresh_all asks for every managed certificate to be fetched again now, for example
+    /// because the client knows the certificates it hands out have changed.
+    pub async fn refresh_all(&self) {
+        let ids: Vec<Identity> = self.worker.certs.lock().await.keys().cloned().collect();
+        for id in ids {
+            self.post(Request::Fetch(id, Priority::Background)).await;
+        }
+    }
+
+    /// refresh asks for the certificate of `id` to be fetched again now, ahead of its scheduled
+    /// refresh. Returns false if the certificate is not managed.
+    pub async fn refresh(&self, id: &Identity) -> bool {
+        if !self.worker.has_id(id).await {
+            return false;
+        }
+        self.post(Request::Fetch(id.clone(), Priority::RealTime))
+            .await;
+        true
+    }
+
     pub async fn forget_certificate(&self, id: &Identity) {
         if self.worker.certs.lock().await.remove(id).is_some() {
             self.post(Request::Forget(id.clone())).await;
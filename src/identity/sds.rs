@@ -0,0 +1,187 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use hyper::{Request, Response, Uri};
+use prost::Message;
+use tokio::net::UnixStream;
+use tonic::body::BoxBody;
+use tower_hyper_http_body_compat::{HttpBody04ToHttpBody1, HttpBody1ToHttpBody04};
+use tracing::{debug, instrument};
+
+use crate::identity::{CaClientTrait, Error, Identity};
+use crate::tls::secret::SecretBytes;
+use crate::tls::{self, DefaultIncoming, SanChecker};
+use crate::xds::extensions::transport_sockets::tls::v3::{data_source, secret, DataSource, Secret};
+use crate::xds::service::discovery::v3::DiscoveryRequest;
+use crate::xds::service::secret::v3::secret_discovery_service_client::SecretDiscoveryServiceClient;
+use crate::xds::SECRET_TYPE;
+
+/// The resource name SDS providers (SPIRE, the Istio agent) use for the trust bundle.
+const ROOT_RESOURCE: &str = "ROOTCA";
+
+/// SdsClient fetches certificates from an Envoy SDS server listening on a Unix socket, such as
+/// SPIRE or cert-manager's csi-driver. Each identity is requested by its SPIFFE ID.
+pub struct SdsClient {
+    client: SecretDiscoveryServiceClient<UdsGrpcChannel>,
+}
+
+impl SdsClient {
+    pub fn new(path: impl Into<PathBuf>) -> SdsClient {
+        SdsClient {
+            client: SecretDiscoveryServiceClient::new(UdsGrpcChannel { path: path.into() }),
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let name = id.to_string();
+        let resp = self
+            .client
+            .clone()
+            .fetch_secrets(DiscoveryRequest {
+                type_url: SECRET_TYPE.to_string(),
+                resource_names: vec![name.clone(), ROOT_RESOURCE.to_string()],
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+
+        let mut cert = None;
+        let mut roots = None;
+        for res in resp.resources {
+            let secret = Secret::decode(&*res.value).map_err(|e| Error::Sds(e.to_string()))?;
+            match secret.r#type {
+                Some(secret::Type::TlsCertificate(c)) if secret.name == name => cert = Some(c),
+                Some(secret::Type::ValidationContext(v)) if secret.name == ROOT_RESOURCE => {
+                    roots = v.trusted_ca
+                }
+                _ => debug!(name = %secret.name, "ignoring unexpected secret"),
+            }
+        }
+        let cert = cert.ok_or_else(|| Error::EmptyResponse(id.to_owned()))?;
+        let chain = read(cert.certificate_chain, "certificate chain")?;
+        let key = SecretBytes::new(read(cert.private_key, "private key")?);
+        let roots = match roots {
+            Some(roots) => read(Some(roots), "trusted CA")?,
+            None => Vec::new(),
+        };
+        let certs = tls::load_certs(&key, &chain, &roots)?;
+        certs
+            .verify_san(&[id.clone()])
+            .map_err(|_| Error::SanError(id.to_owned()))?;
+        Ok(certs)
+    }
+}
+
+fn read(source: Option<DataSource>, what: &str) -> Result<Vec<u8>, Error> {
+    match source.and_then(|s| s.specifier) {
+        Some(data_source::Specifier::InlineBytes(b)) => Ok(b),
+        Some(data_source::Specifier::InlineString(s)) => Ok(s.into_bytes()),
+        Some(data_source::Specifier::Filename(f)) => {
+            std::fs::read(&f).map_err(|e| Error::CertificateFile(f.into(), e.to_string()))
+        }
+        None => Err(Error::Sds(format!("missing {what}"))),
+    }
+}
+
+#[async_trait]
+impl CaClientTrait for SdsClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        self.fetch_certificate(id).await
+    }
+}
+
+/// UdsGrpcChannel is a plaintext gRPC channel to a Unix socket. Certificates are fetched rarely, so
+/// each request simply gets its own connection.
+#[derive(Clone)]
+pub(super) struct UdsGrpcChannel {
+    pub(super) path: PathBuf,
+}
+
+impl tower::Service<Request<BoxBody>> for UdsGrpcChannel {
+    type Response = Response<HttpBody1ToHttpBody04<DefaultIncoming>>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        let mut req = req.map(HttpBody04ToHttpBody1::new);
+        // HTTP/2 requires an authority, even though there is nothing to address over a Unix socket.
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority("localhost")
+            .path_and_query(req.uri().path_and_query().unwrap().to_owned())
+            .build()
+            .unwrap();
+        *req.uri_mut() = uri;
+        let path = self.path.clone();
+        Box::pin(async move {
+            let stream = UnixStream::connect(&path).await?;
+            let builder = crate::hyper_util::http2_client();
+            let (mut sender, conn) = builder.handshake(stream).await?;
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    debug!("SDS connection closed: {e}");
+                }
+            });
+            let res = sender.send_request(req).await?;
+            Ok(res
+                .map(DefaultIncoming::Some)
+                .map(HttpBody1ToHttpBody04::new))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_data_source() {
+        let inline = |s: data_source::Specifier| Some(DataSource { specifier: Some(s) });
+        assert_eq!(
+            read(
+                inline(data_source::Specifier::InlineBytes(b"a".to_vec())),
+                "a"
+            )
+            .unwrap(),
+            b"a"
+        );
+        assert_eq!(
+            read(
+                inline(data_source::Specifier::InlineString("b".into())),
+                "b"
+            )
+            .unwrap(),
+            b"b"
+        );
+        assert!(matches!(
+            read(
+                inline(data_source::Specifier::Filename("/nonexistent".into())),
+                "c"
+            ),
+            Err(Error::CertificateFile(_, _))
+        ));
+        assert!(matches!(read(None, "d"), Err(Error::Sds(_))));
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tonic::metadata::MetadataValue;
+use tracing::{info, warn};
+
+use super::sds::UdsGrpcChannel;
+use crate::identity::{CaClientTrait, Error, Identity, SecretManager};
+use crate::tls;
+use crate::tls::secret::SecretBytes;
+use crate::xds::spiffe::spiffe_workload_api_client::SpiffeWorkloadApiClient;
+use crate::xds::spiffe::{X509svidRequest, X509svidResponse};
+
+/// Header the Workload API requires on every request, to tell it apart from SSRF.
+const SECURITY_HEADER: &str = "workload.spiffe.io";
+
+/// How long fetch_certificate waits for the first SVIDs to arrive.
+const INITIAL_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+type Svids = Arc<HashMap<Identity, tls::Certs>>;
+
+/// WorkloadApiClient serves the X.509-SVIDs streamed by the SPIFFE Workload API (for example the
+/// SPIRE agent) on a Unix socket. Only identities the Workload API issues SVIDs for can be served;
+/// bundles of federated trust domains are trusted when verifying peers.
+#[derive(Clone)]
+pub struct WorkloadApiClient {
+    path: PathBuf,
+    tx: Arc<watch::Sender<Option<Svids>>>,
+    rx: watch::Receiver<Option<Svids>>,
+}
+
+impl WorkloadApiClient {
+    pub fn new(path: impl Into<PathBuf>) -> WorkloadApiClient {
+        let (tx, rx) = watch::channel(None);
+        WorkloadApiClient {
+            path: path.into(),
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// watch keeps a stream of SVID updates open, reconnecting when it breaks, and has `manager`
+    /// pick up rotated SVIDs. It returns once `manager` is dropped.
+    pub async fn watch(self, manager: Weak<SecretManager>) {
+        let mut delay = MIN_RETRY_DELAY;
+        while manager.strong_count() > 0 {
+            match self.stream(&manager).await {
+                // The stream delivered updates, so whatever broke it is likely transient.
+                Ok(true) => delay = MIN_RETRY_DELAY,
+                Ok(false) => {}
+                Err(e) => warn!("SPIFFE Workload API stream failed: {e}"),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Returns whether any update was received before the stream ended.
+    async fn stream(&self, manager: &Weak<SecretManager>) -> Result<bool, Error> {
+        let mut client = SpiffeWorkloadApiClient::new(UdsGrpcChannel {
+            path: self.path.clone(),
+        });
+        let mut req = tonic::Request::new(X509svidRequest {});
+        req.metadata_mut()
+            .insert(SECURITY_HEADER, MetadataValue::from_static("true"));
+        let mut stream = client.fetch_x509svid(req).await?.into_inner();
+        let mut updated = false;
+        while let Some(resp) = stream.message().await? {
+            let svids = match parse(resp) {
+                Ok(svids) => svids,
+                Err(e) => {
+                    warn!("ignoring invalid SVID update: {e}");
+                    continue;
+                }
+            };
+            info!(svids = svids.len(), "received SVID update");
+            self.tx.send_replace(Some(Arc::new(svids)));
+            updated = true;
+            let Some(manager) = manager.upgrade() else {
+                return Ok(updated);
+            };
+            manager.refresh_all().await;
+        }
+        Ok(updated)
+    }
+}
+
+fn parse(resp: X509svidResponse) -> Result<HashMap<Identity, tls::Certs>, Error> {
+    let mut federated = Vec::new();
+    for bundle in resp.federated_bundles.values() {
+        federated.extend(tls::split_der(bundle)?);
+    }
+    resp.svids
+        .into_iter()
+        .map(|mut svid| -> Result<_, Error> {
+            let id = Identity::from_str(&svid.spiffe_id)?;
+            let key = SecretBytes::new(std::mem::take(&mut svid.x509_svid_key));
+            let certs = tls::load_der_certs(&key, &svid.x509_svid, &svid.bundle)?
+                .with_trusted_roots(federated.iter().cloned());
+            Ok((id, certs))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl CaClientTrait for WorkloadApiClient {
+    async fn fetch_certificate(&self, id: &Identity) -> Result<tls::Certs, Error> {
+        let mut rx = self.rx.clone();
+        let svids = tokio::time::timeout(INITIAL_FETCH_TIMEOUT, rx.wait_for(Option::is_some))
+            .await
+            .map_err(|_| Error::EmptyResponse(id.to_owned()))?
+            .map_err(|_| Error::EmptyResponse(id.to_owned()))?
+            .clone()
+            .expect("waited for SVIDs");
+        svids
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::SanError(id.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::SanChecker;
+    use crate::xds::spiffe::X509svid;
+
+    const TEST_CERT_CHAIN: &[u8] = include_bytes!("../tls/cert-chain.pem");
+    const TEST_KEY: &[u8] = include_bytes!("../tls/key.pem");
+    const TEST_ROOT: &[u8] = include_bytes!("../tls/root-cert.pem");
+
+    fn der(pem: &[u8]) -> Vec<u8> {
+        boring::x509::X509::from_pem(pem).unwrap().to_der().unwrap()
+    }
+
+    #[test]
+    fn parse_response() {
+        let id = "spiffe://cluster.local/ns/default/sa/default";
+        let key = boring::pkey::PKey::private_key_from_pem(TEST_KEY)
+            .unwrap()
+            .private_key_to_der()
+            .unwrap();
+        let resp = X509svidResponse {
+            svids: vec![X509svid {
+                spiffe_id: id.to_string(),
+                x509_svid: der(TEST_CERT_CHAIN),
+                x509_svid_key: key,
+                bundle: der(TEST_ROOT),
+                hint: String::new(),
+            }],
+            crl: vec![],
+            federated_bundles: HashMap::from([(
+                "spiffe://other.domain".to_string(),
+                [der(TEST_ROOT), der(TEST_ROOT)].concat(),
+            )]),
+        };
+        let svids = parse(resp).unwrap();
+        let id = Identity::from_str(id).unwrap();
+        let certs = &svids[&id];
+        certs.verify_san(&[id]).unwrap();
+        assert_eq!(certs.iter_chain().count(), 1);
+    }
+
+    #[test]
+    fn parse_rejects_truncated() {
+        let mut chain = der(TEST_CERT_CHAIN);
+        chain.truncate(chain.len() - 1);
+        assert!(tls::split_der(&chain).is_err());
+    }
+}
@@ -12,12 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(all(feature = "fips", feature = "dns-over-tls"))]
+compile_error!(
+    "dns-over-tls uses rustls, which is not FIPS validated; it cannot be enabled with fips"
+);
+
 pub mod admin;
 pub mod app;
 pub mod baggage;
 pub mod cert_fetcher;
 pub mod config;
 pub mod dns;
+pub mod handoff;
 pub mod hyper_util;
 pub mod identity;
 pub mod metrics;
@@ -16,11 +16,19 @@ extern crate core;
 #[cfg(feature = "gperftools")]
 extern crate gperftools;
 
-use tracing::info;
+use tracing::{error, info, warn};
 use ztunnel::*;
 
-// #[global_allocator]
-// static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// Sample an allocation every 512KiB on average, which is cheap enough to leave on in production
+// and lets /debug/pprof/heap dump a profile at any time.
+#[cfg(feature = "jemalloc")]
+#[allow(non_upper_case_globals)]
+#[export_name = "malloc_conf"]
+pub static malloc_conf: &[u8] = b"prof:true,prof_active:true,lg_prof_sample:19\0";
 
 // #[global_allocator]
 // static GLOBAL: tcmalloc::TCMalloc = tcmalloc::TCMalloc;
@@ -30,8 +38,9 @@ fn main() -> anyhow::Result<()> {
     let config: config::Config = config::parse_config()?;
 
     // For now we don't need a complex CLI, so rather than pull in dependencies just use basic argv[1]
-    match std::env::args().nth(1).as_deref() {
-        None | Some("proxy") => (),
+    let validate_only = match std::env::args().nth(1).as_deref() {
+        None | Some("proxy") => false,
+        Some("validate") => true,
         Some("version") => return version(),
         Some("help") => return help(),
         Some(unknown) => {
@@ -41,11 +50,14 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
-    tokio::runtime::Builder::new_current_thread()
+    let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async move { proxy(config).await })
+        .unwrap();
+    if validate_only {
+        return runtime.block_on(validate(config));
+    }
+    runtime.block_on(async move { proxy(config).await })
 }
 
 fn help() -> anyhow::Result<()> {
@@ -56,6 +68,7 @@ Istio Ztunnel ({version})
 
 Commands:
 proxy (default) - Start the ztunnel proxy
+validate        - Check the configuration and environment, without starting the proxy
 version         - Print the version of ztunnel
 help            - Print commands and version of ztunnel"
     );
@@ -67,8 +80,40 @@ fn version() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn validate(cfg: config::Config) -> anyhow::Result<()> {
+    let report = config::validate::validate(&cfg).await;
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    if !report.is_ok() {
+        std::process::exit(1)
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
 async fn proxy(cfg: config::Config) -> anyhow::Result<()> {
     info!("version: {}", version::BuildInfo::new());
     info!("running with config: {}", serde_yaml::to_string(&cfg)?);
+    // Check what would otherwise fail halfway through startup, while the cause is still clear.
+    let report = config::validate::preflight(&cfg).await;
+    log_problems(&report);
+    if !report.is_ok() {
+        anyhow::bail!("pre-flight checks failed, see the errors above");
+    }
+    // ztunnel retries the control plane anyway, so only point out an unreachable one.
+    let probe = cfg.clone();
+    tokio::spawn(async move {
+        log_problems(&config::validate::check_control_plane(&probe).await);
+    });
     app::build(cfg).await?.wait_termination().await
 }
+
+fn log_problems(report: &config::validate::Report) {
+    for problem in &report.problems {
+        match problem.severity {
+            config::validate::Severity::Error => error!("{}", problem.message),
+            config::validate::Severity::Warning => warn!("{}", problem.message),
+        }
+    }
+}
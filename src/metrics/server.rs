@@ -23,15 +23,30 @@ use hyper::{Request, Response};
 use prometheus_client::encoding::text::encode;
 use prometheus_client::registry::Registry;
 
+use crate::admin::AdminCertProvider;
 use crate::config::Config;
 use crate::hyper_util;
+use crate::identity::SecretManager;
 
 pub struct Server {
     s: hyper_util::Server<Mutex<Registry>>,
+    mtls: Option<AdminCertProvider>,
 }
 
 impl Server {
-    pub async fn new(config: Config, drain_rx: Watch, registry: Registry) -> anyhow::Result<Self> {
+    pub async fn new(
+        config: Config,
+        drain_rx: Watch,
+        registry: Registry,
+        cert_manager: Arc<SecretManager>,
+    ) -> anyhow::Result<Self> {
+        // Scrapers must present a certificate from the mesh CA, as admin clients do.
+        let mtls = match &config.admin_auth.mtls_identity {
+            Some(identity) if config.admin_auth.metrics => {
+                Some(AdminCertProvider::new(cert_manager, identity.clone()))
+            }
+            _ => None,
+        };
         hyper_util::Server::<Mutex<Registry>>::bind(
             "stats",
             config.stats_addr,
@@ -39,7 +54,7 @@ impl Server {
             Mutex::new(registry),
         )
         .await
-        .map(|s| Server { s })
+        .map(|s| Server { s, mtls })
     }
 
     pub fn address(&self) -> SocketAddr {
@@ -47,12 +62,20 @@ impl Server {
     }
 
     pub fn spawn(self) {
-        self.s.spawn(|registry, req| async move {
-            match req.uri().path() {
-                "/metrics" | "/stats/prometheus" => Ok(handle_metrics(registry, req).await),
-                _ => Ok(hyper_util::empty_response(hyper::StatusCode::NOT_FOUND)),
-            }
-        })
+        match self.mtls {
+            Some(acceptor) => self.s.spawn_mtls(acceptor, handle),
+            None => self.s.spawn(handle),
+        }
+    }
+}
+
+async fn handle(
+    registry: Arc<Mutex<Registry>>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    match req.uri().path() {
+        "/metrics" | "/stats/prometheus" => Ok(handle_metrics(registry, req).await),
+        _ => Ok(hyper_util::empty_response(hyper::StatusCode::NOT_FOUND)),
     }
 }
 
@@ -19,67 +19,111 @@ use std::time::Duration;
 use std::{fmt, io};
 
 use boring::error::ErrorStack;
+use bytes::Bytes;
 use drain::Watch;
-use hyper::{header, Request};
+use http_body_util::Empty;
+use hyper::{header, Request, Response, StatusCode};
 use inbound::Inbound;
 use rand::Rng;
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::time::timeout;
-use tracing::{error, trace, warn, Instrument};
+use tracing::{debug, error, info, trace, warn, Instrument};
 
 use crate::identity::SecretManager;
 use crate::metrics::Recorder;
+use crate::proxy::filter::FilterChains;
+use crate::proxy::http_connect::HttpConnect;
 use crate::proxy::inbound_passthrough::InboundPassthrough;
 use crate::proxy::outbound::Outbound;
 use crate::proxy::socks5::Socks5;
+use crate::state::service::ConnectPolicy;
 use crate::state::workload::Workload;
 use crate::state::DemandProxyState;
-use crate::{config, identity, socket, tls};
+use crate::{config, identity, rbac, socket, tls};
 
+mod detect;
+mod egress;
+mod events;
+pub mod fault;
+mod filter;
+mod flow;
+mod hooks;
+mod http_connect;
 mod inbound;
 mod inbound_passthrough;
+mod ipfix;
+mod loops;
 #[allow(non_camel_case_types)]
 pub mod metrics;
 mod outbound;
+mod pairs;
 mod pool;
 mod socks5;
+mod supervise;
 mod util;
 
+pub use events::ConnectionEvents;
+pub use filter::{FilterConnection, L4Filter, Verdict};
+pub use flow::{ConnectionTracker, FairQueues};
+pub use hooks::{ConnectionHook, ConnectionHooks};
+pub use ipfix::FlowExporter;
 pub use metrics::*;
+pub use pairs::{PairDump, TrafficPairs};
+pub use socks5::read_request as read_socks5_request;
 
 pub struct Proxy {
     inbound: Inbound,
     inbound_passthrough: InboundPassthrough,
     outbound: Outbound,
     socks5: Socks5,
+    http_connect: HttpConnect,
 }
 
 #[derive(Clone)]
 pub(super) struct ProxyInputs {
     cfg: config::Config,
+    /// live_cfg holds the latest runtime-reloadable settings; see [config::reload].
+    live_cfg: config::LiveConfig,
     cert_manager: Arc<SecretManager>,
     hbone_port: u16,
     pub state: DemandProxyState,
     metrics: Arc<Metrics>,
     pool: pool::Pool,
+    /// Addresses of the egress gateway hostnames, if there is a gateway.
+    egress_hosts: egress::ResolvedHosts,
+    filters: FilterChains,
 }
 
 impl Proxy {
     pub async fn new(
-        cfg: config::Config,
+        cfg: config::LiveConfig,
         state: DemandProxyState,
         cert_manager: Arc<SecretManager>,
         metrics: Metrics,
+        hooks: ConnectionHooks,
         drain: Watch,
     ) -> Result<Proxy, Error> {
-        let metrics = Arc::new(metrics);
+        let static_cfg = cfg.current();
+        let filters = hooks.add_filters(FilterChains::new(&static_cfg.l4_filters)?);
+        let metrics = Arc::new(metrics.with_connection_hooks(hooks));
+        let egress_hosts = match &static_cfg.egress_gateway {
+            Some(egress) => egress::ResolvedHosts::new(&static_cfg, egress),
+            None => Default::default(),
+        };
         let mut pi = ProxyInputs {
-            cfg: cfg.clone(),
+            cfg: static_cfg.as_ref().clone(),
+            pool: if static_cfg.hbone_pooling {
+                pool::Pool::new(cfg.clone())
+            } else {
+                pool::Pool::unpooled()
+            },
+            live_cfg: cfg,
             state,
             cert_manager,
             metrics,
-            pool: pool::Pool::new(),
             hbone_port: 0,
+            egress_hosts,
+            filters,
         };
         // We setup all the listeners first so we can capture any errors that should block startup
         let inbound = Inbound::new(pi.clone(), drain.clone()).await?;
@@ -87,13 +131,15 @@ impl Proxy {
 
         let inbound_passthrough = InboundPassthrough::new(pi.clone()).await?;
         let outbound = Outbound::new(pi.clone(), drain.clone()).await?;
-        let socks5 = Socks5::new(pi.clone(), drain).await?;
+        let socks5 = Socks5::new(pi.clone(), drain.clone()).await?;
+        let http_connect = HttpConnect::new(pi.clone(), drain).await?;
 
         Ok(Proxy {
             inbound,
             inbound_passthrough,
             outbound,
             socks5,
+            http_connect,
         })
     }
 
@@ -103,6 +149,7 @@ impl Proxy {
             tokio::spawn(self.inbound.run().in_current_span()),
             tokio::spawn(self.outbound.run().in_current_span()),
             tokio::spawn(self.socks5.run().in_current_span()),
+            tokio::spawn(self.http_connect.run().in_current_span()),
         ];
 
         futures::future::join_all(tasks).await;
@@ -113,6 +160,7 @@ impl Proxy {
             outbound: self.outbound.address(),
             inbound: self.inbound.address(),
             socks5: self.socks5.address(),
+            http_connect: self.http_connect.address(),
         }
     }
 }
@@ -122,6 +170,7 @@ pub struct Addresses {
     pub outbound: SocketAddr,
     pub inbound: SocketAddr,
     pub socks5: SocketAddr,
+    pub http_connect: SocketAddr,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -143,6 +192,18 @@ pub enum Error {
     #[error("{0}")]
     Generic(Box<dyn std::error::Error + Send + Sync>),
 
+    #[error("connection to {0} timed out")]
+    ConnectTimeout(SocketAddr),
+
+    #[error("denied by filter {0}: {1}")]
+    FilterDenied(String, String),
+
+    #[error("handshake with {0} timed out")]
+    HandshakeTimeout(SocketAddr),
+
+    #[error("no response to CONNECT from {0} in time")]
+    ConnectResponseTimeout(SocketAddr),
+
     #[error("tls handshake failed: {0:?}")]
     TlsHandshake(#[from] tokio_boring::HandshakeError<TcpStream>),
 
@@ -155,6 +216,9 @@ pub enum Error {
     #[error("http status: {0}")]
     HttpStatus(hyper::StatusCode),
 
+    #[error("CONNECT to {0} rejected: {1}")]
+    ConnectRejected(SocketAddr, Rejection),
+
     #[error("tls error: {0}")]
     Tls(#[from] tls::Error),
 
@@ -170,9 +234,18 @@ pub enum Error {
     #[error("unknown waypoint: {0}")]
     UnknownWaypoint(String),
 
+    #[error("unknown gateway for network: {0}")]
+    UnknownNetworkGateway(String),
+
+    #[error("unknown egress gateway: {0}")]
+    UnknownEgressGateway(IpAddr),
+
     #[error("unknown destination: {0}")]
     UnknownDestination(IpAddr),
 
+    #[error("destination workload does not declare port: {0}")]
+    UndeclaredPort(SocketAddr),
+
     #[error("no valid routing destination for workload: {0}")]
     NoValidDestination(Box<Workload>),
 
@@ -187,45 +260,112 @@ pub enum Error {
     #[error("attempted recursive call to ourselves")]
     SelfCall,
 
+    #[error("original destination {0} is ztunnel's own outbound listener")]
+    OutboundLoop(SocketAddr),
+
+    #[error("connection from {0} was opened by ztunnel itself")]
+    SelfLoop(SocketAddr),
+
     #[error("no gateway address: {0}")]
     NoGatewayAddress(Box<Workload>),
 
     #[error("unsupported feature: {0}")]
     UnsupportedFeature(String),
+
+    #[error("outbound traffic policy requires mTLS, but {0} does not support HBONE")]
+    PlaintextNotAllowed(SocketAddr),
 }
 
-// TLS record size max is 16k. But we also have a H2 frame header, so leave a bit of room for that.
-const HBONE_BUFFER_SIZE: usize = 16_384 - 64;
+impl Error {
+    /// response_flags describes why a connection failed, for its telemetry.
+    pub fn response_flags(&self) -> ResponseFlags {
+        match self {
+            Error::ConnectRejected(_, rejection) => rejection.response_flags(),
+            _ => ResponseFlags::ConnectionFailure,
+        }
+    }
+
+    /// is_endpoint_failure returns whether the error points at the endpoint connected to, rather
+    /// than the request or ourselves, for outlier detection.
+    pub fn is_endpoint_failure(&self) -> bool {
+        match self {
+            Error::ConnectTimeout(_)
+            | Error::HandshakeTimeout(_)
+            | Error::ConnectResponseTimeout(_)
+            | Error::TlsHandshake(_)
+            | Error::HttpHandshake(_) => true,
+            Error::ConnectRejected(_, rejection) => matches!(
+                rejection,
+                Rejection::UpstreamRefused | Rejection::UpstreamTimeout | Rejection::UpstreamFailed
+            ),
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
 
 pub async fn copy_hbone(
     upgraded: &mut hyper::upgrade::Upgraded,
     stream: &mut TcpStream,
+    buffer_size: usize,
     metrics: impl AsRef<Metrics>,
     transferred_bytes: BytesTransferred<'_>,
 ) -> Result<(), Error> {
-    use tokio::io::AsyncWriteExt;
     let (mut ri, mut wi) = tokio::io::split(upgraded);
-    let (mut ro, mut wo) = stream.split();
+    let faults = fault::StreamFaults::new(transferred_bytes.connection().reporter.into());
+    let flow = metrics
+        .as_ref()
+        .connections
+        .track(transferred_bytes.connection());
+
+    // Faults are injected into the writes of the streams below, so connections any were picked
+    // for are never copied through the ring.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let Some(ring) = socket::uring::ring().filter(|_| faults.is_none()) {
+        let stream = &*stream;
+        let (received, sent) = tokio::try_join!(
+            flow::copy_to_ring(&ring, &mut ri, stream, buffer_size, &flow.received),
+            flow::copy_from_ring(&ring, stream, &mut wi, buffer_size, &flow.sent),
+        )?;
+        trace!(sent, recv = received, "copy hbone complete");
+        metrics
+            .as_ref()
+            .record(&transferred_bytes, (sent, received));
+        return Ok(());
+    }
+
+    let (mut ro, wo) = stream.split();
+    let mut wo = faults.wrap(wo);
+    let mut wi = faults.without_reset().wrap(wi);
 
     let (mut sent, mut received): (u64, u64) = (0, 0);
 
+    // Each direction only half-closes the other side when it finishes, so the opposite direction
+    // keeps flowing until it finishes as well.
     let client_to_server = async {
-        let mut ri = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ri);
-        let res = tokio::io::copy_buf(&mut ri, &mut wo).await;
+        let res = flow::copy(&mut ri, &mut wo, buffer_size, &flow.received).await;
         trace!(?res, "hbone -> tcp");
         received = res?;
-        wo.shutdown().await
+        Ok::<_, io::Error>(())
     };
 
     let server_to_client = async {
-        let mut ro = tokio::io::BufReader::with_capacity(HBONE_BUFFER_SIZE, &mut ro);
-        let res = tokio::io::copy_buf(&mut ro, &mut wi).await;
+        let res = flow::copy(&mut ro, &mut wi, buffer_size, &flow.sent).await;
         trace!(?res, "tcp -> hbone");
         sent = res?;
-        wi.shutdown().await
+        Ok::<_, io::Error>(())
     };
 
-    tokio::try_join!(client_to_server, server_to_client)?;
+    if let Err(e) = tokio::try_join!(client_to_server, server_to_client) {
+        fault::reset_if_injected(&e, stream);
+        return Err(e.into());
+    }
 
     trace!(sent, recv = received, "copy hbone complete");
     metrics
@@ -234,6 +374,44 @@ pub async fn copy_hbone(
     Ok(())
 }
 
+/// connection_span creates the span a single proxied connection runs in, so every log line emitted
+/// while serving it can be correlated. The workload fields start out empty and are filled in by
+/// [record_workloads] once the peers are resolved, and the annotations by the L4 filters.
+macro_rules! connection_span {
+    ($name:literal $(, $($fields:tt)+)?) => {
+        tracing::info_span!(
+            $name,
+            $($($fields)+,)?
+            src.workload = tracing::field::Empty,
+            src.namespace = tracing::field::Empty,
+            src.identity = tracing::field::Empty,
+            dst.workload = tracing::field::Empty,
+            dst.namespace = tracing::field::Empty,
+            dst.identity = tracing::field::Empty,
+            annotations = tracing::field::Empty,
+        )
+    };
+}
+pub(super) use connection_span;
+
+/// record_workloads fills in the workload fields of a span created by [connection_span].
+pub(super) fn record_workloads(
+    span: &tracing::Span,
+    src: Option<&Workload>,
+    dst: Option<&Workload>,
+) {
+    if let Some(w) = src {
+        span.record("src.workload", w.name.as_str());
+        span.record("src.namespace", w.namespace.as_str());
+        span.record("src.identity", tracing::field::display(w.identity()));
+    }
+    if let Some(w) = dst {
+        span.record("dst.workload", w.name.as_str());
+        span.record("dst.namespace", w.namespace.as_str());
+        span.record("dst.identity", tracing::field::display(w.identity()));
+    }
+}
+
 /// Represents a traceparent, as defined by https://www.w3.org/TR/trace-context/
 #[derive(Eq, PartialEq)]
 pub struct TraceParent {
@@ -244,6 +422,7 @@ pub struct TraceParent {
 }
 
 pub const BAGGAGE_HEADER: &str = "baggage";
+pub const GRPC_STATUS_HEADER: &str = "grpc-status";
 pub const TRACEPARENT_HEADER: &str = "traceparent";
 
 impl TraceParent {
@@ -251,6 +430,8 @@ impl TraceParent {
         hyper::header::HeaderValue::from_bytes(format!("{self:?}").as_bytes()).unwrap()
     }
 }
+const TRACE_FLAG_SAMPLED: u8 = 0x01;
+
 impl TraceParent {
     fn new() -> Self {
         let mut rng = rand::thread_rng();
@@ -261,6 +442,24 @@ impl TraceParent {
             flags: 0,
         }
     }
+
+    /// new_sampled starts a new trace, marking it as sampled with probability `percentage`/100.
+    fn new_sampled(percentage: u8) -> Self {
+        let mut tp = Self::new();
+        if rand::thread_rng().gen_range(0..100) < percentage {
+            tp.flags |= TRACE_FLAG_SAMPLED;
+        }
+        tp
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & TRACE_FLAG_SAMPLED != 0
+    }
+
+    /// sampled_id returns the trace ID if the trace is sampled, and can thus be looked up.
+    pub fn sampled_id(&self) -> Option<String> {
+        self.is_sampled().then(|| self.to_string())
+    }
 }
 
 impl fmt::Debug for TraceParent {
@@ -288,6 +487,9 @@ impl TryFrom<&str> for TraceParent {
         }
 
         let segs: Vec<&str> = value.split('-').collect();
+        if segs.len() != 4 {
+            anyhow::bail!("traceparent malformed, has {} segments", segs.len())
+        }
 
         Ok(Self {
             version: u8::from_str_radix(segs[0], 16)?,
@@ -298,14 +500,215 @@ impl TryFrom<&str> for TraceParent {
     }
 }
 
+pub const CONNECTION_ID_HEADER: &str = "x-ztunnel-connection-id";
+/// Carry the [ConnectPolicy] of the destination service on CONNECT requests, so the proxy that
+/// connects to the destination attempts it the way the client would have.
+pub const CONNECT_TIMEOUT_HEADER: &str = "x-ztunnel-connect-timeout-ms";
+pub const CONNECT_RETRIES_HEADER: &str = "x-ztunnel-connect-retries";
+/// Set on the response when an inbound CONNECT is refused, to tell the client why.
+pub const REASON_HEADER: &str = "x-ztunnel-reason";
+
+/// Rejection is why an inbound CONNECT was refused. It picks the response status, and is sent to
+/// the client in the REASON_HEADER, so that the client can tell the causes apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The authority is not an IP address and port.
+    InvalidAuthority,
+    /// The authority is not the address the client connected to.
+    AddressMismatch,
+    /// The authority is the HBONE port itself.
+    HbonePort,
+    /// No workload is known at the authority.
+    UnknownDestination,
+    /// The workload at the authority runs on another node.
+    NotOnNode,
+    /// Authorization policy does not allow the client to reach the workload.
+    PolicyDenied,
+    /// The workload has a waypoint, and the client is not it.
+    WaypointBypassed,
+    UnsupportedMethod,
+    /// The workload refused the connection, typically because nothing listens on the port.
+    UpstreamRefused,
+    /// Connecting to the workload timed out.
+    UpstreamTimeout,
+    /// Connecting to the workload failed otherwise.
+    UpstreamFailed,
+}
+
+const REJECTIONS: &[Rejection] = &[
+    Rejection::InvalidAuthority,
+    Rejection::AddressMismatch,
+    Rejection::HbonePort,
+    Rejection::UnknownDestination,
+    Rejection::NotOnNode,
+    Rejection::PolicyDenied,
+    Rejection::WaypointBypassed,
+    Rejection::UnsupportedMethod,
+    Rejection::UpstreamRefused,
+    Rejection::UpstreamTimeout,
+    Rejection::UpstreamFailed,
+];
+
+impl Rejection {
+    /// upstream returns the rejection for failing to connect to the workload with `err`.
+    fn upstream(err: &io::Error) -> Rejection {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Rejection::UpstreamRefused,
+            io::ErrorKind::TimedOut => Rejection::UpstreamTimeout,
+            _ => Rejection::UpstreamFailed,
+        }
+    }
+
+    /// from_response returns the rejection a CONNECT response carries, if it names one this
+    /// version knows of.
+    fn from_response<B>(resp: &Response<B>) -> Option<Rejection> {
+        let reason = resp.headers().get(REASON_HEADER)?.to_str().ok()?;
+        REJECTIONS.iter().copied().find(|r| r.reason() == reason)
+    }
+
+    fn status(self) -> StatusCode {
+        match self {
+            Rejection::InvalidAuthority | Rejection::AddressMismatch | Rejection::HbonePort => {
+                StatusCode::BAD_REQUEST
+            }
+            Rejection::UnknownDestination | Rejection::UnsupportedMethod => StatusCode::NOT_FOUND,
+            Rejection::NotOnNode => StatusCode::FORBIDDEN,
+            Rejection::PolicyDenied | Rejection::WaypointBypassed => StatusCode::UNAUTHORIZED,
+            Rejection::UpstreamRefused | Rejection::UpstreamFailed => StatusCode::BAD_GATEWAY,
+            Rejection::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn reason(self) -> &'static str {
+        match self {
+            Rejection::InvalidAuthority => "invalid-authority",
+            Rejection::AddressMismatch => "address-mismatch",
+            Rejection::HbonePort => "hbone-port",
+            Rejection::UnknownDestination => "unknown-destination",
+            Rejection::NotOnNode => "not-on-node",
+            Rejection::PolicyDenied => "policy-denied",
+            Rejection::WaypointBypassed => "waypoint-bypassed",
+            Rejection::UnsupportedMethod => "unsupported-method",
+            Rejection::UpstreamRefused => "upstream-refused",
+            Rejection::UpstreamTimeout => "upstream-timeout",
+            Rejection::UpstreamFailed => "upstream-failed",
+        }
+    }
+
+    fn response(self) -> Response<Empty<Bytes>> {
+        Response::builder()
+            .status(self.status())
+            .header(REASON_HEADER, self.reason())
+            .body(Empty::new())
+            .unwrap()
+    }
+
+    pub fn response_flags(self) -> ResponseFlags {
+        match self {
+            Rejection::NotOnNode | Rejection::PolicyDenied | Rejection::WaypointBypassed => {
+                ResponseFlags::AuthorizationPolicyDenied
+            }
+            Rejection::InvalidAuthority
+            | Rejection::AddressMismatch
+            | Rejection::HbonePort
+            | Rejection::UnknownDestination
+            | Rejection::UnsupportedMethod => ResponseFlags::NoRoute,
+            Rejection::UpstreamRefused | Rejection::UpstreamTimeout | Rejection::UpstreamFailed => {
+                ResponseFlags::ConnectionFailure
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+/// ConnectionId uniquely identifies a proxied connection, as a random (version 4) UUID. It is
+/// generated when a connection is accepted and sent along over HBONE, so that the logs of each hop
+/// can be correlated.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u128);
+
+impl ConnectionId {
+    pub fn new() -> Self {
+        let id: u128 = rand::thread_rng().gen();
+        // Set the version (4) and variant (RFC 4122) bits.
+        Self(id & !(0xf << 76) & !(0x3 << 62) | (0x4 << 76) | (0x2 << 62))
+    }
+
+    pub fn header(&self) -> hyper::header::HeaderValue {
+        hyper::header::HeaderValue::from_str(&self.to_string()).unwrap()
+    }
+}
+
+impl Default for ConnectionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            id >> 96,
+            (id >> 80) & 0xffff,
+            (id >> 64) & 0xffff,
+            (id >> 48) & 0xffff,
+            id & 0xffff_ffff_ffff
+        )
+    }
+}
+
+impl fmt::Debug for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl TryFrom<&str> for ConnectionId {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let segs: Vec<&str> = value.split('-').collect();
+        if segs.iter().map(|s| s.len()).ne([8, 4, 4, 4, 12]) {
+            anyhow::bail!("connection id malformed: {value}")
+        }
+        Ok(Self(u128::from_str_radix(&segs.concat(), 16)?))
+    }
+}
+
+/// listen binds the listeners for one proxy component, sharded as configured, with its `opts`.
+pub(super) async fn listen(
+    pi: &ProxyInputs,
+    addr: SocketAddr,
+    opts: &config::ListenerOptions,
+) -> Result<Vec<TcpListener>, Error> {
+    socket::listen_sharded(
+        addr,
+        pi.cfg.listener_shards,
+        pi.cfg.runtime_mode == config::RuntimeMode::PerCore,
+        opts,
+    )
+    .await
+    .map_err(|e| Error::Bind(addr, e))
+}
+
 pub(super) fn maybe_set_transparent(
     pi: &ProxyInputs,
-    listener: &TcpListener,
+    listeners: &[TcpListener],
 ) -> Result<bool, Error> {
     Ok(match pi.cfg.enable_original_source {
         Some(true) => {
             // Explicitly enabled. Return error if we cannot set it.
-            socket::set_transparent(listener)?;
+            for listener in listeners {
+                socket::set_transparent(listener)?;
+            }
             true
         }
         Some(false) => {
@@ -313,8 +716,10 @@ pub(super) fn maybe_set_transparent(
             false
         }
         None => {
-            // Best effort
-            socket::set_transparent(listener).is_ok()
+            // Best effort; all shards must agree, so stop at the first failure.
+            listeners
+                .iter()
+                .all(|listener| socket::set_transparent(listener).is_ok())
         }
     })
 }
@@ -349,46 +754,341 @@ pub fn get_original_src_from_stream(stream: &TcpStream) -> Option<IpAddr> {
         .map_or(None, |sa| Some(socket::to_canonical(sa).ip()))
 }
 
+/// authorize applies the authorization policies to `conn`, logging and counting the connections
+/// that audited policies would have denied. If ztunnel fails closed, connections are denied
+/// outright while the policies are unsynced.
+pub(super) async fn authorize(
+    state: &DemandProxyState,
+    conn: &rbac::Connection,
+    audit_all: bool,
+    metrics: &Metrics,
+) -> bool {
+    if let Some(reason) = state.unsynced_policies() {
+        warn!(%conn, reason, "RBAC rejected connection: authorization policies are unsynced");
+        metrics.rbac_unsynced_denials.inc();
+        return false;
+    }
+    let decision = state.assert_rbac(conn, audit_all).await;
+    for policy in decision.audit_denials {
+        info!(%conn, %policy, "RBAC audit: policy would reject connection");
+        metrics
+            .rbac_audit_denials
+            .get_or_create(&RbacAuditLabels { policy })
+            .inc();
+    }
+    decision.allowed
+}
+
+/// until_drained runs `serve`, a connection to a local workload, until it completes or the
+/// workload is removed and `drained` is signaled. It returns None in the latter case.
+pub(super) async fn until_drained<F: std::future::Future>(
+    drained: Option<Watch>,
+    serve: F,
+) -> Option<F::Output> {
+    let Some(drained) = drained else {
+        return Some(serve.await);
+    };
+    tokio::select! {
+        res = serve => Some(res),
+        _ = drained.signaled() => None,
+    }
+}
+
+/// workload_drain returns the [Watch] signaled once `wl`, the destination of an inbound
+/// connection, is removed.
+pub(super) fn workload_drain(state: &DemandProxyState, wl: Option<&Workload>) -> Option<Watch> {
+    wl.map(|wl| state.read().connections.watch(&wl.uid))
+}
+
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most retries a [ConnectPolicy] gets, however many a client asks for.
+const MAX_CONNECT_RETRIES: u32 = 3;
+/// The ECN bits of the TOS byte, and of the IPv6 traffic class.
+const ECN_MASK: u8 = 0x03;
+/// The buffer latency-sensitive flows are copied through, as their messages are small.
+const LATENCY_SENSITIVE_BUFFER_SIZE: usize = 4 * 1024;
+
+/// connect_policy_headers returns the headers carrying `policy`, for the parts it sets.
+pub fn connect_policy_headers(policy: &ConnectPolicy) -> Vec<(&'static str, String)> {
+    let mut headers = Vec::new();
+    if let Some(timeout) = policy.timeout {
+        headers.push((CONNECT_TIMEOUT_HEADER, timeout.as_millis().to_string()));
+    }
+    if policy.max_retries > 0 {
+        headers.push((CONNECT_RETRIES_HEADER, policy.max_retries.to_string()));
+    }
+    headers
+}
 
-pub async fn freebind_connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
-    async fn connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Result<TcpStream> {
-        match local {
-            None => {
-                trace!(dest=%addr, "no local address, connect directly");
-                Ok(TcpStream::connect(addr).await?)
+/// parse_connect_policy reads the [ConnectPolicy] a client sent, ignoring values that do not parse.
+pub fn parse_connect_policy(headers: &header::HeaderMap) -> ConnectPolicy {
+    let get = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u32>().ok())
+    };
+    ConnectPolicy {
+        timeout: get(CONNECT_TIMEOUT_HEADER)
+            .filter(|ms| *ms > 0)
+            .map(|ms| Duration::from_millis(ms.into())),
+        max_retries: get(CONNECT_RETRIES_HEADER).unwrap_or_default(),
+    }
+}
+
+/// original_source returns the address to connect to `dst` from to preserve the client's address
+/// `src`, or None to connect from our own. A workload connecting to itself, typically through a
+/// service that load balanced it back to itself, cannot keep its address: it would answer its own
+/// address over its loopback interface, so the replies would never make it back to us. Such
+/// connections come from ztunnel's address instead.
+pub fn original_source(
+    src: Option<IpAddr>,
+    dst: SocketAddr,
+    dst_workload: Option<&Workload>,
+) -> Option<IpAddr> {
+    let src = src?;
+    let hairpin = src == socket::to_canonical(dst).ip()
+        || dst_workload.map_or(false, |wl| wl.workload_ips.contains(&src));
+    if hairpin {
+        trace!(%src, %dst, "destination is the source workload, connecting from our own address");
+        return None;
+    }
+    Some(src)
+}
+
+pub async fn freebind_connect(
+    local: Option<IpAddr>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+) -> io::Result<TcpStream> {
+    // Wrap the entire connect function in a timeout
+    timeout(
+        CONNECTION_TIMEOUT,
+        connect(local.map(|ip| (ip, 0).into()), addr, mark, None, false),
+    )
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
+}
+
+/// freebind_connect_policy connects to `addr` the way `policy` asks, within the limits of our own:
+/// no attempt takes longer than CONNECTION_TIMEOUT, and at most MAX_CONNECT_RETRIES are retried.
+pub(super) async fn freebind_connect_policy(
+    local: Option<IpAddr>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    policy: ConnectPolicy,
+) -> io::Result<TcpStream> {
+    let attempt_timeout = policy
+        .timeout
+        .map_or(CONNECTION_TIMEOUT, |t| t.min(CONNECTION_TIMEOUT));
+    let mut retries = policy.max_retries.min(MAX_CONNECT_RETRIES);
+    loop {
+        let res = timeout(
+            attempt_timeout,
+            connect(local.map(|ip| (ip, 0).into()), addr, mark, tos, false),
+        )
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))
+        .and_then(|res| res);
+        match res {
+            Err(e) if retries > 0 => {
+                retries -= 1;
+                debug!(%addr, retries, "connection attempt failed, retrying: {e}");
             }
-            // TODO: Need figure out how to handle case of loadbalancing to itself.
-            //       We use ztunnel addr instead, otherwise app side will be confused.
-            Some(src) if src == socket::to_canonical(addr).ip() => {
-                trace!(%src, dest=%addr, "dest and source are the same, connect directly");
-                Ok(TcpStream::connect(addr).await?)
+            res => return res,
+        }
+    }
+}
+
+/// freebind_connect_timeout is [freebind_connect] with a caller provided timeout, reported as
+/// [Error::ConnectTimeout], and the TOS of the connection set to `tos`, if any. With `fast_open`,
+/// the first write is sent in the SYN, so the client must speak first.
+pub(super) async fn freebind_connect_timeout(
+    local: Option<IpAddr>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    fast_open: bool,
+    connect_timeout: Duration,
+) -> Result<TcpStream, Error> {
+    timeout(
+        connect_timeout,
+        connect(local.map(|ip| (ip, 0).into()), addr, mark, tos, fast_open),
+    )
+    .await
+    .map_err(|_| Error::ConnectTimeout(addr))?
+    .map_err(Error::Io)
+}
+
+/// freebind_connect_port is [freebind_connect_timeout] from the client's `port` as well as its
+/// address `local`, if both are set. When the port is in use, such as by another connection of
+/// the client to `addr` that is not yet fully closed, an ephemeral port is used instead, and
+/// counted in source_port_conflicts. Failing to use the client's address at all is an error.
+pub(super) async fn freebind_connect_port(
+    local: Option<IpAddr>,
+    port: Option<u16>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    connect_timeout: Duration,
+    metrics: &Metrics,
+) -> Result<TcpStream, Error> {
+    let (Some(src), Some(port)) = (local, port) else {
+        return freebind_connect_timeout(local, addr, mark, tos, false, connect_timeout).await;
+    };
+    let local = SocketAddr::new(src, port);
+    let connecting = async {
+        match connect(Some(local), addr, mark, tos, false).await {
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+                ) =>
+            {
+                warn!(%local, dest=%addr, "source port in use, connecting from an ephemeral port: {e}");
+                metrics.source_port_conflicts.inc();
+                connect(Some(SocketAddr::new(src, 0)), addr, mark, tos, false).await
             }
-            Some(src) => {
-                let socket = if src.is_ipv4() {
-                    TcpSocket::new_v4()?
-                } else {
-                    TcpSocket::new_v6()?
-                };
-
-                let local_addr = SocketAddr::new(src, 0);
-                match socket::set_freebind_and_transparent(&socket) {
-                    Err(err) => warn!("failed to set freebind: {:?}", err),
-                    _ => {
-                        if let Err(err) = socket.bind(local_addr) {
-                            warn!("failed to bind local addr: {:?}", err)
+            res => res,
+        }
+    };
+    timeout(connect_timeout, connecting)
+        .await
+        .map_err(|_| Error::ConnectTimeout(addr))?
+        .map_err(Error::Io)
+}
+
+// connect makes a TCP connection to `addr`, from `local` if set, marking the socket with `mark`
+// and its packets with `tos`, and with TCP Fast Open if `fast_open`. A port of 0 in `local` picks
+// an ephemeral one; failing to bind any other port is an error.
+async fn connect(
+    local: Option<SocketAddr>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    fast_open: bool,
+) -> io::Result<TcpStream> {
+    let stream = connect_socket(local, addr, mark, tos, fast_open).await?;
+    // From the client's own port, the local address is the client's, and would be mistaken for
+    // a loop when the client connects again.
+    if local.map_or(true, |l| l.port() == 0) {
+        if let Ok(local) = stream.local_addr() {
+            loops::opened(local);
+        }
+    }
+    Ok(stream)
+}
+
+async fn connect_socket(
+    local: Option<SocketAddr>,
+    addr: SocketAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    fast_open: bool,
+) -> io::Result<TcpStream> {
+    match local {
+        None => {
+            trace!(dest=%addr, "no local address, connect directly");
+            Ok(new_socket(addr.ip(), mark, tos, fast_open)?
+                .connect(addr)
+                .await?)
+        }
+        Some(local_addr) => {
+            let src = local_addr.ip();
+            let socket = new_socket(src, mark, tos, fast_open)?;
+            if local_addr.port() != 0 {
+                // Allow reusing the port of a connection of the client's that is closing.
+                socket.set_reuseaddr(true)?;
+            }
+
+            match socket::set_freebind_and_transparent(&socket) {
+                // Connecting from elsewhere would lose the client's port along with its address.
+                Err(err) if local_addr.port() != 0 => return Err(err),
+                Err(err) => warn!("failed to set freebind: {:?}", err),
+                _ => {
+                    if let Err(err) = socket.bind(local_addr) {
+                        if local_addr.port() != 0 {
+                            return Err(err);
                         }
+                        warn!("failed to bind local addr: {:?}", err)
                     }
-                };
-                trace!(%src, dest=%addr, "connect with source IP");
-                Ok(socket.connect(addr).await?)
-            }
+                }
+            };
+            trace!(%src, dest=%addr, "connect with source IP");
+            Ok(socket.connect(addr).await?)
+        }
+    }
+}
+
+// new_socket creates a socket of the family of `ip`, with `mark` and `tos` set if there are any,
+// and TCP Fast Open if `fast_open`.
+fn new_socket(
+    ip: IpAddr,
+    mark: Option<u32>,
+    tos: Option<u8>,
+    fast_open: bool,
+) -> io::Result<TcpSocket> {
+    let socket = if ip.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    if let Some(mark) = mark {
+        socket::set_mark(&socket, mark)?;
+    }
+    if let Some(tos) = tos {
+        // Unlike a missing mark, a missing QoS marking does not break routing.
+        if let Err(e) = socket::set_tos(&socket, tos) {
+            debug!(tos, "failed to set TOS: {e}");
+        }
+    }
+    if fast_open {
+        // Without it, the connection just takes the extra round trip.
+        if let Err(e) = socket::set_fast_open_connect(&socket) {
+            debug!("failed to set TCP_FASTOPEN_CONNECT: {e}");
+        }
+    }
+    Ok(socket)
+}
+
+/// set_latency_sensitive sends the small writes of `stream` right away, without waiting for the
+/// data sent before to be acknowledged, if flows to `dst_port` are latency-sensitive. Bulk flows
+/// keep Nagle's algorithm, for fewer and fuller segments.
+pub(super) fn set_latency_sensitive(cfg: &config::Config, dst_port: u16, stream: &TcpStream) {
+    if !cfg.latency_sensitive_ports.contains(&dst_port) {
+        return;
+    }
+    if let Err(e) = stream.set_nodelay(true) {
+        debug!(dst_port, "failed to set TCP_NODELAY: {e}");
+    }
+}
+
+/// copy_buffer_size returns the size of the buffers a flow to `dst_port` is copied through. Bulk
+/// flows are copied through `bulk`, for fewer and fuller writes, while latency-sensitive flows
+/// exchange small messages and are copied through [LATENCY_SENSITIVE_BUFFER_SIZE].
+pub(super) fn copy_buffer_size(
+    latency_sensitive_ports: &[u16],
+    bulk: usize,
+    dst_port: u16,
+) -> usize {
+    if latency_sensitive_ports.contains(&dst_port) {
+        LATENCY_SENSITIVE_BUFFER_SIZE.min(bulk)
+    } else {
+        bulk
+    }
+}
+
+/// downstream_tos returns the QoS marking of the client of `stream`, for the upstream connection
+/// to carry on. Only the DSCP bits are kept: ECN is negotiated by each connection on its own.
+pub(super) fn downstream_tos(stream: &TcpStream) -> Option<u8> {
+    match socket::received_tos(stream) {
+        Ok(tos) => Some(tos & !ECN_MASK).filter(|tos| *tos != 0),
+        Err(e) => {
+            trace!("failed to read the downstream TOS: {e}");
+            None
         }
     }
-    // Wrap the entire connect function in a timeout
-    timeout(CONNECTION_TIMEOUT, connect(local, addr))
-        .await
-        .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))?
 }
 
 pub async fn relay(
@@ -414,10 +1114,230 @@ mod tests {
     use bytes::Bytes;
     use http_body_util::Empty;
     use hyper::http::request;
+    use proptest::prelude::*;
     use test_case::test_case;
 
     use super::*;
 
+    #[test]
+    fn traceparent_sampling() {
+        assert!(!TraceParent::new_sampled(0).is_sampled());
+        assert!(TraceParent::new_sampled(100).is_sampled());
+
+        // The sampling decision of an incoming trace is preserved.
+        let sampled =
+            TraceParent::try_from("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+                .unwrap();
+        assert!(sampled.is_sampled());
+        assert_eq!(
+            sampled.header(),
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+        );
+        let unsampled =
+            TraceParent::try_from("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00")
+                .unwrap();
+        assert!(!unsampled.is_sampled());
+    }
+
+    #[test]
+    fn rejection_round_trip() {
+        for &rejection in REJECTIONS {
+            let resp = rejection.response();
+            assert_eq!(Rejection::from_response(&resp), Some(rejection));
+        }
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let resp = Rejection::upstream(&refused).response();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            Error::ConnectRejected(
+                "127.0.0.1:15008".parse().unwrap(),
+                Rejection::upstream(&refused)
+            )
+            .response_flags(),
+            ResponseFlags::ConnectionFailure
+        );
+        assert_eq!(
+            Rejection::PolicyDenied.response_flags(),
+            ResponseFlags::AuthorizationPolicyDenied
+        );
+
+        // Reasons from newer versions are not guessed at.
+        let resp = Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(REASON_HEADER, "something-new")
+            .body(())
+            .unwrap();
+        assert_eq!(Rejection::from_response(&resp), None);
+    }
+
+    #[test]
+    fn traceparent_malformed() {
+        assert!(
+            TraceParent::try_from("000af7651916cd43dd8448eb211c80319cb7ad6b716920333101000")
+                .is_err()
+        );
+        assert!(
+            TraceParent::try_from("00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-1-01")
+                .is_err()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn traceparent_roundtrip(version: u8, trace_id: u128, parent_id: u64, flags: u8) {
+            let tp = TraceParent { version, trace_id, parent_id, flags };
+            let parsed = TraceParent::try_from(format!("{tp:?}").as_str()).unwrap();
+            prop_assert_eq!(parsed, tp);
+        }
+
+        #[test]
+        fn traceparent_arbitrary(value in "\\PC*") {
+            let _ = TraceParent::try_from(value.as_str());
+        }
+
+        #[test]
+        fn forwarded_roundtrip(ip: IpAddr, port: u16) {
+            let header = match ip {
+                IpAddr::V4(_) => format!(r#"for="{ip}:{port}""#),
+                IpAddr::V6(_) => format!(r#"for="[{ip}]:{port}""#),
+            };
+            let req = request::Builder::new()
+                .header(header::FORWARDED, header)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            prop_assert_eq!(get_original_src_from_fwded(&req), Some(ip));
+        }
+
+        #[test]
+        fn forwarded_arbitrary(value in "[ -~]*") {
+            let req = request::Builder::new()
+                .header(header::FORWARDED, value)
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            let _ = get_original_src_from_fwded(&req);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_from_client_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = Some(IpAddr::from([127, 0, 0, 1]));
+        let metrics = crate::test_helpers::helpers::test_proxy_metrics();
+        let connect = |port| {
+            freebind_connect_port(
+                local,
+                port,
+                addr,
+                None,
+                None,
+                Duration::from_secs(5),
+                &metrics,
+            )
+        };
+        let free_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        if let Err(e) = socket::set_freebind_and_transparent(&TcpSocket::new_v4().unwrap()) {
+            // Without CAP_NET_ADMIN, the client's port can't be kept, which is an error.
+            let err = connect(Some(free_port)).await.unwrap_err();
+            assert!(matches!(err, Error::Io(io) if io.kind() == e.kind()));
+            return;
+        }
+
+        let stream = connect(Some(free_port)).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().port(), free_port);
+        assert_eq!(metrics.source_port_conflicts.get(), 0);
+        // The address is the client's own, so it is not taken for one of our connections.
+        assert!(!loops::is_own(stream.local_addr().unwrap()));
+
+        // The port of our listener is taken, so an ephemeral one is used.
+        let stream = connect(Some(addr.port())).await.unwrap();
+        assert_ne!(stream.local_addr().unwrap().port(), addr.port());
+        assert_eq!(metrics.source_port_conflicts.get(), 1);
+
+        let stream = connect(None).await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), local.unwrap());
+        assert!(loops::is_own(stream.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn connection_id() {
+        let id = ConnectionId::new();
+        let s = id.to_string();
+        assert_eq!(s.len(), 36);
+        assert_eq!(&s[14..15], "4");
+        assert!(matches!(&s[19..20], "8" | "9" | "a" | "b"));
+        assert_eq!(ConnectionId::try_from(s.as_str()).unwrap(), id);
+        assert_ne!(ConnectionId::new(), id);
+
+        let parsed = ConnectionId::try_from("0af76519-16cd-43dd-8448-eb211c80319c").unwrap();
+        assert_eq!(parsed.header(), "0af76519-16cd-43dd-8448-eb211c80319c");
+        assert!(ConnectionId::try_from("0af7651916cd43dd8448eb211c80319c").is_err());
+        assert!(ConnectionId::try_from("0af76519-16cd-43dd-8448-eb211c8031zz").is_err());
+    }
+
+    #[test]
+    fn connect_policy_roundtrip() {
+        let policy = ConnectPolicy {
+            timeout: Some(Duration::from_millis(500)),
+            max_retries: 2,
+        };
+        let mut headers = header::HeaderMap::new();
+        for (name, value) in connect_policy_headers(&policy) {
+            headers.insert(name, value.parse().unwrap());
+        }
+        assert_eq!(parse_connect_policy(&headers), policy);
+
+        assert!(connect_policy_headers(&ConnectPolicy::default()).is_empty());
+        headers.insert(CONNECT_TIMEOUT_HEADER, "soon".parse().unwrap());
+        assert_eq!(parse_connect_policy(&headers).timeout, None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn tos_passthrough() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Expedited forwarding, with ECN bits that are not carried on.
+        let _client = connect(None, addr, None, Some(0xb8 | ECN_MASK), false)
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(downstream_tos(&accepted), Some(0xb8));
+
+        let _client = connect(None, addr, None, None, false).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(downstream_tos(&accepted), None);
+    }
+
+    #[tokio::test]
+    async fn latency_sensitive_nodelay() {
+        let cfg = config::Config {
+            latency_sensitive_ports: vec![6379],
+            ..crate::test_helpers::test_config()
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let chatty = TcpStream::connect(addr).await.unwrap();
+        set_latency_sensitive(&cfg, 6379, &chatty);
+        assert!(chatty.nodelay().unwrap());
+
+        let bulk = TcpStream::connect(addr).await.unwrap();
+        set_latency_sensitive(&cfg, 8080, &bulk);
+        assert!(!bulk.nodelay().unwrap());
+
+        let ports = &cfg.latency_sensitive_ports;
+        assert_eq!(
+            copy_buffer_size(ports, 1 << 20, 6379),
+            LATENCY_SENSITIVE_BUFFER_SIZE
+        );
+        assert_eq!(copy_buffer_size(ports, 1 << 20, 8080), 1 << 20);
+        assert_eq!(copy_buffer_size(ports, 1024, 6379), 1024);
+    }
+
     #[test_case(r#""#, None; "empty")]
     #[test_case(r#"proto=https"#, None; "no for")]
     #[test_case(r#"abc"#, None; "malformed")]
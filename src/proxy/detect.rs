@@ -0,0 +1,145 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use tokio::net::TcpStream;
+use tracing::trace;
+
+use crate::config::ProtocolDetection;
+
+/// Enough to tell apart the protocols we detect.
+const PEEK_SIZE: usize = 16;
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0";
+const HTTP1_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"HEAD ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// Protocol is the application protocol detected on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Protocol {
+    Tls,
+    Http1,
+    Http2,
+    /// The client sent something we do not recognize.
+    Opaque,
+    /// Nothing was detected, because the destination port is server-first, detection is disabled,
+    /// or the client did not send anything in time.
+    Undetected,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Protocol::Tls => "tls",
+            Protocol::Http1 => "http/1.1",
+            Protocol::Http2 => "http/2",
+            Protocol::Opaque => "opaque",
+            Protocol::Undetected => "undetected",
+        };
+        f.write_str(s)
+    }
+}
+
+/// detect peeks at the first bytes the client sent on `stream`, leaving them to be read as usual.
+/// The client of a server-first protocol waits for the server to speak first, so connections to
+/// `server_first_ports` are never peeked at, and others are only waited on for the configured
+/// timeout.
+pub(super) async fn detect(stream: &TcpStream, dst_port: u16, cfg: &ProtocolDetection) -> Protocol {
+    if cfg.timeout.is_zero() || cfg.server_first_ports.contains(&dst_port) {
+        return Protocol::Undetected;
+    }
+    let mut buf = [0u8; PEEK_SIZE];
+    match tokio::time::timeout(cfg.timeout, stream.peek(&mut buf)).await {
+        Ok(Ok(n)) => classify(&buf[..n]),
+        Ok(Err(e)) => {
+            trace!("protocol detection failed: {e}");
+            Protocol::Undetected
+        }
+        Err(_) => {
+            trace!(
+                "client sent nothing in {:?}, skipping detection",
+                cfg.timeout
+            );
+            Protocol::Undetected
+        }
+    }
+}
+
+fn classify(buf: &[u8]) -> Protocol {
+    if buf.is_empty() {
+        return Protocol::Undetected;
+    }
+    // A TLS handshake record, for any TLS version.
+    if buf.len() >= 2 && buf[0] == 0x16 && buf[1] == 0x03 {
+        return Protocol::Tls;
+    }
+    if buf.starts_with(HTTP2_PREFACE) {
+        return Protocol::Http2;
+    }
+    if HTTP1_METHODS.iter().any(|m| buf.starts_with(m)) {
+        return Protocol::Http1;
+    }
+    Protocol::Opaque
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn classify_protocols() {
+        assert_eq!(classify(&[0x16, 0x03, 0x01, 0x02]), Protocol::Tls);
+        assert_eq!(classify(b"PRI * HTTP/2.0\r\n"), Protocol::Http2);
+        assert_eq!(classify(b"GET / HTTP/1.1\r\n"), Protocol::Http1);
+        assert_eq!(classify(b"GETX"), Protocol::Opaque);
+        assert_eq!(classify(b""), Protocol::Undetected);
+    }
+
+    #[tokio::test]
+    async fn server_first() {
+        let cfg = ProtocolDetection {
+            timeout: Duration::from_millis(10),
+            server_first_ports: vec![3306],
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        // The client is waiting for the server, so we give up rather than stall.
+        assert_eq!(detect(&server, 80, &cfg).await, Protocol::Undetected);
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(detect(&server, 3306, &cfg).await, Protocol::Undetected);
+        assert_eq!(detect(&server, 80, &cfg).await, Protocol::Http1);
+        // Peeking does not consume anything.
+        assert_eq!(detect(&server, 80, &cfg).await, Protocol::Http1);
+    }
+}
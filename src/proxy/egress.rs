@@ -0,0 +1,89 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+use trust_dns_resolver::{TokioAsyncResolver, TokioHandle};
+
+use crate::config::{Config, EgressGateway};
+
+/// How often the egress hostnames are resolved again.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+type Addresses = HashMap<String, HashSet<IpAddr>>;
+
+/// ResolvedHosts holds the addresses the egress gateway hostnames currently resolve to, so that
+/// connections to them, which only carry the address, can be recognized.
+#[derive(Clone, Default)]
+pub(super) struct ResolvedHosts(Arc<RwLock<Addresses>>);
+
+impl ResolvedHosts {
+    /// new starts resolving the hostnames of `egress` in the background, until the returned
+    /// ResolvedHosts and all of its clones are dropped.
+    pub(super) fn new(cfg: &Config, egress: &EgressGateway) -> ResolvedHosts {
+        let hosts = ResolvedHosts::default();
+        if egress.hostnames.is_empty() {
+            return hosts;
+        }
+        let resolver = match TokioAsyncResolver::new(
+            cfg.dns_resolver_cfg.clone(),
+            cfg.dns_resolver_opts,
+            TokioHandle,
+        ) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("failed to create resolver, egress hostnames will not be matched: {e}");
+                return hosts;
+            }
+        };
+        let hostnames = egress.hostnames.clone();
+        let addresses = Arc::downgrade(&hosts.0);
+        tokio::spawn(Self::refresh(resolver, hostnames, addresses));
+        hosts
+    }
+
+    pub(super) fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.read().unwrap().values().any(|ips| ips.contains(ip))
+    }
+
+    async fn refresh(
+        resolver: TokioAsyncResolver,
+        hostnames: Vec<String>,
+        addresses: Weak<RwLock<Addresses>>,
+    ) {
+        let mut interval = tokio::time::interval(RESOLVE_INTERVAL);
+        while addresses.strong_count() > 0 {
+            interval.tick().await;
+            for hostname in hostnames.iter() {
+                // A failed lookup keeps the addresses the hostname last resolved to.
+                let ips = match resolver.lookup_ip(hostname.as_str()).await {
+                    Ok(resp) => resp.iter().collect::<HashSet<_>>(),
+                    Err(e) => {
+                        warn!(%hostname, "failed to resolve egress hostname: {e}");
+                        continue;
+                    }
+                };
+                debug!(%hostname, ?ips, "resolved egress hostname");
+                let Some(addresses) = addresses.upgrade() else {
+                    return;
+                };
+                addresses.write().unwrap().insert(hostname.clone(), ips);
+            }
+        }
+    }
+}
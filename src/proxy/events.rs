@@ -0,0 +1,273 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A live feed of the connections ztunnel proxies, for node agents and security tools that need
+//! every connection rather than the aggregates in metrics. Clients connect to a Unix socket and
+//! receive one JSON object per line as connections open and close, starting from when they
+//! connected. A client that falls too far behind skips the events it missed, and is told how many
+//! with a `lagged` event.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use drain::Watch;
+use tokio::io::AsyncWriteExt;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::proxy::metrics::{ConnectionOpen, DerivedWorkload, ResponseFlags};
+#[cfg(unix)]
+use crate::socket;
+use crate::state::workload::Workload;
+
+/// How many events are buffered for each client before it starts missing them.
+const EVENT_BUFFER: usize = 4096;
+
+/// ConnectionEvents broadcasts connection events to the clients of the event stream. Workers
+/// share the same events, so clients see the connections of all of them.
+#[derive(Clone)]
+pub struct ConnectionEvents {
+    tx: broadcast::Sender<Arc<str>>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum EventKind {
+    Open,
+    Close,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct ConnectionEvent {
+    /// When the event happened, in RFC 3339.
+    timestamp: String,
+    event: EventKind,
+    connection_id: String,
+    reporter: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Peer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<Peer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination_service: Option<String>,
+    security_policy: String,
+    /// How long the connection was open for, on close.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_seconds: Option<f64>,
+    /// Why the connection failed, on close.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_flags: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct Peer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    workload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ips: Vec<String>,
+}
+
+impl From<&Workload> for Peer {
+    fn from(w: &Workload) -> Self {
+        Peer {
+            workload: Some(w.workload_name.clone()),
+            namespace: Some(w.namespace.clone()),
+            identity: Some(w.identity().to_string()),
+            ips: w.workload_ips.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl From<&DerivedWorkload> for Peer {
+    fn from(w: &DerivedWorkload) -> Self {
+        Peer {
+            workload: w.workload_name.clone(),
+            namespace: w.namespace.clone(),
+            identity: w.identity.as_ref().map(ToString::to_string),
+            ips: Vec::new(),
+        }
+    }
+}
+
+impl Default for ConnectionEvents {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_BUFFER);
+        ConnectionEvents { tx }
+    }
+}
+
+impl ConnectionEvents {
+    pub(super) fn open(&self, c: &ConnectionOpen) {
+        self.send(|| ConnectionEvent::new(EventKind::Open, c));
+    }
+
+    pub(super) fn close(&self, c: &ConnectionOpen, duration: Duration, flags: ResponseFlags) {
+        self.send(|| ConnectionEvent {
+            duration_seconds: Some(duration.as_secs_f64()),
+            response_flags: Some(flags.as_str().to_string()),
+            ..ConnectionEvent::new(EventKind::Close, c)
+        });
+    }
+
+    /// send serializes an event once for all clients, and not at all if there are none.
+    fn send(&self, event: impl FnOnce() -> ConnectionEvent) {
+        if self.tx.receiver_count() == 0 {
+            return;
+        }
+        let mut line = serde_json::to_string(&event()).expect("events serialize");
+        line.push('\n');
+        // Clients may disconnect in the meantime, which is fine.
+        let _ = self.tx.send(line.into());
+    }
+
+    /// serve streams events to every client that connects to a Unix socket at `path`, until
+    /// ztunnel drains. A socket left behind at `path` by a previous process is replaced. Events
+    /// describe all of the node's traffic, so only clients running as our user are served.
+    #[cfg(unix)]
+    pub fn serve(&self, path: &Path, drain: Watch) -> io::Result<()> {
+        let listener = socket::bind_unix(path)?;
+        info!(path = %path.display(), "streaming connection events");
+        let events = self.clone();
+        tokio::spawn(async move {
+            let accept = async {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            if let Err(e) = socket::check_unix_peer(&stream) {
+                                warn!("refusing connection event client: {e}");
+                                continue;
+                            }
+                            tokio::spawn(stream_events(stream, events.tx.subscribe()));
+                        }
+                        Err(e) => warn!("failed to accept connection event client: {e}"),
+                    }
+                }
+            };
+            tokio::select! {
+                _ = accept => {}
+                _ = drain.signaled() => {}
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn serve(&self, _path: &Path, _drain: Watch) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "connection events are only served on Unix sockets",
+        ))
+    }
+}
+
+#[cfg(unix)]
+async fn stream_events(mut stream: UnixStream, mut rx: broadcast::Receiver<Arc<str>>) {
+    loop {
+        let line = match rx.recv().await {
+            Ok(line) => line,
+            Err(RecvError::Lagged(missed)) => {
+                warn!(missed, "connection event client fell behind");
+                format!("{{\"event\":\"lagged\",\"missed\":{missed}}}\n").into()
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if stream.write_all(line.as_bytes()).await.is_err() {
+            // The client went away.
+            return;
+        }
+    }
+}
+
+impl ConnectionEvent {
+    fn new(event: EventKind, c: &ConnectionOpen) -> Self {
+        ConnectionEvent {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            event,
+            connection_id: c.connection_id.to_string(),
+            reporter: format!("{:?}", c.reporter),
+            source: c
+                .source
+                .as_ref()
+                .map(Peer::from)
+                .or_else(|| c.derived_source.as_ref().map(Peer::from)),
+            destination: c
+                .destination
+                .as_ref()
+                .map(Peer::from)
+                .or_else(|| c.derived_destination.as_ref().map(Peer::from)),
+            destination_service: c.destination_service.as_ref().map(|s| s.hostname.clone()),
+            security_policy: format!("{:?}", c.connection_security_policy),
+            duration_seconds: None,
+            response_flags: None,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::proxy::metrics::Reporter;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    #[tokio::test]
+    async fn stream_connection_events() {
+        let path = std::env::temp_dir().join(format!("events-{}.sock", rand::random::<u64>()));
+        let (drain_tx, drain_rx) = drain::channel();
+        let events = ConnectionEvents::default();
+        events.serve(&path, drain_rx).unwrap();
+
+        let client = UnixStream::connect(&path).await.unwrap();
+        let mut lines = BufReader::new(client).lines();
+        // Wait for the server to subscribe the client.
+        while events.tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let conn = ConnectionOpen {
+            derived_source: Some(DerivedWorkload {
+                workload_name: Some("client".to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            }),
+            ..crate::test_helpers::test_connection_open(Reporter::destination)
+        };
+        events.open(&conn);
+        events.close(&conn, Duration::from_secs(2), ResponseFlags::NoRoute);
+
+        let open: serde_json::Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(open["event"], "open");
+        assert_eq!(open["connection_id"], conn.connection_id.to_string());
+        assert_eq!(open["source"]["workload"], "client");
+        assert!(open.get("duration_seconds").is_none());
+        let close: serde_json::Value =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(close["event"], "close");
+        assert_eq!(close["duration_seconds"], 2.0);
+        assert_eq!(close["response_flags"], "NR");
+
+        drop(lines);
+        drain_tx.drain().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
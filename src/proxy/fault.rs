@@ -0,0 +1,244 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fault injection into proxied connections, as configured by [FaultInjection]. Faults are only
+//! injected by builds with the `fault-injection` feature; other builds ignore the configuration.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use crate::config::FaultInjection;
+use crate::proxy::metrics::Reporter;
+use crate::socket;
+
+/// Connections picked to be reset are reset after a random amount of data, up to this many bytes.
+const MAX_RESET_AFTER: u64 = 64 * 1024;
+
+static FAULTS: OnceCell<FaultInjection> = OnceCell::new();
+
+/// configure sets the faults injected for the rest of the process' lifetime.
+pub fn configure(faults: &FaultInjection) {
+    if !faults.is_enabled() {
+        return;
+    }
+    #[cfg(feature = "fault-injection")]
+    {
+        warn!(?faults, "fault injection is enabled");
+        let _ = FAULTS.set(faults.clone());
+    }
+    #[cfg(not(feature = "fault-injection"))]
+    warn!("fault injection requested, but not supported by this build; ignoring");
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl From<Reporter> for Direction {
+    fn from(reporter: Reporter) -> Direction {
+        match reporter {
+            Reporter::source => Direction::Outbound,
+            Reporter::destination => Direction::Inbound,
+        }
+    }
+}
+
+fn faults(direction: Direction) -> Option<&'static FaultInjection> {
+    FAULTS.get().filter(|f| match direction {
+        Direction::Inbound => f.inbound,
+        Direction::Outbound => f.outbound,
+    })
+}
+
+fn roll(percentage: u8) -> bool {
+    percentage > 0 && rand::thread_rng().gen_range(0..100) < percentage
+}
+
+/// delay_connect holds up connecting to the upstream, if the connection is picked to be delayed.
+pub async fn delay_connect(direction: Direction) {
+    let Some(f) = faults(direction) else {
+        return;
+    };
+    if roll(f.connect_delay_percentage) {
+        debug!(delay=?f.connect_delay, "injecting connect delay");
+        tokio::time::sleep(f.connect_delay).await;
+    }
+}
+
+/// fail_handshake returns an error if the connection is picked to fail its handshake.
+pub fn fail_handshake(direction: Direction) -> io::Result<()> {
+    match faults(direction) {
+        Some(f) if roll(f.handshake_failure_percentage) => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "injected handshake failure",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// StreamFaults are the faults picked for the data of a single connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamFaults {
+    corruption_percentage: u8,
+    reset_after: Option<u64>,
+}
+
+impl StreamFaults {
+    pub fn new(direction: Direction) -> StreamFaults {
+        let Some(f) = faults(direction) else {
+            return StreamFaults::default();
+        };
+        StreamFaults {
+            corruption_percentage: f.corruption_percentage,
+            reset_after: roll(f.reset_percentage)
+                .then(|| rand::thread_rng().gen_range(0..MAX_RESET_AFTER)),
+        }
+    }
+
+    /// is_none returns whether no fault was picked at all.
+    pub fn is_none(&self) -> bool {
+        self.corruption_percentage == 0 && self.reset_after.is_none()
+    }
+
+    /// without_reset keeps only the corruption, for writers that should never see a reset.
+    pub fn without_reset(self) -> StreamFaults {
+        StreamFaults {
+            reset_after: None,
+            ..self
+        }
+    }
+
+    pub fn wrap<W>(self, inner: W) -> Faulty<W> {
+        Faulty {
+            inner,
+            faults: self,
+            written: 0,
+        }
+    }
+}
+
+/// Faulty injects the faults picked for a connection into the writes to `inner`.
+pub struct Faulty<W> {
+    inner: W,
+    faults: StreamFaults,
+    written: u64,
+}
+
+#[derive(Debug)]
+struct InjectedReset;
+
+impl fmt::Display for InjectedReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "injected connection reset")
+    }
+}
+
+impl std::error::Error for InjectedReset {}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Faulty<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut buf = buf;
+        if let Some(limit) = this.faults.reset_after {
+            if this.written >= limit {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    InjectedReset,
+                )));
+            }
+            buf = &buf[..buf.len().min((limit - this.written) as usize)];
+        }
+        let n = if !buf.is_empty() && roll(this.faults.corruption_percentage) {
+            let mut corrupted = buf.to_vec();
+            let i = rand::thread_rng().gen_range(0..corrupted.len());
+            corrupted[i] ^= 0xff;
+            ready!(Pin::new(&mut this.inner).poll_write(cx, &corrupted))?
+        } else {
+            ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?
+        };
+        this.written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// reset_if_injected makes `stream` close with a reset rather than a FIN, if `err` is an
+/// injected reset.
+pub fn reset_if_injected(err: &io::Error, stream: &TcpStream) {
+    if err.get_ref().map_or(false, |e| e.is::<InjectedReset>()) {
+        socket::reset_on_close(stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reset() {
+        let faults = StreamFaults {
+            corruption_percentage: 0,
+            reset_after: Some(5),
+        };
+        let mut w = faults.wrap(Vec::new());
+        let err = w.write_all(b"hello world").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(err.get_ref().unwrap().is::<InjectedReset>());
+        assert_eq!(w.inner, b"hello");
+
+        let mut w = faults.without_reset().wrap(Vec::new());
+        w.write_all(b"hello world").await.unwrap();
+        assert_eq!(w.inner, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn corruption() {
+        let faults = StreamFaults {
+            corruption_percentage: 100,
+            reset_after: None,
+        };
+        let mut w = faults.wrap(Vec::new());
+        w.write_all(b"hello world").await.unwrap();
+        let flipped = w
+            .inner
+            .iter()
+            .zip(b"hello world")
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(flipped, 1);
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! L4 filters see the connections a listener accepts before they are proxied, and may deny them,
+//! hold them up, or annotate their logs. Each listener runs its own chain of filters, in order:
+//! first the ones configured through L4_FILTERS, then those that builds embedding ztunnel
+//! register with [ConnectionHooks::with_filter](super::ConnectionHooks::with_filter).
+//! Besides the built-in filters, L4_FILTERS can load filters from shared libraries that export a
+//! C [LibraryFilterFn] named `ztunnel_l4_filter`. WASM modules are not supported.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use tracing::{debug, info, Span};
+
+use crate::config::{BuiltinFilter, FilterListener, FilterSpec};
+use crate::proxy::metrics::FilterLabels;
+use crate::proxy::{Error, Metrics};
+
+/// FilterConnection is what filters know of a connection, and what they may add to it.
+#[derive(Debug)]
+pub struct FilterConnection {
+    pub listener: FilterListener,
+    pub source: IpAddr,
+    pub destination: SocketAddr,
+    /// Keys and values logged along with the connection once the chain allows it.
+    pub annotations: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Pass the connection on to the next filter, or to the proxy after the last one.
+    Continue,
+    /// Close the connection, for the reason given.
+    Deny(String),
+}
+
+#[async_trait]
+pub trait L4Filter: Send + Sync {
+    /// name identifies the filter in logs and errors.
+    fn name(&self) -> &str;
+
+    /// on_connection is called once for each connection the listener accepts. Connections are
+    /// held up until it returns.
+    async fn on_connection(&self, conn: &mut FilterConnection) -> Verdict;
+}
+
+/// FilterChains holds the chain of filters of each listener.
+#[derive(Clone, Default)]
+pub struct FilterChains(HashMap<FilterListener, Vec<Arc<dyn L4Filter>>>);
+
+impl FilterChains {
+    /// new builds the chains of `specs`, and fails if a library filter cannot be loaded.
+    pub fn new(specs: &[FilterSpec]) -> io::Result<FilterChains> {
+        specs
+            .iter()
+            .try_fold(FilterChains::default(), |chains, spec| {
+                let filter: Arc<dyn L4Filter> = match &spec.filter {
+                    BuiltinFilter::Deny(cidr) => Arc::new(Deny(*cidr)),
+                    BuiltinFilter::Delay(delay) => Arc::new(Delay(*delay)),
+                    BuiltinFilter::Annotate(key, value) => {
+                        Arc::new(Annotate(key.clone(), value.clone()))
+                    }
+                    BuiltinFilter::Library(path) => Arc::new(Library::load(path)?),
+                };
+                Ok(chains.with(spec.listener, filter))
+            })
+    }
+
+    /// with adds `filter` to the end of the chain of `listener`.
+    pub fn with(mut self, listener: FilterListener, filter: Arc<dyn L4Filter>) -> FilterChains {
+        self.0.entry(listener).or_default().push(filter);
+        self
+    }
+
+    /// run passes a connection through the chain of `listener`, and returns an error if a filter
+    /// denies it. The annotations of the connection are recorded on the current span, if it is a
+    /// connection's span, so every later log of the connection carries them.
+    pub async fn run(
+        &self,
+        metrics: &Metrics,
+        listener: FilterListener,
+        source: IpAddr,
+        destination: SocketAddr,
+    ) -> Result<(), Error> {
+        let Some(chain) = self.0.get(&listener) else {
+            return Ok(());
+        };
+        let mut conn = FilterConnection {
+            listener,
+            source,
+            destination,
+            annotations: Vec::new(),
+        };
+        for filter in chain {
+            if let Verdict::Deny(reason) = filter.on_connection(&mut conn).await {
+                debug!(filter = filter.name(), %source, %destination, "filter denied connection");
+                metrics
+                    .filter_denials
+                    .get_or_create(&FilterLabels {
+                        listener: listener.as_str().to_string(),
+                        filter: filter.name().to_string(),
+                    })
+                    .inc();
+                return Err(Error::FilterDenied(filter.name().to_string(), reason));
+            }
+        }
+        if conn.annotations.is_empty() {
+            return Ok(());
+        }
+        let span = Span::current();
+        if span.has_field("annotations") {
+            span.record("annotations", tracing::field::debug(&conn.annotations));
+        } else {
+            info!(%source, %destination, annotations = ?conn.annotations, "connection annotated by filters");
+        }
+        Ok(())
+    }
+}
+
+struct Deny(IpNet);
+
+#[async_trait]
+impl L4Filter for Deny {
+    fn name(&self) -> &str {
+        "deny"
+    }
+
+    async fn on_connection(&self, conn: &mut FilterConnection) -> Verdict {
+        if self.0.contains(&conn.source) {
+            return Verdict::Deny(format!("source is in {}", self.0));
+        }
+        Verdict::Continue
+    }
+}
+
+struct Delay(Duration);
+
+#[async_trait]
+impl L4Filter for Delay {
+    fn name(&self) -> &str {
+        "delay"
+    }
+
+    async fn on_connection(&self, _: &mut FilterConnection) -> Verdict {
+        tokio::time::sleep(self.0).await;
+        Verdict::Continue
+    }
+}
+
+struct Annotate(String, String);
+
+#[async_trait]
+impl L4Filter for Annotate {
+    fn name(&self) -> &str {
+        "annotate"
+    }
+
+    async fn on_connection(&self, conn: &mut FilterConnection) -> Verdict {
+        conn.annotations.push((self.0.clone(), self.1.clone()));
+        Verdict::Continue
+    }
+}
+
+/// LibraryConnection is what a library filter is passed of a connection.
+#[repr(C)]
+pub struct LibraryConnection {
+    /// 0 for inbound, 1 for inbound passthrough and 2 for outbound connections.
+    pub listener: u8,
+    /// The source address, with IPv4 addresses mapped into IPv6.
+    pub source: [u8; 16],
+    /// The destination address, with IPv4 addresses mapped into IPv6.
+    pub destination: [u8; 16],
+    pub destination_port: u16,
+}
+
+/// LibraryFilterFn is the signature of `ztunnel_l4_filter`. It returns 0 to pass the connection
+/// on, and any other value to deny it.
+pub type LibraryFilterFn = unsafe extern "C" fn(conn: *const LibraryConnection) -> i32;
+
+/// Library is a filter loaded from a shared library. The library is never unloaded.
+struct Library {
+    name: String,
+    filter: LibraryFilterFn,
+}
+
+impl Library {
+    #[cfg(unix)]
+    #[allow(unsafe_code)]
+    fn load(path: &Path) -> io::Result<Library> {
+        use std::ffi::{CStr, CString};
+        use std::os::unix::ffi::OsStrExt;
+
+        let failed = |what: &str| {
+            // Safety: dlerror returns null, or a string valid until the next dl call.
+            let reason = unsafe {
+                let err = libc::dlerror();
+                if err.is_null() {
+                    "unknown error".into()
+                } else {
+                    CStr::from_ptr(err).to_string_lossy()
+                }
+            };
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to {what} L4 filter {}: {reason}", path.display()),
+            )
+        };
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // Safety: loading runs the library's initializers, which the operator vouched for by
+        // configuring it.
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(failed("load"));
+        }
+        // Safety: the handle was just opened, and the symbol name is nul terminated.
+        let symbol = unsafe { libc::dlsym(handle, b"ztunnel_l4_filter\0".as_ptr().cast()) };
+        if symbol.is_null() {
+            let err = failed("find ztunnel_l4_filter in");
+            // Safety: nothing refers to the library yet.
+            unsafe { libc::dlclose(handle) };
+            return Err(err);
+        }
+        // Safety: the library exports the function with the documented signature, and is never
+        // unloaded, so the pointer stays valid.
+        let filter = unsafe { std::mem::transmute::<*mut libc::c_void, LibraryFilterFn>(symbol) };
+        Ok(Library {
+            name: format!("library:{}", path.display()),
+            filter,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn load(path: &Path) -> io::Result<Library> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "L4 filter {}: libraries are only supported on unix",
+                path.display()
+            ),
+        ))
+    }
+}
+
+#[async_trait]
+impl L4Filter for Library {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[allow(unsafe_code)]
+    async fn on_connection(&self, conn: &mut FilterConnection) -> Verdict {
+        let mapped = |ip: IpAddr| match ip {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let c_conn = LibraryConnection {
+            listener: match conn.listener {
+                FilterListener::Inbound => 0,
+                FilterListener::InboundPassthrough => 1,
+                FilterListener::Outbound => 2,
+            },
+            source: mapped(conn.source),
+            destination: mapped(conn.destination.ip()),
+            destination_port: conn.destination.port(),
+        };
+        // Safety: the connection outlives the call, which must not keep the pointer.
+        match unsafe { (self.filter)(&c_conn) } {
+            0 => Verdict::Continue,
+            code => Verdict::Deny(format!("returned {code}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn chains() {
+        let chains = FilterChains::new(&[
+            FilterSpec {
+                listener: FilterListener::Inbound,
+                filter: BuiltinFilter::Delay(Duration::from_secs(1)),
+            },
+            FilterSpec {
+                listener: FilterListener::Inbound,
+                filter: BuiltinFilter::Deny("10.0.0.0/8".parse().unwrap()),
+            },
+        ])
+        .unwrap();
+        let metrics = Metrics::new(&mut Registry::default());
+        let denials = |listener: FilterListener| {
+            metrics
+                .filter_denials
+                .get_or_create(&FilterLabels {
+                    listener: listener.as_str().to_string(),
+                    filter: "deny".to_string(),
+                })
+                .get()
+        };
+        let dst = "127.0.0.1:8080".parse().unwrap();
+
+        let start = tokio::time::Instant::now();
+        assert!(matches!(
+            chains
+                .run(&metrics, FilterListener::Inbound, "10.0.0.1".parse().unwrap(), dst)
+                .await,
+            Err(Error::FilterDenied(name, _)) if name == "deny"
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(chains
+            .run(
+                &metrics,
+                FilterListener::Inbound,
+                "192.168.0.1".parse().unwrap(),
+                dst
+            )
+            .await
+            .is_ok());
+        // Other listeners have chains of their own.
+        assert!(chains
+            .run(
+                &metrics,
+                FilterListener::Outbound,
+                "10.0.0.1".parse().unwrap(),
+                dst
+            )
+            .await
+            .is_ok());
+        assert_eq!(denials(FilterListener::Inbound), 1);
+        assert_eq!(denials(FilterListener::Outbound), 0);
+    }
+
+    #[tokio::test]
+    async fn library() {
+        unsafe extern "C" fn deny_v4_port_80(conn: *const LibraryConnection) -> i32 {
+            let conn = &*conn;
+            let v4 = conn.destination[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff];
+            (conn.listener == 2 && v4 && conn.destination_port == 80) as i32
+        }
+        let filter = Library {
+            name: "library:test".to_string(),
+            filter: deny_v4_port_80,
+        };
+        let filter = &filter;
+        let verdict = |destination: &str| {
+            let mut conn = FilterConnection {
+                listener: FilterListener::Outbound,
+                source: "10.0.0.1".parse().unwrap(),
+                destination: destination.parse().unwrap(),
+                annotations: Vec::new(),
+            };
+            async move { filter.on_connection(&mut conn).await }
+        };
+        assert_eq!(
+            verdict("10.0.0.2:80").await,
+            Verdict::Deny("returned 1".to_string())
+        );
+        assert_eq!(verdict("10.0.0.2:443").await, Verdict::Continue);
+        assert_eq!(verdict("[::1]:80").await, Verdict::Continue);
+    }
+
+    #[test]
+    fn library_load_errors() {
+        let spec = |path: &str| FilterSpec {
+            listener: FilterListener::Outbound,
+            filter: BuiltinFilter::Library(PathBuf::from(path)),
+        };
+        assert!(FilterChains::new(&[spec("/nonexistent/libfilter.so")]).is_err());
+        // libc loads, but is no filter.
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        {
+            let err = FilterChains::new(&[spec("libc.so.6")]).err().unwrap();
+            assert!(err.to_string().contains("ztunnel_l4_filter"), "{err}");
+        }
+    }
+}
@@ -0,0 +1,720 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracking of the data flowing through proxied connections, in each direction.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+use crate::config::FairQueueing;
+use crate::proxy::metrics::{ConnectionOpen, Reporter};
+use crate::proxy::ConnectionId;
+use crate::socket;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::socket::uring;
+
+/// FlowStats counts the data copied in one direction of a connection.
+#[derive(Default)]
+pub struct FlowStats {
+    bytes: AtomicU64,
+    /// Nanoseconds spent waiting for the destination to accept writes.
+    stalled: AtomicU64,
+    /// Nanoseconds spent waiting for the source workload's fair share of bandwidth.
+    queued: AtomicU64,
+    /// The flow's place in its source workload's fair queue, if fair queueing is enabled.
+    fair: Option<FairFlow>,
+}
+
+impl FlowStats {
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn stalled(&self) -> Duration {
+        Duration::from_nanos(self.stalled.load(Ordering::Relaxed))
+    }
+
+    pub fn queued(&self) -> Duration {
+        Duration::from_nanos(self.queued.load(Ordering::Relaxed))
+    }
+
+    /// acquire waits for the flow's fair share of bandwidth to send `n` bytes, if fair queueing
+    /// is enabled.
+    async fn acquire(&self, n: usize) {
+        if let Some(fair) = &self.fair {
+            let start = tokio::time::Instant::now();
+            fair.acquire(n).await;
+            let queued = start.elapsed().as_nanos() as u64;
+            self.queued.fetch_add(queued, Ordering::Relaxed);
+        }
+    }
+
+    fn fair(fair: Option<FairFlow>) -> FlowStats {
+        FlowStats {
+            fair,
+            ..Default::default()
+        }
+    }
+}
+
+/// copy copies `src` into `dst` until `src` reaches EOF, and then shuts down only the write side
+/// of `dst`. Nothing more is read from `src` until everything read so far has been written, so a
+/// slow `dst` (such as an HTTP/2 stream that ran out of flow control window) holds back `src`
+/// instead of data piling up in ztunnel.
+pub async fn copy<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    buffer_size: usize,
+    stats: &FlowStats,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0;
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            socket::shutdown_write(dst).await?;
+            return Ok(total);
+        }
+        stats.acquire(n).await;
+        write_all(dst, &buf[..n], stats).await?;
+        dst.flush().await?;
+        total += n as u64;
+    }
+}
+
+/// copy_from_ring is [copy], reading `src` through the io_uring `ring`.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub async fn copy_from_ring<W>(
+    ring: &uring::Ring,
+    src: &TcpStream,
+    dst: &mut W,
+    buffer_size: usize,
+    stats: &FlowStats,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0;
+    loop {
+        let (res, b) = ring.recv(src, buf).await;
+        buf = b;
+        let n = res?;
+        if n == 0 {
+            socket::shutdown_write(dst).await?;
+            return Ok(total);
+        }
+        stats.acquire(n).await;
+        write_all(dst, &buf[..n], stats).await?;
+        dst.flush().await?;
+        total += n as u64;
+    }
+}
+
+/// copy_to_ring is [copy], writing `dst` through the io_uring `ring`. The ring only reports a
+/// send once it is done, so all of the time it takes counts as stalled.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub async fn copy_to_ring<R>(
+    ring: &uring::Ring,
+    src: &mut R,
+    dst: &TcpStream,
+    buffer_size: usize,
+    stats: &FlowStats,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut buf = vec![0u8; buffer_size];
+    let mut total = 0;
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            uring::shutdown_write(dst)?;
+            return Ok(total);
+        }
+        stats.acquire(n).await;
+        let start = Instant::now();
+        buf = uring::send_all(ring, dst, buf, n).await?;
+        let stalled = start.elapsed().as_nanos() as u64;
+        stats.stalled.fetch_add(stalled, Ordering::Relaxed);
+        stats.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        total += n as u64;
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin + ?Sized>(
+    dst: &mut W,
+    buf: &[u8],
+    stats: &FlowStats,
+) -> io::Result<()> {
+    let mut written = 0;
+    while written < buf.len() {
+        let mut blocked: Option<Instant> = None;
+        let n = futures::future::poll_fn(|cx| {
+            let res = Pin::new(&mut *dst).poll_write(cx, &buf[written..]);
+            match (&res, blocked) {
+                (Poll::Pending, None) => blocked = Some(Instant::now()),
+                (Poll::Ready(_), Some(since)) => {
+                    let stalled = since.elapsed().as_nanos() as u64;
+                    stats.stalled.fetch_add(stalled, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+            res
+        })
+        .await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        written += n;
+        stats.bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Tags are in bytes scaled by WEIGHT_SCALE and divided by the flow's weight, so heavier flows
+/// advance through virtual time more slowly and are granted proportionally more.
+const WEIGHT_SCALE: u128 = 1 << 16;
+
+/// FairQueues shares the bandwidth of each source workload between its flows with start-time fair
+/// queueing. Each buffer a flow wants to send is tagged with the later of the flow's previous
+/// finish tag and the workload's virtual time, buffers are granted in order of their tags, and
+/// grants are paced to the configured bandwidth. A flow that has been idle does not build up
+/// credit, and a backlogged elephant flow only gets its weighted share while others are active.
+#[derive(Clone)]
+pub struct FairQueues {
+    cfg: Arc<FairQueueing>,
+    links: Arc<Mutex<HashMap<(String, Direction), Weak<Link>>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Direction {
+    Sent,
+    Received,
+}
+
+/// Link is the bandwidth of one source workload in one direction.
+struct Link {
+    bandwidth: f64,
+    state: Mutex<LinkState>,
+    /// Notified whenever a request leaves the queue.
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct LinkState {
+    next_flow: u64,
+    /// The start tag of the last granted request.
+    virtual_time: u128,
+    /// The finish tag of each flow's last request.
+    finish: HashMap<u64, u128>,
+    /// The requests waiting to be granted, by start tag.
+    waiting: BTreeSet<(u128, u64)>,
+    /// When the link will have sent everything granted so far.
+    busy_until: Option<tokio::time::Instant>,
+}
+
+/// FairFlow is one direction of a connection in its source workload's fair queue.
+pub struct FairFlow {
+    link: Arc<Link>,
+    id: u64,
+    weight: u32,
+}
+
+/// Queued removes a request from its queue once granted, or when the copy waiting on it is
+/// cancelled, and lets the next request through.
+struct Queued<'a> {
+    link: &'a Link,
+    request: (u128, u64),
+}
+
+impl FairQueues {
+    pub fn new(cfg: FairQueueing) -> FairQueues {
+        FairQueues {
+            cfg: Arc::new(cfg),
+            links: Default::default(),
+        }
+    }
+
+    fn flow(&self, source: String, direction: Direction, weight: u32) -> FairFlow {
+        let mut links = self.links.lock().unwrap();
+        let key = (source, direction);
+        let link = match links.get(&key).and_then(Weak::upgrade) {
+            Some(link) => link,
+            None => {
+                links.retain(|_, l| l.strong_count() > 0);
+                let link = Arc::new(Link {
+                    bandwidth: self.cfg.bandwidth as f64,
+                    state: Default::default(),
+                    notify: Notify::new(),
+                });
+                links.insert(key, Arc::downgrade(&link));
+                link
+            }
+        };
+        let id = {
+            let mut state = link.state.lock().unwrap();
+            state.next_flow += 1;
+            let id = state.next_flow;
+            let virtual_time = state.virtual_time;
+            state.finish.insert(id, virtual_time);
+            id
+        };
+        FairFlow { link, id, weight }
+    }
+
+    /// weight returns the weight of connections to `conn`'s destination service.
+    fn weight(&self, conn: &ConnectionOpen) -> u32 {
+        conn.destination_service
+            .as_ref()
+            .and_then(|s| self.cfg.weights.get(&s.hostname))
+            .copied()
+            .unwrap_or(1)
+    }
+}
+
+impl FairFlow {
+    /// acquire waits until the flow may send `n` more bytes.
+    async fn acquire(&self, n: usize) {
+        let link = &*self.link;
+        let request = {
+            let mut state = link.state.lock().unwrap();
+            let start = state.finish[&self.id].max(state.virtual_time);
+            let finish = start + n as u128 * WEIGHT_SCALE / self.weight as u128;
+            state.finish.insert(self.id, finish);
+            state.waiting.insert((start, self.id));
+            (start, self.id)
+        };
+        let _queued = Queued { link, request };
+        loop {
+            let notified = link.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let busy_until = {
+                let mut state = link.state.lock().unwrap();
+                if state.waiting.first() != Some(&request) {
+                    None
+                } else {
+                    let now = tokio::time::Instant::now();
+                    match state.busy_until {
+                        Some(until) if until > now => Some(until),
+                        _ => {
+                            state.virtual_time = request.0;
+                            let sending = Duration::from_secs_f64(n as f64 / link.bandwidth);
+                            state.busy_until = Some(now + sending);
+                            return;
+                        }
+                    }
+                }
+            };
+            match busy_until {
+                Some(until) => tokio::time::sleep_until(until).await,
+                None => notified.await,
+            }
+        }
+    }
+}
+
+impl Drop for FairFlow {
+    fn drop(&mut self) {
+        self.link.state.lock().unwrap().finish.remove(&self.id);
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.link
+            .state
+            .lock()
+            .unwrap()
+            .waiting
+            .remove(&self.request);
+        self.link.notify.notify_waiters();
+    }
+}
+
+/// The number of locks the tracked connections and peers are each split across, so that
+/// connections opening and closing at once rarely contend for one.
+const TRACKER_SHARDS: usize = 16;
+
+/// Tracked holds what is being tracked, keyed by the number it was tracked under.
+type Tracked<V> = Arc<[Mutex<HashMap<u64, Arc<V>>>; TRACKER_SHARDS]>;
+
+fn shard<V>(tracked: &Tracked<V>, key: u64) -> MutexGuard<'_, HashMap<u64, Arc<V>>> {
+    tracked[key as usize % TRACKER_SHARDS].lock().unwrap()
+}
+
+/// all returns everything `tracked`, for a dump.
+fn all<V>(tracked: &Tracked<V>) -> Vec<Arc<V>> {
+    tracked
+        .iter()
+        .flat_map(|s| s.lock().unwrap().values().cloned().collect::<Vec<_>>())
+        .collect()
+}
+
+/// ConnectionTracker keeps the flow stats of the connections being proxied, and the CONNECT
+/// streams each inbound HBONE peer has open, so they can be inspected while they are open.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    next: Arc<AtomicU64>,
+    connections: Tracked<TrackedConnection>,
+    peers: Tracked<TrackedPeer>,
+    /// If set, the connections of each source workload are paced to share its bandwidth fairly.
+    fair: Option<FairQueues>,
+}
+
+pub struct TrackedConnection {
+    connection_id: ConnectionId,
+    reporter: Reporter,
+    src_identity: Option<String>,
+    dst_identity: Option<String>,
+    opened: Instant,
+    /// Data sent from the local stream to the peer.
+    pub sent: FlowStats,
+    /// Data received from the peer and written to the local stream.
+    pub received: FlowStats,
+}
+
+/// ConnectionGuard stops tracking its connection when dropped.
+pub struct ConnectionGuard {
+    key: u64,
+    connection: Arc<TrackedConnection>,
+    tracker: ConnectionTracker,
+}
+
+impl std::ops::Deref for ConnectionGuard {
+    type Target = TrackedConnection;
+
+    fn deref(&self) -> &TrackedConnection {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        shard(&self.tracker.connections, self.key).remove(&self.key);
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct ConnectionDump {
+    connection_id: String,
+    reporter: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src_identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst_identity: Option<String>,
+    duration_seconds: f64,
+    sent_bytes: u64,
+    received_bytes: u64,
+    /// Average bytes per second since the connection opened.
+    send_rate: f64,
+    receive_rate: f64,
+    send_stall_seconds: f64,
+    receive_stall_seconds: f64,
+    /// The connection's weight in its source workload's fair queue, if fair queueing is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fair_queue_weight: Option<u32>,
+    /// Time spent waiting for the source workload's fair share of bandwidth.
+    send_queue_seconds: f64,
+    receive_queue_seconds: f64,
+}
+
+/// TrackedPeer is an inbound HBONE connection, which may carry many CONNECT streams at once.
+pub struct TrackedPeer {
+    addr: SocketAddr,
+    identity: Option<String>,
+    opened: Instant,
+    active_streams: AtomicU64,
+    total_streams: AtomicU64,
+}
+
+/// PeerGuard stops tracking its peer when dropped.
+pub struct PeerGuard {
+    key: u64,
+    peer: Arc<TrackedPeer>,
+    tracker: ConnectionTracker,
+}
+
+impl PeerGuard {
+    /// stream counts a CONNECT stream from the peer for as long as the returned guard is held.
+    pub fn stream(&self) -> StreamGuard {
+        self.peer.active_streams.fetch_add(1, Ordering::Relaxed);
+        self.peer.total_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(self.peer.clone())
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        shard(&self.tracker.peers, self.key).remove(&self.key);
+    }
+}
+
+pub struct StreamGuard(Arc<TrackedPeer>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+pub struct PeerDump {
+    peer_address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identity: Option<String>,
+    duration_seconds: f64,
+    active_streams: u64,
+    total_streams: u64,
+}
+
+impl ConnectionTracker {
+    pub fn with_fair_queues(mut self, fair: Option<FairQueues>) -> Self {
+        self.fair = fair;
+        self
+    }
+
+    pub fn track(&self, conn: &ConnectionOpen) -> ConnectionGuard {
+        // Connections from workloads we don't know of are not paced.
+        let source = conn
+            .source
+            .as_ref()
+            .map(|w| format!("{}/{}", w.namespace, w.name));
+        let fair = |direction| {
+            let (queues, source) = self.fair.as_ref().zip(source.clone())?;
+            Some(queues.flow(source, direction, queues.weight(conn)))
+        };
+        let connection = Arc::new(TrackedConnection {
+            connection_id: conn.connection_id,
+            reporter: conn.reporter,
+            src_identity: conn
+                .source
+                .as_ref()
+                .map(|w| w.identity().to_string())
+                .or_else(|| {
+                    conn.derived_source
+                        .as_ref()
+                        .and_then(|d| d.identity.as_ref())
+                        .map(ToString::to_string)
+                }),
+            dst_identity: conn.destination.as_ref().map(|w| w.identity().to_string()),
+            opened: Instant::now(),
+            sent: FlowStats::fair(fair(Direction::Sent)),
+            received: FlowStats::fair(fair(Direction::Received)),
+        });
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        shard(&self.connections, key).insert(key, connection.clone());
+        ConnectionGuard {
+            key,
+            connection,
+            tracker: self.clone(),
+        }
+    }
+
+    /// active returns the number of connections being proxied.
+    pub fn active(&self) -> usize {
+        self.connections
+            .iter()
+            .map(|s| s.lock().unwrap().len())
+            .sum()
+    }
+
+    pub fn dump(&self) -> Vec<ConnectionDump> {
+        all(&self.connections).iter().map(|c| c.dump()).collect()
+    }
+
+    pub fn track_peer(&self, addr: SocketAddr, identity: Option<String>) -> PeerGuard {
+        let peer = Arc::new(TrackedPeer {
+            addr,
+            identity,
+            opened: Instant::now(),
+            active_streams: AtomicU64::new(0),
+            total_streams: AtomicU64::new(0),
+        });
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        shard(&self.peers, key).insert(key, peer.clone());
+        PeerGuard {
+            key,
+            peer,
+            tracker: self.clone(),
+        }
+    }
+
+    pub fn dump_peers(&self) -> Vec<PeerDump> {
+        all(&self.peers)
+            .iter()
+            .map(|p| PeerDump {
+                peer_address: p.addr.to_string(),
+                identity: p.identity.clone(),
+                duration_seconds: p.opened.elapsed().as_secs_f64(),
+                active_streams: p.active_streams.load(Ordering::Relaxed),
+                total_streams: p.total_streams.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+impl TrackedConnection {
+    fn dump(&self) -> ConnectionDump {
+        let duration = self.opened.elapsed().as_secs_f64();
+        let rate = |bytes: u64| {
+            if duration > 0.0 {
+                bytes as f64 / duration
+            } else {
+                0.0
+            }
+        };
+        ConnectionDump {
+            connection_id: self.connection_id.to_string(),
+            reporter: format!("{:?}", self.reporter),
+            src_identity: self.src_identity.clone(),
+            dst_identity: self.dst_identity.clone(),
+            duration_seconds: duration,
+            sent_bytes: self.sent.bytes(),
+            received_bytes: self.received.bytes(),
+            send_rate: rate(self.sent.bytes()),
+            receive_rate: rate(self.received.bytes()),
+            send_stall_seconds: self.sent.stalled().as_secs_f64(),
+            receive_stall_seconds: self.received.stalled().as_secs_f64(),
+            fair_queue_weight: self.sent.fair.as_ref().map(|f| f.weight),
+            send_queue_seconds: self.sent.queued().as_secs_f64(),
+            receive_queue_seconds: self.received.queued().as_secs_f64(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::metrics::SecurityPolicy;
+
+    #[tokio::test]
+    async fn copy_records_stalls() {
+        // A tiny pipe, so the writer has to wait for the reader to drain it.
+        let (mut client, mut server) = tokio::io::duplex(16);
+        let stats = FlowStats::default();
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut out = Vec::new();
+            server.read_to_end(&mut out).await.unwrap();
+            out
+        });
+        let payload = vec![1u8; 1000];
+        let n = copy(&mut payload.as_slice(), &mut client, 64, &stats)
+            .await
+            .unwrap();
+        assert_eq!(n, 1000);
+        assert_eq!(stats.bytes(), 1000);
+        assert!(stats.stalled() > Duration::ZERO);
+        assert_eq!(reader.await.unwrap(), payload);
+    }
+
+    async fn run(stats: &FlowStats) {
+        let _ = copy(
+            &mut tokio::io::repeat(1),
+            &mut tokio::io::sink(),
+            1000,
+            stats,
+        )
+        .await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fair_queueing_shares_bandwidth_by_weight() {
+        let queues = FairQueues::new(FairQueueing {
+            bandwidth: 100_000,
+            weights: HashMap::new(),
+        });
+        let light = FlowStats::fair(Some(queues.flow("ns/a".into(), Direction::Sent, 1)));
+        let heavy = FlowStats::fair(Some(queues.flow("ns/a".into(), Direction::Sent, 3)));
+        // Another workload, or the other direction, has bandwidth of its own.
+        let other = FlowStats::fair(Some(queues.flow("ns/b".into(), Direction::Sent, 1)));
+        let reverse = FlowStats::fair(Some(queues.flow("ns/a".into(), Direction::Received, 1)));
+        tokio::select! {
+            _ = run(&light) => unreachable!(),
+            _ = run(&heavy) => unreachable!(),
+            _ = run(&other) => unreachable!(),
+            _ = run(&reverse) => unreachable!(),
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+        }
+        let within = |bytes: u64, want: u64| bytes.abs_diff(want) <= want / 20;
+        assert!(within(light.bytes(), 250_000), "{}", light.bytes());
+        assert!(within(heavy.bytes(), 750_000), "{}", heavy.bytes());
+        assert!(within(other.bytes(), 1_000_000), "{}", other.bytes());
+        assert!(within(reverse.bytes(), 1_000_000), "{}", reverse.bytes());
+    }
+
+    #[test]
+    fn tracker() {
+        let tracker = ConnectionTracker::default();
+        let conn = ConnectionOpen {
+            reporter: Reporter::destination,
+            source: None,
+            derived_source: None,
+            derived_destination: None,
+            destination: None,
+            destination_service: None,
+            connection_security_policy: SecurityPolicy::unknown,
+            trace_id: None,
+            connection_id: ConnectionId::new(),
+            source_ip: None,
+            destination_addr: None,
+        };
+        let guard = tracker.track(&conn);
+        guard.sent.bytes.fetch_add(10, Ordering::Relaxed);
+        let dump = tracker.dump();
+        assert_eq!(dump.len(), 1);
+        assert_eq!(dump[0].connection_id, conn.connection_id.to_string());
+        assert_eq!(dump[0].reporter, "destination");
+        assert_eq!(dump[0].sent_bytes, 10);
+        assert_eq!(dump[0].received_bytes, 0);
+        assert_eq!(tracker.active(), 1);
+        drop(guard);
+        assert!(tracker.dump().is_empty());
+        assert_eq!(tracker.active(), 0);
+    }
+
+    #[test]
+    fn peer_streams() {
+        let tracker = ConnectionTracker::default();
+        let peer = tracker.track_peer("127.0.0.1:1234".parse().unwrap(), None);
+        let first = peer.stream();
+        let second = peer.stream();
+        drop(first);
+        let dump = tracker.dump_peers();
+        assert_eq!(dump.len(), 1);
+        assert_eq!(dump[0].peer_address, "127.0.0.1:1234");
+        assert_eq!(dump[0].active_streams, 1);
+        assert_eq!(dump[0].total_streams, 2);
+        drop(second);
+        drop(peer);
+        assert!(tracker.dump_peers().is_empty());
+    }
+}
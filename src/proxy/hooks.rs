@@ -0,0 +1,132 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extension points for builds that embed ztunnel, to act on the connections it proxies, such as
+//! for accounting or billing, without changes to the proxy itself.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::FilterListener;
+use crate::proxy::filter::{FilterChains, L4Filter};
+use crate::proxy::metrics::{ConnectionOpen, ResponseFlags};
+
+/// ConnectionHook is called as connections are established and terminated, with everything known
+/// about them at that point. Hooks run inline on the data path, so they must not block; anything
+/// slow should be handed off to a task of its own.
+pub trait ConnectionHook: Send + Sync {
+    /// on_open is called once `conn` is established.
+    fn on_open(&self, _conn: &ConnectionOpen) {}
+
+    /// on_close is called once `conn` terminates, after being open for `duration`, with `flags`
+    /// describing why if it did not end cleanly.
+    fn on_close(&self, _conn: &ConnectionOpen, _duration: Duration, _flags: ResponseFlags) {}
+}
+
+/// ConnectionHooks are the hooks and filters registered with a proxy, called in the order they
+/// were added.
+#[derive(Clone, Default)]
+pub struct ConnectionHooks {
+    hooks: Vec<Arc<dyn ConnectionHook>>,
+    filters: Vec<(FilterListener, Arc<dyn L4Filter>)>,
+}
+
+impl ConnectionHooks {
+    /// with adds `hook` after those already registered.
+    pub fn with(mut self, hook: impl ConnectionHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// with_filter adds `filter` to the chain of `listener`, after the built-in filters.
+    pub fn with_filter(
+        mut self,
+        listener: FilterListener,
+        filter: impl L4Filter + 'static,
+    ) -> Self {
+        self.filters.push((listener, Arc::new(filter)));
+        self
+    }
+
+    /// add_filters adds the registered filters to `chains`.
+    pub(super) fn add_filters(&self, chains: FilterChains) -> FilterChains {
+        self.filters
+            .iter()
+            .fold(chains, |chains, (listener, filter)| {
+                chains.with(*listener, filter.clone())
+            })
+    }
+
+    pub(super) fn open(&self, conn: &ConnectionOpen) {
+        for hook in &self.hooks {
+            hook.on_open(conn);
+        }
+    }
+
+    pub(super) fn close(&self, conn: &ConnectionOpen, duration: Duration, flags: ResponseFlags) {
+        for hook in &self.hooks {
+            hook.on_close(conn, duration, flags);
+        }
+    }
+}
+
+impl fmt::Debug for ConnectionHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionHooks")
+            .field("hooks", &self.hooks.len())
+            .field("filters", &self.filters.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+    use crate::metrics::IncrementRecorder;
+    use crate::proxy::metrics::{ConnectionClose, Metrics, Reporter};
+
+    #[derive(Default)]
+    struct Counting {
+        opens: AtomicUsize,
+        closes: AtomicUsize,
+    }
+
+    impl ConnectionHook for Arc<Counting> {
+        fn on_open(&self, _: &ConnectionOpen) {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_close(&self, _: &ConnectionOpen, _: Duration, _: ResponseFlags) {
+            self.closes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let counting = Arc::new(Counting::default());
+        let metrics = Metrics::new(&mut Registry::default())
+            .with_connection_hooks(ConnectionHooks::default().with(counting.clone()));
+        let conn = crate::test_helpers::test_connection_open(Reporter::destination);
+        metrics.increment(&conn);
+        assert_eq!(counting.opens.load(Ordering::SeqCst), 1);
+        assert_eq!(counting.closes.load(Ordering::SeqCst), 0);
+        metrics.increment(&ConnectionClose::from(&conn));
+        assert_eq!(counting.closes.load(Ordering::SeqCst), 1);
+    }
+}
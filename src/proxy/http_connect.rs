@@ -0,0 +1,388 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An explicit HTTP proxy, for applications configured with `HTTPS_PROXY`. Like SOCKS5, it only
+//! supports CONNECT, and tunnels each connection on behalf of the workload it comes from.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use drain::Watch;
+use hyper::http::uri::Authority;
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn, Instrument};
+
+use crate::proxy::outbound::OutboundConnection;
+use crate::proxy::{connection_span, util, ConnectionId, Error, ProxyInputs, TraceParent};
+use crate::socket;
+use crate::state::workload::NetworkAddress;
+
+/// Request heads larger than this are rejected.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// How long a client has to send its request head once connected.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(super) struct HttpConnect {
+    pi: ProxyInputs,
+    listeners: Vec<TcpListener>,
+    drain: Watch,
+}
+
+impl HttpConnect {
+    pub(super) async fn new(pi: ProxyInputs, drain: Watch) -> Result<HttpConnect, Error> {
+        let listeners = super::listen(
+            &pi,
+            pi.cfg.http_connect_addr,
+            &pi.cfg.listener_tuning.http_connect,
+        )
+        .await?;
+
+        info!(
+            address=%listeners[0].local_addr().unwrap(),
+            component="http_connect",
+            shards=listeners.len(),
+            "listener established",
+        );
+
+        Ok(HttpConnect {
+            pi,
+            listeners,
+            drain,
+        })
+    }
+
+    pub(super) fn address(&self) -> SocketAddr {
+        self.listeners[0].local_addr().unwrap()
+    }
+
+    pub async fn run(self) {
+        let pi = self.pi.clone();
+        let accept = super::supervise::supervise(
+            "http_connect",
+            self.pi.metrics.clone(),
+            self.listeners,
+            move |listener| Self::accept(pi.clone(), listener).in_current_span(),
+        );
+
+        tokio::select! {
+            res = accept => { res }
+            _ = self.drain.signaled() => {
+                info!("http_connect drained");
+            }
+        }
+    }
+
+    async fn accept(pi: ProxyInputs, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, remote)) => {
+                    info!("accepted outbound connection from {}", remote);
+                    let oc = OutboundConnection {
+                        pi: pi.clone(),
+                        id: TraceParent::new_sampled(pi.cfg.trace_sampling_percentage),
+                        connection_id: ConnectionId::new(),
+                    };
+                    let span = connection_span!(
+                        "http_connect",
+                        connection_id = %oc.connection_id,
+                        id = %oc.id,
+                        sampled = oc.id.is_sampled()
+                    );
+                    tokio::spawn(
+                        super::supervise::isolate(pi.metrics.clone(), async move {
+                            if let Err(err) = handle(oc, stream).await {
+                                warn!("http connect failed: {}", err);
+                            }
+                        })
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    if util::is_runtime_shutdown(&e) {
+                        return;
+                    }
+                    error!("Failed TCP handshake {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum RequestError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("request head exceeds {MAX_REQUEST_HEAD} bytes")]
+    TooLarge,
+    #[error("request head not received within {REQUEST_HEAD_TIMEOUT:?}")]
+    Timeout,
+    #[error("malformed request")]
+    Malformed,
+    #[error("unsupported method {0}")]
+    UnsupportedMethod(String),
+    #[error("invalid authority {0}")]
+    InvalidAuthority(String),
+    #[error("unknown host {0}")]
+    UnknownHost(String),
+}
+
+impl RequestError {
+    /// The status to respond with, if the client is still there to read it.
+    fn status(&self) -> Option<StatusCode> {
+        match self {
+            RequestError::Io(_) => None,
+            RequestError::TooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            RequestError::Timeout => Some(StatusCode::REQUEST_TIMEOUT),
+            RequestError::Malformed | RequestError::InvalidAuthority(_) => {
+                Some(StatusCode::BAD_REQUEST)
+            }
+            RequestError::UnsupportedMethod(_) => Some(StatusCode::METHOD_NOT_ALLOWED),
+            RequestError::UnknownHost(_) => Some(StatusCode::BAD_GATEWAY),
+        }
+    }
+}
+
+/// The host and port a client asked to CONNECT to.
+#[derive(Debug, PartialEq, Eq)]
+struct Target {
+    host: String,
+    port: u16,
+}
+
+async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+    let remote_addr = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
+    let dst = match tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(target)) => resolve(&oc.pi, remote_addr.ip(), target).await,
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(RequestError::Timeout),
+    };
+    let dst = match dst {
+        Ok(dst) => dst,
+        Err(e) => {
+            if let Some(status) = e.status() {
+                let resp =
+                    format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+                stream.write_all(resp.as_bytes()).await?;
+            }
+            return Err(e.into());
+        }
+    };
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+
+    info!("accepted connection from {remote_addr} to {dst}");
+    tokio::spawn(
+        super::supervise::isolate(oc.pi.metrics.clone(), async move {
+            let res = oc.proxy_to(stream, remote_addr.ip(), dst, true, None).await;
+            match res {
+                Ok(_) => {}
+                Err(ref e) => warn!("outbound proxy failed: {}", e),
+            };
+        })
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+/// read_request reads a CONNECT request head. It reads no further, as clients may start sending
+/// the tunneled data right behind it: whatever has arrived is peeked at, and only the part up to
+/// the end of the head is taken off the socket.
+async fn read_request(stream: &mut TcpStream) -> Result<Target, RequestError> {
+    const END: &[u8] = b"\r\n\r\n";
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = vec![0; MAX_REQUEST_HEAD];
+    loop {
+        let available = stream
+            .peek(&mut buf[..MAX_REQUEST_HEAD - head.len()])
+            .await?;
+        if available == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        // The end of the head may straddle what was already read and what was peeked at.
+        let read = head.len();
+        let searched = read.saturating_sub(END.len() - 1);
+        head.extend_from_slice(&buf[..available]);
+        let end = head[searched..]
+            .windows(END.len())
+            .position(|w| w == END)
+            .map(|pos| searched + pos + END.len());
+        head.truncate(end.unwrap_or(head.len()));
+        // Peeked data stays on the socket until it is read, so this doesn't wait.
+        stream.read_exact(&mut buf[..head.len() - read]).await?;
+        if end.is_some() {
+            break;
+        }
+        if head.len() >= MAX_REQUEST_HEAD {
+            return Err(RequestError::TooLarge);
+        }
+    }
+    let head = std::str::from_utf8(&head).map_err(|_| RequestError::Malformed)?;
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(RequestError::Malformed);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(RequestError::Malformed);
+    }
+    if method != "CONNECT" {
+        return Err(RequestError::UnsupportedMethod(method.to_string()));
+    }
+    let invalid = || RequestError::InvalidAuthority(target.to_string());
+    let authority: Authority = target.parse().map_err(|_| invalid())?;
+    let port = authority.port_u16().ok_or_else(invalid)?;
+    Ok(Target {
+        host: authority.host().to_ascii_lowercase(),
+        port,
+    })
+}
+
+/// resolve finds the address to reach `target` at. Hostnames are looked up among the services and
+/// workloads ztunnel knows of, rather than in DNS, preferring services in the client's namespace.
+async fn resolve(
+    pi: &ProxyInputs,
+    source: IpAddr,
+    target: Target,
+) -> Result<SocketAddr, RequestError> {
+    let host = target.host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, target.port));
+    }
+    let namespace = pi
+        .state
+        .fetch_workload(&NetworkAddress {
+            network: pi.cfg.network.clone(),
+            address: source,
+        })
+        .await
+        .map(|wl| wl.namespace);
+    let ip = pi
+        .state
+        .read()
+        .find_host_ip(&pi.cfg.network, namespace.as_deref(), host)
+        .ok_or_else(|| RequestError::UnknownHost(target.host.clone()))?;
+    Ok(SocketAddr::new(ip, target.port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// connect returns both ends of a loopback connection, client first.
+    async fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// sent returns the server end of a connection the client sent `data` over before closing it.
+    async fn sent(data: &[u8]) -> TcpStream {
+        let (mut client, server) = connect().await;
+        client.write_all(data).await.unwrap();
+        client.shutdown().await.unwrap();
+        server
+    }
+
+    async fn read(data: &str) -> Result<Target, RequestError> {
+        read_request(&mut sent(data.as_bytes()).await).await
+    }
+
+    #[tokio::test]
+    async fn connect_request() {
+        let target = read("CONNECT Reviews.default.svc.cluster.local:9080 HTTP/1.1\r\nhost: reviews.default.svc.cluster.local:9080\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            target,
+            Target {
+                host: "reviews.default.svc.cluster.local".to_string(),
+                port: 9080
+            }
+        );
+        let target = read("CONNECT [::1]:443 HTTP/1.0\r\n\r\n").await.unwrap();
+        assert_eq!(target.host, "[::1]");
+        assert_eq!(target.port, 443);
+    }
+
+    #[tokio::test]
+    async fn stops_at_head() {
+        let mut stream = sent(b"CONNECT 10.0.0.1:80 HTTP/1.1\r\n\r\n\x16\x03\x01").await;
+        read_request(&mut stream).await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn head_in_pieces() {
+        let (mut client, mut server) = connect().await;
+        client
+            .write_all(b"CONNECT 10.0.0.1:80 HTTP/1.1\r\n\r")
+            .await
+            .unwrap();
+        let read = tokio::spawn(async move {
+            let target = read_request(&mut server).await.unwrap();
+            (target, server)
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.write_all(b"\n\x16").await.unwrap();
+        client.shutdown().await.unwrap();
+        let (target, mut server) = read.await.unwrap();
+        assert_eq!(target.port, 80);
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"\x16");
+    }
+
+    #[tokio::test]
+    async fn rejected_requests() {
+        let status = |res: Result<Target, RequestError>| res.unwrap_err().status();
+        assert_eq!(
+            status(read("GET http://example.com/ HTTP/1.1\r\n\r\n").await),
+            Some(StatusCode::METHOD_NOT_ALLOWED)
+        );
+        assert_eq!(
+            status(read("CONNECT example.com HTTP/1.1\r\n\r\n").await),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            status(read("CONNECT example.com:80\r\n\r\n").await),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            status(
+                read(&format!(
+                    "CONNECT {}:80 HTTP/1.1\r\n\r\n",
+                    "a".repeat(MAX_REQUEST_HEAD)
+                ))
+                .await
+            ),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+        // The client went away before finishing its request.
+        assert_eq!(
+            status(read("CONNECT example.com:80 HTTP/1.1\r\n").await),
+            None
+        );
+    }
+}
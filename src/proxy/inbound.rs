@@ -15,6 +15,7 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -25,62 +26,84 @@ use http_body_util::Empty;
 use hyper::body::Incoming;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info, instrument, trace, trace_span, warn, Instrument};
+use tracing::{debug, error, info, instrument, trace, trace_span, warn, Instrument, Span};
 
 use super::Error;
 use crate::baggage::parse_baggage_header;
-use crate::config::Config;
+use crate::config::{Config, FilterListener, LiveConfig};
 use crate::identity::SecretManager;
 use crate::metrics::Recorder;
 use crate::proxy;
+use crate::proxy::fault::{self, Direction};
+use crate::proxy::filter::FilterChains;
 use crate::proxy::inbound::InboundConnect::{DirectPath, Hbone};
 use crate::proxy::metrics::{ConnectionOpen, Metrics, Reporter};
-use crate::proxy::{metrics, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
+use crate::proxy::outbound::Reply;
+use crate::proxy::{
+    metrics, ConnectionId, ProxyInputs, Rejection, TraceParent, BAGGAGE_HEADER,
+    CONNECTION_ID_HEADER, TRACEPARENT_HEADER,
+};
 use crate::rbac::Connection;
-use crate::socket::to_canonical;
+use crate::socket::{self, to_canonical};
+use crate::state::service::ConnectPolicy;
 use crate::state::workload::{address, GatewayAddress, NetworkAddress, Workload};
 use crate::state::DemandProxyState;
 use crate::tls::TlsError;
 
 pub(super) struct Inbound {
     cfg: Config,
-    listener: TcpListener,
+    live_cfg: LiveConfig,
+    listeners: Vec<TcpListener>,
     cert_manager: Arc<SecretManager>,
     state: DemandProxyState,
     drain: Watch,
     metrics: Arc<Metrics>,
+    filters: FilterChains,
 }
 
 impl Inbound {
     pub(super) async fn new(mut pi: ProxyInputs, drain: Watch) -> Result<Inbound, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.inbound_addr)
-            .await
-            .map_err(|e| Error::Bind(pi.cfg.inbound_addr, e))?;
-        let transparent = super::maybe_set_transparent(&pi, &listener)?;
+        let listeners =
+            super::listen(&pi, pi.cfg.inbound_addr, &pi.cfg.listener_tuning.inbound).await?;
+        let transparent = super::maybe_set_transparent(&pi, &listeners)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
         info!(
-            address=%listener.local_addr().unwrap(),
+            address=%listeners[0].local_addr().unwrap(),
             component="inbound",
+            shards=listeners.len(),
             transparent,
             "listener established",
         );
         Ok(Inbound {
             cfg: pi.cfg,
+            live_cfg: pi.live_cfg,
             state: pi.state,
-            listener,
+            listeners,
             cert_manager: pi.cert_manager,
             metrics: pi.metrics,
+            filters: pi.filters,
             drain,
         })
     }
 
     pub(super) fn address(&self) -> SocketAddr {
-        self.listener.local_addr().unwrap()
+        self.listeners[0].local_addr().unwrap()
+    }
+
+    pub(super) async fn run(mut self) {
+        let listeners = std::mem::take(&mut self.listeners);
+        let inbound = Arc::new(self);
+        super::supervise::supervise("inbound", inbound.metrics.clone(), listeners, |listener| {
+            inbound.clone().accept(listener).in_current_span()
+        })
+        .await;
+        info!("all inbound connections drained");
     }
 
-    pub(super) async fn run(self) {
+    async fn accept(self: Arc<Self>, listener: TcpListener) {
         // let (tx, rx) = oneshot::channel();
         let acceptor = InboundCertProvider {
             state: self.state.clone(),
@@ -88,56 +111,141 @@ impl Inbound {
             network: self.cfg.network.clone(),
         };
         let drain_stream = self.drain.clone();
-        let stream = crate::hyper_util::tls_server(acceptor, self.listener);
+        let stream = crate::hyper_util::tls_server_with_timeout(
+            acceptor,
+            listener,
+            Some((
+                self.live_cfg.clone(),
+                self.metrics.inbound_handshake_timeouts.clone(),
+            )),
+        );
         let mut stream = stream.take_until(Box::pin(drain_stream.signaled()));
         while let Some(socket) = stream.next().await {
             let state = self.state.clone();
             let metrics = self.metrics.clone();
             let drain = self.drain.clone();
             let network = self.cfg.network.clone();
-            tokio::task::spawn(async move {
+            let local_node = self.cfg.local_node.clone();
+            let local_ip = self.cfg.local_ip;
+            let live_cfg = self.live_cfg.current();
+            let rbac_audit = live_cfg.rbac_audit;
+            let enable_original_source = self.cfg.enable_original_source;
+            let socket_mark = self.cfg.socket_marks.inbound;
+            let preserve_tos = self.cfg.preserve_tos.inbound;
+            let buffer_size = self.cfg.hbone_buffer_size;
+            let latency_sensitive_ports: Arc<[u16]> =
+                self.cfg.latency_sensitive_ports.as_slice().into();
+            let handshake_timeout = live_cfg.handshake_timeout;
+            let filters = self.filters.clone();
+            tokio::task::spawn(super::supervise::isolate(metrics.clone(), async move {
+                // The peer may have reset the connection since it was accepted.
+                let src = match socket.get_ref().peer_addr() {
+                    Ok(src) => src,
+                    Err(e) => {
+                        debug!("closing connection, failed to get its peer address: {e}");
+                        return Ok(());
+                    }
+                };
                 let dst = crate::socket::orig_dst_addr_or_default(socket.get_ref());
+                // Every stream of the tunnel carries on the marking of the tunnel itself.
+                let tos = preserve_tos
+                    .then(|| super::downstream_tos(socket.get_ref()))
+                    .flatten();
                 let conn = Connection {
                     src_identity: socket
                         .ssl()
                         .peer_certificate()
                         .and_then(|x| crate::tls::boring::extract_sans(&x).first().cloned()),
-                    src_ip: to_canonical(socket.get_ref().peer_addr().unwrap()).ip(),
+                    src_ip: to_canonical(src).ip(),
                     dst_network: network, // inbound request must be on our network
                     dst,
                 };
                 debug!(%conn, "accepted connection");
-                let enable_original_source = self.cfg.enable_original_source;
-                let serve = crate::hyper_util::http2_server()
-                    .initial_stream_window_size(self.cfg.window_size)
-                    .initial_connection_window_size(self.cfg.connection_window_size)
-                    .max_frame_size(self.cfg.frame_size)
+                if let Err(e) = filters
+                    .run(&metrics, FilterListener::Inbound, conn.src_ip, conn.dst)
+                    .await
+                {
+                    info!(%conn, "closing connection: {e}");
+                    return Ok(());
+                }
+                if let Err(e) = fault::fail_handshake(Direction::Inbound) {
+                    debug!(%conn, "closing connection: {e}");
+                    return Ok(());
+                }
+                let peer = metrics
+                    .connections
+                    .track_peer(src, conn.src_identity.as_ref().map(ToString::to_string));
+                // Set once the client sends its first request, which means the HTTP/2 handshake
+                // has completed.
+                let established = Arc::new(AtomicBool::new(false));
+                let stalled = {
+                    let established = established.clone();
+                    let metrics = metrics.clone();
+                    async move {
+                        tokio::time::sleep(handshake_timeout).await;
+                        if established.load(Ordering::Relaxed) {
+                            futures::future::pending::<()>().await;
+                        }
+                        metrics.inbound_handshake_timeouts.inc();
+                    }
+                };
+                let mut serve = crate::hyper_util::http2_server()
+                    .initial_stream_window_size(live_cfg.window_size)
+                    .initial_connection_window_size(live_cfg.connection_window_size)
+                    .max_frame_size(live_cfg.frame_size)
+                    .max_concurrent_streams(live_cfg.hbone_max_concurrent_streams)
                     .serve_connection(
                         socket,
-                        service_fn(move |req| {
-                            Self::serve_connect(
+                        service_fn(move |mut req: Request<Incoming>| {
+                            established.store(true, Ordering::Relaxed);
+                            // Dropped along with the request, which handle_inbound keeps until
+                            // the stream is done being proxied.
+                            req.extensions_mut().insert(peer.stream());
+                            let serve = Self::serve_connect(
                                 state.clone(),
                                 conn.clone(),
+                                local_node.clone(),
+                                local_ip,
                                 enable_original_source.unwrap_or_default(),
+                                socket_mark,
+                                tos,
+                                buffer_size,
+                                latency_sensitive_ports.clone(),
+                                rbac_audit,
                                 req,
                                 metrics.clone(),
-                            )
+                            );
+                            // A panic only fails its own stream, not the rest of the tunnel.
+                            let metrics = metrics.clone();
+                            async move {
+                                match super::supervise::isolate(metrics, serve).await {
+                                    Some(res) => res,
+                                    None => Ok(Response::builder()
+                                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                        .body(Empty::new())
+                                        .unwrap()),
+                                }
+                            }
                         }),
                     );
-                // Wait for drain to signal or connection serving to complete
-                match futures_util::future::select(Box::pin(drain.signaled()), serve).await {
+                // Wait for drain to signal, connection serving to complete, or the client to stall
+                // before sending its first request.
+                tokio::select! {
+                    // Serving finished, just return the result.
+                    res = &mut serve => res,
                     // We got a shutdown request. Start gracful shutdown and wait for the pending requests to complete.
-                    futures_util::future::Either::Left((_shutdown, mut server)) => {
-                        let drain = std::pin::Pin::new(&mut server);
-                        drain.graceful_shutdown();
-                        server.await
+                    _ = drain.signaled() => {
+                        std::pin::Pin::new(&mut serve).graceful_shutdown();
+                        serve.await
+                    }
+                    // The handshake did not complete in time; dropping the connection closes it.
+                    _ = stalled => {
+                        debug!(%dst, "inbound handshake timed out");
+                        Ok(())
                     }
-                    // Serving finished, just return the result.
-                    futures_util::future::Either::Right((server, _shutdown)) => server,
                 }
-            });
+            }));
         }
-        info!("all inbound connections drained");
     }
 
     /// handle_inbound serves an inbound connection with a target address `addr`.
@@ -145,15 +253,29 @@ impl Inbound {
         request_type: InboundConnect,
         orig_src: Option<IpAddr>,
         addr: SocketAddr,
+        socket_mark: Option<u32>,
+        tos: Option<u8>,
+        connect_policy: ConnectPolicy,
+        buffer_size: usize,
+        drained: Option<Watch>,
         metrics: Arc<Metrics>,
         connection_metrics: ConnectionOpen,
         extra_connection_metrics: Option<ConnectionOpen>,
     ) -> Result<(), std::io::Error> {
         let start = Instant::now();
-        let stream = super::freebind_connect(orig_src, addr).await;
+        let orig_src =
+            super::original_source(orig_src, addr, connection_metrics.destination.as_ref());
+        fault::delay_connect(Direction::Inbound).await;
+        let stream =
+            super::freebind_connect_policy(orig_src, addr, socket_mark, tos, connect_policy).await;
         match stream {
             Err(err) => {
                 warn!(dur=?start.elapsed(), "connection to {} failed: {}", addr, err);
+                if let DirectPath(mut incoming, Some(reply)) = request_type {
+                    // Reported as a remote inbound would reject it.
+                    let e = Error::ConnectRejected(addr, Rejection::upstream(&err));
+                    let _ = incoming.write_all(&reply(Some(&e))).await;
+                }
                 Err(err)
             }
             Ok(stream) => {
@@ -161,7 +283,7 @@ impl Inbound {
                 stream.set_nodelay(true)?;
                 trace!(dur=?start.elapsed(), "connected to: {addr}");
                 tokio::task::spawn(
-                    (async move {
+                    super::supervise::isolate(metrics.clone(), async move {
                         let _connection_close = metrics
                             .increment_defer::<_, metrics::ConnectionClose>(&connection_metrics);
 
@@ -171,48 +293,61 @@ impl Inbound {
 
                         let transferred_bytes =
                             metrics::BytesTransferred::from(&connection_metrics);
-                        match request_type {
-                            DirectPath(mut incoming) => {
-                                match proxy::relay(
-                                    &mut incoming,
-                                    &mut stream,
-                                    &metrics,
-                                    transferred_bytes,
-                                )
-                                .await
-                                {
-                                    Ok(transferred) => {
-                                        if let Some(co) = extra_connection_metrics.as_ref() {
-                                            metrics.record(
-                                                &metrics::BytesTransferred::from(co),
-                                                transferred,
-                                            );
+                        let extra_transferred_bytes = extra_connection_metrics
+                            .as_ref()
+                            .map(metrics::BytesTransferred::from);
+                        let copy = async {
+                            match request_type {
+                                DirectPath(mut incoming, reply) => {
+                                    if let Some(reply) = reply {
+                                        if let Err(e) = incoming.write_all(&reply(None)).await {
+                                            debug!("failed to reply to the client: {e}");
+                                            return;
                                         }
                                     }
-                                    Err(e) => {
-                                        error!(dur=?start.elapsed(), "internal server copy: {}", e)
-                                    }
-                                }
-                            }
-                            Hbone(req) => match hyper::upgrade::on(req).await {
-                                Ok(mut upgraded) => {
-                                    if let Err(e) = super::copy_hbone(
-                                        &mut upgraded,
+                                    match proxy::relay(
+                                        &mut incoming,
                                         &mut stream,
                                         &metrics,
                                         transferred_bytes,
                                     )
-                                    .instrument(trace_span!("hbone server"))
                                     .await
                                     {
-                                        error!(dur=?start.elapsed(), "hbone server copy: {}", e);
+                                        Ok(transferred) => {
+                                            if let Some(extra) = extra_transferred_bytes.as_ref() {
+                                                metrics.record(extra, transferred);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error!(dur=?start.elapsed(), "internal server copy: {}", e)
+                                        }
                                     }
                                 }
-                                Err(e) => {
-                                    // Not sure if this can even happen
-                                    error!(dur=?start.elapsed(), "No upgrade {e}");
-                                }
-                            },
+                                Hbone(mut req) => match hyper::upgrade::on(&mut req).await {
+                                    Ok(mut upgraded) => {
+                                        if let Err(e) = super::copy_hbone(
+                                            &mut upgraded,
+                                            &mut stream,
+                                            buffer_size,
+                                            &metrics,
+                                            transferred_bytes,
+                                        )
+                                        .instrument(trace_span!("hbone server"))
+                                        .await
+                                        {
+                                            error!(dur=?start.elapsed(), "hbone server copy: {}", e);
+                                        }
+                                    }
+                                    Err(e) => {
+                                        // Not sure if this can even happen
+                                        error!(dur=?start.elapsed(), "No upgrade {e}");
+                                    }
+                                },
+                            }
+                        };
+                        if super::until_drained(drained, copy).await.is_none() {
+                            info!(dur=?start.elapsed(), "destination removed, resetting connection");
+                            socket::reset_on_close(&stream);
                         }
                     })
                     .in_current_span(),
@@ -233,38 +368,60 @@ impl Inbound {
             .unwrap_or_else(TraceParent::new)
     }
 
+    /// extract_connection_id returns the ID the client assigned to the connection, so that it can
+    /// be correlated across hops, or a new one if there is none.
+    fn extract_connection_id(req: &Request<Incoming>) -> ConnectionId {
+        req.headers()
+            .get(CONNECTION_ID_HEADER)
+            .and_then(|b| b.to_str().ok())
+            .and_then(|b| ConnectionId::try_from(b).ok())
+            .unwrap_or_else(ConnectionId::new)
+    }
+
     #[instrument(name="inbound", skip_all, fields(
+        connection_id=tracing::field::Empty,
         id=%Self::extract_traceparent(&req),
         peer_ip=%conn.src_ip,
-        peer_id=%OptionDisplay(&conn.src_identity)
+        peer_id=%OptionDisplay(&conn.src_identity),
+        src.workload=tracing::field::Empty,
+        src.namespace=tracing::field::Empty,
+        src.identity=tracing::field::Empty,
+        dst.workload=tracing::field::Empty,
+        dst.namespace=tracing::field::Empty,
+        dst.identity=tracing::field::Empty,
     ))]
     async fn serve_connect(
         state: DemandProxyState,
         conn: Connection,
+        local_node: Option<String>,
+        local_ip: Option<IpAddr>,
         enable_original_source: bool,
+        socket_mark: Option<u32>,
+        tos: Option<u8>,
+        buffer_size: usize,
+        latency_sensitive_ports: Arc<[u16]>,
+        rbac_audit: bool,
         req: Request<Incoming>,
         metrics: Arc<Metrics>,
     ) -> Result<Response<Empty<Bytes>>, hyper::Error> {
+        let connection_id = Self::extract_connection_id(&req);
+        Span::current().record("connection_id", tracing::field::display(connection_id));
         match req.method() {
             &Method::CONNECT => {
                 let uri = req.uri();
                 info!("got {} request to {}", req.method(), uri);
-                let addr: Result<SocketAddr, _> = uri.to_string().as_str().parse();
-                if addr.is_err() {
-                    info!("Sending 400, {:?}", addr.err());
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Empty::new())
-                        .unwrap());
-                }
-
-                let addr: SocketAddr = addr.unwrap();
+                let Ok(addr) = uri.to_string().as_str().parse::<SocketAddr>() else {
+                    info!("Sending 400, invalid authority {uri}");
+                    return Ok(Rejection::InvalidAuthority.response());
+                };
                 if addr.ip() != conn.dst.ip() {
                     info!("Sending 400, ip mismatch {addr} != {}", conn.dst);
-                    return Ok(Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Empty::new())
-                        .unwrap());
+                    return Ok(Rejection::AddressMismatch.response());
+                }
+                if addr.port() == conn.dst.port() {
+                    // The tunnel would just be redirected back to us.
+                    info!("Sending 400, CONNECT to the HBONE port {addr}");
+                    return Ok(Rejection::HbonePort.response());
                 }
                 // Orig has 15008, swap with the real port
                 let conn = Connection { dst: addr, ..conn };
@@ -274,11 +431,15 @@ impl Inbound {
                 };
                 let Some(upstream) = state.fetch_workload(&dst_network_addr).await else {
                     info!(%conn, "unknown destination");
-                    return Ok(Response::builder()
-                        .status(StatusCode::NOT_FOUND)
-                        .body(Empty::new())
-                        .unwrap());
+                    return Ok(Rejection::UnknownDestination.response());
                 };
+                // Only workloads on our node are served, so we cannot be used to reach anything
+                // else the node can. Workloads are matched to our node the way outbound matches
+                // them for the node local fast path.
+                if local_node.is_some() && !upstream.on_node(local_node.as_deref(), local_ip) {
+                    info!(%conn, node=%upstream.node, "destination is not on this node");
+                    return Ok(Rejection::NotOnNode.response());
+                }
                 let has_waypoint = upstream.waypoint.is_some();
                 let from_waypoint = Self::check_waypoint(state.clone(), &upstream, &conn).await;
                 let from_gateway = Self::check_gateway(state.clone(), &upstream, &conn).await;
@@ -288,19 +449,13 @@ impl Inbound {
                 }
                 if from_waypoint {
                     debug!("request from waypoint, skipping policy");
-                } else if !state.assert_rbac(&conn).await {
+                } else if !super::authorize(&state, &conn, rbac_audit, &metrics).await {
                     info!(%conn, "RBAC rejected");
-                    return Ok(Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .body(Empty::new())
-                        .unwrap());
+                    return Ok(Rejection::PolicyDenied.response());
                 }
                 if has_waypoint && !from_waypoint {
                     info!(%conn, "bypassed waypoint");
-                    return Ok(Response::builder()
-                        .status(StatusCode::UNAUTHORIZED)
-                        .body(Empty::new())
-                        .unwrap());
+                    return Ok(Rejection::WaypointBypassed.response());
                 }
                 let source_ip = if from_waypoint {
                     // If the request is from our waypoint, trust the Forwarded header.
@@ -328,49 +483,55 @@ impl Inbound {
                     }
                 };
 
-                let derived_source = metrics::DerivedWorkload {
-                    identity: conn.src_identity,
-                    cluster_id: baggage.cluster_id,
-                    namespace: baggage.namespace,
-                    workload_name: baggage.workload_name,
-                    revision: baggage.revision,
-                    ..Default::default()
-                };
+                proxy::record_workloads(&Span::current(), source.as_ref(), Some(&upstream));
+                // Tell the client who it reached, in case it does not know the workload.
+                let peer_baggage = crate::baggage::encode(&upstream, &upstream.cluster_id);
+                let derived_source =
+                    metrics::DerivedWorkload::from_baggage(baggage, conn.src_identity);
+                let drained = super::workload_drain(&state, Some(&upstream));
                 let connection_metrics = ConnectionOpen {
                     reporter: Reporter::destination,
                     source,
                     derived_source: Some(derived_source),
+                    derived_destination: None,
                     destination: Some(upstream),
                     connection_security_policy: metrics::SecurityPolicy::mutual_tls,
                     destination_service: None,
+                    trace_id: Self::extract_traceparent(&req).sampled_id(),
+                    connection_id,
+                    source_ip: Some(source_ip),
+                    destination_addr: Some(addr),
                 };
-                let status_code = match Self::handle_inbound(
+                // Honored from any client: each hop bounds it by its own limits.
+                let connect_policy = super::parse_connect_policy(req.headers());
+                let res = Self::handle_inbound(
                     Hbone(req),
                     enable_original_source.then_some(source_ip),
                     addr,
+                    socket_mark,
+                    tos,
+                    connect_policy,
+                    super::copy_buffer_size(&latency_sensitive_ports, buffer_size, addr.port()),
+                    drained,
                     metrics,
                     connection_metrics,
                     None,
                 )
                 .in_current_span()
-                .await
-                {
-                    Ok(_) => StatusCode::OK,
-                    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
-                };
-
-                Ok(Response::builder()
-                    .status(status_code)
-                    .body(Empty::new())
-                    .unwrap())
+                .await;
+                match res {
+                    Ok(_) => Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .header(BAGGAGE_HEADER, peer_baggage)
+                        .body(Empty::new())
+                        .unwrap()),
+                    Err(e) => Ok(Rejection::upstream(&e).response()),
+                }
             }
             // Return the 404 Not Found for other routes.
             method => {
                 info!("Sending 404, got {method}");
-                Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(Empty::new())
-                    .unwrap())
+                Ok(Rejection::UnsupportedMethod.response())
             }
         }
     }
@@ -435,8 +596,9 @@ impl<'a, T: Display> Display for OptionDisplay<'a, T> {
 pub(super) enum InboundConnect {
     /// DirectPath is an optimization when we are connecting to an endpoint on the same node.
     /// Rather than doing a full HBONE connection over the localhost network, we just pass the outbound
-    /// context directly to the inbound handling in memory.
-    DirectPath(TcpStream),
+    /// context directly to the inbound handling in memory, along with the reply its client is owed,
+    /// if any.
+    DirectPath(TcpStream, Option<Reply>),
     /// Hbone is a standard HBONE request coming from the network.
     Hbone(Request<Incoming>),
 }
@@ -469,7 +631,7 @@ impl crate::tls::CertProvider for InboundCertProvider {
             "fetching cert"
         );
         let cert = self.cert_manager.fetch_certificate(&identity).await?;
-        let acc = cert.mtls_acceptor(Some(&identity))?;
+        let acc = cert.hbone_acceptor(Some(&identity))?;
         Ok(acc)
     }
 }
@@ -479,6 +641,7 @@ mod test {
     use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
 
     use super::*;
+    use crate::proxy::REASON_HEADER;
     use crate::state::service::endpoint_uid;
     use crate::state::workload::NamespacedHostname;
     use crate::{
@@ -495,6 +658,16 @@ mod test {
         sync::RwLock,
     };
 
+    #[test]
+    fn rejection_response() {
+        let resp = Rejection::NotOnNode.response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()[REASON_HEADER], "not-on-node");
+        let resp = Rejection::PolicyDenied.response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[REASON_HEADER], "policy-denied");
+    }
+
     #[tokio::test]
     async fn check_gateway() {
         let w = mock_default_gateway_workload();
@@ -569,6 +742,9 @@ mod test {
 
             authorization_policies: Vec::new(),
             native_tunnel: false,
+            excluded_outbound_ports: Vec::new(),
+            included_outbound_ports: Vec::new(),
+            ports: Vec::new(),
         }
     }
 
@@ -596,6 +772,9 @@ mod test {
 
             authorization_policies: Vec::new(),
             native_tunnel: false,
+            excluded_outbound_ports: Vec::new(),
+            included_outbound_ports: Vec::new(),
+            ports: Vec::new(),
         }
     }
 
@@ -632,6 +811,8 @@ mod test {
             ports,
             endpoints,
             subject_alt_names: vec![],
+            session_affinity: Default::default(),
+            connect_policy: Default::default(),
         }
     }
 
@@ -12,61 +12,80 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, trace, warn, Instrument};
+use tracing::{debug, error, info, trace, warn, Instrument, Span};
 
-use crate::config::ProxyMode;
+use crate::config::{Config, FilterListener, InboundPortPolicy, InboundSourcePolicy, ProxyMode};
 use crate::proxy::metrics::Reporter;
 use crate::proxy::outbound::OutboundConnection;
-use crate::proxy::{metrics, util, ProxyInputs};
-use crate::proxy::{Error, TraceParent};
+use crate::proxy::{connection_span, detect, metrics, util, ProxyInputs};
+use crate::proxy::{ConnectionId, Error, TraceParent};
 use crate::rbac;
 use crate::state::workload::NetworkAddress;
 use crate::{proxy, socket};
 
 pub(super) struct InboundPassthrough {
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
     pi: ProxyInputs,
 }
 
 impl InboundPassthrough {
     pub(super) async fn new(mut pi: ProxyInputs) -> Result<InboundPassthrough, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.inbound_plaintext_addr)
-            .await
-            .map_err(|e| Error::Bind(pi.cfg.inbound_plaintext_addr, e))?;
-        let transparent = super::maybe_set_transparent(&pi, &listener)?;
+        let listeners = super::listen(
+            &pi,
+            pi.cfg.inbound_plaintext_addr,
+            &pi.cfg.listener_tuning.inbound_passthrough,
+        )
+        .await?;
+        let transparent = super::maybe_set_transparent(&pi, &listeners)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
 
         info!(
-            address=%listener.local_addr().unwrap(),
+            address=%listeners[0].local_addr().unwrap(),
             component="inbound plaintext",
+            shards=listeners.len(),
             transparent,
             "listener established",
         );
-        Ok(InboundPassthrough { listener, pi })
+        Ok(InboundPassthrough { listeners, pi })
     }
 
     pub(super) async fn run(self) {
+        let pi = self.pi.clone();
+        super::supervise::supervise(
+            "inbound plaintext",
+            self.pi.metrics.clone(),
+            self.listeners,
+            move |listener| Self::accept(pi.clone(), listener).in_current_span(),
+        )
+        .await;
+    }
+
+    async fn accept(pi: ProxyInputs, listener: TcpListener) {
         loop {
             // Asynchronously wait for an inbound socket.
-            let socket = self.listener.accept().await;
-            let pi = self.pi.clone();
+            let socket = listener.accept().await;
+            let pi = pi.clone();
             match socket {
                 Ok((stream, remote)) => {
-                    tokio::spawn(async move {
+                    let connection_id = ConnectionId::new();
+                    let span =
+                        connection_span!("inbound plaintext", connection_id = %connection_id);
+                    tokio::spawn(super::supervise::isolate(pi.metrics.clone(), async move {
                         if let Err(e) = Self::proxy_inbound_plaintext(
                             pi, // pi cloned above; OK to move
                             socket::to_canonical(remote),
                             stream,
+                            connection_id,
                         )
                         .await
                         {
                             warn!(source=%socket::to_canonical(remote), component="inbound plaintext", "proxying failed: {}", e)
                         }
-                    }.in_current_span());
+                    }).instrument(span));
                 }
                 Err(e) => {
                     if util::is_runtime_shutdown(&e) {
@@ -82,13 +101,26 @@ impl InboundPassthrough {
         pi: ProxyInputs,
         source: SocketAddr,
         mut inbound: TcpStream,
+        connection_id: ConnectionId,
     ) -> Result<(), Error> {
         let orig = socket::orig_dst_addr_or_default(&inbound);
         // Check if it is a recursive call when proxy mode is Node.
         if pi.cfg.proxy_mode == ProxyMode::Shared && Some(orig.ip()) == pi.cfg.local_ip {
             return Err(Error::SelfCall);
         }
+        if super::loops::is_own(source) {
+            pi.metrics.self_loops.inc();
+            return Err(Error::SelfLoop(source));
+        }
         info!(%source, destination=%orig, component="inbound plaintext", "accepted connection");
+        pi.filters
+            .run(
+                &pi.metrics,
+                FilterListener::InboundPassthrough,
+                source.ip(),
+                orig,
+            )
+            .await?;
         let network_addr = NetworkAddress {
             network: pi.cfg.network.clone(), // inbound request must be on our network
             address: orig.ip(),
@@ -96,6 +128,33 @@ impl InboundPassthrough {
         let Some(upstream) = pi.state.fetch_workload(&network_addr).await else {
             return Err(Error::UnknownDestination(orig.ip()))
         };
+        if !upstream.declares_port(orig.port()) {
+            match pi.cfg.inbound_port_policy {
+                InboundPortPolicy::Enforce => return Err(Error::UndeclaredPort(orig)),
+                InboundPortPolicy::Permissive => {
+                    info!(%source, destination=%orig, component="inbound plaintext", "destination workload does not declare port, allowing")
+                }
+            }
+        }
+
+        // Attribute the connection to the workload it comes from, if we know of one. Without mTLS
+        // this is only as trustworthy as the source IP, so it is used for telemetry and the source
+        // policy, but never as an identity to authorize.
+        let source_workload = pi
+            .state
+            .fetch_workload(&NetworkAddress {
+                // inbound request must be on our network since this is passthrough
+                // rather than HBONE, which can be tunneled across networks through gateways.
+                // by definition, without the gateway our source must be on our network.
+                network: pi.cfg.network.clone(),
+                address: source.ip(),
+            })
+            .await;
+        proxy::record_workloads(&Span::current(), source_workload.as_ref(), Some(&upstream));
+        if source_workload.is_none() && !unknown_source_allowed(&pi.cfg, source.ip()) {
+            return Err(Error::UnknownSource(source.ip()));
+        }
+
         if upstream.waypoint.is_some() {
             // This is an inbound request not over HBONE, but we have a waypoint.
             // The request needs to go through the waypoint for policy enforcement.
@@ -104,12 +163,16 @@ impl InboundPassthrough {
             // To handle this, we forward it to the waypoint ourselves, which will hairpin back to us.
             let mut oc = OutboundConnection {
                 pi: pi.clone(),
-                id: TraceParent::new(),
+                id: TraceParent::new_sampled(pi.cfg.trace_sampling_percentage),
+                connection_id,
             };
             // Spoofing the source IP only works when the destination or the source are on our node.
             // In this case, the source and the destination might both be remote, so we need to disable it.
             oc.pi.cfg.enable_original_source = Some(false);
-            return oc.proxy_to(inbound, source.ip(), orig, false).await;
+            // The inbound passthrough filters allowed the connection already.
+            return oc
+                .proxy_to_unfiltered(inbound, source.ip(), orig, false, None)
+                .await;
         }
 
         // We enforce RBAC only for non-hairpin cases. This is because we may not be able to properly
@@ -125,10 +188,13 @@ impl InboundPassthrough {
             dst_network: pi.cfg.network.clone(),
             dst: orig,
         };
-        if !pi.state.assert_rbac(&conn).await {
+        let rbac_audit = pi.live_cfg.current().rbac_audit;
+        if !super::authorize(&pi.state, &conn, rbac_audit, &pi.metrics).await {
             info!(%conn, "RBAC rejected");
             return Ok(());
         }
+        let protocol = detect::detect(&inbound, orig.port(), &pi.cfg.protocol_detection).await;
+        debug!(%protocol, "detected protocol");
         let source_ip = super::get_original_src_from_stream(&inbound);
         let orig_src = pi
             .cfg
@@ -136,41 +202,88 @@ impl InboundPassthrough {
             .unwrap_or_default()
             .then_some(source_ip)
             .flatten();
+        let orig_src = super::original_source(orig_src, orig, Some(&upstream));
         trace!(%source, destination=%orig, component="inbound plaintext", "connect to {orig:?} from {orig_src:?}");
-        let mut outbound = super::freebind_connect(orig_src, orig).await?;
+        let mut outbound = super::freebind_connect_port(
+            orig_src,
+            pi.cfg.preserve_source_port.then_some(source.port()),
+            orig,
+            pi.cfg.socket_marks.inbound,
+            pi.cfg
+                .preserve_tos
+                .inbound_passthrough
+                .then(|| super::downstream_tos(&inbound))
+                .flatten(),
+            super::CONNECTION_TIMEOUT,
+            &pi.metrics,
+        )
+        .await?;
         trace!(%source, destination=%orig, component="inbound plaintext", "connected");
+        super::set_latency_sensitive(&pi.cfg, orig.port(), &inbound);
+        super::set_latency_sensitive(&pi.cfg, orig.port(), &outbound);
 
-        // Find source info. We can lookup by XDS or from connection attributes
-        let source_workload = if let Some(source_ip) = source_ip {
-            let network_addr_srcip = NetworkAddress {
-                // inbound request must be on our network since this is passthrough
-                // rather than HBONE, which can be tunneled across networks through gateways.
-                // by definition, without the gateway our source must be on our network.
-                network: pi.cfg.network.clone(),
-                address: source_ip,
-            };
-            pi.state.fetch_workload(&network_addr_srcip).await
-        } else {
-            None
-        };
         let derived_source = metrics::DerivedWorkload {
             identity: conn.src_identity,
             ..Default::default()
         };
+        let drained = super::workload_drain(&pi.state, Some(&upstream));
         let connection_metrics = metrics::ConnectionOpen {
             reporter: Reporter::destination,
             source: source_workload,
             derived_source: Some(derived_source),
+            derived_destination: None,
             destination: Some(upstream),
             connection_security_policy: metrics::SecurityPolicy::unknown,
             destination_service: None,
+            trace_id: None,
+            connection_id,
+            source_ip: Some(source.ip()),
+            destination_addr: Some(orig),
         };
         let _connection_close = pi
             .metrics
             .increment_defer::<_, metrics::ConnectionClose>(&connection_metrics);
         let transferred_bytes = metrics::BytesTransferred::from(&connection_metrics);
-        proxy::relay(&mut outbound, &mut inbound, &pi.metrics, transferred_bytes).await?;
+        let relay = proxy::relay(&mut outbound, &mut inbound, &pi.metrics, transferred_bytes);
+        let Some(res) = super::until_drained(drained, relay).await else {
+            info!(%source, destination=%orig, component="inbound plaintext", "destination workload removed, resetting connection");
+            socket::reset_on_close(&inbound);
+            socket::reset_on_close(&outbound);
+            return Ok(());
+        };
+        res?;
         info!(%source, destination=%orig, component="inbound plaintext", "connection complete");
         Ok(())
     }
 }
+
+/// unknown_source_allowed returns whether a plaintext connection may be proxied from `ip`, which
+/// is not a workload known to the mesh.
+fn unknown_source_allowed(cfg: &Config, ip: IpAddr) -> bool {
+    match cfg.inbound_source_policy {
+        InboundSourcePolicy::Permissive => true,
+        InboundSourcePolicy::Strict => cfg
+            .inbound_allowed_source_cidrs
+            .iter()
+            .any(|cidr| cidr.contains(&ip)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    #[test]
+    fn unknown_sources() {
+        let mut cfg = test_helpers::test_config();
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(unknown_source_allowed(&cfg, ip));
+
+        cfg.inbound_source_policy = InboundSourcePolicy::Strict;
+        assert!(!unknown_source_allowed(&cfg, ip));
+        cfg.inbound_allowed_source_cidrs = vec!["10.0.0.0/24".parse().unwrap()];
+        assert!(unknown_source_allowed(&cfg, ip));
+        assert!(!unknown_source_allowed(&cfg, "10.0.1.1".parse().unwrap()));
+    }
+}
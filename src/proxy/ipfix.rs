@@ -0,0 +1,366 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Export of the connections ztunnel proxies as IPFIX (RFC 7011) flow records, so mesh traffic,
+//! which is encrypted and tunneled past traditional taps, shows up in the flow collectors network
+//! teams already use. Each connection is exported once it finishes, as a biflow with the bytes
+//! sent by the client and the server. ztunnel copies streams rather than packets, so there are no
+//! packet counts. Templates are sent in every message, as collectors may start listening at any
+//! point of a UDP export.
+
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use drain::Watch;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::IpfixExport;
+use crate::proxy::metrics::{ConnectionOpen, Reporter};
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+/// Keep messages under the MTU, as UDP export does not fragment them.
+const MAX_MESSAGE_LEN: usize = 1400;
+const HEADER_LEN: usize = 16;
+const SET_HEADER_LEN: usize = 4;
+const PROTOCOL_TCP: u8 = 6;
+/// How many finished connections may wait for the export task. Records past that are dropped,
+/// rather than slowing down the connections closing.
+const QUEUED_RECORDS: usize = 4096;
+/// How many records the export task holds before exporting them early, ahead of the interval.
+const PENDING_RECORDS: usize = 1024;
+
+/// The information elements of the records, as (IANA element ID, length). Only the addresses
+/// differ between the IPv4 and IPv6 templates.
+const FIELDS_V4: &[(u16, u16)] = &[
+    (8, 4),   // sourceIPv4Address
+    (12, 4),  // destinationIPv4Address
+    (11, 2),  // destinationTransportPort
+    (4, 1),   // protocolIdentifier
+    (61, 1),  // flowDirection
+    (152, 8), // flowStartMilliseconds
+    (153, 8), // flowEndMilliseconds
+    (231, 8), // initiatorOctets
+    (232, 8), // responderOctets
+];
+const FIELDS_V6: &[(u16, u16)] = &[
+    (27, 16), // sourceIPv6Address
+    (28, 16), // destinationIPv6Address
+    (11, 2),
+    (4, 1),
+    (61, 1),
+    (152, 8),
+    (153, 8),
+    (231, 8),
+    (232, 8),
+];
+
+/// FlowExporter collects the flow records of finished connections, and exports them to a
+/// collector periodically. Workers share the same exporter, so its records are sequenced as one
+/// export.
+#[derive(Clone)]
+pub struct FlowExporter {
+    tx: mpsc::Sender<FlowRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct FlowRecord {
+    source: IpAddr,
+    destination: SocketAddr,
+    /// 0 for connections into the node's workloads, 1 for connections out of them.
+    direction: u8,
+    start_ms: u64,
+    end_ms: u64,
+    initiator_octets: u64,
+    responder_octets: u64,
+}
+
+impl FlowExporter {
+    /// record queues the flow record of `c`, which started being copied at `started` and has now
+    /// finished.
+    pub(super) fn record(
+        &self,
+        c: &ConnectionOpen,
+        started: Instant,
+        initiator_octets: u64,
+        responder_octets: u64,
+    ) {
+        let (Some(source), Some(destination)) = (c.source_ip, c.destination_addr) else {
+            return;
+        };
+        let end = SystemTime::now();
+        let millis = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64)
+        };
+        let record = FlowRecord {
+            source,
+            destination,
+            direction: match c.reporter {
+                Reporter::destination => 0,
+                Reporter::source => 1,
+            },
+            start_ms: millis(end - started.elapsed()),
+            end_ms: millis(end),
+            initiator_octets,
+            responder_octets,
+        };
+        if self.tx.try_send(record).is_err() {
+            debug!("flow record export is behind, dropping record");
+        }
+    }
+
+    /// export starts a task sending the queued records to the collector of `cfg` every interval,
+    /// until ztunnel drains, when the records left are sent one last time.
+    pub fn export(cfg: &IpfixExport, drain: Watch) -> io::Result<FlowExporter> {
+        let bind: SocketAddr = match cfg.collector {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = std::net::UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        info!(collector = %cfg.collector, "exporting IPFIX flow records");
+        let (tx, mut rx) = mpsc::channel(QUEUED_RECORDS);
+        let cfg = cfg.clone();
+        tokio::spawn(async move {
+            let mut sequence = 0;
+            let mut pending = Vec::new();
+            let mut interval = tokio::time::interval(cfg.interval);
+            let mut drained = Box::pin(drain.signaled());
+            loop {
+                let done = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = &mut drained => true,
+                    Some(record) = rx.recv() => {
+                        pending.push(record);
+                        if pending.len() < PENDING_RECORDS {
+                            continue;
+                        }
+                        false
+                    }
+                };
+                if done {
+                    while let Ok(record) = rx.try_recv() {
+                        pending.push(record);
+                    }
+                }
+                let records = std::mem::take(&mut pending);
+                let export_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as u32);
+                for message in encode(
+                    &records,
+                    export_time,
+                    &mut sequence,
+                    cfg.observation_domain_id,
+                ) {
+                    if let Err(e) = socket.send_to(&message, cfg.collector).await {
+                        warn!(collector = %cfg.collector, "failed to export flow records: {e}");
+                    }
+                }
+                if done {
+                    return;
+                }
+            }
+        });
+        Ok(FlowExporter { tx })
+    }
+}
+
+impl FlowRecord {
+    fn template(&self) -> u16 {
+        match (self.source, self.destination.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => TEMPLATE_V4,
+            _ => TEMPLATE_V6,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let fields = match self.template() {
+            TEMPLATE_V4 => FIELDS_V4,
+            _ => FIELDS_V6,
+        };
+        fields.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        match (self.source, self.destination.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                buf.extend_from_slice(&src.octets());
+                buf.extend_from_slice(&dst.octets());
+            }
+            (src, dst) => {
+                buf.extend_from_slice(&to_ipv6(src).octets());
+                buf.extend_from_slice(&to_ipv6(dst).octets());
+            }
+        }
+        buf.extend_from_slice(&self.destination.port().to_be_bytes());
+        buf.push(PROTOCOL_TCP);
+        buf.push(self.direction);
+        buf.extend_from_slice(&self.start_ms.to_be_bytes());
+        buf.extend_from_slice(&self.end_ms.to_be_bytes());
+        buf.extend_from_slice(&self.initiator_octets.to_be_bytes());
+        buf.extend_from_slice(&self.responder_octets.to_be_bytes());
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// encode packs `records` into as few IPFIX messages as fit under [MAX_MESSAGE_LEN]. `sequence`
+/// counts the data records exported so far, as the header of each message holds it.
+fn encode(
+    records: &[FlowRecord],
+    export_time: u32,
+    sequence: &mut u32,
+    observation_domain_id: u32,
+) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    let mut records = records.iter().peekable();
+    while records.peek().is_some() {
+        let mut msg = vec![0; HEADER_LEN];
+        write_templates(&mut msg);
+        // The start and template of the data set being written.
+        let mut set: Option<(usize, u16)> = None;
+        let mut count = 0;
+        while let Some(record) = records.peek() {
+            let template = record.template();
+            let new_set = set.map(|(_, t)| t) != Some(template);
+            let needed = record.encoded_len() + if new_set { SET_HEADER_LEN } else { 0 };
+            if msg.len() + needed > MAX_MESSAGE_LEN {
+                break;
+            }
+            if new_set {
+                finish_set(&mut msg, set);
+                set = Some((msg.len(), template));
+                msg.extend_from_slice(&template.to_be_bytes());
+                msg.extend_from_slice(&[0, 0]);
+            }
+            record.write(&mut msg);
+            records.next();
+            count += 1;
+        }
+        finish_set(&mut msg, set);
+        let len = msg.len() as u16;
+        msg[0..2].copy_from_slice(&VERSION.to_be_bytes());
+        msg[2..4].copy_from_slice(&len.to_be_bytes());
+        msg[4..8].copy_from_slice(&export_time.to_be_bytes());
+        msg[8..12].copy_from_slice(&sequence.to_be_bytes());
+        msg[12..16].copy_from_slice(&observation_domain_id.to_be_bytes());
+        *sequence = sequence.wrapping_add(count);
+        messages.push(msg);
+    }
+    messages
+}
+
+fn write_templates(buf: &mut Vec<u8>) {
+    let start = buf.len();
+    buf.extend_from_slice(&TEMPLATE_SET_ID.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    for (id, fields) in [(TEMPLATE_V4, FIELDS_V4), (TEMPLATE_V6, FIELDS_V6)] {
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (element, len) in fields {
+            buf.extend_from_slice(&element.to_be_bytes());
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+    finish_set(buf, Some((start, TEMPLATE_SET_ID)));
+}
+
+/// finish_set fills in the length of the set starting at `set`, now that it is fully written.
+fn finish_set(buf: &mut [u8], set: Option<(usize, u16)>) {
+    if let Some((start, _)) = set {
+        let len = (buf.len() - start) as u16;
+        buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(source: &str, destination: &str) -> FlowRecord {
+        FlowRecord {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            direction: 0,
+            start_ms: 1_000,
+            end_ms: 3_000,
+            initiator_octets: 10,
+            responder_octets: 20,
+        }
+    }
+
+    fn u16_at(buf: &[u8], i: usize) -> u16 {
+        u16::from_be_bytes([buf[i], buf[i + 1]])
+    }
+
+    #[test]
+    fn encode_records() {
+        let records = vec![
+            record("10.0.0.1", "10.0.0.2:8080"),
+            record("10.0.0.3", "10.0.0.2:8080"),
+            record("ff06::c3", "[ff06::c4]:80"),
+        ];
+        let mut sequence = 7;
+        let messages = encode(&records, 42, &mut sequence, 3);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(sequence, 10);
+        let msg = &messages[0];
+        assert_eq!(u16_at(msg, 0), VERSION);
+        assert_eq!(u16_at(msg, 2) as usize, msg.len());
+        assert_eq!(&msg[8..12], &7u32.to_be_bytes());
+        assert_eq!(&msg[12..16], &3u32.to_be_bytes());
+
+        // The template set, then one data set for each address family.
+        let mut i = HEADER_LEN;
+        assert_eq!(u16_at(msg, i), TEMPLATE_SET_ID);
+        i += u16_at(msg, i + 2) as usize;
+        assert_eq!(u16_at(msg, i), TEMPLATE_V4);
+        let v4 = &msg[i + SET_HEADER_LEN..i + u16_at(msg, i + 2) as usize];
+        assert_eq!(v4.len(), 2 * records[0].encoded_len());
+        assert_eq!(&v4[0..4], &[10, 0, 0, 1]);
+        assert_eq!(u16_at(v4, 8), 8080);
+        i += u16_at(msg, i + 2) as usize;
+        assert_eq!(u16_at(msg, i), TEMPLATE_V6);
+        assert_eq!(
+            u16_at(msg, i + 2) as usize,
+            SET_HEADER_LEN + records[2].encoded_len()
+        );
+        i += u16_at(msg, i + 2) as usize;
+        assert_eq!(i, msg.len());
+    }
+
+    #[test]
+    fn encode_splits_messages() {
+        let records = vec![record("10.0.0.1", "10.0.0.2:8080"); 100];
+        let mut sequence = 0;
+        let messages = encode(&records, 0, &mut sequence, 0);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.len() <= MAX_MESSAGE_LEN));
+        assert_eq!(sequence, 100);
+        assert!(encode(&[], 0, &mut sequence, 0).is_empty());
+    }
+}
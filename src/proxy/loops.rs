@@ -0,0 +1,98 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Detection of connections ztunnel opens to itself. If the redirection rules capture ztunnel's
+//! own upstream connections, for instance because its socket mark is not exempted, each proxied
+//! connection would be captured again and proxied once more, until ztunnel runs out of file
+//! descriptors. The local address of every upstream connection ztunnel opens from an ephemeral
+//! port is remembered for a short while, so a listener that accepts a connection from one of them
+//! knows it is looping. Connections that keep the client's source port are not remembered, as
+//! their local address is the client's own.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::socket;
+
+/// How long upstream connections are remembered. A looping connection is accepted as soon as it
+/// is opened, so this only needs to outlast the accept queue.
+const REMEMBER_FOR: Duration = Duration::from_secs(1);
+
+/// The number of locks the remembered addresses are spread across, so connections opened and
+/// accepted at once seldom contend.
+const OWN_SHARDS: usize = 16;
+
+static OWN: Lazy<[Mutex<OwnSockets>; OWN_SHARDS]> = Lazy::new(Default::default);
+
+fn shard(addr: &SocketAddr) -> MutexGuard<'static, OwnSockets> {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    OWN[hasher.finish() as usize % OWN_SHARDS].lock().unwrap()
+}
+
+#[derive(Default)]
+struct OwnSockets {
+    /// When each address was last opened.
+    addrs: HashMap<SocketAddr, Instant>,
+    opened: VecDeque<(Instant, SocketAddr)>,
+}
+
+/// opened remembers `local`, the local address of an upstream connection we opened.
+pub(super) fn opened(local: SocketAddr) {
+    let local = socket::to_canonical(local);
+    let now = Instant::now();
+    let mut own = shard(&local);
+    while let Some((at, addr)) = own.opened.front().copied() {
+        if now.duration_since(at) < REMEMBER_FOR {
+            break;
+        }
+        own.opened.pop_front();
+        // Unless it was opened again since.
+        if own.addrs.get(&addr) == Some(&at) {
+            own.addrs.remove(&addr);
+        }
+    }
+    own.addrs.insert(local, now);
+    own.opened.push_back((now, local));
+}
+
+/// is_own returns whether `peer`, the client of an accepted connection, is one of our own
+/// upstream connections.
+pub(super) fn is_own(peer: SocketAddr) -> bool {
+    let peer = socket::to_canonical(peer);
+    shard(&peer)
+        .addrs
+        .get(&peer)
+        .map_or(false, |at| at.elapsed() < REMEMBER_FOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_connections() {
+        let ours = "10.0.0.1:40000".parse().unwrap();
+        opened(ours);
+        assert!(is_own(ours));
+        assert!(!is_own("10.0.0.1:40001".parse().unwrap()));
+        assert!(is_own("[::ffff:10.0.0.1]:40000".parse().unwrap()));
+    }
+}
@@ -12,27 +12,167 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Instant;
 
 use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder};
 use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::exemplar::HistogramWithExemplars;
 use prometheus_client::metrics::family::Family;
-use prometheus_client::registry::Registry;
+use prometheus_client::metrics::histogram::exponential_buckets;
+use prometheus_client::registry::{Registry, Unit};
 
+use crate::baggage::Baggage;
 use crate::identity::Identity;
 use crate::metrics::{DefaultedUnknown, DeferRecorder, Deferred, IncrementRecorder, Recorder};
+use crate::proxy::{
+    ConnectionEvents, ConnectionHooks, ConnectionId, ConnectionTracker, FairQueues, FlowExporter,
+    TrafficPairs,
+};
 use crate::state::service::ServiceDescription;
 use crate::state::workload::Workload;
 
+/// TrafficHistogram attaches the trace ID of sampled connections to its observations as exemplars.
+pub type TrafficHistogram = Family<CommonTrafficLabels, HistogramWithExemplars<TraceLabels>>;
+
 pub struct Metrics {
     pub connection_opens: Family<CommonTrafficLabels, Counter>,
     pub connection_close: Family<CommonTrafficLabels, Counter>,
     pub received_bytes: Family<CommonTrafficLabels, Counter>,
     pub sent_bytes: Family<CommonTrafficLabels, Counter>,
+    pub connection_duration: TrafficHistogram,
+    pub connection_received_bytes: TrafficHistogram,
+    pub connection_sent_bytes: TrafficHistogram,
+    pub plaintext_denied: Family<CommonTrafficLabels, Counter>,
+    pub connect_timeouts: Family<CommonTrafficLabels, Counter>,
+    pub handshake_timeouts: Family<CommonTrafficLabels, Counter>,
+    pub connect_response_timeouts: Family<CommonTrafficLabels, Counter>,
+    pub inbound_handshake_timeouts: Counter,
+    pub upstream_grpc_unhealthy: Family<CommonTrafficLabels, Counter>,
+    pub rbac_audit_denials: Family<RbacAuditLabels, Counter>,
+    pub rbac_unsynced_denials: Counter,
+    pub filter_denials: Family<FilterLabels, Counter>,
+    pub source_port_conflicts: Counter,
+    pub plaintext_allowed: Family<CommonTrafficLabels, Counter>,
+    pub outbound_loops: Counter,
+    pub self_loops: Counter,
+    pub connection_panics: Counter,
+    pub accept_loop_restarts: Counter,
 
     // on-demand DNS is not a part of DNS proxy, but part of ztunnel proxy itself
     pub on_demand_dns: Family<OnDemandDnsLabels, Counter>,
     pub on_demand_dns_cache_misses: Family<OnDemandDnsLabels, Counter>,
+
+    /// The number of series recorded with service level labels, as their namespace was over budget.
+    pub series_aggregated: Counter,
+    series_budget: SeriesBudget,
+
+    /// The connections currently being proxied, for the admin server.
+    pub connections: ConnectionTracker,
+    /// If set, connections are also streamed to the clients of the connection event socket.
+    events: Option<ConnectionEvents>,
+    /// If set, a flow record of each connection is exported to an IPFIX collector.
+    flows: Option<FlowExporter>,
+    /// If set, connections are counted by the pair of identities at their ends.
+    pairs: Option<TrafficPairs>,
+    /// Called as connections open and close, for builds that embed ztunnel.
+    hooks: ConnectionHooks,
+}
+
+/// SeriesBudget bounds the number of distinct traffic metric series for each destination
+/// namespace. Connections that would add a series past the budget are recorded with their labels
+/// collapsed to the service level instead, so a namespace of many workloads does not overwhelm
+/// Prometheus.
+#[derive(Default)]
+struct SeriesBudget {
+    limit: Option<usize>,
+    namespaces: RwLock<HashMap<DefaultedUnknown<String>, Arc<NamespaceSeries>>>,
+}
+
+/// The number of locks the series of a namespace are spread across, so connections recording
+/// different series seldom contend.
+const SERIES_SHARDS: usize = 16;
+
+/// NamespaceSeries remembers, by hash, the series of a namespace recorded with all of their
+/// labels and the collapsed series recorded in place of others. No more than the limit of each
+/// are remembered, so collapsed series past it are not counted.
+#[derive(Default)]
+struct NamespaceSeries {
+    admitted: AtomicUsize,
+    aggregated: AtomicUsize,
+    shards: [Mutex<SeriesShard>; SERIES_SHARDS],
+}
+
+#[derive(Default)]
+struct SeriesShard {
+    admitted: HashSet<u64>,
+    aggregated: HashSet<u64>,
+}
+
+impl SeriesBudget {
+    /// admit returns the labels to record `labels` under, and whether this is the first time
+    /// series were collapsed into them.
+    fn admit(&self, labels: CommonTrafficLabels) -> (CommonTrafficLabels, bool) {
+        let Some(limit) = self.limit else {
+            return (labels, false);
+        };
+        let ns = self.namespace(&labels.destination_workload_namespace);
+        let hash = series_hash(&labels);
+        {
+            let mut shard = ns.shard(hash);
+            if shard.admitted.contains(&hash)
+                || (reserve(&ns.admitted, limit) && shard.admitted.insert(hash))
+            {
+                return (labels, false);
+            }
+        }
+        let labels = labels.aggregated();
+        let hash = series_hash(&labels);
+        let mut shard = ns.shard(hash);
+        let first = !shard.aggregated.contains(&hash)
+            && reserve(&ns.aggregated, limit)
+            && shard.aggregated.insert(hash);
+        (labels, first)
+    }
+
+    fn namespace(&self, namespace: &DefaultedUnknown<String>) -> Arc<NamespaceSeries> {
+        if let Some(ns) = self.namespaces.read().unwrap().get(namespace) {
+            return ns.clone();
+        }
+        self.namespaces
+            .write()
+            .unwrap()
+            .entry(namespace.clone())
+            .or_default()
+            .clone()
+    }
+}
+
+impl NamespaceSeries {
+    fn shard(&self, hash: u64) -> MutexGuard<'_, SeriesShard> {
+        self.shards[hash as usize % SERIES_SHARDS].lock().unwrap()
+    }
+}
+
+/// reserve counts one more series in `count`, unless it has reached `limit`.
+fn reserve(count: &AtomicUsize, limit: usize) -> bool {
+    count
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+            (n < limit).then_some(n + 1)
+        })
+        .is_ok()
+}
+
+fn series_hash(labels: &CommonTrafficLabels) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    labels.hash(&mut hasher);
+    hasher.finish()
 }
 
 impl Metrics {
@@ -63,6 +203,18 @@ impl Metrics {
             metrics.increment(&m2);
         })
     }
+
+    /// open_connection is [Metrics::increment_defer] for connections whose close is recorded with
+    /// what was learned about them once open, such as response flags.
+    pub fn open_connection(&self, c: &ConnectionOpen) -> ConnectionGuard<'_> {
+        self.increment(c);
+        ConnectionGuard {
+            metrics: self,
+            conn: c.clone(),
+            opened: Instant::now(),
+            response_flags: ResponseFlags::none,
+        }
+    }
 }
 
 impl DeferRecorder for Metrics {}
@@ -83,20 +235,40 @@ pub enum RequestProtocol {
     http,
 }
 
+/// ResponseFlags tells why a connection failed, like Envoy's response flags.
 #[derive(Default, Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum ResponseFlags {
     #[default]
     none,
+    /// Authorization policy at the destination denied the connection.
+    AuthorizationPolicyDenied,
+    /// The destination was not found, or could not be routed to.
+    NoRoute,
+    /// The connection to the next hop, or from it to the destination, failed.
+    ConnectionFailure,
+    /// ztunnel panicked while handling the connection, and terminated it.
+    Panic,
 }
 
-impl EncodeLabelValue for ResponseFlags {
-    fn encode(&self, writer: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+impl ResponseFlags {
+    /// as_str returns the short form of the flags, as used in the response_flags metric label.
+    pub fn as_str(&self) -> &'static str {
         match self {
-            ResponseFlags::none => writer.write_str("-"),
+            ResponseFlags::none => "-",
+            ResponseFlags::AuthorizationPolicyDenied => "DENY",
+            ResponseFlags::NoRoute => "NR",
+            ResponseFlags::ConnectionFailure => "CONNECT",
+            ResponseFlags::Panic => "PANIC",
         }
     }
 }
 
+impl EncodeLabelValue for ResponseFlags {
+    fn encode(&self, writer: &mut LabelValueEncoder) -> Result<(), std::fmt::Error> {
+        writer.write_str(self.as_str())
+    }
+}
+
 #[derive(Default, Copy, Clone, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
 pub enum SecurityPolicy {
     #[default]
@@ -104,9 +276,46 @@ pub enum SecurityPolicy {
     mutual_tls,
 }
 
-pub struct ConnectionClose<'a>(&'a ConnectionOpen);
+/// ConnectionClose is created when the connection opens, so it knows how long it was open for.
+pub struct ConnectionClose<'a>(&'a ConnectionOpen, Instant, ResponseFlags);
+
+/// ConnectionGuard records a connection as opened when created, and as closed when dropped, with
+/// what was learned about it while it was open.
+#[must_use = "the connection is recorded as closed once the guard is dropped"]
+pub struct ConnectionGuard<'a> {
+    metrics: &'a Metrics,
+    conn: ConnectionOpen,
+    opened: Instant,
+    response_flags: ResponseFlags,
+}
+
+impl ConnectionGuard<'_> {
+    pub fn connection(&self) -> &ConnectionOpen {
+        &self.conn
+    }
+
+    pub fn set_response_flags(&mut self, flags: ResponseFlags) {
+        self.response_flags = flags;
+    }
+
+    pub fn set_derived_destination(&mut self, w: DerivedWorkload) {
+        self.conn.derived_destination = Some(w);
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.increment(&ConnectionClose(
+            &self.conn,
+            self.opened,
+            self.response_flags,
+        ));
+    }
+}
 
-pub struct BytesTransferred<'a>(&'a ConnectionOpen);
+/// BytesTransferred is created when the connection starts being copied, so flow records know
+/// when it started.
+pub struct BytesTransferred<'a>(&'a ConnectionOpen, Instant);
 
 #[derive(Clone, Debug, Default)]
 pub struct DerivedWorkload {
@@ -118,25 +327,65 @@ pub struct DerivedWorkload {
     pub cluster_id: Option<String>,
 }
 
+impl DerivedWorkload {
+    /// from_baggage describes a peer by the metadata it sent, and the identity it authenticated as.
+    pub fn from_baggage(baggage: Baggage, identity: Option<Identity>) -> Self {
+        DerivedWorkload {
+            workload_name: baggage.workload_name,
+            app: baggage.service_name,
+            revision: baggage.revision,
+            namespace: baggage.namespace,
+            identity,
+            cluster_id: baggage.cluster_id,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionOpen {
     pub reporter: Reporter,
     pub source: Option<Workload>,
     pub derived_source: Option<DerivedWorkload>,
     pub destination: Option<Workload>,
+    /// What the destination said about itself, for destinations that are not in our view of the
+    /// mesh.
+    pub derived_destination: Option<DerivedWorkload>,
     pub destination_service: Option<ServiceDescription>,
     pub connection_security_policy: SecurityPolicy,
+    /// The trace ID of a sampled connection, attached to its histogram observations as an exemplar
+    /// along with the connection ID.
+    pub trace_id: Option<String>,
+    pub connection_id: ConnectionId,
+    /// The address of the client, if known.
+    pub source_ip: Option<IpAddr>,
+    /// The address the connection is to.
+    pub destination_addr: Option<SocketAddr>,
+}
+
+impl ConnectionOpen {
+    fn exemplar(&self) -> Option<TraceLabels> {
+        self.trace_id.clone().map(|trace_id| TraceLabels {
+            trace_id,
+            connection_id: self.connection_id.to_string(),
+        })
+    }
 }
 
 impl<'a> From<&'a ConnectionOpen> for ConnectionClose<'a> {
     fn from(c: &'a ConnectionOpen) -> Self {
-        ConnectionClose(c)
+        ConnectionClose(c, Instant::now(), ResponseFlags::none)
+    }
+}
+
+impl BytesTransferred<'_> {
+    pub fn connection(&self) -> &ConnectionOpen {
+        self.0
     }
 }
 
 impl<'a> From<&'a ConnectionOpen> for BytesTransferred<'a> {
     fn from(c: &'a ConnectionOpen) -> Self {
-        BytesTransferred(c)
+        BytesTransferred(c, Instant::now())
     }
 }
 
@@ -145,6 +394,22 @@ impl CommonTrafficLabels {
         Default::default()
     }
 
+    /// aggregated drops the labels that identify individual workloads, keeping those of their
+    /// services.
+    fn aggregated(self) -> Self {
+        CommonTrafficLabels {
+            source_workload: Default::default(),
+            source_canonical_revision: Default::default(),
+            source_principal: Default::default(),
+            source_version: Default::default(),
+            destination_workload: Default::default(),
+            destination_canonical_revision: Default::default(),
+            destination_principal: Default::default(),
+            destination_version: Default::default(),
+            ..self
+        }
+    }
+
     fn with_source(mut self, w: Option<&Workload>) -> Self {
         let Some(w) = w else {
             return self
@@ -175,6 +440,21 @@ impl CommonTrafficLabels {
         self
     }
 
+    fn with_derived_destination(mut self, w: Option<&DerivedWorkload>) -> Self {
+        let Some(w) = w else {
+            return self
+        };
+        self.destination_workload = w.workload_name.clone().into();
+        self.destination_canonical_service = w.app.clone().into();
+        self.destination_canonical_revision = w.revision.clone().into();
+        self.destination_workload_namespace = w.namespace.clone().into();
+        self.destination_principal = w.identity.clone().into();
+        self.destination_app = w.app.clone().into();
+        self.destination_version = w.revision.clone().into();
+        self.destination_cluster = w.cluster_id.clone().into();
+        self
+    }
+
     fn with_destination(mut self, w: Option<&Workload>) -> Self {
         let Some(w) = w else {
             return self
@@ -218,6 +498,7 @@ impl From<&ConnectionOpen> for CommonTrafficLabels {
                 // Intentionally before with_source; source is more reliable
                 .with_derived_source(c.derived_source.as_ref())
                 .with_source(c.source.as_ref())
+                .with_derived_destination(c.derived_destination.as_ref())
                 .with_destination(c.destination.as_ref())
                 .with_destination_service(c.destination_service.as_ref())
         }
@@ -256,6 +537,26 @@ pub struct CommonTrafficLabels {
     connection_security_policy: SecurityPolicy,
 }
 
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct TraceLabels {
+    trace_id: String,
+    connection_id: String,
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct RbacAuditLabels {
+    /// The audited policy, as namespace/name.
+    pub policy: String,
+}
+
+#[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct FilterLabels {
+    /// The listener of the chain, as named in L4_FILTERS.
+    pub listener: String,
+    /// The filter that denied the connection.
+    pub filter: String,
+}
+
 #[derive(Clone, Hash, Default, Debug, PartialEq, Eq, EncodeLabelSet)]
 pub struct OnDemandDnsLabels {
     // on-demand DNS client information is just nice-to-have
@@ -296,6 +597,55 @@ impl OnDemandDnsLabels {
 }
 
 impl Metrics {
+    /// with_namespace_series_limit caps the traffic metric series of each destination namespace,
+    /// past which workload labels are aggregated away.
+    pub fn with_namespace_series_limit(mut self, limit: Option<usize>) -> Self {
+        self.series_budget.limit = limit;
+        self
+    }
+
+    /// with_fair_queues paces the connections of each source workload to share its bandwidth.
+    /// Workers pass the same queues, so a workload's connections share it whichever worker they
+    /// land on.
+    pub fn with_fair_queues(mut self, fair: Option<FairQueues>) -> Self {
+        self.connections = self.connections.with_fair_queues(fair);
+        self
+    }
+
+    /// with_connection_events streams the connections opened and closed to the event socket.
+    pub fn with_connection_events(mut self, events: Option<ConnectionEvents>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// with_flow_exporter exports a flow record of each connection once its bytes are recorded.
+    pub fn with_flow_exporter(mut self, flows: Option<FlowExporter>) -> Self {
+        self.flows = flows;
+        self
+    }
+
+    /// with_traffic_pairs counts connections and their bytes by the pair of identities at their
+    /// ends. Workers pass the same counts, so each pair is counted as one.
+    pub fn with_traffic_pairs(mut self, pairs: Option<TrafficPairs>) -> Self {
+        self.pairs = pairs;
+        self
+    }
+
+    /// with_connection_hooks calls `hooks` as connections open and close.
+    pub fn with_connection_hooks(mut self, hooks: ConnectionHooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// traffic_labels returns the labels to record the traffic of `c` under.
+    pub fn traffic_labels(&self, c: &ConnectionOpen) -> CommonTrafficLabels {
+        let (labels, first) = self.series_budget.admit(c.into());
+        if first {
+            self.series_aggregated.inc();
+        }
+        labels
+    }
+
     pub fn new(registry: &mut Registry) -> Self {
         let connection_opens = Family::default();
         registry.register(
@@ -322,6 +672,125 @@ impl Metrics {
             "The size of total bytes sent during response in case of a TCP connection",
             sent_bytes.clone(),
         );
+        let connection_duration: TrafficHistogram =
+            Family::new_with_constructor(duration_histogram);
+        registry.register_with_unit(
+            "tcp_connection_duration",
+            "The duration of TCP connections, from open to close",
+            Unit::Seconds,
+            connection_duration.clone(),
+        );
+        let connection_received_bytes: TrafficHistogram =
+            Family::new_with_constructor(bytes_histogram);
+        registry.register_with_unit(
+            "tcp_connection_received",
+            "The size of bytes received during request, per TCP connection",
+            Unit::Bytes,
+            connection_received_bytes.clone(),
+        );
+        let connection_sent_bytes: TrafficHistogram = Family::new_with_constructor(bytes_histogram);
+        registry.register_with_unit(
+            "tcp_connection_sent",
+            "The size of bytes sent during response, per TCP connection",
+            Unit::Bytes,
+            connection_sent_bytes.clone(),
+        );
+        let plaintext_denied = Family::default();
+        registry.register(
+            "tcp_connections_plaintext_denied",
+            "The total number of plaintext TCP connections rejected by the outbound traffic policy",
+            plaintext_denied.clone(),
+        );
+        let connect_timeouts = Family::default();
+        registry.register(
+            "tcp_connect_timeouts",
+            "The total number of outbound TCP connections that timed out connecting to the upstream",
+            connect_timeouts.clone(),
+        );
+        let handshake_timeouts = Family::default();
+        registry.register(
+            "tcp_handshake_timeouts",
+            "The total number of outbound HBONE connections that timed out during the TLS or HTTP/2 handshake",
+            handshake_timeouts.clone(),
+        );
+        let connect_response_timeouts = Family::default();
+        registry.register(
+            "tcp_connect_response_timeouts",
+            "The total number of outbound HBONE CONNECT requests that the upstream did not respond to in time",
+            connect_response_timeouts.clone(),
+        );
+        let inbound_handshake_timeouts = Counter::default();
+        registry.register(
+            "tcp_inbound_handshake_timeouts",
+            "The total number of inbound HBONE connections closed because the client did not complete the TLS or HTTP/2 handshake in time",
+            inbound_handshake_timeouts.clone(),
+        );
+        let upstream_grpc_unhealthy = Family::default();
+        registry.register(
+            "tcp_upstream_grpc_unhealthy",
+            "The total number of outbound HBONE CONNECT responses from waypoints reporting an unhealthy gRPC status of the upstream",
+            upstream_grpc_unhealthy.clone(),
+        );
+        let rbac_audit_denials = Family::default();
+        registry.register(
+            "rbac_audit_denials",
+            "The total number of connections that authorization policies in audit mode would have denied",
+            rbac_audit_denials.clone(),
+        );
+        let rbac_unsynced_denials = Counter::default();
+        registry.register(
+            "rbac_unsynced_denials",
+            "The total number of connections denied because authorization policies were not in sync with the control plane",
+            rbac_unsynced_denials.clone(),
+        );
+        let filter_denials = Family::default();
+        registry.register(
+            "l4_filter_denials",
+            "The total number of connections denied by L4 filters",
+            filter_denials.clone(),
+        );
+        let source_port_conflicts = Counter::default();
+        registry.register(
+            "source_port_conflicts",
+            "The total number of connections that could not keep the client's source port, as it was in use, and used an ephemeral port instead",
+            source_port_conflicts.clone(),
+        );
+        let outbound_loops = Counter::default();
+        registry.register(
+            "outbound_loops",
+            "The total number of outbound connections refused because their original destination was ztunnel itself",
+            outbound_loops.clone(),
+        );
+        let self_loops = Counter::default();
+        registry.register(
+            "self_loops",
+            "The total number of connections refused because ztunnel opened them itself, as they were redirected back to it",
+            self_loops.clone(),
+        );
+        let connection_panics = Counter::default();
+        registry.register(
+            "connection_panics",
+            "The total number of connections terminated because ztunnel panicked while handling them",
+            connection_panics.clone(),
+        );
+        let accept_loop_restarts = Counter::default();
+        registry.register(
+            "accept_loop_restarts",
+            "The total number of accept loops restarted because they panicked",
+            accept_loop_restarts.clone(),
+        );
+        let series_aggregated = Counter::default();
+        registry.register(
+            "metrics_series_aggregated",
+            "The total number of traffic metric series recorded without workload labels, as their destination namespace exceeded its series limit",
+            series_aggregated.clone(),
+        );
+        let plaintext_allowed = Family::default();
+        registry.register(
+            "tcp_connections_plaintext_allowed",
+            "The total number of TCP connections to mesh workloads the outbound traffic policy let through in plaintext",
+            plaintext_allowed.clone(),
+        );
         let on_demand_dns = Family::default();
         registry.register(
             "on_demand_dns",
@@ -340,25 +809,82 @@ impl Metrics {
             connection_close,
             received_bytes,
             sent_bytes,
+            connection_duration,
+            connection_received_bytes,
+            connection_sent_bytes,
+            plaintext_denied,
+            connect_timeouts,
+            handshake_timeouts,
+            connect_response_timeouts,
+            inbound_handshake_timeouts,
+            upstream_grpc_unhealthy,
+            rbac_audit_denials,
+            rbac_unsynced_denials,
+            filter_denials,
+            source_port_conflicts,
+            plaintext_allowed,
+            outbound_loops,
+            self_loops,
+            connection_panics,
+            accept_loop_restarts,
             on_demand_dns,
             on_demand_dns_cache_misses,
+            series_aggregated,
+            series_budget: Default::default(),
+            connections: Default::default(),
+            events: None,
+            flows: None,
+            pairs: None,
+            hooks: Default::default(),
         }
     }
 }
 
+fn duration_histogram() -> HistogramWithExemplars<TraceLabels> {
+    // 1ms to ~9 minutes
+    HistogramWithExemplars::new(exponential_buckets(0.001, 2.0, 20))
+}
+
+fn bytes_histogram() -> HistogramWithExemplars<TraceLabels> {
+    // 64B to 256MiB
+    HistogramWithExemplars::new(exponential_buckets(64.0, 4.0, 12))
+}
+
 impl Recorder<ConnectionOpen, u64> for Metrics {
     fn record(&self, reason: &ConnectionOpen, count: u64) {
         self.connection_opens
-            .get_or_create(&CommonTrafficLabels::from(reason))
+            .get_or_create(&self.traffic_labels(reason))
             .inc_by(count);
+        if let Some(events) = &self.events {
+            events.open(reason);
+        }
+        if let Some(pairs) = &self.pairs {
+            pairs.opened(reason);
+        }
+        self.hooks.open(reason);
     }
 }
 
 impl Recorder<ConnectionClose<'_>, u64> for Metrics {
     fn record(&self, reason: &ConnectionClose, count: u64) {
-        self.connection_close
-            .get_or_create(&CommonTrafficLabels::from(reason.0))
-            .inc_by(count);
+        let response_flags = if super::supervise::panicked() {
+            ResponseFlags::Panic
+        } else {
+            reason.2
+        };
+        let labels = CommonTrafficLabels {
+            response_flags,
+            ..self.traffic_labels(reason.0)
+        };
+        self.connection_close.get_or_create(&labels).inc_by(count);
+        self.connection_duration
+            .get_or_create(&labels)
+            .observe(reason.1.elapsed().as_secs_f64(), reason.0.exemplar());
+        if let Some(events) = &self.events {
+            events.close(reason.0, reason.1.elapsed(), response_flags);
+        }
+        self.hooks
+            .close(reason.0, reason.1.elapsed(), response_flags);
     }
 }
 
@@ -370,15 +896,145 @@ impl Recorder<BytesTransferred<'_>, (u64, u64)> for super::Metrics {
         } else {
             (m.0, m.1)
         };
+        let labels = self.traffic_labels(event.0);
         if sent != 0 {
-            self.sent_bytes
-                .get_or_create(&CommonTrafficLabels::from(event.0))
-                .inc_by(sent);
+            self.sent_bytes.get_or_create(&labels).inc_by(sent);
         }
         if recv != 0 {
-            self.received_bytes
-                .get_or_create(&CommonTrafficLabels::from(event.0))
-                .inc_by(recv);
+            self.received_bytes.get_or_create(&labels).inc_by(recv);
+        }
+        self.connection_sent_bytes
+            .get_or_create(&labels)
+            .observe(sent as f64, event.0.exemplar());
+        self.connection_received_bytes
+            .get_or_create(&labels)
+            .observe(recv as f64, event.0.exemplar());
+        if let Some(flows) = &self.flows {
+            // The bytes received by the destination are the ones the client sent.
+            flows.record(event.0, event.1, recv, sent);
+        }
+        if let Some(pairs) = &self.pairs {
+            pairs.transferred(event.0, recv, sent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_series_limit() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry).with_namespace_series_limit(Some(1));
+        let conn = |workload_name: &str| ConnectionOpen {
+            reporter: Reporter::destination,
+            source: None,
+            derived_source: None,
+            derived_destination: None,
+            destination: Some(Workload {
+                workload_name: workload_name.to_string(),
+                namespace: "ns".to_string(),
+                ..crate::test_helpers::test_default_workload()
+            }),
+            destination_service: None,
+            connection_security_policy: SecurityPolicy::unknown,
+            trace_id: None,
+            connection_id: ConnectionId::new(),
+            source_ip: None,
+            destination_addr: None,
+        };
+        let (first, second) = (conn("first"), conn("second"));
+
+        let labels = metrics.traffic_labels(&first);
+        assert_eq!(labels.destination_workload, "first".to_string().into());
+        // Past the limit, the workload is aggregated away, but only counted once.
+        for _ in 0..2 {
+            let labels = metrics.traffic_labels(&second);
+            assert_eq!(labels.destination_workload, DefaultedUnknown::default());
+            assert_eq!(
+                labels.destination_workload_namespace,
+                "ns".to_string().into()
+            );
         }
+        assert_eq!(metrics.series_aggregated.get(), 1);
+        // Another workload of the same service collapses into the same series.
+        let labels = metrics.traffic_labels(&conn("third"));
+        assert_eq!(labels.destination_workload, DefaultedUnknown::default());
+        assert_eq!(metrics.series_aggregated.get(), 1);
+        // Series already admitted keep their labels.
+        let labels = metrics.traffic_labels(&first);
+        assert_eq!(labels.destination_workload, "first".to_string().into());
+    }
+
+    #[test]
+    fn histogram_exemplars() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        let conn = ConnectionOpen {
+            reporter: Reporter::destination,
+            source: None,
+            derived_source: None,
+            derived_destination: None,
+            destination: None,
+            destination_service: None,
+            connection_security_policy: SecurityPolicy::unknown,
+            trace_id: Some("4bf92f3577b34da6a3ce929d0e0e4736".to_string()),
+            connection_id: ConnectionId::try_from("0af76519-16cd-43dd-8448-eb211c80319c").unwrap(),
+            source_ip: None,
+            destination_addr: None,
+        };
+        metrics.increment(&ConnectionClose::from(&conn));
+        metrics.record(&BytesTransferred::from(&conn), (10, 20));
+
+        let mut buf = String::new();
+        prometheus_client::encoding::text::encode(&mut buf, &registry).unwrap();
+        for metric in [
+            "tcp_connection_duration_seconds_bucket",
+            "tcp_connection_received_bytes_bucket",
+            "tcp_connection_sent_bytes_bucket",
+        ] {
+            assert!(
+                buf.lines().any(|l| l.starts_with(metric)
+                    && l.contains(
+                        "# {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\",\
+                         connection_id=\"0af76519-16cd-43dd-8448-eb211c80319c\"}"
+                    )),
+                "no exemplar on {metric}:\n{buf}"
+            );
+        }
+    }
+
+    #[test]
+    fn close_response_flags() {
+        let mut registry = Registry::default();
+        let metrics = Metrics::new(&mut registry);
+        let conn = ConnectionOpen {
+            reporter: Reporter::source,
+            source: None,
+            derived_source: None,
+            derived_destination: None,
+            destination: None,
+            destination_service: None,
+            connection_security_policy: SecurityPolicy::mutual_tls,
+            trace_id: None,
+            connection_id: ConnectionId::new(),
+            source_ip: None,
+            destination_addr: None,
+        };
+        let mut guard = metrics.open_connection(&conn);
+        guard.set_response_flags(ResponseFlags::AuthorizationPolicyDenied);
+        drop(guard);
+
+        let mut buf = String::new();
+        prometheus_client::encoding::text::encode(&mut buf, &registry).unwrap();
+        let flags = |metric: &str| {
+            buf.lines()
+                .find(|l| l.starts_with(metric))
+                .map(|l| l.contains("response_flags=\"DENY\""))
+        };
+        // Only the close knows how the connection went.
+        assert_eq!(flags("tcp_connections_opened_total"), Some(false));
+        assert_eq!(flags("tcp_connections_closed_total"), Some(true));
     }
 }
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::future::Future;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
@@ -21,88 +22,81 @@ use boring::ssl::ConnectConfiguration;
 use bytes::Bytes;
 use drain::Watch;
 use http_body_util::Empty;
+use hyper::body::Incoming;
 use hyper::header::FORWARDED;
-use hyper::StatusCode;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info, info_span, trace, trace_span, warn, Instrument};
+use tracing::{debug, error, info, trace, trace_span, warn, Instrument, Span};
 
-use crate::config::ProxyMode;
+use crate::baggage::parse_baggage_header;
+use crate::config::{EgressGateway, FilterListener, OutboundTrafficPolicy, ProxyMode};
 use crate::identity::Identity;
+use crate::proxy::fault;
 use crate::proxy::inbound::{Inbound, InboundConnect};
 use crate::proxy::metrics::Reporter;
+use crate::proxy::{
+    connection_span, util, ConnectionId, Error, ProxyInputs, Rejection, TraceParent,
+    BAGGAGE_HEADER, CONNECTION_ID_HEADER, GRPC_STATUS_HEADER, TRACEPARENT_HEADER,
+};
 use crate::proxy::{metrics, pool};
-use crate::proxy::{util, Error, ProxyInputs, TraceParent, BAGGAGE_HEADER, TRACEPARENT_HEADER};
 
-use crate::state::service::ServiceDescription;
-use crate::state::set_gateway_address;
+use crate::state::service::{ConnectPolicy, ServiceDescription};
+use crate::state::workload::address::Address;
+use crate::state::workload::gatewayaddress::Destination;
 use crate::state::workload::{NetworkAddress, Protocol, Workload};
-use crate::{hyper_util, proxy, rbac, socket};
+use crate::state::{set_gateway_address, Hairpin};
+use crate::{proxy, rbac, socket, tls};
+
+/// How many times to pick an endpoint of the service again, looking for one other than the peer
+/// that left a CONNECT unanswered.
+const CONNECT_RESPONSE_RETRY_PICKS: usize = 3;
+
+/// Reply is the response an explicit proxy client, such as a SOCKS5 one, waits for: written to
+/// the client once its upstream is connected, with no error, or failed to be, with why. Nothing
+/// from the upstream reaches the client before it.
+pub(super) type Reply = fn(Option<&Error>) -> Vec<u8>;
 
 pub struct Outbound {
     pi: ProxyInputs,
     drain: Watch,
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
 }
 
 impl Outbound {
     pub(super) async fn new(mut pi: ProxyInputs, drain: Watch) -> Result<Outbound, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.outbound_addr)
-            .await
-            .map_err(|e| Error::Bind(pi.cfg.outbound_addr, e))?;
-        let transparent = super::maybe_set_transparent(&pi, &listener)?;
+        let listeners =
+            super::listen(&pi, pi.cfg.outbound_addr, &pi.cfg.listener_tuning.outbound).await?;
+        let transparent = super::maybe_set_transparent(&pi, &listeners)?;
         // Override with our explicitly configured setting
         pi.cfg.enable_original_source = Some(transparent);
 
         info!(
-            address=%listener.local_addr().unwrap(),
+            address=%listeners[0].local_addr().unwrap(),
             component="outbound",
+            shards=listeners.len(),
             transparent,
             "listener established",
         );
         Ok(Outbound {
             pi,
-            listener,
+            listeners,
             drain,
         })
     }
 
     pub(super) fn address(&self) -> SocketAddr {
-        self.listener.local_addr().unwrap()
+        self.listeners[0].local_addr().unwrap()
     }
 
     pub(super) async fn run(self) {
-        let accept = async move {
-            loop {
-                // Asynchronously wait for an inbound socket.
-                let socket = self.listener.accept().await;
-                let start_outbound_instant = Instant::now();
-                match socket {
-                    Ok((stream, _remote)) => {
-                        let mut oc = OutboundConnection {
-                            pi: self.pi.clone(),
-                            id: TraceParent::new(),
-                        };
-                        let span = info_span!("outbound", id=%oc.id);
-                        tokio::spawn(
-                            (async move {
-                                let res = oc.proxy(stream).await;
-                                match res {
-                                    Ok(_) => info!(dur=?start_outbound_instant.elapsed(), "complete"),
-                                    Err(e) => warn!(dur=?start_outbound_instant.elapsed(), err=%e, "failed")
-                                };
-                            })
-                            .instrument(span),
-                        );
-                    }
-                    Err(e) => {
-                        if util::is_runtime_shutdown(&e) {
-                            return;
-                        }
-                        error!("Failed TCP handshake {}", e);
-                    }
-                }
-            }
-        }.in_current_span();
+        // Each shard gets its own accept loop, so they can run on different worker threads.
+        let pi = self.pi.clone();
+        let accept = super::supervise::supervise(
+            "outbound",
+            self.pi.metrics.clone(),
+            self.listeners,
+            move |listener| Self::accept(pi.clone(), listener).in_current_span(),
+        );
 
         // Stop accepting once we drain.
         // Note: we are *not* waiting for all connections to be closed. In the future, we may consider
@@ -114,33 +108,137 @@ impl Outbound {
             }
         }
     }
+
+    async fn accept(pi: ProxyInputs, listener: TcpListener) {
+        let listener_addr = listener.local_addr().expect("must get listener address");
+        loop {
+            // Asynchronously wait for an inbound socket.
+            let socket = listener.accept().await;
+            let start_outbound_instant = Instant::now();
+            match socket {
+                Ok((stream, _remote)) => {
+                    let mut oc = OutboundConnection {
+                        pi: pi.clone(),
+                        id: TraceParent::new_sampled(pi.cfg.trace_sampling_percentage),
+                        connection_id: ConnectionId::new(),
+                    };
+                    let span = connection_span!(
+                        "outbound",
+                        connection_id = %oc.connection_id,
+                        id = %oc.id,
+                        sampled = oc.id.is_sampled()
+                    );
+                    tokio::spawn(
+                        super::supervise::isolate(pi.metrics.clone(), async move {
+                            let res = oc.proxy(stream, listener_addr).await;
+                            match res {
+                                Ok(_) => info!(dur=?start_outbound_instant.elapsed(), "complete"),
+                                Err(e) => {
+                                    warn!(dur=?start_outbound_instant.elapsed(), err=%e, "failed")
+                                }
+                            };
+                        })
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    if util::is_runtime_shutdown(&e) {
+                        return;
+                    }
+                    error!("Failed TCP handshake {}", e);
+                }
+            }
+        }
+    }
 }
 
 pub(super) struct OutboundConnection {
     pub(super) pi: ProxyInputs,
     pub(super) id: TraceParent,
+    pub(super) connection_id: ConnectionId,
 }
 
 impl OutboundConnection {
-    async fn proxy(&mut self, stream: TcpStream) -> Result<(), Error> {
+    async fn proxy(&mut self, stream: TcpStream, listener_addr: SocketAddr) -> Result<(), Error> {
         let peer = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
         let orig_dst_addr = socket::orig_dst_addr_or_default(&stream);
-        self.proxy_to(stream, peer.ip(), orig_dst_addr, false).await
+        if super::loops::is_own(peer) {
+            self.pi.metrics.self_loops.inc();
+            return Err(Error::SelfLoop(peer));
+        }
+        // With a single catch-all REDIRECT, traffic that was not redirected, or that was sent to
+        // the listener itself, would be proxied straight back to us, over and over.
+        if is_loop(orig_dst_addr, listener_addr, self.pi.cfg.local_ip) {
+            self.pi.metrics.outbound_loops.inc();
+            return Err(Error::OutboundLoop(orig_dst_addr));
+        }
+        self.proxy_to(stream, peer.ip(), orig_dst_addr, false, None)
+            .await
     }
 
+    /// proxy_to proxies `stream` to `orig_dst_addr`, once the outbound filters allow it. If the
+    /// client is owed a `reply`, it is written once the upstream is connected, or failed to be.
     pub async fn proxy_to(
         &mut self,
         mut stream: TcpStream,
         remote_addr: IpAddr,
         orig_dst_addr: SocketAddr,
         block_passthrough: bool,
+        reply: Option<Reply>,
+    ) -> Result<(), Error> {
+        if let Err(e) = self
+            .pi
+            .filters
+            .run(
+                &self.pi.metrics,
+                FilterListener::Outbound,
+                remote_addr,
+                orig_dst_addr,
+            )
+            .await
+        {
+            return fail(&mut stream, reply, e).await;
+        }
+        self.proxy_to_unfiltered(stream, remote_addr, orig_dst_addr, block_passthrough, reply)
+            .await
+    }
+
+    /// proxy_to_unfiltered is [Self::proxy_to] for connections that another listener's filters
+    /// already allowed, such as inbound plaintext connections hairpinned through a waypoint.
+    pub async fn proxy_to_unfiltered(
+        &mut self,
+        mut stream: TcpStream,
+        remote_addr: IpAddr,
+        orig_dst_addr: SocketAddr,
+        block_passthrough: bool,
+        reply: Option<Reply>,
     ) -> Result<(), Error> {
         if self.pi.cfg.proxy_mode == ProxyMode::Shared
             && Some(orig_dst_addr.ip()) == self.pi.cfg.local_ip
         {
-            return Err(Error::SelfCall);
+            return fail(&mut stream, reply, Error::SelfCall).await;
+        }
+        // The HBONE legs never batch small writes, and neither do inbound connections to the
+        // destination, so the client's connection is the last one to set for end-to-end.
+        super::set_latency_sensitive(&self.pi.cfg, orig_dst_addr.port(), &stream);
+        // Explicit clients such as socks5 only reach mesh destinations, so never bypass for them.
+        if !block_passthrough && self.should_bypass(remote_addr, orig_dst_addr).await {
+            return self.bypass(stream, orig_dst_addr).await;
+        }
+        let req = match self.build_request(remote_addr, orig_dst_addr).await {
+            Ok(req) => req,
+            Err(e) => return fail(&mut stream, reply, e).await,
+        };
+        // Ports the source workload excludes from capture are checked once the request has looked
+        // the workload up, rather than looking it up for every connection in should_bypass.
+        if !block_passthrough && !req.source.captures_outbound_port(orig_dst_addr.port()) {
+            return self.bypass(stream, orig_dst_addr).await;
         }
-        let req = self.build_request(remote_addr, orig_dst_addr).await?;
+        proxy::record_workloads(
+            &Span::current(),
+            Some(&req.source),
+            req.destination_workload.as_ref(),
+        );
         debug!(
             "request from {} to {} via {} type {:#?} dir {:#?}",
             req.source.name, orig_dst_addr, req.gateway, req.request_type, req.direction
@@ -148,7 +246,8 @@ impl OutboundConnection {
         if block_passthrough && req.destination_workload.is_none() {
             // This is mostly used by socks5. For typical outbound calls, we need to allow calls to arbitrary
             // domains. But for socks5
-            return Err(Error::UnknownDestination(req.destination.ip()));
+            let e = Error::UnknownDestination(req.destination.ip());
+            return fail(&mut stream, reply, e).await;
         }
         let can_fastpath = self.pi.cfg.proxy_mode == ProxyMode::Shared
             && req.protocol == Protocol::HBONE
@@ -160,6 +259,7 @@ impl OutboundConnection {
         let connection_metrics = metrics::ConnectionOpen {
             reporter: Reporter::source,
             derived_source: None,
+            derived_destination: None,
             source: Some(req.source.clone()),
             destination: req.destination_workload.clone(),
             connection_security_policy: if req.protocol == Protocol::HBONE {
@@ -168,8 +268,31 @@ impl OutboundConnection {
                 metrics::SecurityPolicy::unknown
             },
             destination_service: req.destination_service.clone(),
+            trace_id: self.id.sampled_id(),
+            connection_id: self.connection_id,
+            source_ip: Some(remote_addr),
+            destination_addr: Some(req.destination),
         };
 
+        if let Err(e) =
+            enforce_traffic_policy(self.pi.live_cfg.current().outbound_traffic_policy, &req)
+        {
+            self.pi
+                .metrics
+                .plaintext_denied
+                .get_or_create(&self.pi.metrics.traffic_labels(&connection_metrics))
+                .inc();
+            return fail(&mut stream, reply, e).await;
+        }
+        // Mesh destinations reached over mTLS are counted with the mutual_tls security policy.
+        if req.protocol == Protocol::TCP && req.destination_workload.is_some() {
+            self.pi
+                .metrics
+                .plaintext_allowed
+                .get_or_create(&self.pi.metrics.traffic_labels(&connection_metrics))
+                .inc();
+        }
+
         if req.request_type == RequestType::DirectLocal && can_fastpath {
             // For same node, we just access it directly rather than making a full network connection.
             // Pass our `stream` over to the inbound handler, which will process as usual
@@ -187,14 +310,17 @@ impl OutboundConnection {
                 dst_network: req.source.network.clone(), // since this is node local, it's the same network
                 dst: req.destination,
             };
-            if !self.pi.state.assert_rbac(&conn).await {
+            let rbac_audit = self.pi.live_cfg.current().rbac_audit;
+            if !super::authorize(&self.pi.state, &conn, rbac_audit, &self.pi.metrics).await {
                 info!(%conn, "RBAC rejected");
-                return Err(Error::HttpStatus(StatusCode::UNAUTHORIZED));
+                let e = Error::ConnectRejected(req.destination, Rejection::PolicyDenied);
+                return fail(&mut stream, reply, e).await;
             }
             // same as above but inverted, this is the "inbound" metric
             let inbound_connection_metrics = metrics::ConnectionOpen {
                 reporter: Reporter::destination,
                 derived_source: None,
+                derived_destination: None,
                 source: Some(req.source.clone()),
                 destination: req.destination_workload.clone(),
                 connection_security_policy: if req.protocol == Protocol::HBONE {
@@ -203,11 +329,25 @@ impl OutboundConnection {
                     metrics::SecurityPolicy::unknown
                 },
                 destination_service: None, // TODO: in Envoy, we guess the destination service for inbound
+                trace_id: self.id.sampled_id(),
+                connection_id: self.connection_id,
+                source_ip: Some(remote_addr),
+                destination_addr: Some(req.destination),
             };
+            let tos = self.downstream_tos(&stream);
             return Inbound::handle_inbound(
-                InboundConnect::DirectPath(stream),
+                InboundConnect::DirectPath(stream, reply),
                 origin_src,
                 req.destination,
+                self.pi.cfg.socket_marks.inbound,
+                tos,
+                req.connect_policy,
+                super::copy_buffer_size(
+                    &self.pi.cfg.latency_sensitive_ports,
+                    self.pi.cfg.hbone_buffer_size,
+                    req.destination.port(),
+                ),
+                super::workload_drain(&self.pi.state, req.destination_workload.as_ref()),
                 self.pi.metrics.to_owned(), // self is a borrow so this clone is to return an owned
                 connection_metrics,
                 Some(inbound_connection_metrics),
@@ -216,13 +356,8 @@ impl OutboundConnection {
             .map_err(Error::Io);
         }
 
-        let transferred_bytes = metrics::BytesTransferred::from(&connection_metrics);
-
-        // _connection_close will record once dropped
-        let _connection_close = self
-            .pi
-            .metrics
-            .increment_defer::<_, metrics::ConnectionClose>(&connection_metrics);
+        // connection_close will record once dropped
+        let mut connection_close = self.pi.metrics.open_connection(&connection_metrics);
         match req.protocol {
             Protocol::HBONE => {
                 info!(
@@ -230,91 +365,55 @@ impl OutboundConnection {
                     req.destination, req.gateway, req.request_type
                 );
 
-                let mut allowed_sans: Vec<Identity> = Vec::new();
-                for san in req.upstream_sans.iter() {
-                    match Identity::from_str(san) {
-                        Ok(ident) => allowed_sans.push(ident.clone()),
-                        Err(err) => {
-                            warn!("error parsing SAN {}: {}", san, err)
+                let connected = self
+                    .connect_hbone(&req, remote_addr, &connection_metrics)
+                    .await;
+                self.record_health(&req, &connected);
+                let connected = match connected {
+                    Err(Error::ConnectResponseTimeout(gateway))
+                        if req.destination_service.is_some() =>
+                    {
+                        // The service may have another endpoint that is not hung.
+                        match self.retry_request(&req, remote_addr, orig_dst_addr).await {
+                            Some(retry) => {
+                                info!(
+                                    "no response to CONNECT from {gateway}, retrying via {}",
+                                    retry.gateway
+                                );
+                                let connected = self
+                                    .connect_hbone(&retry, remote_addr, &connection_metrics)
+                                    .await;
+                                self.record_health(&retry, &connected);
+                                connected
+                            }
+                            None => Err(Error::ConnectResponseTimeout(gateway)),
                         }
                     }
-                }
-
-                allowed_sans.push(req.expected_identity.clone().unwrap());
-                let dst_identity = allowed_sans;
-
-                let pool_key = pool::Key {
-                    src_id: req.source.identity(),
-                    dst_id: dst_identity.clone(),
-                    dst: req.gateway,
+                    res => res,
                 };
-
-                // Setup our connection future. This won't always run if we have an existing connection
-                // in the pool.
-                let connect = async {
-                    let mut builder =
-                        hyper::client::conn::http2::Builder::new(hyper_util::TokioExecutor);
-                    let builder = builder
-                        .initial_stream_window_size(self.pi.cfg.window_size)
-                        .max_frame_size(self.pi.cfg.frame_size)
-                        .initial_connection_window_size(self.pi.cfg.connection_window_size);
-
-                    let local = self
-                        .pi
-                        .cfg
-                        .enable_original_source
-                        .unwrap_or_default()
-                        .then_some(remote_addr);
-                    let id = &req.source.identity();
-                    let cert = self.pi.cert_manager.fetch_certificate(id).await?;
-                    let connector = cert
-                        .connector(dst_identity)?
-                        .configure()
-                        .expect("configure");
-                    let tcp_stream = super::freebind_connect(local, req.gateway).await?;
-                    tcp_stream.set_nodelay(true)?; // TODO: this is backwards of expectations
-                    let tls_stream = connect_tls(connector, tcp_stream).await?;
-                    let (request_sender, connection) = builder
-                        .handshake(tls_stream)
-                        .await
-                        .map_err(Error::HttpHandshake)?;
-                    // spawn a task to poll the connection and drive the HTTP state
-                    tokio::spawn(async move {
-                        if let Err(e) = connection.await {
-                            error!("Error in HBONE connection handshake: {:?}", e);
-                        }
-                    });
-                    Ok(request_sender)
+                let (mut upgraded, peer) = match connected {
+                    Ok(connected) => connected,
+                    Err(e) => {
+                        connection_close.set_response_flags(e.response_flags());
+                        return fail(&mut stream, reply, e).await;
+                    }
                 };
-                let mut connection = self.pi.pool.connect(pool_key.clone(), connect).await?;
-
-                let mut f = http_types::proxies::Forwarded::new();
-                f.add_for(remote_addr.to_string());
-
-                let request = hyper::Request::builder()
-                    .uri(&req.destination.to_string())
-                    .method(hyper::Method::CONNECT)
-                    .version(hyper::Version::HTTP_2)
-                    .header(
-                        BAGGAGE_HEADER,
-                        baggage(&req, self.pi.cfg.cluster_id.clone()),
-                    )
-                    .header(FORWARDED, f.value().unwrap())
-                    .header(TRACEPARENT_HEADER, self.id.header())
-                    .body(Empty::<Bytes>::new())
-                    .unwrap();
-
-                let response = connection.send_request(request).await?;
-
-                let code = response.status();
-                if code != 200 {
-                    return Err(Error::HttpStatus(code));
+                succeed(&mut stream, reply, &mut connection_close).await?;
+                // Workloads we know of are better described by what we know than by what they say.
+                if req.destination_workload.is_none() {
+                    connection_close.set_derived_destination(peer);
                 }
-                let mut upgraded = hyper::upgrade::on(response).await?;
+                let transferred_bytes =
+                    metrics::BytesTransferred::from(connection_close.connection());
 
                 super::copy_hbone(
                     &mut upgraded,
                     &mut stream,
+                    super::copy_buffer_size(
+                        &self.pi.cfg.latency_sensitive_ports,
+                        self.pi.cfg.hbone_buffer_size,
+                        orig_dst_addr.port(),
+                    ),
                     &self.pi.metrics,
                     transferred_bytes,
                 )
@@ -328,11 +427,44 @@ impl OutboundConnection {
                 );
                 // Create a TCP connection to upstream
                 let local = if self.pi.cfg.enable_original_source.unwrap_or_default() {
-                    super::get_original_src_from_stream(&stream)
+                    super::original_source(
+                        super::get_original_src_from_stream(&stream),
+                        req.gateway,
+                        req.destination_workload.as_ref(),
+                    )
                 } else {
                     None
                 };
-                let mut outbound = super::freebind_connect(local, req.gateway).await?;
+                let port = self
+                    .pi
+                    .cfg
+                    .preserve_source_port
+                    .then(|| stream.peer_addr().ok().map(|addr| addr.port()))
+                    .flatten();
+                fault::delay_connect(fault::Direction::Outbound).await;
+                let connected = super::freebind_connect_port(
+                    local,
+                    port,
+                    req.gateway,
+                    self.pi.cfg.socket_marks.outbound,
+                    self.downstream_tos(&stream),
+                    self.pi.live_cfg.current().connect_timeout,
+                    &self.pi.metrics,
+                )
+                .await;
+                self.record_health(&req, &connected);
+                let mut outbound = match connected {
+                    Ok(outbound) => outbound,
+                    Err(e) => {
+                        connection_close.set_response_flags(e.response_flags());
+                        let e = self.record_timeout(e, &connection_metrics);
+                        return fail(&mut stream, reply, e).await;
+                    }
+                };
+                super::set_latency_sensitive(&self.pi.cfg, orig_dst_addr.port(), &outbound);
+                succeed(&mut stream, reply, &mut connection_close).await?;
+                let transferred_bytes =
+                    metrics::BytesTransferred::from(connection_close.connection());
                 // Proxying data between downstrean and upstream
                 proxy::relay(
                     &mut stream,
@@ -346,6 +478,463 @@ impl OutboundConnection {
         }
     }
 
+    /// should_bypass checks the connection against the outbound bypass rules. Destinations are
+    /// checked first, since source workloads need a lookup.
+    async fn should_bypass(&self, remote_addr: IpAddr, dst: SocketAddr) -> bool {
+        let live_cfg = self.pi.live_cfg.current();
+        let bypass = &live_cfg.outbound_bypass;
+        if bypass.matches_destination(dst) {
+            return true;
+        }
+        if bypass.workloads.is_empty() {
+            return false;
+        }
+        let source = NetworkAddress {
+            network: self.pi.cfg.network.clone(),
+            address: remote_addr,
+        };
+        self.pi
+            .state
+            .fetch_workload(&source)
+            .await
+            .map(|w| bypass.matches_source(&w.namespace, &w.workload_name))
+            .unwrap_or(false)
+    }
+
+    /// bypass connects `stream` straight to its original destination, as if it was never captured.
+    async fn bypass(&self, mut stream: TcpStream, dst: SocketAddr) -> Result<(), Error> {
+        debug!("bypassing proxy for {dst}");
+        let local = if self.pi.cfg.enable_original_source.unwrap_or_default() {
+            super::original_source(super::get_original_src_from_stream(&stream), dst, None)
+        } else {
+            None
+        };
+        let port = self
+            .pi
+            .cfg
+            .preserve_source_port
+            .then(|| stream.peer_addr().ok().map(|addr| addr.port()))
+            .flatten();
+        let mut outbound = super::freebind_connect_port(
+            local,
+            port,
+            dst,
+            self.pi.cfg.socket_marks.outbound,
+            self.downstream_tos(&stream),
+            self.pi.live_cfg.current().connect_timeout,
+            &self.pi.metrics,
+        )
+        .await?;
+        super::set_latency_sensitive(&self.pi.cfg, dst.port(), &outbound);
+        socket::relay(&mut stream, &mut outbound)
+            .await
+            .map(|_| ())
+            .map_err(Error::Io)
+    }
+
+    /// downstream_tos returns the QoS marking of the client of `stream`, if it is carried on.
+    fn downstream_tos(&self, stream: &TcpStream) -> Option<u8> {
+        self.pi
+            .cfg
+            .preserve_tos
+            .outbound
+            .then(|| super::downstream_tos(stream))
+            .flatten()
+    }
+
+    fn connect_request(
+        &self,
+        req: &Request,
+        authority: SocketAddr,
+        remote_addr: IpAddr,
+    ) -> hyper::Request<Empty<Bytes>> {
+        let mut f = http_types::proxies::Forwarded::new();
+        f.add_for(remote_addr.to_string());
+
+        let mut builder = hyper::Request::builder()
+            .uri(&authority.to_string())
+            .method(hyper::Method::CONNECT)
+            .version(hyper::Version::HTTP_2)
+            .header(BAGGAGE_HEADER, baggage(req, self.pi.cfg.cluster_id.clone()))
+            .header(FORWARDED, f.value().unwrap())
+            .header(TRACEPARENT_HEADER, self.id.header())
+            .header(CONNECTION_ID_HEADER, self.connection_id.header());
+        for (name, value) in super::connect_policy_headers(&req.connect_policy) {
+            builder = builder.header(name, value);
+        }
+        builder.body(Empty::<Bytes>::new()).unwrap()
+    }
+
+    /// connect_hbone opens the HBONE stream for `req`, reusing a pooled connection to the next hop
+    /// if there is one.
+    async fn connect_hbone(
+        &self,
+        req: &Request,
+        remote_addr: IpAddr,
+        connection_metrics: &metrics::ConnectionOpen,
+    ) -> Result<(hyper::upgrade::Upgraded, metrics::DerivedWorkload), Error> {
+        let mut allowed_sans: Vec<Identity> = Vec::new();
+        for san in req.upstream_sans.iter() {
+            match Identity::from_str(san) {
+                Ok(ident) => allowed_sans.push(ident.clone()),
+                Err(err) => {
+                    warn!("error parsing SAN {}: {}", san, err)
+                }
+            }
+        }
+
+        allowed_sans.push(req.expected_identity.clone().unwrap());
+        let dst_identity = allowed_sans;
+
+        // Through a network gateway, the pooled connection is to the gateway, and the one
+        // to `req.gateway` is tunneled over it.
+        let (next_hop, next_hop_identity) = match &req.network_gateway {
+            Some(gw) => (gw.address, vec![gw.identity.clone()]),
+            None => (req.gateway, dst_identity.clone()),
+        };
+        let pool_key = pool::Key {
+            src_id: req.source.identity(),
+            dst_id: next_hop_identity.clone(),
+            dst: next_hop,
+        };
+
+        // Setup our connection future. This won't always run if we have an existing connection
+        // in the pool.
+        let connect = async {
+            let live_cfg = self.pi.live_cfg.current();
+            let mut builder = hyper::client::conn::http2::Builder::new(pool::TokioExec::new(
+                self.pi.metrics.clone(),
+            ));
+            let builder = builder
+                .initial_stream_window_size(live_cfg.window_size)
+                .max_frame_size(live_cfg.frame_size)
+                .initial_connection_window_size(live_cfg.connection_window_size);
+
+            let local = self
+                .pi
+                .cfg
+                .enable_original_source
+                .unwrap_or_default()
+                .then_some(remote_addr);
+            let local = super::original_source(local, next_hop, None);
+            let id = &req.source.identity();
+            let cert = self.pi.cert_manager.fetch_certificate(id).await?;
+            let mut connector = cert
+                .connector(next_hop_identity.clone())?
+                .configure()
+                .expect("configure");
+            // Sessions are resumed only with the same peer, as the same identity.
+            let peer = format!("{}/{}/{:?}", id, next_hop, next_hop_identity);
+            let early_data = tls::early_data::resume(&mut connector, peer);
+            fault::delay_connect(fault::Direction::Outbound).await;
+            // Pooled connections are shared by many clients, so they keep our own marking.
+            let tcp_stream = super::freebind_connect_timeout(
+                local,
+                next_hop,
+                self.pi.cfg.socket_marks.outbound,
+                None,
+                // The TLS client speaks first.
+                self.pi.cfg.listener_tuning.hbone_fast_open,
+                live_cfg.connect_timeout,
+            )
+            .await
+            .map_err(|e| self.record_timeout(e, connection_metrics))?;
+            tcp_stream.set_nodelay(true)?; // TODO: this is backwards of expectations
+            fault::fail_handshake(fault::Direction::Outbound)?;
+            let handshake = async {
+                let tls_stream = connect_tls(connector, tcp_stream).await?;
+                builder
+                    .handshake(tls::early_data::Stream::new(tls_stream, early_data))
+                    .await
+                    .map_err(Error::HttpHandshake)
+            };
+            let (request_sender, connection) =
+                tokio::time::timeout(live_cfg.handshake_timeout, handshake)
+                    .await
+                    .map_err(|_| {
+                        self.record_timeout(Error::HandshakeTimeout(next_hop), connection_metrics)
+                    })??;
+            // spawn a task to poll the connection and drive the HTTP state
+            tokio::spawn(super::supervise::isolate(
+                self.pi.metrics.clone(),
+                async move {
+                    if let Err(e) = connection.await {
+                        error!("Error in HBONE connection handshake: {:?}", e);
+                    }
+                },
+            ));
+            Ok(request_sender)
+        };
+        let mut connection = self.pi.pool.connect(pool_key.clone(), connect).await?;
+
+        // The network gateway only forwards the tunnel to the destination's HBONE address.
+        let authority = match req.network_gateway {
+            Some(_) => req.gateway,
+            None => req.destination,
+        };
+        let request = self.connect_request(req, authority, remote_addr);
+        let response = self
+            .await_response(connection.send_request(request), next_hop)
+            .await
+            .map_err(|e| self.record_timeout(e, connection_metrics))?;
+
+        if response.status() != 200 {
+            return Err(connect_refused(next_hop, &response));
+        }
+        if self.pi.cfg.grpc_health_signals && req.request_type == RequestType::ToServerWaypoint {
+            let unhealthy = grpc_unhealthy(&response);
+            if let Some(status) = unhealthy {
+                debug!(%next_hop, status, "waypoint reported an unhealthy gRPC upstream");
+                self.pi
+                    .metrics
+                    .upstream_grpc_unhealthy
+                    .get_or_create(&self.pi.metrics.traffic_labels(connection_metrics))
+                    .inc();
+            }
+            self.record_grpc_health(req, unhealthy.is_none());
+        }
+        let peer = peer_metadata(&response, req.expected_identity.clone());
+        let upgraded = hyper::upgrade::on(response).await?;
+        match req.network_gateway {
+            Some(_) => self
+                .tunnel(req, upgraded, dst_identity, remote_addr)
+                .await
+                .map_err(|e| self.record_timeout(e, connection_metrics)),
+            None => Ok((upgraded, peer)),
+        }
+    }
+
+    /// await_response waits for the response to a CONNECT request. A peer that accepted the
+    /// connection but hangs would otherwise stall the client indefinitely.
+    async fn await_response(
+        &self,
+        response: impl Future<Output = hyper::Result<hyper::Response<Incoming>>>,
+        peer: SocketAddr,
+    ) -> Result<hyper::Response<Incoming>, Error> {
+        let timeout = self.pi.live_cfg.current().connect_response_timeout;
+        tokio::time::timeout(timeout, response)
+            .await
+            .map_err(|_| Error::ConnectResponseTimeout(peer))?
+            .map_err(Error::from)
+    }
+
+    /// retry_request picks the request to retry `req` with after its CONNECT went unanswered, if
+    /// another endpoint of its service can be found.
+    async fn retry_request(
+        &self,
+        req: &Request,
+        remote_addr: IpAddr,
+        orig_dst_addr: SocketAddr,
+    ) -> Option<Request> {
+        for _ in 0..CONNECT_RESPONSE_RETRY_PICKS {
+            match self.build_request(remote_addr, orig_dst_addr).await {
+                Ok(retry) if retry.gateway != req.gateway && retry.protocol == Protocol::HBONE => {
+                    return Some(retry)
+                }
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+        None
+    }
+
+    /// tunnel completes a request through a network gateway: over `outer`, the gateway's tunnel to
+    /// the destination ztunnel, it makes an HBONE connection of its own and opens the stream to
+    /// the destination on it. Unlike the outer connection, this one is not pooled.
+    async fn tunnel(
+        &self,
+        req: &Request,
+        outer: hyper::upgrade::Upgraded,
+        dst_identity: Vec<Identity>,
+        remote_addr: IpAddr,
+    ) -> Result<(hyper::upgrade::Upgraded, metrics::DerivedWorkload), Error> {
+        let cert = self
+            .pi
+            .cert_manager
+            .fetch_certificate(&req.source.identity())
+            .await?;
+        let connector = cert
+            .connector(dst_identity)?
+            .configure()
+            .expect("configure");
+        let live_cfg = self.pi.live_cfg.current();
+        let mut builder =
+            hyper::client::conn::http2::Builder::new(pool::TokioExec::new(self.pi.metrics.clone()));
+        let builder = builder
+            .initial_stream_window_size(live_cfg.window_size)
+            .max_frame_size(live_cfg.frame_size)
+            .initial_connection_window_size(live_cfg.connection_window_size);
+        let handshake = async {
+            let tls_stream = connect_tls(connector, outer)
+                .await
+                .map_err(|e| Error::Generic(e.to_string().into()))?;
+            builder
+                .handshake(tls_stream)
+                .await
+                .map_err(Error::HttpHandshake)
+        };
+        let (mut request_sender, connection) =
+            tokio::time::timeout(live_cfg.handshake_timeout, handshake)
+                .await
+                .map_err(|_| Error::HandshakeTimeout(req.gateway))??;
+        tokio::spawn(super::supervise::isolate(
+            self.pi.metrics.clone(),
+            async move {
+                if let Err(e) = connection.await {
+                    error!("Error in tunneled HBONE connection: {:?}", e);
+                }
+            },
+        ));
+
+        let request = self.connect_request(req, req.destination, remote_addr);
+        let response = self
+            .await_response(request_sender.send_request(request), req.gateway)
+            .await?;
+        if response.status() != 200 {
+            return Err(connect_refused(req.gateway, &response));
+        }
+        let peer = peer_metadata(&response, req.expected_identity.clone());
+        Ok((hyper::upgrade::on(response).await?, peer))
+    }
+
+    /// record_timeout counts `err` in the matching timeout metric, if it is a timeout.
+    fn record_timeout(&self, err: Error, connection_metrics: &metrics::ConnectionOpen) -> Error {
+        let timeouts = match err {
+            Error::ConnectTimeout(_) => &self.pi.metrics.connect_timeouts,
+            Error::HandshakeTimeout(_) => &self.pi.metrics.handshake_timeouts,
+            Error::ConnectResponseTimeout(_) => &self.pi.metrics.connect_response_timeouts,
+            _ => return err,
+        };
+        timeouts
+            .get_or_create(&self.pi.metrics.traffic_labels(connection_metrics))
+            .inc();
+        err
+    }
+
+    /// record_health reports whether connecting to the endpoint of a service succeeded to outlier
+    /// detection. Only direct connections are judged: through a waypoint or gateway, a failure
+    /// need not be the endpoint's.
+    fn record_health<T>(&self, req: &Request, res: &Result<T, Error>) {
+        let Some(wl) = req.destination_workload.as_ref().filter(|_| {
+            req.destination_service.is_some() && req.request_type == RequestType::Direct
+        }) else {
+            return;
+        };
+        let outliers = self.pi.state.read().outliers.clone();
+        match res {
+            Ok(_) => outliers.success(&wl.uid),
+            Err(e) if e.is_endpoint_failure() => outliers.error(&wl.uid),
+            Err(_) => {}
+        }
+    }
+
+    /// record_grpc_health reports to outlier detection whether the waypoint in front of a workload
+    /// found its gRPC upstream healthy. Only requests to the workload's own address are judged: to
+    /// a service address, the waypoint picks the upstream itself, which need not be the endpoint
+    /// we picked.
+    fn record_grpc_health(&self, req: &Request, healthy: bool) {
+        let Some(wl) = req
+            .destination_workload
+            .as_ref()
+            .filter(|wl| wl.workload_ips.contains(&req.destination.ip()))
+        else {
+            return;
+        };
+        let outliers = self.pi.state.read().outliers.clone();
+        if healthy {
+            outliers.success(&wl.uid);
+        } else {
+            outliers.error(&wl.uid);
+        }
+    }
+
+    /// on_local_node returns whether `wl` runs on our node, so that its HBONE would only be dialed
+    /// back to ourselves.
+    fn on_local_node(&self, wl: &Workload) -> bool {
+        wl.on_node(self.pi.cfg.local_node.as_deref(), self.pi.cfg.local_ip)
+    }
+
+    /// fetch_network_gateway finds the gateway into the network of `wl`, and the identity it is
+    /// expected to present.
+    async fn fetch_network_gateway(&self, wl: &Workload) -> Result<NetworkGateway, Error> {
+        let unknown = || Error::UnknownNetworkGateway(wl.network.clone());
+        let gw = wl.network_gateway.as_ref().ok_or_else(unknown)?;
+        let Destination::Address(gw_addr) = &gw.destination else {
+            return Err(Error::UnknownNetworkGateway(format!(
+                "{} (hostname lookup not supported yet)",
+                wl.network
+            )));
+        };
+        let identity = match self.pi.state.fetch_address(gw_addr).await {
+            Some(Address::Workload(gw_wl)) => gw_wl.identity(),
+            // A load balanced gateway; its replicas all share an identity.
+            Some(Address::Service(svc)) => {
+                let mut identity = None;
+                for ep in svc.endpoints.values() {
+                    if let Some(gw_wl) = self.pi.state.fetch_workload_by_uid(&ep.workload_uid).await
+                    {
+                        identity = Some(gw_wl.identity());
+                        break;
+                    }
+                }
+                identity.ok_or_else(unknown)?
+            }
+            None => return Err(unknown()),
+        };
+        Ok(NetworkGateway {
+            address: SocketAddr::new(gw_addr.address, gw.port),
+            identity,
+        })
+    }
+
+    /// egress_gateway returns the egress gateway to send traffic for `ip` through, if any. Besides
+    /// the addresses the hostnames resolve to, `ip` may be one istiod allocated to the host in
+    /// the name table.
+    fn egress_gateway(&self, ip: IpAddr) -> Option<&EgressGateway> {
+        self.pi.cfg.egress_gateway.as_ref().filter(|egress| {
+            egress.cidrs.iter().any(|c| c.contains(&ip))
+                || self.pi.egress_hosts.contains(&ip)
+                || self
+                    .pi
+                    .state
+                    .read()
+                    .names
+                    .get_by_ip(&ip)
+                    .map_or(false, |info| egress.hostnames.contains(&info.hostname))
+        })
+    }
+
+    /// egress_request sends a request for `target` to the egress gateway over HBONE. The CONNECT
+    /// authority carries `target`, which the gateway connects to.
+    async fn egress_request(
+        &self,
+        source_workload: Workload,
+        target: SocketAddr,
+        egress: &EgressGateway,
+    ) -> Result<Request, Error> {
+        let gateway_addr = NetworkAddress {
+            network: source_workload.network.clone(),
+            address: egress.address,
+        };
+        let Some(gateway) = self.pi.state.fetch_workload(&gateway_addr).await else {
+            return Err(Error::UnknownEgressGateway(egress.address));
+        };
+        Ok(Request {
+            protocol: Protocol::HBONE,
+            source: source_workload,
+            destination: target,
+            destination_workload: None,
+            destination_service: None,
+            expected_identity: Some(gateway.identity()),
+            gateway: SocketAddr::new(egress.address, self.pi.hbone_port),
+            direction: Direction::Outbound,
+            request_type: RequestType::ToEgressGateway,
+            upstream_sans: vec![],
+            network_gateway: None,
+            connect_policy: ConnectPolicy::default(),
+        })
+    }
+
     async fn build_request(
         &self,
         downstream: IpAddr,
@@ -364,9 +953,20 @@ impl OutboundConnection {
         let us = self
             .pi
             .state
-            .fetch_upstream(&source_workload.network, target)
+            .fetch_upstream(
+                &source_workload.network,
+                Some(downstream),
+                target,
+                Some(Hairpin {
+                    client_uid: &source_workload.uid,
+                    policy: self.pi.cfg.service_hairpin_policy,
+                }),
+            )
             .await;
         if us.is_none() {
+            if let Some(egress) = self.egress_gateway(target.ip()) {
+                return self.egress_request(source_workload, target, egress).await;
+            }
             // For case no upstream found, passthrough it
             return Ok(Request {
                 protocol: Protocol::TCP,
@@ -379,10 +979,15 @@ impl OutboundConnection {
                 direction: Direction::Outbound,
                 request_type: RequestType::Passthrough,
                 upstream_sans: vec![],
+                network_gateway: None,
+                connect_policy: ConnectPolicy::default(),
             });
         }
 
         let mut mutable_us = us.unwrap();
+        if mutable_us.workload.uid == source_workload.uid {
+            debug!(destination=%target, "destination load balanced back to the source workload");
+        }
         let workload_ip = self
             .pi
             .state
@@ -427,6 +1032,8 @@ impl OutboundConnection {
                     direction: Direction::Inbound,
                     request_type: RequestType::ToServerWaypoint,
                     upstream_sans: mutable_us.sans,
+                    network_gateway: None,
+                    connect_policy: mutable_us.connect_policy,
                 });
             }
             // we expected the workload to have a waypoint, but could not find one
@@ -445,11 +1052,39 @@ impl OutboundConnection {
             return Err(Error::NoGatewayAddress(Box::new(us.workload)));
         }
 
+        // For case the upstream is on another network, and can only be reached through its gateway
+        if us.workload.network != source_workload.network {
+            if us.workload.protocol != Protocol::HBONE {
+                return Err(Error::NoValidDestination(Box::new(us.workload)));
+            }
+            let network_gateway = self.fetch_network_gateway(&us.workload).await?;
+            trace!(
+                network = us.workload.network,
+                gateway = %network_gateway.address,
+                "select {:?}",
+                RequestType::ViaNetworkGateway
+            );
+            return Ok(Request {
+                protocol: Protocol::HBONE,
+                source: source_workload,
+                destination: SocketAddr::from((workload_ip, us.port)),
+                destination_workload: Some(us.workload.clone()),
+                destination_service: us.destination_service.clone(),
+                expected_identity: Some(us.workload.identity()),
+                gateway: us
+                    .workload
+                    .gateway_address
+                    .expect("gateway address confirmed"),
+                direction: Direction::Outbound,
+                request_type: RequestType::ViaNetworkGateway,
+                upstream_sans: us.sans,
+                network_gateway: Some(network_gateway),
+                connect_policy: us.connect_policy,
+            });
+        }
+
         // For case source client and upstream server are on the same node
-        if !us.workload.node.is_empty()
-            && self.pi.cfg.local_node.as_ref() == Some(&us.workload.node) // looks weird but in Rust borrows can be compared and will behave the same as owned (https://doc.rust-lang.org/std/primitive.reference.html)
-            && us.workload.protocol == Protocol::HBONE
-        {
+        if self.on_local_node(&us.workload) && us.workload.protocol == Protocol::HBONE {
             trace!(
                 workload_node = us.workload.node,
                 local_node = self.pi.cfg.local_node,
@@ -471,10 +1106,12 @@ impl OutboundConnection {
                     self.pi.hbone_port,
                 )),
                 direction: Direction::Outbound,
-                // Sending to a node on the same node (ourselves).
-                // In the future this could be optimized to avoid a full network traversal.
+                // Sending to a node on the same node (ourselves). Rather than a full network
+                // traversal, this is handed to our inbound directly.
                 request_type: RequestType::DirectLocal,
                 upstream_sans: us.sans,
+                network_gateway: None,
+                connect_policy: us.connect_policy,
             });
         }
         // For case no waypoint for both side and direct to remote node proxy
@@ -492,18 +1129,104 @@ impl OutboundConnection {
             direction: Direction::Outbound,
             request_type: RequestType::Direct,
             upstream_sans: us.sans,
+            network_gateway: None,
+            connect_policy: us.connect_policy,
         })
     }
 }
 
+/// fail returns `e`, after telling the client why, if it is owed a reply.
+async fn fail(stream: &mut TcpStream, reply: Option<Reply>, e: Error) -> Result<(), Error> {
+    if let Some(reply) = reply {
+        // The client may be gone already; the error is what to report.
+        let _ = stream.write_all(&reply(Some(&e))).await;
+    }
+    Err(e)
+}
+
+/// succeed tells the client the connection is established, if it is owed a reply. A client that
+/// can't be told is gone, and the connection is recorded as failed when `connection_close` drops.
+async fn succeed(
+    stream: &mut TcpStream,
+    reply: Option<Reply>,
+    connection_close: &mut metrics::ConnectionGuard<'_>,
+) -> Result<(), Error> {
+    let Some(reply) = reply else {
+        return Ok(());
+    };
+    stream.write_all(&reply(None)).await.map_err(|e| {
+        let e = Error::Io(e);
+        connection_close.set_response_flags(e.response_flags());
+        e
+    })
+}
+
+/// is_loop returns whether `orig_dst` is the outbound listener at `listener`, on any of our
+/// addresses if it listens on all of them.
+fn is_loop(orig_dst: SocketAddr, listener: SocketAddr, local_ip: Option<IpAddr>) -> bool {
+    if orig_dst.port() != listener.port() {
+        return false;
+    }
+    let listener = socket::to_canonical(listener).ip();
+    orig_dst.ip() == listener
+        || (listener.is_unspecified()
+            && (orig_dst.ip().is_loopback() || Some(orig_dst.ip()) == local_ip))
+}
+
+/// connect_refused returns the error for a CONNECT that `peer` did not accept. Peers that do not
+/// say why, such as older versions or waypoints, only leave the status to go by.
+fn connect_refused<B>(peer: SocketAddr, response: &hyper::Response<B>) -> Error {
+    let code = response.status();
+    match Rejection::from_response(response) {
+        Some(rejection) => {
+            info!(%rejection, "CONNECT refused with {code}");
+            Error::ConnectRejected(peer, rejection)
+        }
+        None => Error::HttpStatus(code),
+    }
+}
+
+/// The gRPC statuses that point at the upstream rather than the request, and so count against its
+/// health: UNKNOWN, DEADLINE_EXCEEDED, INTERNAL, UNAVAILABLE and DATA_LOSS.
+const GRPC_UNHEALTHY_STATUSES: [u32; 5] = [2, 4, 13, 14, 15];
+
+/// grpc_unhealthy returns the gRPC status a waypoint reported for the upstream of a CONNECT, in a
+/// `grpc-status` header of its response, if that status is unhealthy.
+fn grpc_unhealthy<B>(response: &hyper::Response<B>) -> Option<u32> {
+    let status: u32 = response
+        .headers()
+        .get(GRPC_STATUS_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    GRPC_UNHEALTHY_STATUSES.contains(&status).then_some(status)
+}
+
+/// peer_metadata describes the peer that accepted a CONNECT, by the baggage it responded with.
+fn peer_metadata<B>(
+    response: &hyper::Response<B>,
+    identity: Option<Identity>,
+) -> metrics::DerivedWorkload {
+    let baggage =
+        parse_baggage_header(response.headers().get_all(BAGGAGE_HEADER)).unwrap_or_default();
+    metrics::DerivedWorkload::from_baggage(baggage, identity)
+}
+
+/// enforce_traffic_policy decides whether the transport selected for a request is permitted.
+/// Under the strict policy, plaintext is only allowed for passthrough traffic to destinations
+/// outside of the mesh; any known workload that cannot accept HBONE is rejected.
+fn enforce_traffic_policy(policy: OutboundTrafficPolicy, req: &Request) -> Result<(), Error> {
+    match (policy, req.protocol, &req.destination_workload) {
+        (OutboundTrafficPolicy::Strict, Protocol::TCP, Some(_)) => {
+            Err(Error::PlaintextNotAllowed(req.destination))
+        }
+        _ => Ok(()),
+    }
+}
+
 fn baggage(r: &Request, cluster: String) -> String {
-    format!("k8s.cluster.name={cluster},k8s.namespace.name={namespace},k8s.{workload_type}.name={workload_name},service.name={name},service.version={version}",
-            namespace = r.source.namespace,
-            workload_type = r.source.workload_type,
-            workload_name = r.source.workload_name,
-            name = r.source.canonical_name,
-            version = r.source.canonical_revision,
-    )
+    crate::baggage::encode(&r.source, &cluster)
 }
 
 #[derive(Debug)]
@@ -523,6 +1246,19 @@ struct Request {
     request_type: RequestType,
 
     upstream_sans: Vec<String>,
+
+    // The gateway into the destination's network, if it is on another network.
+    network_gateway: Option<NetworkGateway>,
+    // How connections to the destination are attempted, passed on to the next hop.
+    connect_policy: ConnectPolicy,
+}
+
+/// NetworkGateway is the east-west gateway of another network, which tunnels HBONE connections to
+/// the workloads on it.
+#[derive(Debug)]
+struct NetworkGateway {
+    address: SocketAddr,
+    identity: Identity,
 }
 
 #[derive(Debug)]
@@ -541,41 +1277,92 @@ enum RequestType {
     DirectLocal,
     /// Passthrough refers to requests with an unknown target
     Passthrough,
+    /// ToEgressGateway refers to requests for an external target, sent through the egress gateway
+    ToEgressGateway,
+    /// ViaNetworkGateway refers to requests to a pod on another network, tunneled through that
+    /// network's east-west gateway
+    ViaNetworkGateway,
 }
 
-pub async fn connect_tls(
+pub async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
     mut connector: ConnectConfiguration,
-    stream: TcpStream,
-) -> Result<tokio_boring::SslStream<TcpStream>, tokio_boring::HandshakeError<TcpStream>> {
+    stream: S,
+) -> Result<tokio_boring::SslStream<S>, tokio_boring::HandshakeError<S>> {
     connector.set_verify_hostname(false);
     connector.set_use_server_name_indication(false);
-    tokio_boring::connect(connector, "", stream).await
+    crate::tls::keyless::handshake(tokio_boring::connect(connector, "", stream)).await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::time::Duration;
 
     use bytes::Bytes;
 
     use super::*;
-    use crate::config::Config;
+    use crate::config::{Config, LiveConfig};
+    use crate::state::DemandProxyState;
     use crate::test_helpers::helpers::test_proxy_metrics;
-    use crate::test_helpers::new_proxy_state;
+    use crate::test_helpers::{new_proxy_state, test_default_workload};
     use crate::xds::istio::workload::NetworkAddress as XdsNetworkAddress;
+    use crate::xds::istio::workload::Port as XdsPort;
+    use crate::xds::istio::workload::PortList as XdsPortList;
+    use crate::xds::istio::workload::Service as XdsService;
     use crate::xds::istio::workload::TunnelProtocol as XdsProtocol;
     use crate::xds::istio::workload::Workload as XdsWorkload;
     use crate::{identity, xds};
 
+    fn test_outbound(cfg: Config, state: DemandProxyState) -> OutboundConnection {
+        OutboundConnection {
+            pi: ProxyInputs {
+                cert_manager: identity::mock::new_secret_manager(Duration::from_secs(10)),
+                state,
+                hbone_port: 15008,
+                live_cfg: LiveConfig::fixed(cfg.clone()),
+                pool: pool::Pool::new(LiveConfig::fixed(cfg.clone())),
+                cfg,
+                metrics: test_proxy_metrics(),
+                egress_hosts: Default::default(),
+                filters: Default::default(),
+            },
+            id: TraceParent::new(),
+            connection_id: ConnectionId::new(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_response_timeout() {
+        let cfg = Config {
+            connect_response_timeout: Duration::from_secs(1),
+            ..crate::config::parse_config().unwrap()
+        };
+        let outbound = test_outbound(cfg, new_proxy_state(&[], &[], &[]));
+        let peer = "127.0.0.2:15008".parse().unwrap();
+        let res = outbound.await_response(std::future::pending(), peer).await;
+        assert!(matches!(res, Err(Error::ConnectResponseTimeout(p)) if p == peer));
+    }
+
     async fn run_build_request(
         from: &str,
         to: &str,
         xds: XdsWorkload,
         expect: Option<ExpectedRequest<'_>>,
+    ) {
+        let cfg = crate::config::parse_config().unwrap();
+        run_build_request_with_config(cfg, from, to, xds, expect).await
+    }
+
+    async fn run_build_request_with_config(
+        cfg: Config,
+        from: &str,
+        to: &str,
+        xds: XdsWorkload,
+        expect: Option<ExpectedRequest<'_>>,
     ) {
         let cfg = Config {
             local_node: Some("local-node".to_string()),
-            ..crate::config::parse_config().unwrap()
+            ..cfg
         };
         let source = XdsWorkload {
             uid: "cluster1//v1/Pod/ns/source-workload".to_string(),
@@ -594,17 +1381,7 @@ mod tests {
             ..Default::default()
         };
         let state = new_proxy_state(&[source, waypoint, xds], &[], &[]);
-        let outbound = OutboundConnection {
-            pi: ProxyInputs {
-                cert_manager: identity::mock::new_secret_manager(Duration::from_secs(10)),
-                state,
-                hbone_port: 15008,
-                cfg,
-                metrics: test_proxy_metrics(),
-                pool: pool::Pool::new(),
-            },
-            id: TraceParent::new(),
-        };
+        let outbound = test_outbound(cfg, state);
 
         let req = outbound
             .build_request(from.parse().unwrap(), to.parse().unwrap())
@@ -645,6 +1422,132 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn build_request_egress_gateway() {
+        let cfg = Config {
+            egress_gateway: Some(EgressGateway {
+                address: "127.0.0.20".parse().unwrap(),
+                cidrs: vec!["1.2.3.0/24".parse().unwrap()],
+                hostnames: vec![],
+            }),
+            ..crate::config::parse_config().unwrap()
+        };
+        let gateway = XdsWorkload {
+            uid: "cluster1//v1/Pod/istio-egress/egress-gateway".to_string(),
+            name: "egress-gateway".to_string(),
+            namespace: "istio-egress".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 20])],
+            ..Default::default()
+        };
+        run_build_request_with_config(
+            cfg.clone(),
+            "127.0.0.1",
+            "1.2.3.4:443",
+            gateway.clone(),
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                destination: "1.2.3.4:443",
+                gateway: "127.0.0.20:15008",
+                request_type: RequestType::ToEgressGateway,
+            }),
+        )
+        .await;
+        // Other external destinations are still passed through.
+        run_build_request_with_config(
+            cfg,
+            "127.0.0.1",
+            "1.2.4.4:443",
+            gateway,
+            Some(ExpectedRequest {
+                protocol: Protocol::TCP,
+                destination: "1.2.4.4:443",
+                gateway: "1.2.4.4:443",
+                request_type: RequestType::Passthrough,
+            }),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn build_request_remote_network() {
+        let source = XdsWorkload {
+            uid: "cluster1//v1/Pod/ns/source-workload".to_string(),
+            name: "source-workload".to_string(),
+            namespace: "ns".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 1])],
+            ..Default::default()
+        };
+        let gateway = XdsWorkload {
+            uid: "cluster2//v1/Pod/istio-system/eastwest".to_string(),
+            name: "eastwest".to_string(),
+            namespace: "istio-system".to_string(),
+            network: "remote".to_string(),
+            service_account: "eastwest".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[10, 0, 0, 1])],
+            ..Default::default()
+        };
+        let remote = XdsWorkload {
+            uid: "cluster2//v1/Pod/ns/remote".to_string(),
+            name: "remote".to_string(),
+            namespace: "ns".to_string(),
+            network: "remote".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&[10, 0, 0, 2])],
+            tunnel_protocol: XdsProtocol::Hbone as i32,
+            network_gateway: Some(xds::istio::workload::GatewayAddress {
+                destination: Some(xds::istio::workload::gateway_address::Destination::Address(
+                    XdsNetworkAddress {
+                        network: "remote".to_string(),
+                        address: [10, 0, 0, 1].to_vec(),
+                    },
+                )),
+                port: 15443,
+            }),
+            services: HashMap::from([(
+                "ns/svc.ns.svc.cluster.local".to_string(),
+                XdsPortList {
+                    ports: vec![XdsPort {
+                        service_port: 80,
+                        target_port: 8080,
+                    }],
+                },
+            )]),
+            ..Default::default()
+        };
+        let svc = XdsService {
+            name: "svc".to_string(),
+            namespace: "ns".to_string(),
+            hostname: "svc.ns.svc.cluster.local".to_string(),
+            addresses: vec![XdsNetworkAddress {
+                network: "".to_string(),
+                address: [127, 0, 1, 1].to_vec(),
+            }],
+            ports: vec![XdsPort {
+                service_port: 80,
+                target_port: 8080,
+            }],
+            ..Default::default()
+        };
+        let state = new_proxy_state(&[source, gateway, remote], &[svc], &[]);
+        let outbound = test_outbound(crate::config::parse_config().unwrap(), state);
+
+        let req = outbound
+            .build_request(
+                "127.0.0.1".parse().unwrap(),
+                "127.0.1.1:80".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(req.request_type, RequestType::ViaNetworkGateway);
+        assert_eq!(req.destination.to_string(), "10.0.0.2:8080");
+        assert_eq!(req.gateway.to_string(), "10.0.0.2:15008");
+        let gw = req.network_gateway.unwrap();
+        assert_eq!(gw.address.to_string(), "10.0.0.1:15443");
+        assert_eq!(
+            gw.identity.to_string(),
+            "spiffe://cluster.local/ns/istio-system/sa/eastwest"
+        );
+    }
+
     #[tokio::test]
     async fn build_request_known_dest_remote_node_tcp() {
         run_build_request(
@@ -741,6 +1644,35 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn build_request_known_dest_local_ip_hbone() {
+        // A workload without a node, whose tunnel would be dialed back to ourselves.
+        let cfg = Config {
+            local_ip: Some("127.0.0.2".parse().unwrap()),
+            ..crate::config::parse_config().unwrap()
+        };
+        run_build_request_with_config(
+            cfg,
+            "127.0.0.1",
+            "127.0.0.2:80",
+            XdsWorkload {
+                uid: "cluster1//v1/WorkloadEntry/ns/test-vm".to_string(),
+                name: "test-vm".to_string(),
+                namespace: "ns".to_string(),
+                addresses: vec![Bytes::copy_from_slice(&[127, 0, 0, 2])],
+                tunnel_protocol: XdsProtocol::Hbone as i32,
+                ..Default::default()
+            },
+            Some(ExpectedRequest {
+                protocol: Protocol::HBONE,
+                destination: "127.0.0.2:80",
+                gateway: "127.0.0.2:15008",
+                request_type: RequestType::DirectLocal,
+            }),
+        )
+        .await;
+    }
+
     #[tokio::test]
     async fn build_request_unknown_source() {
         run_build_request(
@@ -815,6 +1747,103 @@ mod tests {
         .await;
     }
 
+    #[test]
+    fn traffic_policy() {
+        let req = |protocol, destination_workload| Request {
+            protocol,
+            direction: Direction::Outbound,
+            source: test_default_workload(),
+            destination: "127.0.0.2:80".parse().unwrap(),
+            destination_workload,
+            destination_service: None,
+            expected_identity: None,
+            gateway: "127.0.0.2:80".parse().unwrap(),
+            request_type: RequestType::Direct,
+            upstream_sans: vec![],
+            network_gateway: None,
+            connect_policy: ConnectPolicy::default(),
+        };
+        let permissive = OutboundTrafficPolicy::Permissive;
+        let strict = OutboundTrafficPolicy::Strict;
+        let known = Some(test_default_workload());
+
+        assert!(enforce_traffic_policy(permissive, &req(Protocol::TCP, known.clone())).is_ok());
+        assert!(enforce_traffic_policy(strict, &req(Protocol::HBONE, known.clone())).is_ok());
+        // Passthrough to destinations outside the mesh is not subject to the policy
+        assert!(enforce_traffic_policy(strict, &req(Protocol::TCP, None)).is_ok());
+        assert!(matches!(
+            enforce_traffic_policy(strict, &req(Protocol::TCP, known)),
+            Err(Error::PlaintextNotAllowed(_))
+        ));
+    }
+
+    #[test]
+    fn outbound_loop() {
+        let local = Some("10.0.0.1".parse().unwrap());
+        let any = "0.0.0.0:15001".parse().unwrap();
+        let loopback = "127.0.0.1:15001".parse().unwrap();
+        assert!(is_loop(loopback, loopback, local));
+        assert!(is_loop(loopback, any, local));
+        assert!(is_loop("10.0.0.1:15001".parse().unwrap(), any, local));
+        assert!(!is_loop("10.0.0.2:15001".parse().unwrap(), any, local));
+        assert!(!is_loop("127.0.0.1:8080".parse().unwrap(), loopback, local));
+        assert!(!is_loop("10.0.0.1:15001".parse().unwrap(), loopback, local));
+    }
+
+    #[test]
+    fn baggage_round_trip() {
+        let req = Request {
+            protocol: Protocol::HBONE,
+            direction: Direction::Outbound,
+            source: Workload {
+                namespace: "ns".to_string(),
+                workload_name: "app".to_string(),
+                service_account: "sa".to_string(),
+                canonical_name: "svc".to_string(),
+                canonical_revision: "v1".to_string(),
+                ..test_default_workload()
+            },
+            destination: "127.0.0.2:80".parse().unwrap(),
+            destination_workload: None,
+            destination_service: None,
+            expected_identity: None,
+            gateway: "127.0.0.2:15008".parse().unwrap(),
+            request_type: RequestType::Direct,
+            upstream_sans: vec![],
+            network_gateway: None,
+            connect_policy: ConnectPolicy::default(),
+        };
+        let mut headers = hyper::HeaderMap::new();
+        headers.append(
+            BAGGAGE_HEADER,
+            baggage(&req, "cluster1".to_string()).parse().unwrap(),
+        );
+        let parsed = parse_baggage_header(headers.get_all(BAGGAGE_HEADER)).unwrap();
+        assert_eq!(parsed.cluster_id.as_deref(), Some("cluster1"));
+        assert_eq!(parsed.namespace.as_deref(), Some("ns"));
+        assert_eq!(parsed.workload_name.as_deref(), Some("app"));
+        assert_eq!(parsed.service_account.as_deref(), Some("sa"));
+        assert_eq!(parsed.service_name.as_deref(), Some("svc"));
+        assert_eq!(parsed.revision.as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn grpc_health_signals() {
+        let response = |status: Option<&str>| {
+            let mut builder = hyper::Response::builder().status(200);
+            if let Some(status) = status {
+                builder = builder.header(GRPC_STATUS_HEADER, status);
+            }
+            builder.body(()).unwrap()
+        };
+        assert_eq!(grpc_unhealthy(&response(None)), None);
+        assert_eq!(grpc_unhealthy(&response(Some("0"))), None);
+        // Errors of the request itself say nothing about the upstream.
+        assert_eq!(grpc_unhealthy(&response(Some("3"))), None);
+        assert_eq!(grpc_unhealthy(&response(Some("14"))), Some(14));
+        assert_eq!(grpc_unhealthy(&response(Some("unavailable"))), None);
+    }
+
     #[derive(PartialEq, Debug)]
     struct ExpectedRequest<'a> {
         protocol: Protocol,
@@ -0,0 +1,360 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregation of the traffic ztunnel proxies by the identities at either end, for audits of
+//! which service accounts talk to each other. Counts are kept over a rolling window, split into
+//! [BUCKETS] slots so that old traffic ages out a slot at a time. Each side of a connection
+//! counts it, so a connection between two workloads of this node is counted once as outbound
+//! and once as inbound.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use drain::Watch;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::{RootCert, TrafficPairs as TrafficPairsConfig};
+use crate::identity::{AuthSource, CachedToken, Identity};
+use crate::proxy::metrics::{ConnectionOpen, DerivedWorkload, Reporter};
+use crate::state::workload::Workload;
+use crate::tls;
+use crate::xds::istio::telemetry::traffic::v1::traffic_report_service_client::TrafficReportServiceClient;
+use crate::xds::istio::telemetry::traffic::v1::{TrafficPair as XdsTrafficPair, TrafficReport};
+
+/// The number of slots the window is split into.
+const BUCKETS: u32 = 12;
+/// The number of locks the counts are spread across, by pair, so that connections counted at
+/// once seldom contend.
+const PAIR_SHARDS: usize = 16;
+/// How long a report may take before it is given up on, until the next interval.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pair is a source and destination identity, as seen from one end of their connections.
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pair {
+    /// `inbound` if the destination is a local workload, `outbound` if the source is.
+    pub direction: &'static str,
+    /// The SPIFFE identity of the client, if known.
+    pub source: Option<String>,
+    /// The SPIFFE identity of the server, if known.
+    pub destination: Option<String>,
+}
+
+#[derive(serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PairCounts {
+    pub connections: u64,
+    /// The bytes sent by the client.
+    pub source_bytes: u64,
+    /// The bytes sent by the server.
+    pub destination_bytes: u64,
+}
+
+impl PairCounts {
+    fn add(&mut self, other: &PairCounts) {
+        self.connections += other.connections;
+        self.source_bytes += other.source_bytes;
+        self.destination_bytes += other.destination_bytes;
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct PairDump {
+    #[serde(flatten)]
+    pub pair: Pair,
+    #[serde(flatten)]
+    pub counts: PairCounts,
+}
+
+/// TrafficPairs counts the traffic of each pair over the last window. Workers share the same
+/// counts, so a pair is counted as one whichever worker its connections land on. Each shard keeps
+/// the slots of its own pairs.
+#[derive(Clone)]
+pub struct TrafficPairs {
+    window: Duration,
+    shards: Arc<[Mutex<VecDeque<Bucket>>; PAIR_SHARDS]>,
+}
+
+struct Bucket {
+    start: Instant,
+    /// The counts of each pair, by the hash of its identities, so counting a pair seen before
+    /// does not allocate.
+    pairs: HashMap<u64, (Pair, PairCounts)>,
+}
+
+/// PeerIdentity is the trust domain, namespace and service account of a peer.
+type PeerIdentity<'a> = (&'a str, &'a str, &'a str);
+
+/// peer_identity returns the identity of a peer: that of its workload if it is known, or
+/// otherwise the one it authenticated as.
+fn peer_identity<'a>(
+    workload: Option<&'a Workload>,
+    derived: Option<&'a DerivedWorkload>,
+) -> Option<PeerIdentity<'a>> {
+    if let Some(w) = workload {
+        return Some((
+            w.trust_domain.as_str(),
+            w.namespace.as_str(),
+            w.service_account.as_str(),
+        ));
+    }
+    match derived.and_then(|d| d.identity.as_ref())? {
+        Identity::Spiffe {
+            trust_domain,
+            namespace,
+            service_account,
+        } => Some((
+            trust_domain.as_str(),
+            namespace.as_str(),
+            service_account.as_str(),
+        )),
+    }
+}
+
+fn spiffe((trust_domain, namespace, service_account): PeerIdentity) -> String {
+    Identity::Spiffe {
+        trust_domain: trust_domain.to_string(),
+        namespace: namespace.to_string(),
+        service_account: service_account.to_string(),
+    }
+    .to_string()
+}
+
+impl TrafficPairs {
+    pub fn new(window: Duration) -> TrafficPairs {
+        TrafficPairs {
+            window,
+            shards: Default::default(),
+        }
+    }
+
+    /// opened counts the connection `c`.
+    pub(super) fn opened(&self, c: &ConnectionOpen) {
+        self.add(
+            c,
+            PairCounts {
+                connections: 1,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// transferred counts the bytes of the connection `c`, once it is done.
+    pub(super) fn transferred(
+        &self,
+        c: &ConnectionOpen,
+        source_bytes: u64,
+        destination_bytes: u64,
+    ) {
+        self.add(
+            c,
+            PairCounts {
+                connections: 0,
+                source_bytes,
+                destination_bytes,
+            },
+        );
+    }
+
+    fn add(&self, c: &ConnectionOpen, counts: PairCounts) {
+        let direction = match c.reporter {
+            Reporter::source => "outbound",
+            Reporter::destination => "inbound",
+        };
+        let source = peer_identity(c.source.as_ref(), c.derived_source.as_ref());
+        let destination = peer_identity(c.destination.as_ref(), c.derived_destination.as_ref());
+        let mut hasher = DefaultHasher::new();
+        (direction, source, destination).hash(&mut hasher);
+        let key = hasher.finish();
+
+        let now = Instant::now();
+        let mut buckets = self.shards[key as usize % PAIR_SHARDS].lock().unwrap();
+        self.expire(&mut buckets, now);
+        let width = self.window / BUCKETS;
+        if buckets
+            .back()
+            .map_or(true, |b| now.duration_since(b.start) >= width)
+        {
+            buckets.push_back(Bucket {
+                start: now,
+                pairs: HashMap::new(),
+            });
+        }
+        let bucket = buckets.back_mut().expect("just pushed");
+        let (_, total) = bucket.pairs.entry(key).or_insert_with(|| {
+            let pair = Pair {
+                direction,
+                source: source.map(spiffe),
+                destination: destination.map(spiffe),
+            };
+            (pair, PairCounts::default())
+        });
+        total.add(&counts);
+    }
+
+    fn expire(&self, buckets: &mut VecDeque<Bucket>, now: Instant) {
+        while buckets
+            .front()
+            .map_or(false, |b| now.duration_since(b.start) >= self.window)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    /// dump returns the counts of every pair with traffic in the window, ordered by pair.
+    pub fn dump(&self) -> Vec<PairDump> {
+        let now = Instant::now();
+        let mut total: BTreeMap<Pair, PairCounts> = BTreeMap::new();
+        for shard in self.shards.iter() {
+            let mut buckets = shard.lock().unwrap();
+            self.expire(&mut buckets, now);
+            for bucket in buckets.iter() {
+                for (pair, counts) in bucket.pairs.values() {
+                    match total.get_mut(pair) {
+                        Some(total) => total.add(counts),
+                        None => {
+                            total.insert(pair.clone(), *counts);
+                        }
+                    }
+                }
+            }
+        }
+        total
+            .into_iter()
+            .map(|(pair, counts)| PairDump { pair, counts })
+            .collect()
+    }
+
+    /// report sends the counts to the control plane at `cfg.report_address` every interval, as
+    /// the given `node`, until ztunnel drains.
+    pub fn report(
+        &self,
+        cfg: &TrafficPairsConfig,
+        root_cert: RootCert,
+        auth: AuthSource,
+        node: String,
+        drain: Watch,
+    ) -> Result<(), tls::Error> {
+        let Some(address) = cfg.report_address.clone() else {
+            return Ok(());
+        };
+        let svc = tls::grpc_connector(address.clone(), root_cert)?;
+        let mut client = TrafficReportServiceClient::with_interceptor(svc, CachedToken::new(auth));
+        info!(%address, "reporting traffic pairs");
+        let pairs = self.clone();
+        let interval = cfg.report_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick is immediate, before there is anything to report.
+            interval.tick().await;
+            let mut drained = Box::pin(drain.signaled());
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = &mut drained => return,
+                }
+                let report = TrafficReport {
+                    node: node.clone(),
+                    window_seconds: pairs.window.as_secs() as u32,
+                    pairs: pairs.dump().into_iter().map(Into::into).collect(),
+                };
+                match tokio::time::timeout(REPORT_TIMEOUT, client.report(report)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => warn!(%address, "failed to report traffic pairs: {e}"),
+                    Err(_) => warn!(%address, "timed out reporting traffic pairs"),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl From<PairDump> for XdsTrafficPair {
+    fn from(d: PairDump) -> Self {
+        XdsTrafficPair {
+            source_identity: d.pair.source.unwrap_or_default(),
+            destination_identity: d.pair.destination.unwrap_or_default(),
+            direction: d.pair.direction.to_string(),
+            connections: d.counts.connections,
+            source_bytes: d.counts.source_bytes,
+            destination_bytes: d.counts.destination_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn(reporter: Reporter, source: &str, destination: &str) -> ConnectionOpen {
+        let workload = |sa: &str| Workload {
+            service_account: sa.to_string(),
+            namespace: "ns".to_string(),
+            ..crate::test_helpers::test_default_workload()
+        };
+        ConnectionOpen {
+            source: Some(workload(source)),
+            destination: Some(workload(destination)),
+            ..crate::test_helpers::test_connection_open(reporter)
+        }
+    }
+
+    #[test]
+    fn aggregate() {
+        let pairs = TrafficPairs::new(Duration::from_secs(60));
+        let client = conn(Reporter::source, "client", "server");
+        pairs.opened(&client);
+        pairs.transferred(&client, 10, 100);
+        pairs.opened(&client);
+        pairs.transferred(&client, 5, 50);
+        pairs.opened(&conn(Reporter::destination, "other", "server"));
+
+        let dump = pairs.dump();
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0].pair.direction, "inbound");
+        assert_eq!(
+            dump[1],
+            PairDump {
+                pair: Pair {
+                    direction: "outbound",
+                    source: Some(client.source.as_ref().unwrap().identity().to_string()),
+                    destination: Some(client.destination.as_ref().unwrap().identity().to_string()),
+                },
+                counts: PairCounts {
+                    connections: 2,
+                    source_bytes: 15,
+                    destination_bytes: 150,
+                },
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_expires() {
+        let pairs = TrafficPairs::new(Duration::from_secs(60));
+        pairs.opened(&conn(Reporter::source, "client", "server"));
+        tokio::time::advance(Duration::from_secs(30)).await;
+        pairs.opened(&conn(Reporter::source, "client", "server"));
+        assert_eq!(pairs.dump()[0].counts.connections, 2);
+        // The first connection ages out with its slot, and the second one with its own.
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(pairs.dump()[0].counts.connections, 1);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(pairs.dump().is_empty());
+    }
+}
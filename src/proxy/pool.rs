@@ -14,6 +14,7 @@
 
 use std::future::Future;
 use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use bytes::Bytes;
@@ -28,36 +29,107 @@ use hyper_util::client::pool;
 use hyper_util::client::pool::{Pool as HyperPool, Poolable, Pooled, Reservation};
 use tracing::debug;
 
+use crate::config::{Config, LiveConfig};
 use crate::identity::Identity;
-use crate::proxy::Error;
+use crate::proxy::{Error, Metrics};
 
 #[derive(Clone)]
 pub struct Pool {
-    pool: HyperPool<Client, Key>,
+    /// The pool connections are checked out from, and the limits it was built with.
+    current: Arc<RwLock<(Limits, HyperPool<Client, Key>)>>,
+    /// Where changes to the limits come from, if the pool is kept.
+    live_cfg: Option<LiveConfig>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Limits {
+    idle_timeout: Option<Duration>,
+    max_idle_per_host: usize,
+}
+
+impl Limits {
+    fn of(cfg: &Config) -> Limits {
+        Limits {
+            idle_timeout: Some(cfg.hbone_pool_idle_timeout),
+            max_idle_per_host: cfg.hbone_pool_max_idle,
+        }
+    }
+
+    fn pool(self) -> HyperPool<Client, Key> {
+        HyperPool::new(
+            hyper_util::client::pool::Config {
+                idle_timeout: self.idle_timeout,
+                max_idle_per_host: self.max_idle_per_host,
+            },
+            &hyper_util::Exec::Default,
+        )
+    }
 }
 
 impl Pool {
-    pub fn new() -> Pool {
+    /// new returns a Pool that shares connections, within the current pool limits of `live_cfg`.
+    pub fn new(live_cfg: LiveConfig) -> Pool {
+        let limits = Limits::of(&live_cfg.current());
         Self {
-            pool: HyperPool::new(
-                hyper_util::client::pool::Config {
-                    idle_timeout: Some(Duration::from_secs(90)),
-                    max_idle_per_host: std::usize::MAX,
-                },
-                &hyper_util::Exec::Default,
-            ),
+            current: Arc::new(RwLock::new((limits, limits.pool()))),
+            live_cfg: Some(live_cfg),
+        }
+    }
+
+    /// unpooled returns a Pool that establishes a new connection for every request, and never
+    /// shares or keeps them.
+    pub fn unpooled() -> Pool {
+        let limits = Limits {
+            idle_timeout: None,
+            max_idle_per_host: 0,
+        };
+        Self {
+            current: Arc::new(RwLock::new((limits, limits.pool()))),
+            live_cfg: None,
+        }
+    }
+
+    /// pool returns the pool to check connections out from. When the limits have changed, that is
+    /// a new one: the connections of the old pool finish the streams they carry, but are no longer
+    /// shared.
+    fn pool(&self) -> HyperPool<Client, Key> {
+        let Some(live_cfg) = &self.live_cfg else {
+            return self.current.read().unwrap().1.clone();
+        };
+        let limits = Limits::of(&live_cfg.current());
+        {
+            let current = self.current.read().unwrap();
+            if current.0 == limits {
+                return current.1.clone();
+            }
+        }
+        let mut current = self.current.write().unwrap();
+        if current.0 != limits {
+            debug!("connection pool limits changed, starting a new pool");
+            *current = (limits, limits.pool());
         }
+        current.1.clone()
+    }
+}
+
+/// TokioExec runs the tasks that drive pooled HBONE connections and their streams, each isolated,
+/// so a panic only fails that task.
+#[derive(Clone)]
+pub struct TokioExec(Arc<Metrics>);
+
+impl TokioExec {
+    pub fn new(metrics: Arc<Metrics>) -> TokioExec {
+        TokioExec(metrics)
     }
 }
 
-pub struct TokioExec;
 impl<F> hyper::rt::Executor<F> for TokioExec
 where
     F: std::future::Future + Send + 'static,
     F::Output: Send + 'static,
 {
     fn execute(&self, fut: F) {
-        tokio::spawn(fut);
+        tokio::spawn(super::supervise::isolate(self.0.clone(), fut));
     }
 }
 
@@ -106,17 +178,26 @@ impl Pool {
     where
         F: Future<Output = Result<http2::SendRequest<Empty<Bytes>>, Error>>,
     {
-        let reuse_connection = self.pool.checkout(key.clone());
+        let hyper_pool = self.pool();
+        if self.live_cfg.is_none() {
+            // A disabled pool always lets us connect, and never keeps the connection.
+            let connecting = hyper_pool
+                .connecting(&key, pool::Ver::Http2)
+                .expect("disabled pool is always connecting");
+            let pc = Client(connect.await?);
+            return Ok(Connection(hyper_pool.pooled(connecting, pc)));
+        }
+        let reuse_connection = hyper_pool.checkout(key.clone());
 
         let connect_pool = async {
             let ver = pool::Ver::Http2;
-            let Some(connecting) = self.pool.connecting(&key, ver) else {
+            let Some(connecting) = hyper_pool.connecting(&key, ver) else {
                 // There is already an existing connection establishment in flight.
                 // Return an error so
                 return Err(Error::PoolAlreadyConnecting)
             };
             let pc = Client(connect.await?);
-            let pooled = self.pool.pooled(connecting, pc);
+            let pooled = hyper_pool.pooled(connecting, pc);
             Ok::<_, Error>(pooled)
         };
         pin_mut!(connect_pool);
@@ -194,14 +275,18 @@ mod test {
                 });
             }
         });
-        let pool = Pool::new();
+        let pool = Pool::new(LiveConfig::fixed(
+            crate::config::construct_config(Default::default()).unwrap(),
+        ));
         let key = Key {
             src_id: Identity::default(),
             dst_id: vec![Identity::default()],
             dst: addr,
         };
         let connect = || async {
-            let builder = http2::Builder::new(TokioExec);
+            let builder = http2::Builder::new(TokioExec::new(Arc::new(Metrics::new(
+                &mut prometheus_client::registry::Registry::default(),
+            ))));
 
             let tcp_stream = TcpStream::connect(addr).await?;
             let (request_sender, connection) = builder.handshake(tcp_stream).await?;
@@ -229,5 +314,24 @@ mod test {
         assert_eq!(c1.send_request(req()).await.unwrap().status(), 200);
         assert_eq!(c1.send_request(req()).await.unwrap().status(), 200);
         assert_eq!(c2.send_request(req()).await.unwrap().status(), 200);
+
+        let pool = Pool::unpooled();
+        let key = Key {
+            src_id: Identity::default(),
+            dst_id: vec![Identity::default()],
+            dst: addr,
+        };
+        let connects = std::sync::atomic::AtomicUsize::new(0);
+        for _ in 0..2 {
+            let mut c = pool
+                .connect(key.clone(), async {
+                    connects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    connect().await
+                })
+                .await
+                .unwrap();
+            assert_eq!(c.send_request(req()).await.unwrap().status(), 200);
+        }
+        assert_eq!(connects.into_inner(), 2);
     }
 }
@@ -17,71 +17,62 @@ use byteorder::{BigEndian, ByteOrder};
 use drain::Watch;
 use std::net::{IpAddr, SocketAddr};
 
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, warn};
+use tracing::{error, info, warn, Instrument};
 
 use crate::proxy::outbound::OutboundConnection;
-use crate::proxy::{util, Error, ProxyInputs, TraceParent};
+use crate::proxy::{
+    connection_span, util, ConnectionId, Error, ProxyInputs, Rejection, TraceParent,
+};
 use crate::socket;
 
+// The reply codes of RFC 1928, section 6.
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const NETWORK_UNREACHABLE: u8 = 0x03;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const TTL_EXPIRED: u8 = 0x06;
+
 pub(super) struct Socks5 {
     pi: ProxyInputs,
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
     drain: Watch,
 }
 
 impl Socks5 {
     pub(super) async fn new(pi: ProxyInputs, drain: Watch) -> Result<Socks5, Error> {
-        let listener: TcpListener = TcpListener::bind(pi.cfg.socks5_addr)
-            .await
-            .map_err(|e| Error::Bind(pi.cfg.socks5_addr, e))?;
+        let listeners =
+            super::listen(&pi, pi.cfg.socks5_addr, &pi.cfg.listener_tuning.socks5).await?;
 
         info!(
-            address=%listener.local_addr().unwrap(),
+            address=%listeners[0].local_addr().unwrap(),
             component="socks5",
+            shards=listeners.len(),
             "listener established",
         );
 
         Ok(Socks5 {
             pi,
-            listener,
+            listeners,
             drain,
         })
     }
 
     pub(super) fn address(&self) -> SocketAddr {
-        self.listener.local_addr().unwrap()
+        self.listeners[0].local_addr().unwrap()
     }
 
     pub async fn run(self) {
-        let accept = async move {
-            loop {
-                // Asynchronously wait for an inbound socket.
-                let socket = self.listener.accept().await;
-                match socket {
-                    Ok((stream, remote)) => {
-                        info!("accepted outbound connection from {}", remote);
-                        let oc = OutboundConnection {
-                            pi: self.pi.clone(),
-                            id: TraceParent::new(),
-                        };
-                        tokio::spawn(async move {
-                            if let Err(err) = handle(oc, stream).await {
-                                log::error!("handshake error: {}", err);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        if util::is_runtime_shutdown(&e) {
-                            return;
-                        }
-                        error!("Failed TCP handshake {}", e);
-                    }
-                }
-            }
-        };
+        let pi = self.pi.clone();
+        let accept = super::supervise::supervise(
+            "socks5",
+            self.pi.metrics.clone(),
+            self.listeners,
+            move |listener| Self::accept(pi.clone(), listener).in_current_span(),
+        );
 
         tokio::select! {
             res = accept => { res }
@@ -90,6 +81,43 @@ impl Socks5 {
             }
         }
     }
+
+    async fn accept(pi: ProxyInputs, listener: TcpListener) {
+        loop {
+            // Asynchronously wait for an inbound socket.
+            let socket = listener.accept().await;
+            match socket {
+                Ok((stream, remote)) => {
+                    info!("accepted outbound connection from {}", remote);
+                    let oc = OutboundConnection {
+                        pi: pi.clone(),
+                        id: TraceParent::new_sampled(pi.cfg.trace_sampling_percentage),
+                        connection_id: ConnectionId::new(),
+                    };
+                    let span = connection_span!(
+                        "socks5",
+                        connection_id = %oc.connection_id,
+                        id = %oc.id,
+                        sampled = oc.id.is_sampled()
+                    );
+                    tokio::spawn(
+                        super::supervise::isolate(pi.metrics.clone(), async move {
+                            if let Err(err) = handle(oc, stream).await {
+                                log::error!("handshake error: {}", err);
+                            }
+                        })
+                        .instrument(span),
+                    );
+                }
+                Err(e) => {
+                    if util::is_runtime_shutdown(&e) {
+                        return;
+                    }
+                    error!("Failed TCP handshake {}", e);
+                }
+            }
+        }
+    }
 }
 
 // hande will process a SOCKS5 connection. This supports a minimal subset of the protocol,
@@ -97,6 +125,78 @@ impl Socks5 {
 // - only unauthenticated requests
 // - only CONNECT, with IPv4 or IPv6
 async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+    let host = read_request(&mut stream).await?;
+
+    let remote_addr = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
+
+    info!("accepted connection from {remote_addr} to {host}");
+    tokio::spawn(
+        super::supervise::isolate(oc.pi.metrics.clone(), async move {
+            // The reply waits for the upstream, so a client that cannot reach it learns why.
+            let res = oc
+                .proxy_to(stream, remote_addr.ip(), host, true, Some(reply))
+                .await;
+            match res {
+                Ok(_) => {}
+                Err(ref e) => warn!("outbound proxy failed: {}", e),
+            };
+        })
+        .in_current_span(),
+    );
+    Ok(())
+}
+
+/// reply encodes the reply to a CONNECT that succeeded, or failed with `err`. The bound address is
+/// not meaningful through a proxy, and clients generally ignore it, so it is left unspecified.
+fn reply(err: Option<&Error>) -> Vec<u8> {
+    vec![
+        0x05, // version
+        err.map_or(SUCCEEDED, reply_code),
+        0x00, // rsv
+        0x01,
+        0x00,
+        0x00,
+        0x00,
+        0x00, // IPv4
+        0x00,
+        0x00, // port
+    ]
+}
+
+/// reply_code returns the reply that describes `err` best, for a CONNECT that failed with it.
+fn reply_code(err: &Error) -> u8 {
+    match err {
+        Error::UnknownDestination(_)
+        | Error::NoValidDestination(_)
+        | Error::NoResolvedAddresses(_)
+        | Error::EmptyResolvedAddresses(_) => HOST_UNREACHABLE,
+        Error::UnknownNetworkGateway(_) | Error::NoGatewayAddress(_) => NETWORK_UNREACHABLE,
+        Error::SelfCall | Error::FilterDenied(_, _) | Error::PlaintextNotAllowed(_) => NOT_ALLOWED,
+        Error::ConnectTimeout(_)
+        | Error::HandshakeTimeout(_)
+        | Error::ConnectResponseTimeout(_) => TTL_EXPIRED,
+        Error::ConnectRejected(_, rejection) => match rejection {
+            Rejection::PolicyDenied | Rejection::WaypointBypassed => NOT_ALLOWED,
+            Rejection::UnknownDestination | Rejection::NotOnNode => HOST_UNREACHABLE,
+            Rejection::UpstreamRefused => CONNECTION_REFUSED,
+            Rejection::UpstreamTimeout => TTL_EXPIRED,
+            _ => GENERAL_FAILURE,
+        },
+        Error::Io(e) => match Rejection::upstream(e) {
+            Rejection::UpstreamRefused => CONNECTION_REFUSED,
+            Rejection::UpstreamTimeout => TTL_EXPIRED,
+            _ => GENERAL_FAILURE,
+        },
+        _ => GENERAL_FAILURE,
+    }
+}
+
+/// read_request negotiates authentication with a SOCKS5 client and reads its CONNECT request,
+/// returning the address the client wants to reach.
+pub async fn read_request<S>(stream: &mut S) -> Result<SocketAddr, anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     // Version(5), Number of auth methods
     let mut version = [0u8; 2];
     stream.read_exact(&mut version).await?;
@@ -174,26 +274,118 @@ async fn handle(mut oc: OutboundConnection, mut stream: TcpStream) -> Result<(),
     stream.read_exact(&mut port).await?;
     let port = BigEndian::read_u16(&port);
 
-    let host = SocketAddr::new(ip, port);
+    Ok(SocketAddr::new(ip, port))
+}
 
-    let remote_addr = socket::to_canonical(stream.peer_addr().expect("must receive peer addr"));
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
-    // Send dummy values - the client generally ignores it.
-    let buf = [
-        0x05u8, // versuib
-        0x00, 0x00, // success, rsv
-        0x01, 0x00, 0x00, 0x00, 0x00, // IPv4
-        0x00, 0x00, // port
-    ];
-    stream.write_all(&buf).await?;
+    use proptest::prelude::*;
 
-    info!("accepted connection from {remote_addr} to {host}");
-    tokio::spawn(async move {
-        let res = oc.proxy_to(stream, remote_addr.ip(), host, true).await;
-        match res {
-            Ok(_) => {}
-            Err(ref e) => warn!("outbound proxy failed: {}", e),
-        };
-    });
-    Ok(())
+    use super::*;
+
+    fn read(data: &[u8]) -> Result<SocketAddr> {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            // Leave room for the method selection reply too.
+            let (mut client, mut server) = tokio::io::duplex(data.len() + 2);
+            client.write_all(data).await?;
+            client.shutdown().await?;
+            read_request(&mut server).await
+        })
+    }
+
+    fn encode(addr: SocketAddr) -> Vec<u8> {
+        let mut req = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                req.push(0x01);
+                req.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                req.push(0x04);
+                req.extend(ip.octets());
+            }
+        }
+        req.extend(addr.port().to_be_bytes());
+        req
+    }
+
+    #[test]
+    fn connect_request() {
+        let v4 = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 8080);
+        assert_eq!(read(&encode(v4)).unwrap(), v4);
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+        assert_eq!(read(&encode(v6)).unwrap(), v6);
+
+        // Truncated, without the port.
+        let mut req = encode(v4);
+        req.truncate(req.len() - 2);
+        assert!(read(&req).is_err());
+        // Only username/password authentication.
+        assert!(read(&[0x05, 0x01, 0x02]).is_err());
+        // BIND rather than CONNECT.
+        let mut req = encode(v4);
+        req[4] = 0x02;
+        assert!(read(&req).is_err());
+        // Domain names are not resolved.
+        assert!(read(&[0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 0x01, b'a', 0x00, 0x50]).is_err());
+    }
+
+    #[test]
+    fn reply_codes() {
+        let addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 8080);
+        assert_eq!(reply(None)[1], SUCCEEDED);
+        let code = |e: Error| reply(Some(&e))[1];
+        assert_eq!(code(Error::UnknownDestination(addr.ip())), HOST_UNREACHABLE);
+        assert_eq!(
+            code(Error::ConnectRejected(addr, Rejection::UnknownDestination)),
+            HOST_UNREACHABLE
+        );
+        assert_eq!(
+            code(Error::UnknownNetworkGateway("net".to_string())),
+            NETWORK_UNREACHABLE
+        );
+        assert_eq!(
+            code(Error::ConnectRejected(addr, Rejection::PolicyDenied)),
+            NOT_ALLOWED
+        );
+        assert_eq!(code(Error::PlaintextNotAllowed(addr)), NOT_ALLOWED);
+        assert_eq!(code(Error::ConnectTimeout(addr)), TTL_EXPIRED);
+        assert_eq!(
+            code(Error::ConnectRejected(addr, Rejection::UpstreamTimeout)),
+            TTL_EXPIRED
+        );
+        assert_eq!(
+            code(Error::Io(std::io::ErrorKind::TimedOut.into())),
+            TTL_EXPIRED
+        );
+        assert_eq!(
+            code(Error::Io(std::io::ErrorKind::ConnectionRefused.into())),
+            CONNECTION_REFUSED
+        );
+        assert_eq!(
+            code(Error::ConnectRejected(addr, Rejection::UpstreamRefused)),
+            CONNECTION_REFUSED
+        );
+        assert_eq!(
+            code(Error::Io(std::io::ErrorKind::BrokenPipe.into())),
+            GENERAL_FAILURE
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn connect_roundtrip(addr: SocketAddr) {
+            prop_assert_eq!(read(&encode(addr)).unwrap(), addr);
+        }
+
+        #[test]
+        fn arbitrary_request(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = read(&data);
+        }
+    }
 }
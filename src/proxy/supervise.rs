@@ -0,0 +1,259 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Containment of the bugs that make ztunnel panic. A panic while handling a connection only
+//! terminates that connection, recorded with the PANIC response flag, rather than leaving it to
+//! die unaccounted for. An accept loop that panics is replaced by a new one on the same listener,
+//! so a bug in one connection's handling can't silently take the whole listener down.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tracing::{error, warn};
+
+use crate::proxy::Metrics;
+
+/// How long to wait before accepting from a listener again after its accept loop panicked, so a
+/// loop that panics right away does not spin.
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// Set while the state of a connection whose handling panicked is dropped.
+    static PANICKED: Cell<bool> = Cell::new(false);
+}
+
+/// panicked returns whether the connection being closed on this thread is terminated by a panic.
+pub(super) fn panicked() -> bool {
+    std::thread::panicking() || PANICKED.with(Cell::get)
+}
+
+/// isolate runs `conn`, the handling of a connection, terminating the connection if it panics.
+/// Returns the output of `conn`, unless it panicked.
+pub(super) async fn isolate<F: Future>(metrics: impl AsRef<Metrics>, conn: F) -> Option<F::Output> {
+    let mut conn = Box::pin(conn);
+    let panic = match AssertUnwindSafe(&mut conn).catch_unwind().await {
+        Ok(output) => return Some(output),
+        Err(panic) => panic,
+    };
+    metrics.as_ref().connection_panics.inc();
+    error!(
+        "panicked while handling the connection, terminating it: {}",
+        message(&*panic)
+    );
+    // The connection is closed, and recorded as closed, as its state is dropped.
+    PANICKED.with(|p| p.set(true));
+    let _reset = Reset;
+    drop(conn);
+    None
+}
+
+struct Reset;
+
+impl Drop for Reset {
+    fn drop(&mut self) {
+        PANICKED.with(|p| p.set(false));
+    }
+}
+
+/// supervise runs `accept` on each of `listeners`, running it again on a listener whose loop
+/// panicked. Each loop accepts from its own handle of the listener, as the handle of a loop that
+/// panicked is dropped with it. Returns once every loop has returned, which they only do on
+/// shutdown; dropping it aborts the loops, but not the connections they spawned.
+pub(super) async fn supervise<F, Fut>(
+    component: &'static str,
+    metrics: Arc<Metrics>,
+    listeners: Vec<TcpListener>,
+    accept: F,
+) where
+    F: Fn(TcpListener) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut shards = JoinSet::new();
+    // The listeners we keep, to hand to new loops.
+    let mut spares = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let shard = spares.len();
+        match handle(&listener) {
+            Ok(handle) => {
+                shards.spawn(run(shard, accept(handle)));
+                spares.push(Some(listener));
+            }
+            Err(e) => {
+                warn!(
+                    component,
+                    "accept loop not supervised, failed to duplicate its listener: {e}"
+                );
+                shards.spawn(run(shard, accept(listener)));
+                spares.push(None);
+            }
+        }
+    }
+    while let Some(res) = shards.join_next().await {
+        // Aborted loops are not restarted.
+        let Ok((shard, Err(panic))) = res else {
+            continue;
+        };
+        let Some(spare) = &spares[shard] else {
+            error!(
+                component,
+                "unsupervised accept loop panicked: {}",
+                message(&*panic)
+            );
+            continue;
+        };
+        error!(
+            component,
+            "accept loop panicked, restarting it: {}",
+            message(&*panic)
+        );
+        match handle(spare) {
+            Ok(listener) => {
+                metrics.accept_loop_restarts.inc();
+                let accept = accept(listener);
+                shards.spawn(run(shard, async move {
+                    tokio::time::sleep(RESTART_DELAY).await;
+                    accept.await
+                }));
+            }
+            Err(e) => error!(component, "failed to restart the accept loop: {e}"),
+        }
+    }
+}
+
+type Panic = Box<dyn Any + Send>;
+
+async fn run(shard: usize, accept: impl Future<Output = ()>) -> (usize, Result<(), Panic>) {
+    (shard, AssertUnwindSafe(accept).catch_unwind().await)
+}
+
+/// handle returns a new handle of `listener`, sharing its queue of connections, for an accept loop
+/// to own.
+fn handle(listener: &TcpListener) -> std::io::Result<TcpListener> {
+    let socket = socket2::SockRef::from(listener).try_clone()?;
+    TcpListener::from_std(socket.into())
+}
+
+fn message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use prometheus_client::registry::Registry;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::proxy::metrics::Reporter;
+
+    #[tokio::test]
+    async fn panicking_connection() {
+        let metrics = Arc::new(Metrics::new(&mut Registry::default()));
+        assert_eq!(isolate(metrics.clone(), async { 1 }).await, Some(1));
+        assert_eq!(metrics.connection_panics.get(), 0);
+
+        let observed = Arc::new(std::sync::Mutex::new(None));
+        struct Observe(Arc<std::sync::Mutex<Option<bool>>>);
+        impl Drop for Observe {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() = Some(panicked());
+            }
+        }
+        let guard = Observe(observed.clone());
+        let res = isolate(metrics.clone(), async move {
+            let _guard = guard;
+            tokio::task::yield_now().await;
+            panic!("bug");
+        })
+        .await;
+        assert_eq!(res, None::<()>);
+        assert_eq!(metrics.connection_panics.get(), 1);
+        assert_eq!(*observed.lock().unwrap(), Some(true));
+        assert!(!panicked());
+    }
+
+    #[tokio::test]
+    async fn close_after_panic() {
+        let mut registry = Registry::default();
+        let metrics = Arc::new(Metrics::new(&mut registry));
+        let conn = crate::test_helpers::test_connection_open(Reporter::destination);
+        let res = isolate(metrics.clone(), async {
+            let _connection_close = metrics.open_connection(&conn);
+            tokio::task::yield_now().await;
+            panic!("bug");
+        })
+        .await;
+        assert_eq!(res, None::<()>);
+
+        let mut buf = String::new();
+        prometheus_client::encoding::text::encode(&mut buf, &registry).unwrap();
+        assert!(
+            buf.lines().any(|l| l.contains("tcp_connections_closed")
+                && l.contains("response_flags=\"PANIC\"")
+                && l.ends_with(" 1")),
+            "{buf}"
+        );
+    }
+
+    #[tokio::test]
+    async fn restarts_accept_loop() {
+        let metrics = Arc::new(Metrics::new(&mut Registry::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let supervisor = tokio::spawn(supervise(
+            "test",
+            metrics.clone(),
+            vec![listener],
+            move |listener| {
+                let accepts = accepts.clone();
+                let tx = tx.clone();
+                async move {
+                    loop {
+                        let (socket, _) = listener.accept().await.unwrap();
+                        // The first connection hits a bug, which takes the loop down with it.
+                        if accepts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            panic!("bug");
+                        }
+                        tx.send(socket).await.unwrap();
+                    }
+                }
+            },
+        ));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+        let accepted = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap();
+        assert!(accepted.is_some());
+        assert_eq!(metrics.accept_loop_restarts.get(), 1);
+        supervisor.abort();
+    }
+}
@@ -37,6 +37,42 @@ pub struct Authorization {
     pub scope: RbacScope,
     pub action: RbacAction,
     pub rules: Vec<Vec<Vec<RbacMatch>>>,
+    /// If true, the policy is evaluated but never enforced.
+    #[serde(default)]
+    pub audit: bool,
+}
+
+/// The result of evaluating the authorization policies for a connection.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Decision {
+    pub allowed: bool,
+    /// Policies in audit mode that would have denied the connection had they been enforced.
+    pub audit_denials: Vec<String>,
+    /// The enforced rule the decision was made on. Unset when no policy applies, or none matched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<RuleMatch>,
+}
+
+impl Decision {
+    pub fn allow() -> Decision {
+        Decision {
+            allowed: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn deny() -> Decision {
+        Decision::default()
+    }
+}
+
+/// A rule of a policy that matched a connection.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct RuleMatch {
+    pub policy: String,
+    /// The index of the rule within the policy.
+    pub rule: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -75,8 +111,13 @@ impl Authorization {
         format!("{}/{}", self.namespace, self.name)
     }
 
-    #[instrument(level = "trace", skip_all, fields(policy=self.to_key()))]
     pub fn matches(&self, conn: &Connection) -> bool {
+        self.matching_rule(conn).is_some()
+    }
+
+    /// matching_rule returns the first rule of the policy that matches `conn`.
+    #[instrument(level = "trace", skip_all, fields(policy=self.to_key()))]
+    pub fn matching_rule(&self, conn: &Connection) -> Option<RuleMatch> {
         let id = conn
             .src_identity
             .as_ref()
@@ -91,11 +132,11 @@ impl Authorization {
             .unwrap_or_default();
         if self.rules.is_empty() {
             trace!(matches = false, "empty rules");
-            return false;
+            return None;
         }
         // An Authorization Policy can have multiple rules
         // If ANY rule matches it's a match...
-        for rule in self.rules.iter() {
+        for (index, rule) in self.rules.iter().enumerate() {
             // Rule typically has 1-3 clauses (from,to,when)
             // If ALL clauses match, it is a match...
             let mut rule_match = true;
@@ -161,10 +202,13 @@ impl Authorization {
             }
             trace!(matches = rule_match, "rule");
             if rule_match {
-                return true;
+                return Some(RuleMatch {
+                    policy: self.to_key(),
+                    rule: index,
+                });
             }
         }
-        false
+        None
     }
 
     #[instrument(name= "match", level = "trace", skip_all, fields(%desc))]
@@ -326,6 +370,7 @@ impl TryFrom<&XdsRbac> for Authorization {
             scope: RbacScope::try_from(xds::istio::security::Scope::from_i32(resource.scope))?,
             action: RbacAction::try_from(xds::istio::security::Action::from_i32(resource.action))?,
             rules,
+            audit: resource.audit,
         })
     }
 }
@@ -434,6 +479,7 @@ mod tests {
             scope: RbacScope::Global,
             action: RbacAction::Allow,
             rules,
+            audit: false,
         }
     }
 
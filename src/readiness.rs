@@ -13,15 +13,33 @@
 // limitations under the License.
 
 use crate::telemetry;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::sync::{Arc, Mutex};
-use tracing::info;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+mod file;
 mod server;
+pub use file::publish;
 pub use server::*;
 
+/// How often a [Heartbeat] reports in when driven by [Heartbeat::run].
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a [Heartbeat] may go without reporting before the process is considered not live.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Ready tracks whether the process is ready.
 #[derive(Clone, Debug, Default)]
-pub struct Ready(Arc<Mutex<HashSet<String>>>);
+pub struct Ready(Arc<ReadyInner>);
+
+#[derive(Debug, Default)]
+struct ReadyInner {
+    pending: Mutex<HashSet<String>>,
+    // Notified whenever a task completes
+    released: Notify,
+}
 
 impl Ready {
     pub fn new() -> Ready {
@@ -30,7 +48,7 @@ impl Ready {
 
     /// register_task allows a caller to add a dependency to be marked "ready".
     pub fn register_task(&self, name: &str) -> BlockReady {
-        self.0.lock().unwrap().insert(name.to_string());
+        self.0.pending.lock().unwrap().insert(name.to_string());
         BlockReady {
             parent: self.to_owned(),
             name: name.to_string(),
@@ -38,7 +56,31 @@ impl Ready {
     }
 
     pub fn pending(&self) -> HashSet<String> {
-        self.0.lock().unwrap().clone()
+        self.0.pending.lock().unwrap().clone()
+    }
+
+    /// wait_for completes once the named task no longer blocks readiness.
+    async fn wait_for(&self, name: &str) {
+        loop {
+            // Register interest before checking, so we cannot miss a release in between.
+            let released = self.0.released.notified();
+            let pending = self.0.pending.lock().unwrap().contains(name);
+            if !pending {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    /// wait_ready completes once no task blocks readiness.
+    pub async fn wait_ready(&self) {
+        loop {
+            let released = self.0.released.notified();
+            if self.0.pending.lock().unwrap().is_empty() {
+                return;
+            }
+            released.await;
+        }
     }
 }
 
@@ -52,11 +94,19 @@ impl BlockReady {
     pub fn subtask(&self, name: &str) -> BlockReady {
         self.parent.register_task(name)
     }
+
+    /// released returns a future that completes once this task no longer blocks readiness. This
+    /// allows a gate to depend on another gate, even after the [BlockReady] has been handed off.
+    pub fn released(&self) -> impl Future<Output = ()> + Send + 'static {
+        let parent = self.parent.clone();
+        let name = self.name.clone();
+        async move { parent.wait_for(&name).await }
+    }
 }
 
 impl Drop for BlockReady {
     fn drop(&mut self) {
-        let mut pending = self.parent.0.lock().unwrap();
+        let mut pending = self.parent.0.pending.lock().unwrap();
         let removed = pending.remove(&self.name);
         debug_assert!(removed); // It is a bug to somehow remove something twice
         let left = pending.len();
@@ -72,5 +122,110 @@ impl Drop for BlockReady {
                 self.name
             );
         }
+        drop(pending);
+        self.parent.0.released.notify_waiters();
+    }
+}
+
+/// Liveness tracks whether the process is live, based on heartbeats from long running tasks.
+#[derive(Clone, Debug, Default)]
+pub struct Liveness(Arc<Mutex<HashMap<String, Instant>>>);
+
+impl Liveness {
+    pub fn new() -> Liveness {
+        Liveness(Default::default())
+    }
+
+    /// register_heartbeat allows a caller to add a task that must regularly report in for the
+    /// process to be considered live.
+    pub fn register_heartbeat(&self, name: &str) -> Heartbeat {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Instant::now());
+        Heartbeat {
+            parent: self.to_owned(),
+            name: name.to_string(),
+        }
+    }
+
+    /// stalled returns all tasks which have not reported a heartbeat within the liveness timeout.
+    pub fn stalled(&self) -> HashSet<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, last)| last.elapsed() > LIVENESS_TIMEOUT)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// Heartbeat keeps the process live as long as it keeps beating. Dropping it unregisters the task.
+pub struct Heartbeat {
+    parent: Liveness,
+    name: String,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        if let Some(last) = self.parent.0.lock().unwrap().get_mut(&self.name) {
+            *last = Instant::now();
+        }
+    }
+
+    /// run beats forever. Spawned onto a runtime, it acts as a watchdog: if the runtime is
+    /// deadlocked or its workers are blocked, the beats stop and liveness fails.
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.beat();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if self.parent.0.lock().unwrap().remove(&self.name).is_none() {
+            warn!("heartbeat '{}' was already removed", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn released() {
+        let ready = Ready::new();
+        let first = ready.register_task("first");
+        let second = ready.register_task("second");
+        let first_released = tokio::spawn(first.released());
+
+        drop(second);
+        tokio::task::yield_now().await;
+        assert!(!first_released.is_finished());
+
+        drop(first);
+        first_released.await.unwrap();
+        assert!(ready.pending().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn liveness() {
+        let live = Liveness::new();
+        let beating = live.register_heartbeat("beating");
+        let stuck = live.register_heartbeat("stuck");
+        tokio::spawn(beating.run());
+        assert!(live.stalled().is_empty());
+
+        tokio::time::sleep(LIVENESS_TIMEOUT * 2).await;
+        assert_eq!(live.stalled(), HashSet::from(["stuck".to_string()]));
+
+        drop(stuck);
+        assert!(live.stalled().is_empty());
     }
 }
@@ -0,0 +1,89 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The readiness file is the handshake with the CNI plugin that redirects workload traffic to
+//! ztunnel. The file exists only while this process is ready: it is written once certificates are
+//! fetched and xDS is synced, and removed as soon as draining starts. Until it exists, the CNI is
+//! expected to hold or reject traffic of new pods, rather than redirect it to a proxy that cannot
+//! serve it yet, or let it bypass the proxy.
+
+use std::path::{Path, PathBuf};
+
+use drain::Watch;
+use tracing::{info, warn};
+
+use super::Ready;
+
+/// publish maintains the readiness file at `path` for the lifetime of the process.
+pub async fn publish(ready: Ready, path: PathBuf, drain: Watch) {
+    // A file left behind by a previous process that did not exit cleanly is stale.
+    remove(&path);
+    tokio::select! {
+        _ = ready.wait_ready() => {}
+        _ = drain.clone().signaled() => return,
+    }
+    match write(&path) {
+        Ok(()) => info!(path = %path.display(), "wrote readiness file"),
+        Err(e) => warn!(path = %path.display(), "failed to write readiness file: {e}"),
+    }
+    // Hold the drain until the file is gone, so no traffic is redirected to us once we exit.
+    let _release = drain.signaled().await;
+    remove(&path);
+}
+
+/// write creates the file with our pid atomically, so it is never seen partially written.
+fn write(path: &Path) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, format!("{}\n", std::process::id()))?;
+    std::fs::rename(&tmp, path)
+}
+
+fn remove(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => info!(path = %path.display(), "removed readiness file"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!(path = %path.display(), "failed to remove readiness file: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_file() {
+        let path = std::env::temp_dir().join(format!("ready-{}", rand::random::<u64>()));
+        std::fs::write(&path, "stale").unwrap();
+        let ready = Ready::new();
+        let task = ready.register_task("task");
+        let (drain_tx, drain_rx) = drain::channel();
+        let publisher = tokio::spawn(publish(ready, path.clone(), drain_rx));
+
+        tokio::task::yield_now().await;
+        assert!(!path.exists());
+
+        drop(task);
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        drain_tx.drain().await;
+        publisher.await.unwrap();
+        assert!(!path.exists());
+    }
+}
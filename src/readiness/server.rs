@@ -24,8 +24,13 @@ use itertools::Itertools;
 use crate::hyper_util;
 use crate::{config, readiness};
 
+struct State {
+    ready: readiness::Ready,
+    liveness: readiness::Liveness,
+}
+
 pub struct Server {
-    s: hyper_util::Server<readiness::Ready>,
+    s: hyper_util::Server<State>,
     ready: readiness::Ready,
 }
 
@@ -34,12 +39,16 @@ impl Server {
         config: config::Config,
         drain_rx: Watch,
         ready: readiness::Ready,
+        liveness: readiness::Liveness,
     ) -> anyhow::Result<Self> {
-        hyper_util::Server::<readiness::Ready>::bind(
+        hyper_util::Server::<State>::bind(
             "readiness",
             config.readiness_addr,
             drain_rx,
-            ready.clone(),
+            State {
+                ready: ready.clone(),
+                liveness,
+            },
         )
         .await
         .map(|s| Server { s, ready })
@@ -54,9 +63,10 @@ impl Server {
     }
 
     pub fn spawn(self) {
-        self.s.spawn(|ready, req| async move {
+        self.s.spawn(|state, req| async move {
             match req.uri().path() {
-                "/healthz/ready" => Ok(handle_ready(&ready, req).await),
+                "/healthz/ready" => Ok(handle_ready(&state.ready, req).await),
+                "/healthz/live" => Ok(handle_live(&state.liveness, req).await),
                 _ => Ok(hyper_util::empty_response(hyper::StatusCode::NOT_FOUND)),
             }
         })
@@ -81,3 +91,25 @@ async fn handle_ready(ready: &readiness::Ready, req: Request<Incoming>) -> Respo
         _ => hyper_util::empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
     }
 }
+
+async fn handle_live(
+    liveness: &readiness::Liveness,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    match *req.method() {
+        hyper::Method::GET => {
+            let stalled = liveness.stalled();
+            if stalled.is_empty() {
+                return hyper_util::plaintext_response(hyper::StatusCode::OK, "live\n".into());
+            }
+            hyper_util::plaintext_response(
+                hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                format!(
+                    "not live, stalled: {}\n",
+                    stalled.into_iter().sorted().join(", ")
+                ),
+            )
+        }
+        _ => hyper_util::empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
@@ -14,38 +14,304 @@
 
 use std::io::Error;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
-use tokio::io;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::net::TcpSocket;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+
+use crate::config::ListenerOptions;
+use crate::handoff;
 
 #[cfg(target_os = "linux")]
-use {
-    realm_io,
-    socket2::{Domain, SockRef},
-    std::io::ErrorKind,
-    tracing::warn,
-};
+use {realm_io, socket2::Domain};
 
 #[cfg(target_os = "linux")]
-pub fn set_transparent(l: &TcpListener) -> io::Result<()> {
-    SockRef::from(l).set_ip_transparent(true)
+mod linux;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+#[cfg(windows)]
+mod windows;
+
+/// Platform provides the socket options transparent proxying relies on, which each operating
+/// system supports differently, if at all.
+trait Platform {
+    /// set_transparent lets `l` accept connections addressed to any IP.
+    fn set_transparent(l: &TcpListener) -> io::Result<()>;
+    /// set_freebind_and_transparent lets `socket` bind to an IP that is not local, so it can
+    /// connect from the original source.
+    fn set_freebind_and_transparent(socket: &TcpSocket) -> io::Result<()>;
+    /// set_mark sets SO_MARK on `socket`, so routing rules can match the traffic sent from it.
+    fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()>;
+    /// orig_dst_addr returns the destination a connection redirected to us was addressed to.
+    fn orig_dst_addr(stream: &TcpStream) -> io::Result<SocketAddr>;
+    /// received_tos returns the TOS byte, or IPv6 traffic class, of the packet that opened
+    /// `stream`, which carries the QoS marking its client set.
+    fn received_tos(stream: &TcpStream) -> io::Result<u8>;
+    /// set_tos sets the TOS byte, or IPv6 traffic class, of the packets sent from `socket`.
+    fn set_tos(socket: &TcpSocket, tos: u8) -> io::Result<()>;
+    /// set_fast_open lets clients of `l` send data in their SYN, with at most `backlog` such
+    /// connections waiting to be accepted.
+    fn set_fast_open(l: &TcpListener, backlog: u32) -> io::Result<()>;
+    /// set_fast_open_connect has `socket` send its first write in the SYN, if the kernel holds a
+    /// cookie of the server. The SYN is held back until then.
+    fn set_fast_open_connect(socket: &TcpSocket) -> io::Result<()>;
 }
 
 #[cfg(target_os = "linux")]
+type Os = linux::Linux;
+#[cfg(windows)]
+type Os = windows::Windows;
+#[cfg(not(any(target_os = "linux", windows)))]
+type Os = Unsupported;
+
+/// Unsupported is the platform of operating systems without any transparent proxying support.
+#[cfg(not(any(target_os = "linux", windows)))]
+struct Unsupported;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl Platform for Unsupported {
+    fn set_transparent(_: &TcpListener) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "IP_TRANSPARENT not supported on this operating system",
+        ))
+    }
+
+    fn set_freebind_and_transparent(_: &TcpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "IP_TRANSPARENT and IP_FREEBIND are not supported on this operating system",
+        ))
+    }
+
+    fn set_mark(_: &TcpSocket, _: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SO_MARK is not supported on this operating system",
+        ))
+    }
+
+    fn orig_dst_addr(_: &TcpStream) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "SO_ORIGINAL_DST not supported on this operating system",
+        ))
+    }
+
+    fn received_tos(_: &TcpStream) -> io::Result<u8> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "reading the TOS of received packets is not supported on this operating system",
+        ))
+    }
+
+    fn set_tos(_: &TcpSocket, _: u8) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "setting the TOS is not supported on this operating system",
+        ))
+    }
+
+    fn set_fast_open(_: &TcpListener, _: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "TCP_FASTOPEN is not supported on this operating system",
+        ))
+    }
+
+    fn set_fast_open_connect(_: &TcpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "TCP_FASTOPEN_CONNECT is not supported on this operating system",
+        ))
+    }
+}
+
+/// enable_io_uring switches proxied connections to the io_uring data path, where the kernel
+/// supports it.
+pub fn enable_io_uring() {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    uring::enable();
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    tracing::warn!("io_uring requested, but not supported by this build; using epoll");
+}
+
+pub fn set_transparent(l: &TcpListener) -> io::Result<()> {
+    Os::set_transparent(l)
+}
+
 pub fn set_freebind_and_transparent(socket: &TcpSocket) -> io::Result<()> {
-    let socket = SockRef::from(socket);
-    match socket.domain()? {
-        Domain::IPV4 => {
-            socket.set_ip_transparent(true)?;
-            socket.set_freebind(true)?;
+    Os::set_freebind_and_transparent(socket)
+}
+
+/// set_mark sets SO_MARK on `socket`, so routing rules can match the traffic sent from it.
+pub fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+    Os::set_mark(socket, mark)
+}
+
+/// received_tos returns the TOS byte, or IPv6 traffic class, of the packet that opened `stream`.
+pub fn received_tos(stream: &TcpStream) -> io::Result<u8> {
+    Os::received_tos(stream)
+}
+
+/// set_tos sets the TOS byte, or IPv6 traffic class, of the packets sent from `socket`.
+pub fn set_tos(socket: &TcpSocket, tos: u8) -> io::Result<()> {
+    Os::set_tos(socket, tos)
+}
+
+/// set_fast_open_connect has `socket` send its first write in the SYN, if the kernel holds a
+/// cookie of the server. Nothing is sent until the first write, so it is only for protocols in
+/// which the client speaks first.
+pub fn set_fast_open_connect(socket: &TcpSocket) -> io::Result<()> {
+    Os::set_fast_open_connect(socket)
+}
+
+/// listen returns a listener for `addr`, reusing a socket inherited from a previous process if
+/// there is one. The listener is registered so it can be handed to our own replacement.
+pub async fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    listen_tcp(addr, addr, handoff::enabled(), &ListenerOptions::default())
+}
+
+/// listen_sharded returns `shards` listeners that share `addr` through SO_REUSEPORT, so the kernel
+/// spreads incoming connections across them. Each can then be accepted from on its own task.
+/// Where SO_REUSEPORT does not balance connections, a single listener is returned.
+/// If `shared` is set, SO_REUSEPORT is used even for a single shard, as other listeners in this
+/// process will bind the same address. Every listener is set up with `opts`.
+pub async fn listen_sharded(
+    addr: SocketAddr,
+    shards: usize,
+    shared: bool,
+    opts: &ListenerOptions,
+) -> io::Result<Vec<TcpListener>> {
+    if !cfg!(target_os = "linux") || (shards <= 1 && !shared) {
+        return Ok(vec![listen_tcp(addr, addr, handoff::enabled(), opts)?]);
+    }
+    let first = listen_tcp(addr, addr, true, opts)?;
+    // Resolve the port, in case we were asked for any port, so all shards share the same one.
+    let bound = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..shards {
+        listeners.push(listen_tcp(addr, bound, true, opts)?);
+    }
+    Ok(listeners)
+}
+
+// listen_tcp binds `bind` with `opts`, registering the socket for handoff under the configured
+// `addr`. A socket inherited from a previous process keeps the backlog it was listening with.
+fn listen_tcp(
+    addr: SocketAddr,
+    bind: SocketAddr,
+    reuseport: bool,
+    opts: &ListenerOptions,
+) -> io::Result<TcpListener> {
+    let listener = match handoff::take(handoff::Kind::Tcp, addr) {
+        Some(s) => {
+            let std: std::net::TcpListener = s.into();
+            std.set_nonblocking(true)?;
+            TcpListener::from_std(std)?
         }
-        Domain::IPV6 => {
-            linux::set_ipv6_transparent(&socket)?;
-            socket.set_freebind_ipv6(true)?
+        None => {
+            let socket = match bind {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            socket.set_reuseaddr(true)?;
+            if reuseport {
+                #[cfg(target_os = "linux")]
+                socket.set_reuseport(true)?;
+            }
+            socket.bind(bind)?;
+            socket.listen(opts.backlog)?
         }
-        _ => return Err(Error::new(ErrorKind::Unsupported, "unsupported domain")),
     };
+    tune(&listener, opts)?;
+    handoff::register(handoff::Kind::Tcp, addr, socket2::SockRef::from(&listener))?;
+    Ok(listener)
+}
+
+/// tune sets the options of `opts` on `listener`. Accepted connections inherit the buffer sizes
+/// and TCP_NODELAY of their listener.
+fn tune(listener: &TcpListener, opts: &ListenerOptions) -> io::Result<()> {
+    let socket = socket2::SockRef::from(listener);
+    if let Some(size) = opts.recv_buffer_size {
+        socket.set_recv_buffer_size(size as usize)?;
+    }
+    if let Some(size) = opts.send_buffer_size {
+        socket.set_send_buffer_size(size as usize)?;
+    }
+    if opts.nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(backlog) = opts.fast_open_backlog {
+        Os::set_fast_open(listener, backlog)?;
+    }
+    Ok(())
+}
+
+/// bind_udp is the [listen] equivalent for UDP sockets.
+pub async fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = match handoff::take(handoff::Kind::Udp, addr) {
+        Some(s) => {
+            let std: std::net::UdpSocket = s.into();
+            std.set_nonblocking(true)?;
+            UdpSocket::from_std(std)?
+        }
+        #[cfg(target_os = "linux")]
+        None if handoff::enabled() => {
+            let socket =
+                socket2::Socket::new(Domain::for_address(addr), socket2::Type::DGRAM, None)?;
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            UdpSocket::from_std(socket.into())?
+        }
+        None => UdpSocket::bind(addr).await?,
+    };
+    handoff::register(handoff::Kind::Udp, addr, socket2::SockRef::from(&socket))?;
+    Ok(socket)
+}
+
+/// bind_unix listens on a Unix socket at `path` that only our own user can connect to. A socket
+/// left at `path` by an earlier process is replaced, but anything else there is an error rather
+/// than being removed.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// check_unix_peer fails unless the process at the other end of `stream` runs as our user.
+#[cfg(unix)]
+pub fn check_unix_peer(stream: &tokio::net::UnixStream) -> io::Result<()> {
+    let cred = stream.peer_cred()?;
+    // Safety: geteuid cannot fail.
+    let uid = unsafe { libc::geteuid() };
+    if cred.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "peer pid {:?} runs as uid {}, not {uid}",
+                cred.pid(),
+                cred.uid()
+            ),
+        ));
+    }
     Ok(())
 }
 
@@ -64,94 +330,19 @@ pub fn to_canonical(addr: SocketAddr) -> SocketAddr {
     SocketAddr::from((ip, addr.port()))
 }
 
+/// reset_on_close makes closing `stream` reset the connection, so that the peer learns of it
+/// right away rather than after a graceful shutdown.
+pub fn reset_on_close(stream: &TcpStream) {
+    let _ = socket2::SockRef::from(stream).set_linger(Some(Duration::ZERO));
+}
+
 pub fn orig_dst_addr_or_default(stream: &tokio::net::TcpStream) -> SocketAddr {
-    to_canonical(match orig_dst_addr(stream) {
+    to_canonical(match Os::orig_dst_addr(stream) {
         Ok(addr) => addr,
         _ => stream.local_addr().expect("must get local address"),
     })
 }
 
-#[cfg(target_os = "linux")]
-fn orig_dst_addr(stream: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
-    let sock = SockRef::from(stream);
-    // Dual-stack IPv4/IPv6 sockets require us to check both options.
-    match linux::original_dst(&sock) {
-        Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
-        Err(e4) => match linux::original_dst_ipv6(&sock) {
-            Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
-            Err(e6) => {
-                if !sock.ip_transparent().unwrap_or(false) {
-                    // In TPROXY mode, this is normal, so don't bother logging
-                    warn!(
-                        peer=?stream.peer_addr().unwrap(),
-                        local=?stream.local_addr().unwrap(),
-                        "failed to read SO_ORIGINAL_DST: {e4:?}, {e6:?}"
-                    );
-                }
-                Err(e6)
-            }
-        },
-    }
-}
-
-#[cfg(not(target_os = "linux"))]
-fn orig_dst_addr(_: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "SO_ORIGINAL_DST not supported on this operating system",
-    ))
-}
-
-#[cfg(not(target_os = "linux"))]
-pub fn set_freebind_and_transparent(_: &TcpSocket) -> io::Result<()> {
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "IP_TRANSPARENT and IP_FREEBIND are not supported on this operating system",
-    ))
-}
-
-#[cfg(not(target_os = "linux"))]
-pub fn set_transparent(_: &TcpListener) -> io::Result<()> {
-    Err(io::Error::new(
-        io::ErrorKind::Other,
-        "IP_TRANSPARENT not supported on this operating system",
-    ))
-}
-
-#[cfg(target_os = "linux")]
-#[allow(unsafe_code)]
-mod linux {
-    use std::os::unix::io::AsRawFd;
-
-    use socket2::{SockAddr, SockRef};
-    use tokio::io;
-
-    pub fn set_ipv6_transparent(sock: &SockRef) -> io::Result<()> {
-        unsafe {
-            let optval: libc::c_int = 1;
-            let ret = libc::setsockopt(
-                sock.as_raw_fd(),
-                libc::IPPROTO_IPV6,
-                libc::IPV6_TRANSPARENT,
-                &optval as *const _ as *const libc::c_void,
-                std::mem::size_of_val(&optval) as libc::socklen_t,
-            );
-            if ret != 0 {
-                return Err(io::Error::last_os_error());
-            }
-        }
-        Ok(())
-    }
-
-    pub fn original_dst(sock: &SockRef) -> io::Result<SockAddr> {
-        sock.original_dst()
-    }
-
-    pub fn original_dst_ipv6(sock: &SockRef) -> io::Result<SockAddr> {
-        sock.original_dst_ipv6()
-    }
-}
-
 #[cfg(target_os = "linux")]
 pub async fn relay(
     downstream: &mut tokio::net::TcpStream,
@@ -159,10 +350,15 @@ pub async fn relay(
 ) -> Result<(u64, u64), Error> {
     const EINVAL: i32 = 22;
 
+    #[cfg(feature = "io-uring")]
+    if let Some(ring) = uring::ring() {
+        return uring::relay(&ring, downstream, upstream).await;
+    }
+
     match realm_io::bidi_zero_copy(downstream, upstream).await {
         Ok(d) => Ok(d),
         Err(ref e) if e.raw_os_error().map_or(false, |ec| ec == EINVAL) => {
-            tokio::io::copy_bidirectional(downstream, upstream).await
+            copy_bidirectional(downstream, upstream).await
         }
         Err(e) => Err(e),
     }
@@ -173,5 +369,123 @@ pub async fn relay(
     downstream: &mut tokio::net::TcpStream,
     upstream: &mut tokio::net::TcpStream,
 ) -> Result<(u64, u64), Error> {
-    tokio::io::copy_bidirectional(downstream, upstream).await
+    copy_bidirectional(downstream, upstream).await
+}
+
+const RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+async fn copy_bidirectional(
+    downstream: &mut tokio::net::TcpStream,
+    upstream: &mut tokio::net::TcpStream,
+) -> Result<(u64, u64), Error> {
+    let (mut dr, mut dw) = downstream.split();
+    let (mut ur, mut uw) = upstream.split();
+    tokio::try_join!(
+        copy_half(&mut dr, &mut uw, RELAY_BUFFER_SIZE),
+        copy_half(&mut ur, &mut dw, RELAY_BUFFER_SIZE)
+    )
+}
+
+/// copy_half copies `src` into `dst` until `src` reaches EOF, and then shuts down only the write
+/// side of `dst`. The opposite direction keeps flowing until it finishes too, as protocols relying
+/// on TCP half-close expect.
+pub async fn copy_half<R, W>(src: &mut R, dst: &mut W, buffer_size: usize) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut src = io::BufReader::with_capacity(buffer_size, src);
+    let n = io::copy_buf(&mut src, dst).await?;
+    shutdown_write(dst).await?;
+    Ok(n)
+}
+
+/// shutdown_write sends a FIN on `dst`. The peer may have closed the connection entirely by then,
+/// which is no reason to fail the direction that is still running.
+pub async fn shutdown_write<W: AsyncWrite + Unpin + ?Sized>(dst: &mut W) -> Result<(), Error> {
+    match dst.shutdown().await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotConnected => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_half_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        // The server only responds once the client has finished sending.
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut req = Vec::new();
+            s.read_to_end(&mut req).await.unwrap();
+            s.write_all(&req).await.unwrap();
+        });
+
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let front_addr = front.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut down, _) = front.accept().await.unwrap();
+            let mut up = TcpStream::connect(server_addr).await.unwrap();
+            relay(&mut down, &mut up).await.unwrap()
+        });
+
+        let mut client = TcpStream::connect(front_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).await.unwrap();
+        assert_eq!(resp, b"ping");
+        assert_eq!(proxy.await.unwrap(), (4, 4));
+        server.await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn listener_options() {
+        let opts = ListenerOptions {
+            nodelay: true,
+            fast_open_backlog: Some(16),
+            ..Default::default()
+        };
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listener = listen_tcp(addr, addr, false, &opts).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert!(accepted.nodelay().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bind_unix_replaces_only_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("ztunnel-socket-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        drop(bind_unix(&path).unwrap());
+
+        // The socket left behind is replaced.
+        let listener = bind_unix(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        check_unix_peer(&accepted).unwrap();
+
+        let file = dir.join("file");
+        std::fs::write(&file, "keep").unwrap();
+        assert_eq!(
+            bind_unix(&file).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
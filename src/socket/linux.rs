@@ -0,0 +1,193 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Linux redirects traffic to ztunnel with iptables, either by NAT (recording the original
+//! destination in SO_ORIGINAL_DST) or by TPROXY (keeping it as the local address).
+
+#![allow(unsafe_code)]
+
+use std::io::{self, Error, ErrorKind};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use socket2::{Domain, SockRef};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::warn;
+
+use super::Platform;
+
+// The options of the last packet received on a TCP socket, which libc does not expose
+// (linux/in.h and linux/in6.h).
+const IP_PKTOPTIONS: libc::c_int = 9;
+const IPV6_2292PKTOPTIONS: libc::c_int = 6;
+
+pub struct Linux;
+
+impl Platform for Linux {
+    fn set_transparent(l: &TcpListener) -> io::Result<()> {
+        SockRef::from(l).set_ip_transparent(true)
+    }
+
+    fn set_freebind_and_transparent(socket: &TcpSocket) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        match socket.domain()? {
+            Domain::IPV4 => {
+                socket.set_ip_transparent(true)?;
+                socket.set_freebind(true)?;
+            }
+            Domain::IPV6 => {
+                set_ipv6_transparent(&socket)?;
+                socket.set_freebind_ipv6(true)?
+            }
+            _ => return Err(Error::new(ErrorKind::Unsupported, "unsupported domain")),
+        };
+        Ok(())
+    }
+
+    fn set_mark(socket: &TcpSocket, mark: u32) -> io::Result<()> {
+        SockRef::from(socket).set_mark(mark)
+    }
+
+    fn orig_dst_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        let sock = SockRef::from(stream);
+        // Dual-stack IPv4/IPv6 sockets require us to check both options.
+        match sock.original_dst() {
+            Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
+            Err(e4) => match sock.original_dst_ipv6() {
+                Ok(addr) => Ok(addr.as_socket().expect("failed to convert to SocketAddr")),
+                Err(e6) => {
+                    if !sock.ip_transparent().unwrap_or(false) {
+                        // In TPROXY mode, this is normal, so don't bother logging
+                        warn!(
+                            peer=?stream.peer_addr().unwrap(),
+                            local=?stream.local_addr().unwrap(),
+                            "failed to read SO_ORIGINAL_DST: {e4:?}, {e6:?}"
+                        );
+                    }
+                    Err(e6)
+                }
+            },
+        }
+    }
+
+    fn received_tos(stream: &TcpStream) -> io::Result<u8> {
+        // The kernel keeps the TOS of the SYN of every accepted connection, and reports it in the
+        // packet options once asked to. Clients of IPv4 connect over IPv4, even to IPv6 listeners.
+        let sock = SockRef::from(stream);
+        let (level, recv, options, tos) = if super::to_canonical(stream.peer_addr()?).is_ipv4() {
+            (
+                libc::IPPROTO_IP,
+                libc::IP_RECVTOS,
+                IP_PKTOPTIONS,
+                libc::IP_TOS,
+            )
+        } else {
+            (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_RECVTCLASS,
+                IPV6_2292PKTOPTIONS,
+                libc::IPV6_TCLASS,
+            )
+        };
+        setsockopt(&sock, level, recv, 1)?;
+        // u64s, so the control messages are aligned.
+        let mut buf = [0u64; 32];
+        let mut len = mem::size_of_val(&buf) as libc::socklen_t;
+        unsafe {
+            let ret = libc::getsockopt(
+                sock.as_raw_fd(),
+                level,
+                options,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            );
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_control = buf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = len as _;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == level && (*cmsg).cmsg_type == tos {
+                    let data = libc::CMSG_DATA(cmsg);
+                    // IP_TOS is reported as a byte, IPV6_TCLASS as an int.
+                    return Ok(if level == libc::IPPROTO_IP {
+                        *data
+                    } else {
+                        std::ptr::read_unaligned(data as *const libc::c_int) as u8
+                    });
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NotFound,
+            "no TOS recorded for the connection",
+        ))
+    }
+
+    fn set_tos(socket: &TcpSocket, tos: u8) -> io::Result<()> {
+        let socket = SockRef::from(socket);
+        match socket.domain()? {
+            Domain::IPV4 => socket.set_tos(tos as u32),
+            Domain::IPV6 => setsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as _),
+            _ => Err(Error::new(ErrorKind::Unsupported, "unsupported domain")),
+        }
+    }
+
+    fn set_fast_open(l: &TcpListener, backlog: u32) -> io::Result<()> {
+        setsockopt(
+            &SockRef::from(l),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN,
+            backlog as _,
+        )
+    }
+
+    fn set_fast_open_connect(socket: &TcpSocket) -> io::Result<()> {
+        setsockopt(
+            &SockRef::from(socket),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            1,
+        )
+    }
+}
+
+fn set_ipv6_transparent(sock: &SockRef) -> io::Result<()> {
+    setsockopt(sock, libc::IPPROTO_IPV6, libc::IPV6_TRANSPARENT, 1)
+}
+
+fn setsockopt(
+    sock: &SockRef,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    unsafe {
+        let ret = libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,424 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! io_uring backed copy loops for proxied TCP connections.
+//!
+//! Each thread lazily creates one ring, which is shared by all connections whose copy loops run on
+//! it. A driver task waits on the ring's completion queue and wakes the operations that finished.
+//! If the kernel does not support io_uring, or any of the operations used, the ring is not created
+//! and callers fall back to the regular epoll based path.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use socket2::SockRef;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tracing::warn;
+
+const RING_ENTRIES: u32 = 256;
+const BUFFER_SIZE: usize = 16_384;
+/// user_data for entries whose completion nobody waits on.
+const IGNORED: u64 = u64::MAX;
+/// Set in the user_data of the poll an operation is linked behind, so it can be cancelled too.
+const POLL_BIT: u64 = 1 << 63;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static RING: once_cell::unsync::OnceCell<Option<Arc<Ring>>> = once_cell::unsync::OnceCell::new();
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// ring returns the io_uring for the current thread, or None if io_uring is disabled or not
+/// supported by the kernel.
+pub fn ring() -> Option<Arc<Ring>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    RING.with(|r| {
+        r.get_or_init(|| match Ring::new() {
+            Ok(ring) => {
+                let ring = Arc::new(ring);
+                tokio::spawn(ring.clone().drive());
+                Some(ring)
+            }
+            Err(e) => {
+                // No point in every thread trying again.
+                ENABLED.store(false, Ordering::Relaxed);
+                warn!("io_uring is not available, falling back to epoll: {e}");
+                None
+            }
+        })
+        .clone()
+    })
+}
+
+pub struct Ring {
+    // Declared first, so it is deregistered before the ring is closed.
+    fd: AsyncFd<RawFd>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    uring: IoUring,
+    next_id: u64,
+    ops: HashMap<u64, Op>,
+}
+
+struct Op {
+    // The buffer the kernel reads from or writes to. It lives here, rather than in the future
+    // waiting on the operation, so it cannot be freed while the kernel may still use it.
+    buf: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    Waiting(Option<Waker>),
+    Done(i32),
+    // The waiting future was dropped; the operation is being cancelled.
+    Abandoned,
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let uring = IoUring::new(RING_ENTRIES)?;
+        // Kernels that have io_uring may still lack some of the operations the copy loops need.
+        let mut probe = Probe::new();
+        uring.submitter().register_probe(&mut probe)?;
+        for (name, code) in [
+            ("PollAdd", opcode::PollAdd::CODE),
+            ("Recv", opcode::Recv::CODE),
+            ("Send", opcode::Send::CODE),
+            ("AsyncCancel", opcode::AsyncCancel::CODE),
+        ] {
+            if !probe.is_supported(code) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("io_uring does not support {name}"),
+                ));
+            }
+        }
+        let fd = AsyncFd::with_interest(uring.as_raw_fd(), Interest::READABLE)?;
+        Ok(Ring {
+            fd,
+            inner: Mutex::new(Inner {
+                uring,
+                next_id: 0,
+                ops: HashMap::new(),
+            }),
+        })
+    }
+
+    async fn drive(self: Arc<Self>) {
+        loop {
+            match self.fd.readable().await {
+                // Clear first, so completions that arrive while reaping wake us again.
+                Ok(mut guard) => guard.clear_ready(),
+                Err(e) => {
+                    warn!("io_uring driver failed: {e}");
+                    return;
+                }
+            }
+            self.inner.lock().unwrap().reap();
+        }
+    }
+
+    /// submit waits for `fd` to be ready for `events`, then runs the operation built by `op`
+    /// against `buf`.
+    fn submit(
+        &self,
+        fd: RawFd,
+        events: libc::c_short,
+        mut buf: Vec<u8>,
+        op: impl FnOnce(types::Fd, &mut Vec<u8>) -> squeue::Entry,
+    ) -> io::Result<Completion<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id = (inner.next_id + 1) % (POLL_BIT - 1);
+        // Sockets are non-blocking, so the kernel fails the operation rather than waiting for
+        // readiness itself. Link it behind a poll instead.
+        let entries = [
+            opcode::PollAdd::new(types::Fd(fd), events as u32)
+                .build()
+                .flags(squeue::Flags::IO_LINK)
+                .user_data(id | POLL_BIT),
+            op(types::Fd(fd), &mut buf).user_data(id),
+        ];
+        inner.ops.insert(
+            id,
+            Op {
+                buf,
+                state: State::Waiting(None),
+            },
+        );
+        // Safety: the buffer the entry refers to is kept in `ops` until the kernel reports the
+        // operation complete, and moving the Vec does not move its heap allocation.
+        let submitted = unsafe {
+            let mut res = Ok(());
+            while res.is_ok() && inner.uring.submission().push_multiple(&entries).is_err() {
+                res = inner.uring.submit().map(|_| ());
+            }
+            res.and_then(|_| inner.uring.submit().map(|_| ()))
+        };
+        if let Err(e) = submitted {
+            // The entries may still be queued, so leave the buffer for the completion to free.
+            if let Some(op) = inner.ops.get_mut(&id) {
+                op.state = State::Abandoned;
+            }
+            return Err(e);
+        }
+        Ok(Completion { ring: self, id })
+    }
+
+    /// recv reads from `stream` into `buf`, returning how much was read along with the buffer.
+    pub async fn recv(&self, stream: &TcpStream, mut buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        loop {
+            let op = self.submit(stream.as_raw_fd(), libc::POLLIN, buf, |fd, buf| {
+                opcode::Recv::new(fd, buf.as_mut_ptr(), buf.len() as u32).build()
+            });
+            let (res, b) = match op {
+                Ok(op) => op.await,
+                Err(e) => return (Err(e), Vec::new()),
+            };
+            match result(res) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => buf = b,
+                res => return (res, b),
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        stream: &TcpStream,
+        mut buf: Vec<u8>,
+        start: usize,
+        end: usize,
+    ) -> (io::Result<usize>, Vec<u8>) {
+        loop {
+            let op = self.submit(stream.as_raw_fd(), libc::POLLOUT, buf, |fd, buf| {
+                opcode::Send::new(fd, buf[start..end].as_ptr(), (end - start) as u32).build()
+            });
+            let (res, b) = match op {
+                Ok(op) => op.await,
+                Err(e) => return (Err(e), Vec::new()),
+            };
+            match result(res) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => buf = b,
+                res => return (res, b),
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn reap(&mut self) {
+        let completed: Vec<(u64, i32)> = self
+            .uring
+            .completion()
+            .map(|c| (c.user_data(), c.result()))
+            .collect();
+        for (id, res) in completed {
+            let Some(op) = self.ops.get_mut(&id) else {
+                continue;
+            };
+            match std::mem::replace(&mut op.state, State::Done(res)) {
+                State::Waiting(waker) => {
+                    if let Some(w) = waker {
+                        w.wake();
+                    }
+                }
+                // Nobody wants the result; this frees the buffer.
+                State::Abandoned => {
+                    self.ops.remove(&id);
+                }
+                State::Done(_) => {}
+            }
+        }
+    }
+}
+
+fn result(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// Completion resolves to the result of a submitted operation and its buffer.
+struct Completion<'a> {
+    ring: &'a Ring,
+    id: u64,
+}
+
+impl Future for Completion<'_> {
+    type Output = (i32, Vec<u8>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.ring.inner.lock().unwrap();
+        let op = inner.ops.get_mut(&self.id).expect("operation must exist");
+        match op.state {
+            State::Done(res) => {
+                let op = inner.ops.remove(&self.id).unwrap();
+                Poll::Ready((res, op.buf))
+            }
+            _ => {
+                op.state = State::Waiting(Some(cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Completion<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.ring.inner.lock().unwrap();
+        let Some(op) = inner.ops.get_mut(&self.id) else {
+            // Already completed and returned.
+            return;
+        };
+        if let State::Done(_) = op.state {
+            inner.ops.remove(&self.id);
+            return;
+        }
+        op.state = State::Abandoned;
+        // The operation may still be waiting behind its poll, which holds the socket open, so
+        // cancel both.
+        let cancel = [
+            opcode::AsyncCancel::new(self.id | POLL_BIT)
+                .build()
+                .user_data(IGNORED),
+            opcode::AsyncCancel::new(self.id).build().user_data(IGNORED),
+        ];
+        // Safety: cancellation does not reference any memory.
+        unsafe {
+            if inner.uring.submission().push_multiple(&cancel).is_err() {
+                let _ = inner.uring.submit();
+                let _ = inner.uring.submission().push_multiple(&cancel);
+            }
+        }
+        let _ = inner.uring.submit();
+    }
+}
+
+/// relay copies data between the two streams in both directions until both reach EOF, returning
+/// the bytes sent from downstream to upstream and back.
+pub async fn relay(
+    ring: &Ring,
+    downstream: &TcpStream,
+    upstream: &TcpStream,
+) -> io::Result<(u64, u64)> {
+    tokio::try_join!(
+        copy(ring, downstream, upstream),
+        copy(ring, upstream, downstream)
+    )
+}
+
+async fn copy(ring: &Ring, src: &TcpStream, dst: &TcpStream) -> io::Result<u64> {
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let (res, b) = ring.recv(src, buf).await;
+        let n = res?;
+        if n == 0 {
+            shutdown_write(dst)?;
+            return Ok(total);
+        }
+        buf = send_all(ring, dst, b, n).await?;
+        total += n as u64;
+    }
+}
+
+/// shutdown_write sends a FIN on `dst`, leaving the other direction of the connection open.
+pub fn shutdown_write(dst: &TcpStream) -> io::Result<()> {
+    match SockRef::from(dst).shutdown(Shutdown::Write) {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        res => res,
+    }
+}
+
+/// send_all sends the first `n` bytes of `buf` on `dst`, handing the buffer back once they are.
+pub async fn send_all(
+    ring: &Ring,
+    dst: &TcpStream,
+    mut buf: Vec<u8>,
+    n: usize,
+) -> io::Result<Vec<u8>> {
+    let mut written = 0;
+    while written < n {
+        let (res, b) = ring.send(dst, buf, written, n).await;
+        buf = b;
+        match res? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            w => written += w,
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn relay_round_trip() {
+        enable();
+        let Some(ring) = ring() else {
+            // Not supported by this kernel; the fallback path is covered elsewhere.
+            return;
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let echo = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = s.split();
+            tokio::io::copy(&mut r, &mut w).await.unwrap();
+        });
+
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let front_addr = front.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (down, _) = front.accept().await.unwrap();
+            let up = TcpStream::connect(server).await.unwrap();
+            relay(&ring, &down, &up).await.unwrap()
+        });
+
+        let mut client = TcpStream::connect(front_addr).await.unwrap();
+        let payload = vec![7u8; 3 * BUFFER_SIZE + 5];
+        client.write_all(&payload).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+        let n = payload.len() as u64;
+        assert_eq!(proxy.await.unwrap(), (n, n));
+        echo.await.unwrap();
+    }
+}
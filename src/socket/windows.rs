@@ -0,0 +1,105 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Windows has no transparent sockets. Instead, traffic is redirected to ztunnel by a Windows
+//! Filtering Platform (WFP) callout driver, which attaches the original destination, as a
+//! `SOCKADDR_STORAGE`, to each connection it redirects as the connection's redirect context.
+//! Listener handoff is not available either, so every process binds its own listeners.
+
+#![allow(unsafe_code)]
+
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::windows::io::AsRawSocket;
+use std::ptr;
+
+use socket2::SockAddr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use windows_sys::Win32::Networking::WinSock::{
+    WSAIoctl, SIO_QUERY_WFP_CONNECTION_REDIRECT_CONTEXT, SOCKADDR_STORAGE, SOCKET, SOCKET_ERROR,
+};
+
+use super::Platform;
+
+pub struct Windows;
+
+impl Platform for Windows {
+    fn set_transparent(_: &TcpListener) -> io::Result<()> {
+        Err(unsupported("transparent sockets are"))
+    }
+
+    fn set_freebind_and_transparent(_: &TcpSocket) -> io::Result<()> {
+        Err(unsupported("binding to non-local addresses is"))
+    }
+
+    fn set_mark(_: &TcpSocket, _: u32) -> io::Result<()> {
+        Err(unsupported("SO_MARK is"))
+    }
+
+    fn received_tos(_: &TcpStream) -> io::Result<u8> {
+        Err(unsupported("reading the TOS of received packets is"))
+    }
+
+    fn set_tos(_: &TcpSocket, _: u8) -> io::Result<()> {
+        // Windows ignores IP_TOS unless a registry override is set; QoS is set by policy instead.
+        Err(unsupported("setting the TOS is"))
+    }
+
+    fn set_fast_open(_: &TcpListener, _: u32) -> io::Result<()> {
+        Err(unsupported("TCP_FASTOPEN is"))
+    }
+
+    fn set_fast_open_connect(_: &TcpSocket) -> io::Result<()> {
+        Err(unsupported("TCP_FASTOPEN_CONNECT is"))
+    }
+
+    fn orig_dst_addr(stream: &TcpStream) -> io::Result<SocketAddr> {
+        let (_, addr) = unsafe {
+            SockAddr::try_init(|storage, len| {
+                let mut returned = 0;
+                let ret = WSAIoctl(
+                    stream.as_raw_socket() as SOCKET,
+                    SIO_QUERY_WFP_CONNECTION_REDIRECT_CONTEXT,
+                    ptr::null(),
+                    0,
+                    storage as *mut c_void,
+                    mem::size_of::<SOCKADDR_STORAGE>() as u32,
+                    &mut returned,
+                    ptr::null_mut(),
+                    None,
+                );
+                if ret == SOCKET_ERROR {
+                    return Err(io::Error::last_os_error());
+                }
+                *len = returned as _;
+                Ok(())
+            })?
+        };
+        addr.as_socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "redirect context is not an IP address",
+            )
+        })
+    }
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{what} not supported on Windows"),
+    )
+}
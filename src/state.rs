@@ -12,12 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::config::HairpinPolicy;
 use crate::identity::SecretManager;
 use crate::proxy;
 use crate::proxy::{Error, OnDemandDnsLabels};
-use crate::state::policy::PolicyStore;
-use crate::state::service::ServiceDescription;
-use crate::state::service::ServiceStore;
+use crate::state::connections::WorkloadConnections;
+use crate::state::names::NameTableStore;
+use crate::state::outlier::OutlierDetector;
+use crate::state::policy::{PolicyStore, PolicySync};
+use crate::state::service::{ConnectPolicy, Endpoint, ServiceStore, SessionAffinity};
+use crate::state::service::{Service, ServiceDescription};
 use crate::state::workload::{
     address::Address, gatewayaddress::Destination, network_addr, NamespacedHostname,
     NetworkAddress, Protocol, WaypointError, Workload, WorkloadStore,
@@ -25,6 +29,7 @@ use crate::state::workload::{
 use crate::xds::metrics::Metrics;
 use crate::xds::{AdsClient, Demander, LocalClient, ProxyStateUpdater};
 use crate::{cert_fetcher, config, rbac, readiness, xds};
+use anyhow::Context;
 use rand::prelude::IteratorRandom;
 use rand::seq::SliceRandom;
 use std::collections::{HashMap, HashSet};
@@ -32,12 +37,17 @@ use std::convert::Into;
 use std::default::Default;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use tracing::{debug, trace, warn};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, error, info, trace, warn};
 
 use trust_dns_resolver::config::*;
 use trust_dns_resolver::{TokioAsyncResolver, TokioHandle};
 
+pub mod connections;
+pub mod names;
+pub mod outlier;
 pub mod policy;
 pub mod service;
 pub mod workload;
@@ -48,6 +58,15 @@ pub struct Upstream {
     pub port: u16,
     pub sans: Vec<String>,
     pub destination_service: Option<ServiceDescription>,
+    pub connect_policy: ConnectPolicy,
+}
+
+/// Hairpin describes a client that may be an endpoint of the service it connects to.
+#[derive(Debug, Clone, Copy)]
+pub struct Hairpin<'a> {
+    /// The UID of the client workload.
+    pub client_uid: &'a str,
+    pub policy: HairpinPolicy,
 }
 
 impl fmt::Display for Upstream {
@@ -82,6 +101,15 @@ pub struct ProxyState {
 
     #[serde(flatten)]
     pub resolved_dns: ResolvedDnsStore,
+
+    #[serde(flatten)]
+    pub names: NameTableStore,
+
+    #[serde(skip_serializing)]
+    pub connections: WorkloadConnections,
+
+    /// The endpoints currently ejected from load balancing, with how long for.
+    pub outliers: OutlierDetector,
 }
 
 /// A ResolvedDnsStore encapsulates all resolved DNS information for workloads in the mesh
@@ -106,6 +134,64 @@ pub struct ResolvedDns {
     dns_refresh_rate: std::time::Duration,
 }
 
+/// How long a resource that could not be found on-demand is remembered as unknown.
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(10);
+/// The maximum number of unknown resources remembered at once.
+const NEGATIVE_CACHE_CAPACITY: usize = 1024;
+
+/// NegativeCache remembers on-demand lookups that recently failed to resolve, so repeated
+/// connections to genuinely unknown destinations fail fast rather than each waiting on the
+/// control plane.
+#[derive(Debug, Default)]
+struct NegativeCache {
+    // Map from the on-demand resource key to the time the entry expires.
+    entries: HashMap<String, Instant>,
+}
+
+impl NegativeCache {
+    fn contains(&mut self, key: &str) -> bool {
+        match self.entries.get(key) {
+            Some(expiry) if *expiry > Instant::now() => true,
+            Some(_) => {
+                self.entries.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: String) {
+        let now = Instant::now();
+        if self.entries.len() >= NEGATIVE_CACHE_CAPACITY {
+            self.entries.retain(|_, expiry| *expiry > now);
+        }
+        if self.entries.len() >= NEGATIVE_CACHE_CAPACITY {
+            // Still full; make room by dropping the entry closest to expiring.
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, expiry)| **expiry)
+                .map(|(k, _)| k.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, now + NEGATIVE_CACHE_TTL);
+    }
+}
+
+impl serde::Serialize for NegativeCache {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let now = Instant::now();
+        serializer.collect_map(
+            self.entries
+                .iter()
+                .filter(|(_, expiry)| **expiry > now)
+                .map(|(k, expiry)| (k, format!("{:?}", *expiry - now))),
+        )
+    }
+}
+
 impl ProxyState {
     /// Find either a workload or service by the destination.
     pub fn find_destination(&self, dest: &Destination) -> Option<Address> {
@@ -121,7 +207,7 @@ impl ProxyState {
         match self.workloads.find_address(network_addr) {
             None => {
                 // 2. handle service
-                if let Some(svc) = self.services.get_by_vip(network_addr) {
+                if let Some(svc) = self.find_service_by_vip(network_addr) {
                     return Some(Address::Service(Box::new(svc)));
                 }
                 None
@@ -145,28 +231,155 @@ impl ProxyState {
         }
     }
 
-    pub fn find_upstream(&self, network: &str, addr: SocketAddr) -> Option<Upstream> {
-        if let Some(svc) = self.services.get_by_vip(&network_addr(network, addr.ip())) {
-            let Some(target_port) = svc.ports.get(&addr.port()) else {
+    /// find_service_by_vip finds the service `addr` is a VIP of. A service without addresses, such
+    /// as a `ServiceEntry`, may also be found by an address istiod allocated to it in the name table.
+    pub fn find_service_by_vip(&self, addr: &NetworkAddress) -> Option<Service> {
+        if let Some(svc) = self.services.get_by_vip(addr) {
+            return Some(svc);
+        }
+        let info = self.names.get_by_ip(&addr.address)?;
+        self.services
+            .get_by_host(&info.hostname)?
+            .into_iter()
+            .find(|svc| svc.vips.is_empty() && info.allocated_for(svc))
+            .map(|svc| self.with_allocated_vips(svc, &addr.network))
+    }
+
+    /// with_allocated_vips gives a service without addresses the ones istiod allocated to it on
+    /// `network`, if any, so it is answered and routed like a service with VIPs.
+    pub fn with_allocated_vips(&self, mut svc: Service, network: &str) -> Service {
+        if svc.vips.is_empty() {
+            if let Some(info) = self
+                .names
+                .get(&svc.hostname)
+                .filter(|info| info.allocated_for(&svc))
+            {
+                svc.vips = info.as_service(network).vips;
+            }
+        }
+        svc
+    }
+
+    /// find_host_ip finds the address on `network` of a service or workload by hostname. If several
+    /// services share the hostname, the one in `namespace` is preferred.
+    pub fn find_host_ip(
+        &self,
+        network: &str,
+        namespace: Option<&str>,
+        hostname: &str,
+    ) -> Option<IpAddr> {
+        if let Some(services) = self.services.get_by_host(&hostname.to_string()) {
+            let svc = services
+                .iter()
+                .find(|svc| Some(svc.namespace.as_str()) == namespace)
+                .or_else(|| services.first())?;
+            return self
+                .with_allocated_vips(svc.clone(), network)
+                .vips
+                .iter()
+                .find(|vip| vip.network == network)
+                .map(|vip| vip.address);
+        }
+        let wl = self.workloads.find_hostname(hostname)?;
+        if wl.network != network {
+            return None;
+        }
+        wl.workload_ips.first().copied()
+    }
+
+    /// find_upstream finds the workload and port to send a connection to `addr` to. If `addr` is a
+    /// service, and `source`, the client's IP, is known, it is used for session affinity.
+    pub fn find_upstream(
+        &self,
+        network: &str,
+        source: Option<IpAddr>,
+        addr: SocketAddr,
+    ) -> Option<Upstream> {
+        self.find_upstream_hairpin(network, source, addr, None)
+    }
+
+    /// find_upstream_hairpin is [ProxyState::find_upstream] for a client that may itself be an
+    /// endpoint of the service it connects to, in which case `hairpin` decides whether it is picked.
+    pub fn find_upstream_hairpin(
+        &self,
+        network: &str,
+        source: Option<IpAddr>,
+        addr: SocketAddr,
+        hairpin: Option<Hairpin<'_>>,
+    ) -> Option<Upstream> {
+        if let Some(svc) = self.find_service_by_vip(&network_addr(network, addr.ip())) {
+            let Some(&target_port) = svc.ports.get(&addr.port()) else {
                 debug!("found VIP {}, but port {} was unknown", addr.ip(), addr.port());
                 return None
             };
-            // Randomly pick an upstream
-            // TODO: do this more efficiently, and not just randomly
-            let Some((_, ep)) = svc.endpoints.iter().choose(&mut rand::thread_rng()) else {
-                debug!("VIP {} has no healthy endpoints", addr);
+            // Only pick among the endpoints the service port maps to a port on. A named target port,
+            // for example, is only known per endpoint, and is 0 on the service.
+            let resolve = |ep: &Endpoint| {
+                // If endpoint overrides the target port, use that instead
+                let port = ep.port.get(&addr.port()).copied().unwrap_or(target_port);
+                (port != 0).then_some(port)
+            };
+            // The client's own endpoint, if it is one of the service's.
+            let own = hairpin.and_then(|h| {
+                let ep = svc
+                    .endpoints
+                    .values()
+                    .find(|ep| ep.workload_uid == h.client_uid)?;
+                resolve(ep).map(|port| (ep, port))
+            });
+            let policy = hairpin.map(|h| h.policy).unwrap_or_default();
+            let eligible = |ep: &Endpoint, ejected: &HashSet<&str>| {
+                (policy != HairpinPolicy::Avoid
+                    || own.map_or(true, |(own, _)| own.workload_uid != ep.workload_uid))
+                    && !ejected.contains(ep.workload_uid.as_str())
+            };
+            let pick = |ejected: &HashSet<&str>| match (policy, own, svc.session_affinity, source) {
+                (HairpinPolicy::Prefer, Some(own), _, _) => Some(own),
+                (_, _, SessionAffinity::ClientIp, Some(source)) => self
+                    .services
+                    .ring(&svc.namespaced_hostname())
+                    .and_then(|ring| {
+                        ring.lookup(source).find_map(|uid| {
+                            let ep = svc.endpoints.get(uid).filter(|ep| eligible(ep, ejected))?;
+                            resolve(ep).map(|port| (ep, port))
+                        })
+                    }),
+                // Randomly pick an upstream
+                // TODO: do this more efficiently, and not just randomly
+                _ => svc
+                    .endpoints
+                    .values()
+                    .filter(|ep| eligible(ep, ejected))
+                    .filter_map(|ep| resolve(ep).map(|port| (ep, port)))
+                    .choose(&mut rand::thread_rng()),
+            };
+            // Ejected endpoints are only picked when no other is left.
+            let ejected = self
+                .outliers
+                .ejected(svc.endpoints.values().map(|ep| ep.workload_uid.as_str()));
+            let picked = pick(&ejected)
+                .or_else(|| {
+                    if ejected.is_empty() {
+                        None
+                    } else {
+                        pick(&HashSet::new())
+                    }
+                })
+                // Avoiding the client still picks it if it is the only endpoint left.
+                .or(own);
+            let Some((ep, target_port)) = picked else {
+                debug!("VIP {} has no healthy endpoints for its target port", addr);
                 return None
             };
             let Some(wl) = self.workloads.find_uid(&ep.workload_uid) else {
                 debug!("failed to fetch workload for {}", ep.workload_uid);
                 return None
             };
-            // If endpoint overrides the target port, use that instead
-            let target_port = ep.port.get(&addr.port()).unwrap_or(target_port);
             let us = Upstream {
                 workload: wl,
-                port: *target_port,
+                port: target_port,
                 sans: svc.subject_alt_names.clone(),
+                connect_policy: svc.connect_policy,
                 destination_service: Some(svc.into()),
             };
             return Some(us);
@@ -180,6 +393,7 @@ impl ProxyState {
                 port: addr.port(),
                 sans: Vec::new(),
                 destination_service: None,
+                connect_policy: ConnectPolicy::default(),
             };
             return Some(us);
         }
@@ -198,11 +412,18 @@ pub struct DemandProxyState {
     #[serde(skip_serializing)]
     demand: Option<Demander>,
 
+    /// Resources recently requested on-demand that the control plane did not know about.
+    negative_cache: Arc<Mutex<NegativeCache>>,
+
     #[serde(skip_serializing)]
     pub dns_resolver_cfg: ResolverConfig,
 
     #[serde(skip_serializing)]
     pub dns_resolver_opts: ResolverOpts,
+
+    /// If present, connections are denied while authorization policies are unsynced.
+    #[serde(skip_serializing)]
+    policy_sync: Option<PolicySync>,
 }
 
 impl DemandProxyState {
@@ -215,11 +436,24 @@ impl DemandProxyState {
         Self {
             state,
             demand,
+            negative_cache: Default::default(),
             dns_resolver_cfg,
             dns_resolver_opts,
+            policy_sync: None,
         }
     }
 
+    pub fn with_policy_sync(mut self, policy_sync: Option<PolicySync>) -> Self {
+        self.policy_sync = policy_sync;
+        self
+    }
+
+    /// unsynced_policies returns why authorization policies cannot be enforced, if ztunnel fails
+    /// closed and they cannot.
+    pub fn unsynced_policies(&self) -> Option<String> {
+        self.policy_sync.as_ref().and_then(PolicySync::unsynced)
+    }
+
     pub fn read(&self) -> RwLockReadGuard<'_, ProxyState> {
         self.state.read().unwrap()
     }
@@ -228,11 +462,14 @@ impl DemandProxyState {
         self.state.write().unwrap()
     }
 
-    pub async fn assert_rbac(&self, conn: &rbac::Connection) -> bool {
+    /// assert_rbac evaluates the authorization policies for `conn`. Policies in audit mode, or all
+    /// of them if `audit_all` is set, never deny the connection; instead, the decision lists those
+    /// that would have.
+    pub async fn assert_rbac(&self, conn: &rbac::Connection, audit_all: bool) -> rbac::Decision {
         let nw_addr = network_addr(&conn.dst_network, conn.dst.ip());
         let Some(wl) = self.fetch_workload(&nw_addr).await else {
             debug!("destination workload not found {}", nw_addr);
-            return false;
+            return rbac::Decision::deny();
         };
 
         let state = self.state.read().unwrap();
@@ -257,33 +494,56 @@ impl DemandProxyState {
         );
 
         // Allow and deny logic follows https://istio.io/latest/docs/reference/config/security/authorization-policy/
+        // Audited policies are left out of the enforced decision; we only note when the decision
+        // would have been to deny had they been enforced as well.
+        let audited = |p: &&rbac::Authorization| audit_all || p.audit;
+        let mut decision = rbac::Decision::allow();
 
         // "If there are any DENY policies that match the request, deny the request."
         for pol in deny.iter() {
-            if pol.matches(conn) {
-                debug!(policy = pol.to_key(), "deny policy match");
-                return false;
-            } else {
-                trace!(policy = pol.to_key(), "deny policy does not match");
+            match pol.matching_rule(conn) {
+                None => trace!(policy = pol.to_key(), "deny policy does not match"),
+                Some(_) if audited(pol) => {
+                    debug!(policy = pol.to_key(), "audited deny policy match");
+                    decision.audit_denials.push(pol.to_key());
+                }
+                matched => {
+                    debug!(policy = pol.to_key(), "deny policy match");
+                    return rbac::Decision {
+                        matched,
+                        ..rbac::Decision::deny()
+                    };
+                }
             }
         }
+        let (audited_allow, allow): (Vec<_>, Vec<_>) = allow.into_iter().partition(audited);
         // "If there are no ALLOW policies for the workload, allow the request."
         if allow.is_empty() {
-            debug!("no allow policies, allow");
-            return true;
+            if audited_allow.is_empty() {
+                debug!("no allow policies, allow");
+            } else if audited_allow.iter().any(|p| p.matches(conn)) {
+                debug!("audited allow policy match");
+            } else {
+                debug!("no audited allow policies matched");
+                decision
+                    .audit_denials
+                    .extend(audited_allow.iter().map(|p| p.to_key()));
+            }
+            return decision;
         }
         // "If any of the ALLOW policies match the request, allow the request."
         for pol in allow.iter() {
-            if pol.matches(conn) {
+            if let Some(matched) = pol.matching_rule(conn) {
                 debug!(policy = pol.to_key(), "allow policy match");
-                return true;
+                decision.matched = Some(matched);
+                return decision;
             } else {
                 trace!(policy = pol.to_key(), "allow policy does not match");
             }
         }
         // "Deny the request."
         debug!("no allow policies matched");
-        false
+        rbac::Decision::deny()
     }
 
     // this should only be called once per request (for the workload itself and potentially its waypoint)
@@ -456,8 +716,8 @@ impl DemandProxyState {
         if let Some(wl) = self.state.read().unwrap().workloads.find_address(addr) {
             return Some(wl);
         }
-        self.fetch_on_demand(addr.to_string()).await;
-        self.state.read().unwrap().workloads.find_address(addr)
+        self.fetch_on_demand(addr.to_string(), |s| s.workloads.find_address(addr))
+            .await
     }
 
     // only support workload
@@ -467,13 +727,22 @@ impl DemandProxyState {
         if let Some(wl) = self.state.read().unwrap().workloads.find_uid(uid) {
             return Some(wl);
         }
-        self.fetch_on_demand(uid.to_string()).await;
-        self.state.read().unwrap().workloads.find_uid(uid)
+        self.fetch_on_demand(uid.to_string(), |s| s.workloads.find_uid(uid))
+            .await
     }
 
-    pub async fn fetch_upstream(&self, network: &str, addr: SocketAddr) -> Option<Upstream> {
+    pub async fn fetch_upstream(
+        &self,
+        network: &str,
+        source: Option<IpAddr>,
+        addr: SocketAddr,
+        hairpin: Option<Hairpin<'_>>,
+    ) -> Option<Upstream> {
         self.fetch_address(&network_addr(network, addr.ip())).await;
-        self.state.read().unwrap().find_upstream(network, addr)
+        self.state
+            .read()
+            .unwrap()
+            .find_upstream_hairpin(network, source, addr, hairpin)
     }
 
     pub async fn fetch_waypoint(
@@ -496,7 +765,7 @@ impl DemandProxyState {
         };
         let wp_socket_addr = SocketAddr::new(wp_nw_addr.address, gw_address.port);
         match self
-            .fetch_upstream(&wp_nw_addr.network, wp_socket_addr)
+            .fetch_upstream(&wp_nw_addr.network, None, wp_socket_addr, None)
             .await
         {
             Some(mut upstream) => {
@@ -534,8 +803,8 @@ impl DemandProxyState {
             return Some(address);
         }
         // if both cache not found, start on demand fetch
-        self.fetch_on_demand(network_addr.to_string()).await;
-        self.state.read().unwrap().find_address(network_addr)
+        self.fetch_on_demand(network_addr.to_string(), |s| s.find_address(network_addr))
+            .await
     }
 
     /// Looks for the given hostname to find either a workload or service by IP. If not found
@@ -547,16 +816,36 @@ impl DemandProxyState {
             return Some(address);
         }
         // if both cache not found, start on demand fetch
-        self.fetch_on_demand(hostname.to_string()).await;
-        self.state.read().unwrap().find_hostname(hostname)
+        self.fetch_on_demand(hostname.to_string(), |s| s.find_hostname(hostname))
+            .await
     }
 
-    async fn fetch_on_demand(&self, key: String) {
-        if let Some(demand) = &self.demand {
-            debug!(%key, "sending demand request");
-            demand.demand(key.clone()).await.recv().await;
+    /// fetch_on_demand requests `key` from the control plane, if on-demand is enabled, and then
+    /// looks it up with `find`. Keys that recently failed to resolve are not requested again until
+    /// their negative cache entry expires.
+    async fn fetch_on_demand<T>(
+        &self,
+        key: String,
+        find: impl Fn(&ProxyState) -> Option<T>,
+    ) -> Option<T> {
+        let Some(demand) = &self.demand else {
+            return find(&self.state.read().unwrap());
+        };
+        if self.negative_cache.lock().unwrap().contains(&key) {
+            debug!(%key, "skipping demand request for recently unknown resource");
+            return find(&self.state.read().unwrap());
+        }
+        debug!(%key, "sending demand request");
+        if demand.demand(key.clone()).await.recv().await {
             debug!(%key, "on demand ready");
+        } else {
+            warn!(%key, "no on demand response, timed out or dropped");
         }
+        let found = find(&self.state.read().unwrap());
+        if found.is_none() {
+            self.negative_cache.lock().unwrap().insert(key);
+        }
+        found
     }
 }
 
@@ -587,27 +876,97 @@ pub struct ProxyStateManager {
 
     #[serde(skip_serializing)]
     xds_client: Option<AdsClient>,
+
+    /// The clients of the control planes of remote clusters, by cluster ID.
+    #[serde(skip_serializing)]
+    remote_xds_clients: Vec<(String, AdsClient)>,
+
+    #[serde(skip_serializing)]
+    policy_sync: Option<PolicySync>,
 }
 
 impl ProxyStateManager {
+    /// new creates the manager. `remote_metrics` holds the metrics of the client of each of
+    /// `config.remote_xds_clusters`, in order.
     pub async fn new(
         config: config::Config,
         metrics: Metrics,
+        remote_metrics: Vec<Metrics>,
+        cert_metrics: cert_fetcher::Metrics,
+        prefetch_history: Option<cert_fetcher::History>,
         awaiting_ready: readiness::BlockReady,
         cert_manager: Arc<SecretManager>,
     ) -> anyhow::Result<ProxyStateManager> {
-        let cert_fetcher = cert_fetcher::new(&config, cert_manager);
-        let state: Arc<RwLock<ProxyState>> = Arc::new(RwLock::new(ProxyState::default()));
-        let xds_client = if config.xds_address.is_some() {
-            let updater = ProxyStateUpdater::new(state.clone(), cert_fetcher.clone());
-            Some(
-                xds::Config::new(config.clone())
-                    .with_address_handler(updater.clone())
-                    .with_authorization_handler(updater)
+        let cert_fetcher = cert_fetcher::new(
+            &config,
+            cert_manager,
+            cert_metrics,
+            prefetch_history,
+            awaiting_ready.subtask("certificates"),
+            awaiting_ready.released(),
+        );
+        let state: Arc<RwLock<ProxyState>> = Arc::new(RwLock::new(ProxyState {
+            connections: WorkloadConnections::new(config.workload_drain_duration),
+            outliers: OutlierDetector::new(config.outlier_detection.clone()),
+            ..Default::default()
+        }));
+        let updater = ProxyStateUpdater::new(state.clone(), cert_fetcher.clone());
+        let remote_xds_clients = config
+            .remote_xds_clusters
+            .iter()
+            .zip(remote_metrics)
+            .map(|(remote, metrics)| {
+                info!(
+                    cluster = remote.cluster_id,
+                    address = remote.address,
+                    "discovering remote cluster"
+                );
+                // A remote cluster that can't be reached only costs us its workloads, so it
+                // doesn't hold up readiness.
+                let client = xds::Config::new(config.clone())
+                    .remote(remote.address.clone())
+                    .with_address_handler(updater.clone().for_cluster(remote.cluster_id.clone()))
                     .watch(xds::ADDRESS_TYPE.into())
-                    .watch(xds::AUTHORIZATION_TYPE.into())
-                    .build(metrics, awaiting_ready),
-            )
+                    .build_detached(metrics);
+                (remote.cluster_id.clone(), client)
+            })
+            .collect();
+        let mut xds_config = xds::Config::new(config.clone())
+            .with_address_handler(updater.clone())
+            .with_authorization_handler(updater.clone())
+            .with_name_table_handler(updater)
+            .watch(xds::ADDRESS_TYPE.into())
+            .watch(xds::AUTHORIZATION_TYPE.into())
+            // Not every control plane serves a name table.
+            .watch_optional(xds::NAME_TABLE_TYPE.into());
+        // Only policies from a control plane can be out of sync.
+        let policy_sync = if config.policy_fail_closed && config.xds_address.is_some() {
+            let status = xds::SyncStatus::default();
+            xds_config = xds_config.track_sync(xds::AUTHORIZATION_TYPE, status.clone());
+            Some(PolicySync {
+                status,
+                stale_after: config.policy_stale_after,
+            })
+        } else {
+            None
+        };
+        let xds_client = if config.xds_address.is_some() {
+            let xds_config = match &config.xds_record_path {
+                Some(path) => {
+                    warn!(path = %path.display(), "recording xds responses");
+                    xds_config.record(xds::Recorder::create(path).with_context(|| {
+                        format!("failed to create xds recording {}", path.display())
+                    })?)
+                }
+                None => xds_config,
+            };
+            Some(xds_config.build(metrics, awaiting_ready))
+        } else if let Some(path) = &config.xds_replay_path {
+            xds_config
+                .build(metrics, awaiting_ready)
+                .replay(path)
+                .await?;
+            None
         } else {
             None
         };
@@ -622,12 +981,15 @@ impl ProxyStateManager {
         let demand = xds_client.as_ref().and_then(AdsClient::demander);
         Ok(ProxyStateManager {
             xds_client,
-            state: DemandProxyState {
+            remote_xds_clients,
+            state: DemandProxyState::new(
                 state,
                 demand,
-                dns_resolver_cfg: config.dns_resolver_cfg,
-                dns_resolver_opts: config.dns_resolver_opts,
-            },
+                config.dns_resolver_cfg,
+                config.dns_resolver_opts,
+            )
+            .with_policy_sync(policy_sync.clone()),
+            policy_sync,
         })
     }
 
@@ -635,7 +997,23 @@ impl ProxyStateManager {
         self.state.clone()
     }
 
+    /// policy_sync returns how authorization policies are kept in sync, if ztunnel fails closed
+    /// while they are not.
+    pub fn policy_sync(&self) -> Option<PolicySync> {
+        self.policy_sync.clone()
+    }
+
     pub async fn run(self) -> anyhow::Result<()> {
+        for (cluster, remote) in self.remote_xds_clients {
+            tokio::spawn(async move {
+                // The client reconnects for as long as it runs, so it only stops on a bug.
+                match tokio::spawn(remote.run()).await {
+                    Ok(Ok(())) => warn!(cluster, "remote cluster discovery stopped"),
+                    Ok(Err(e)) => error!(cluster, "remote cluster discovery failed: {e}"),
+                    Err(e) => error!(cluster, "remote cluster discovery panicked: {e}"),
+                }
+            });
+        }
         match self.xds_client {
             Some(xds) => xds.run().await.map_err(|e| anyhow::anyhow!(e)),
             None => Ok(()),
@@ -718,4 +1096,287 @@ mod tests {
         )
         .await;
     }
+
+    #[test]
+    fn find_upstream_target_port() {
+        let mut state = ProxyState::default();
+        let mut svc = test_helpers::mock_default_service();
+        // A named target port, only resolved on some of the endpoints.
+        svc.ports.insert(9090, 0);
+        state.services.insert(svc.clone());
+        for (uid, ip, ports) in [
+            ("resolved", Ipv4Addr::new(127, 0, 0, 2), vec![(9090, 9000)]),
+            ("unresolved", Ipv4Addr::new(127, 0, 0, 3), vec![]),
+        ] {
+            state
+                .workloads
+                .insert(Workload {
+                    uid: uid.to_string(),
+                    workload_ips: vec![IpAddr::V4(ip)],
+                    ..test_helpers::test_default_workload()
+                })
+                .unwrap();
+            state.services.insert_endpoint(Endpoint {
+                workload_uid: uid.to_string(),
+                service: svc.namespaced_hostname(),
+                address: Some(network_addr("", IpAddr::V4(ip))),
+                port: HashMap::from_iter(ports),
+            });
+        }
+        let vip = svc.vips[0].address;
+
+        for _ in 0..10 {
+            let us = state
+                .find_upstream("", None, SocketAddr::new(vip, 9090))
+                .unwrap();
+            assert_eq!(us.workload.uid, "resolved");
+            assert_eq!(us.port, 9000);
+        }
+        let us = state
+            .find_upstream("", None, SocketAddr::new(vip, 8080))
+            .unwrap();
+        assert_eq!(us.port, 80);
+        assert!(state
+            .find_upstream("", None, SocketAddr::new(vip, 1234))
+            .is_none());
+    }
+
+    #[test]
+    fn find_upstream_allocated_vip() {
+        use crate::xds::istio::networking::nds::v1::name_table::NameInfo as XdsNameInfo;
+        use crate::xds::istio::networking::nds::v1::NameTable as XdsNameTable;
+
+        let mut state = ProxyState::default();
+        // A ServiceEntry without addresses.
+        let svc = service::Service {
+            vips: vec![],
+            ..test_helpers::mock_default_service()
+        };
+        state.services.insert(svc.clone());
+        state
+            .workloads
+            .insert(test_helpers::test_default_workload())
+            .unwrap();
+        state.services.insert_endpoint(Endpoint {
+            workload_uid: test_helpers::test_default_workload().uid,
+            service: svc.namespaced_hostname(),
+            address: Some(network_addr("", IpAddr::V4(Ipv4Addr::LOCALHOST))),
+            port: HashMap::new(),
+        });
+        let allocated = IpAddr::V4(Ipv4Addr::new(240, 240, 0, 1));
+        let table = XdsNameTable {
+            table: HashMap::from([(
+                svc.hostname.clone(),
+                XdsNameInfo {
+                    ips: vec![allocated.to_string()],
+                    namespace: svc.namespace.clone(),
+                    ..Default::default()
+                },
+            )]),
+        };
+        state
+            .names
+            .replace(NameTableStore::try_from(&table).unwrap());
+
+        let us = state
+            .find_upstream("", None, SocketAddr::new(allocated, 8080))
+            .unwrap();
+        assert_eq!(us.workload.uid, test_helpers::test_default_workload().uid);
+        assert_eq!(
+            state.find_host_ip("", Some(&svc.namespace), &svc.hostname),
+            Some(allocated)
+        );
+        // Addresses are only bridged to the service they were allocated for.
+        let other = service::Service {
+            namespace: "other".to_string(),
+            ..svc
+        };
+        assert_eq!(state.with_allocated_vips(other, "").vips, vec![]);
+    }
+
+    #[test]
+    fn find_upstream_session_affinity() {
+        let mut state = ProxyState::default();
+        let svc = service::Service {
+            session_affinity: SessionAffinity::ClientIp,
+            ..test_helpers::mock_default_service()
+        };
+        state.services.insert(svc.clone());
+        let endpoint = |i: u8| {
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 1, i));
+            let wl = Workload {
+                uid: format!("wl{i}"),
+                workload_ips: vec![ip],
+                ..test_helpers::test_default_workload()
+            };
+            let ep = Endpoint {
+                workload_uid: wl.uid.clone(),
+                service: svc.namespaced_hostname(),
+                address: Some(network_addr("", ip)),
+                port: HashMap::new(),
+            };
+            (wl, ep)
+        };
+        for i in 0..10 {
+            let (wl, ep) = endpoint(i);
+            state.workloads.insert(wl).unwrap();
+            state.services.insert_endpoint(ep);
+        }
+        let vip = SocketAddr::new(svc.vips[0].address, 8080);
+        let pick = |state: &ProxyState, client: u8| {
+            let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, client));
+            state
+                .find_upstream("", Some(client), vip)
+                .unwrap()
+                .workload
+                .uid
+        };
+
+        let before: Vec<_> = (0..50).map(|client| pick(&state, client)).collect();
+        assert_eq!(before, (0..50).map(|c| pick(&state, c)).collect::<Vec<_>>());
+        // Clients keep their endpoint, unless it is the one removed.
+        let (removed, ep) = endpoint(0);
+        state.services.remove_endpoint(
+            &removed.uid,
+            &service::endpoint_uid(&ep.workload_uid, ep.address.as_ref()),
+        );
+        for (client, uid) in before.iter().enumerate() {
+            let now = pick(&state, client as u8);
+            if *uid == removed.uid {
+                assert_ne!(now, removed.uid);
+            } else {
+                assert_eq!(now, *uid);
+            }
+        }
+    }
+
+    #[test]
+    fn find_upstream_hairpin() {
+        let mut state = ProxyState::default();
+        let svc = test_helpers::mock_default_service();
+        state.services.insert(svc.clone());
+        for i in 0..2 {
+            let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 1, i));
+            let wl = Workload {
+                uid: format!("wl{i}"),
+                workload_ips: vec![ip],
+                ..test_helpers::test_default_workload()
+            };
+            state.services.insert_endpoint(Endpoint {
+                workload_uid: wl.uid.clone(),
+                service: svc.namespaced_hostname(),
+                address: Some(network_addr("", ip)),
+                port: HashMap::new(),
+            });
+            state.workloads.insert(wl).unwrap();
+        }
+        let vip = SocketAddr::new(svc.vips[0].address, 8080);
+        let pick = |state: &ProxyState, policy| {
+            let hairpin = Hairpin {
+                client_uid: "wl0",
+                policy,
+            };
+            state
+                .find_upstream_hairpin("", None, vip, Some(hairpin))
+                .unwrap()
+                .workload
+                .uid
+        };
+        for _ in 0..20 {
+            assert_eq!(pick(&state, HairpinPolicy::Prefer), "wl0");
+            assert_eq!(pick(&state, HairpinPolicy::Avoid), "wl1");
+        }
+
+        // With no other endpoint left, avoiding the client still picks it.
+        state.services.remove_endpoint(
+            "wl1",
+            &service::endpoint_uid("wl1", Some(&network_addr("", "127.0.1.1".parse().unwrap()))),
+        );
+        assert_eq!(pick(&state, HairpinPolicy::Avoid), "wl0");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn negative_cache() {
+        let mut cache = NegativeCache::default();
+        assert!(!cache.contains("10.0.0.1"));
+        cache.insert("10.0.0.1".to_string());
+        assert!(cache.contains("10.0.0.1"));
+
+        tokio::time::advance(NEGATIVE_CACHE_TTL).await;
+        assert!(!cache.contains("10.0.0.1"));
+        assert!(cache.entries.is_empty());
+
+        // When full, the entry closest to expiring makes room for the new one.
+        for i in 0..NEGATIVE_CACHE_CAPACITY {
+            cache.insert(i.to_string());
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        cache.insert("new".to_string());
+        assert_eq!(cache.entries.len(), NEGATIVE_CACHE_CAPACITY);
+        assert!(!cache.contains("0"));
+        assert!(cache.contains("1"));
+        assert!(cache.contains("new"));
+    }
+
+    #[tokio::test]
+    async fn rbac_audit() {
+        let policy = |name: &str, action, audit| rbac::Authorization {
+            name: name.to_string(),
+            namespace: "".to_string(),
+            scope: rbac::RbacScope::Global,
+            action,
+            // A single empty clause matches every connection.
+            rules: vec![vec![vec![]]],
+            audit,
+        };
+        let conn = rbac::Connection {
+            src_identity: None,
+            src_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            dst_network: "".to_string(),
+            dst: SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)),
+        };
+        let mut state = ProxyState::default();
+        state
+            .workloads
+            .insert(test_helpers::test_default_workload())
+            .unwrap();
+        state
+            .policies
+            .insert(policy("audited", rbac::RbacAction::Deny, true));
+        let state = DemandProxyState::new(
+            Arc::new(RwLock::new(state)),
+            None,
+            ResolverConfig::default(),
+            ResolverOpts::default(),
+        );
+
+        assert_eq!(
+            state.assert_rbac(&conn, false).await,
+            rbac::Decision {
+                allowed: true,
+                audit_denials: vec!["/audited".to_string()],
+                matched: None,
+            }
+        );
+
+        state.state.write().unwrap().policies.insert(policy(
+            "enforced",
+            rbac::RbacAction::Deny,
+            false,
+        ));
+        assert_eq!(
+            state.assert_rbac(&conn, false).await,
+            rbac::Decision {
+                matched: Some(rbac::RuleMatch {
+                    policy: "/enforced".to_string(),
+                    rule: 0,
+                }),
+                ..rbac::Decision::deny()
+            }
+        );
+        // The global override audits every policy.
+        let decision = state.assert_rbac(&conn, true).await;
+        assert!(decision.allowed);
+        assert_eq!(decision.audit_denials.len(), 2);
+    }
 }
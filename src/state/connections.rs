@@ -0,0 +1,89 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use drain::{Signal, Watch};
+use tracing::debug;
+
+/// WorkloadConnections tracks the open inbound connections to each local workload, so that they
+/// can be closed when the workload is removed rather than linger until they time out.
+#[derive(Clone, Debug, Default)]
+pub struct WorkloadConnections {
+    /// How long connections are given to finish once their workload is removed.
+    grace: Duration,
+    by_workload: Arc<Mutex<HashMap<String, (Signal, Watch)>>>,
+}
+
+impl WorkloadConnections {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            by_workload: Default::default(),
+        }
+    }
+
+    /// watch returns a [Watch] that is signaled once the workload with `uid` is removed and the
+    /// grace period has passed. Connections to the workload hold on to it while open.
+    pub fn watch(&self, uid: &str) -> Watch {
+        let mut by_workload = self.by_workload.lock().unwrap();
+        by_workload
+            .entry(uid.to_string())
+            .or_insert_with(drain::channel)
+            .1
+            .clone()
+    }
+
+    /// drain signals the connections to the workload with `uid`, if there are any, to close once
+    /// the grace period has passed.
+    pub fn drain(&self, uid: &str) {
+        let Some((signal, _)) = self.by_workload.lock().unwrap().remove(uid) else {
+            return;
+        };
+        let grace = self.grace;
+        let uid = uid.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            debug!(workload = uid, "closing connections to removed workload");
+            signal.drain().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn drain_after_grace() {
+        let conns = WorkloadConnections::new(Duration::from_secs(5));
+        let removed = conns.watch("wl1");
+        let other = conns.watch("wl2");
+        conns.drain("wl1");
+
+        let mut removed = Box::pin(removed.signaled());
+        tokio::select! {
+            _ = &mut removed => panic!("signaled before the grace period passed"),
+            _ = tokio::time::sleep(Duration::from_secs(4)) => {}
+        }
+        tokio::time::timeout(Duration::from_secs(2), removed)
+            .await
+            .expect("signaled once the grace period passed");
+        tokio::time::timeout(Duration::from_secs(10), other.signaled())
+            .await
+            .expect_err("other workloads are left alone");
+    }
+}
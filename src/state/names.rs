@@ -0,0 +1,164 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::state::service::Service;
+use crate::state::workload::network_addr;
+use crate::xds::istio::networking::nds::v1::NameTable as XdsNameTable;
+
+/// A NameInfo describes a hostname from the name table, such as a `ServiceEntry` host.
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct NameInfo {
+    pub hostname: String,
+    /// The addresses of the host. For a `ServiceEntry` without addresses, these are allocated by
+    /// istiod and only have meaning within the mesh.
+    pub ips: Vec<IpAddr>,
+    pub shortname: String,
+    pub namespace: String,
+}
+
+impl NameInfo {
+    /// allocated_for returns whether this host's addresses belong to `svc`. Hosts in the table
+    /// without a namespace match the service of that hostname in any namespace.
+    pub fn allocated_for(&self, svc: &Service) -> bool {
+        self.hostname == svc.hostname
+            && (self.namespace.is_empty() || self.namespace == svc.namespace)
+    }
+
+    /// as_service describes the host as a service with VIPs on `network`, so it can be answered
+    /// like any other service.
+    pub fn as_service(&self, network: &str) -> Service {
+        Service {
+            name: self.shortname.clone(),
+            namespace: self.namespace.clone(),
+            hostname: self.hostname.clone(),
+            vips: self
+                .ips
+                .iter()
+                .map(|ip| network_addr(network, *ip))
+                .collect(),
+            ports: Default::default(),
+            endpoints: Default::default(),
+            subject_alt_names: Default::default(),
+            session_affinity: Default::default(),
+            connect_policy: Default::default(),
+        }
+    }
+}
+
+/// A NameTableStore holds the name table istiod sends over NDS, mapping hostnames that are not
+/// otherwise known to ztunnel to their addresses.
+#[derive(serde::Serialize, Default, Debug)]
+pub struct NameTableStore {
+    /// name_table maps hostnames to their info.
+    name_table: HashMap<String, NameInfo>,
+
+    /// by_ip maps addresses back to the hostname they were allocated for.
+    #[serde(skip)]
+    by_ip: HashMap<IpAddr, String>,
+}
+
+impl NameTableStore {
+    pub fn get(&self, hostname: &str) -> Option<&NameInfo> {
+        self.name_table.get(hostname)
+    }
+
+    /// get_by_ip returns the host `ip` is an address of.
+    pub fn get_by_ip(&self, ip: &IpAddr) -> Option<&NameInfo> {
+        self.by_ip.get(ip).and_then(|h| self.name_table.get(h))
+    }
+
+    /// replace swaps in a new table. istiod always sends the whole table.
+    pub fn replace(&mut self, table: NameTableStore) {
+        *self = table;
+    }
+
+    pub fn clear(&mut self) {
+        *self = NameTableStore::default();
+    }
+}
+
+impl TryFrom<&XdsNameTable> for NameTableStore {
+    type Error = anyhow::Error;
+
+    fn try_from(t: &XdsNameTable) -> Result<Self, Self::Error> {
+        let mut store = NameTableStore::default();
+        for (hostname, info) in &t.table {
+            let ips = info
+                .ips
+                .iter()
+                .map(|ip| ip.parse())
+                .collect::<Result<Vec<IpAddr>, _>>()
+                .map_err(|e| anyhow::anyhow!("invalid address for host {hostname}: {e}"))?;
+            for ip in &ips {
+                store.by_ip.insert(*ip, hostname.clone());
+            }
+            store.name_table.insert(
+                hostname.clone(),
+                NameInfo {
+                    hostname: hostname.clone(),
+                    ips,
+                    shortname: info.shortname.clone(),
+                    namespace: info.namespace.clone(),
+                },
+            );
+        }
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xds::istio::networking::nds::v1::name_table::NameInfo as XdsNameInfo;
+
+    #[test]
+    fn name_table() {
+        let table = XdsNameTable {
+            table: HashMap::from([(
+                "api.example.com".to_string(),
+                XdsNameInfo {
+                    ips: vec!["240.240.0.1".to_string(), "2001:2::1".to_string()],
+                    registry: "External".to_string(),
+                    shortname: "api".to_string(),
+                    namespace: "default".to_string(),
+                    alt_hosts: vec![],
+                },
+            )]),
+        };
+        let store = NameTableStore::try_from(&table).unwrap();
+        let info = store.get("api.example.com").unwrap();
+        assert_eq!(info.namespace, "default");
+        assert_eq!(store.get_by_ip(&"2001:2::1".parse().unwrap()), Some(info));
+        assert_eq!(store.get_by_ip(&"240.240.0.2".parse().unwrap()), None);
+
+        let svc = info.as_service("net");
+        assert_eq!(
+            svc.vips[0],
+            network_addr("net", "240.240.0.1".parse().unwrap())
+        );
+
+        let mut invalid = table;
+        invalid
+            .table
+            .get_mut("api.example.com")
+            .unwrap()
+            .ips
+            .push("not-an-ip".to_string());
+        assert!(NameTableStore::try_from(&invalid).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::config::OutlierDetection;
+
+/// The longest an endpoint is ejected for, however often it was before.
+const MAX_EJECTION_TIME: Duration = Duration::from_secs(300);
+
+/// The number of locks the endpoints are spread across, so connections to different endpoints
+/// seldom contend.
+const HEALTH_SHARDS: usize = 16;
+
+/// OutlierDetector tracks the connections to service endpoints that fail to connect or to complete
+/// their handshake, and ejects endpoints that keep failing from load balancing for a while. Only
+/// our own connections are seen, so this is independent of the health the control plane reports.
+#[derive(Clone, Debug, Default)]
+pub struct OutlierDetector {
+    cfg: Option<OutlierDetection>,
+    health: Arc<Health>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    /// How many endpoints are tracked across the shards, so picks need not lock any while none
+    /// are failing.
+    tracked: AtomicUsize,
+    shards: [Mutex<HashMap<String, EndpointHealth>>; HEALTH_SHARDS],
+}
+
+impl Health {
+    fn shard(&self, uid: &str) -> MutexGuard<'_, HashMap<String, EndpointHealth>> {
+        let mut hasher = DefaultHasher::new();
+        uid.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % HEALTH_SHARDS]
+            .lock()
+            .unwrap()
+    }
+}
+
+#[derive(Debug)]
+struct EndpointHealth {
+    consecutive_errors: u32,
+    /// How often the endpoint was ejected since it last succeeded.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    last_error: Instant,
+}
+
+impl OutlierDetector {
+    pub fn new(cfg: Option<OutlierDetection>) -> Self {
+        Self {
+            cfg,
+            health: Default::default(),
+        }
+    }
+
+    /// success records a connection to the workload with `uid` that succeeded, which clears its
+    /// errors.
+    pub fn success(&self, uid: &str) {
+        if self.cfg.is_none() || self.health.tracked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if self.health.shard(uid).remove(uid).is_some() {
+            self.health.tracked.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// error records a connection to the workload with `uid` that failed. Too many in a row eject
+    /// it, for longer each time it is ejected again without succeeding in between.
+    pub fn error(&self, uid: &str) {
+        let Some(cfg) = &self.cfg else {
+            return;
+        };
+        let now = Instant::now();
+        let mut shard = self.health.shard(uid);
+        let health = shard.entry(uid.to_string()).or_insert_with(|| {
+            self.health.tracked.fetch_add(1, Ordering::Relaxed);
+            EndpointHealth {
+                consecutive_errors: 0,
+                ejections: 0,
+                ejected_until: None,
+                last_error: now,
+            }
+        });
+        health.last_error = now;
+        if health.ejected_until.map_or(false, |until| until > now) {
+            return;
+        }
+        health.consecutive_errors += 1;
+        if health.consecutive_errors < cfg.consecutive_errors {
+            return;
+        }
+        health.consecutive_errors = 0;
+        health.ejections += 1;
+        let ejection = std::cmp::min(cfg.base_ejection_time * health.ejections, MAX_EJECTION_TIME);
+        health.ejected_until = Some(now + ejection);
+        info!(
+            workload = uid,
+            ?ejection,
+            "ejecting endpoint after consecutive errors"
+        );
+        // Forget endpoints that have not failed in a long time, such as removed ones.
+        let before = shard.len();
+        shard.retain(|_, h| now.duration_since(h.last_error) < MAX_EJECTION_TIME);
+        self.health
+            .tracked
+            .fetch_sub(before - shard.len(), Ordering::Relaxed);
+    }
+
+    /// ejected returns the workloads among `uids`, the endpoints of a service, that load balancing
+    /// should avoid. No more than the ejection cap are, and never all of them: past the cap, those
+    /// that stay ejected the longest are avoided.
+    pub fn ejected<'a>(&self, uids: impl IntoIterator<Item = &'a str>) -> HashSet<&'a str> {
+        let Some(cfg) = &self.cfg else {
+            return HashSet::new();
+        };
+        if self.health.tracked.load(Ordering::Relaxed) == 0 {
+            return HashSet::new();
+        }
+        let now = Instant::now();
+        let mut total = 0;
+        let mut ejected: Vec<(&str, Instant)> = uids
+            .into_iter()
+            .inspect(|_| total += 1)
+            .filter_map(|uid| {
+                let until = self.health.shard(uid).get(uid)?.ejected_until?;
+                (until > now).then_some((uid, until))
+            })
+            .collect();
+        // At least one endpoint may be ejected, as long as it is not the last.
+        let cap = std::cmp::max(1, total * cfg.max_ejection_percent as usize / 100)
+            .min(total.saturating_sub(1));
+        if ejected.len() > cap {
+            ejected.sort_by(|a, b| b.1.cmp(&a.1));
+            ejected.truncate(cap);
+        }
+        ejected.into_iter().map(|(uid, _)| uid).collect()
+    }
+}
+
+impl serde::Serialize for OutlierDetector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let now = Instant::now();
+        let mut ejected = HashMap::new();
+        for shard in &self.health.shards {
+            for (uid, h) in shard.lock().unwrap().iter() {
+                if let Some(until) = h.ejected_until.filter(|until| *until > now) {
+                    ejected.insert(uid.clone(), format!("{:?}", until - now));
+                }
+            }
+        }
+        serializer.collect_map(ejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn ejection() {
+        let detector = OutlierDetector::new(Some(OutlierDetection {
+            consecutive_errors: 2,
+            base_ejection_time: Duration::from_secs(10),
+            max_ejection_percent: 50,
+        }));
+        let endpoints = ["a", "b", "c", "d"];
+
+        detector.error("a");
+        detector.success("a");
+        detector.error("a");
+        assert!(detector.ejected(endpoints).is_empty());
+        detector.error("a");
+        assert_eq!(detector.ejected(endpoints), HashSet::from(["a"]));
+        // The cap of 50% allows a second ejection, but not a third.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        detector.error("b");
+        detector.error("b");
+        assert_eq!(detector.ejected(endpoints), HashSet::from(["a", "b"]));
+        // Past the cap, those whose ejections end last are avoided.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        detector.error("c");
+        detector.error("c");
+        assert_eq!(detector.ejected(endpoints), HashSet::from(["c", "b"]));
+        // Nor may the only endpoint left be ejected.
+        assert!(detector.ejected(["a"]).is_empty());
+
+        // Ejections expire, and last longer each time.
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(detector.ejected(endpoints).is_empty());
+        detector.error("a");
+        detector.error("a");
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(detector.ejected(endpoints), HashSet::from(["a"]));
+
+        let disabled = OutlierDetector::default();
+        disabled.error("a");
+        disabled.error("a");
+        assert!(disabled.ejected(endpoints).is_empty());
+    }
+}
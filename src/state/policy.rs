@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use crate::rbac::{Authorization, RbacScope};
+use crate::readiness;
+use crate::xds::{Freshness, SyncStatus};
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 /// A PolicyStore encapsulates all policy information about workloads in the mesh
 #[derive(serde::Serialize, Default, Debug)]
@@ -77,3 +80,40 @@ impl PolicyStore {
         }
     }
 }
+
+/// PolicySync decides whether our authorization policies are too far out of sync with the control
+/// plane to be enforced, in which case connections are denied rather than authorized against them.
+#[derive(Clone, Debug)]
+pub struct PolicySync {
+    pub status: SyncStatus,
+    /// How long the policies may be stale for before they are treated as unsynced. If unset, only
+    /// the initial sync counts.
+    pub stale_after: Option<Duration>,
+}
+
+impl PolicySync {
+    /// unsynced returns why the policies cannot be enforced, if they cannot.
+    pub fn unsynced(&self) -> Option<String> {
+        match self.status.freshness() {
+            Freshness::NeverSynced => Some("policies have not been synced".to_string()),
+            Freshness::Stale(stale) if self.stale_after.map_or(false, |max| stale > max) => {
+                Some(format!("policies have not been synced for {stale:?}"))
+            }
+            _ => None,
+        }
+    }
+
+    /// gate blocks readiness for as long as the policies are unsynced.
+    pub async fn gate(self, ready: readiness::Ready) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let mut blocked = None;
+        loop {
+            interval.tick().await;
+            match (self.unsynced(), &blocked) {
+                (Some(_), None) => blocked = Some(ready.register_task("policy sync")),
+                (None, Some(_)) => blocked = None,
+                _ => {}
+            }
+        }
+    }
+}
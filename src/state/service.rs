@@ -18,9 +18,13 @@ use crate::state::workload::{
 use crate::xds;
 use crate::xds::istio::workload::PortList;
 use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::trace;
 use xds::istio::workload::Service as XdsService;
 
@@ -38,6 +42,10 @@ pub struct Service {
     pub endpoints: HashMap<String, Endpoint>,
     #[serde(default)]
     pub subject_alt_names: Vec<String>,
+    #[serde(default)]
+    pub session_affinity: SessionAffinity,
+    #[serde(default)]
+    pub connect_policy: ConnectPolicy,
 }
 
 impl Service {
@@ -49,6 +57,95 @@ impl Service {
     }
 }
 
+#[derive(
+    Default, Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize,
+)]
+pub enum SessionAffinity {
+    #[default]
+    None,
+    /// Connections from the same source IP go to the same endpoint, using a [RingHash].
+    ClientIp,
+}
+
+impl TryFrom<Option<xds::istio::workload::SessionAffinity>> for SessionAffinity {
+    type Error = WorkloadError;
+
+    fn try_from(value: Option<xds::istio::workload::SessionAffinity>) -> Result<Self, Self::Error> {
+        match value {
+            Some(xds::istio::workload::SessionAffinity::None) => Ok(SessionAffinity::None),
+            Some(xds::istio::workload::SessionAffinity::ClientIp) => Ok(SessionAffinity::ClientIp),
+            None => Err(WorkloadError::EnumParse("unknown session affinity".into())),
+        }
+    }
+}
+
+/// ConnectPolicy is how TCP connections to the endpoints of a service are attempted. It is passed
+/// on to the proxies along the path, in the CONNECT_TIMEOUT_HEADER and CONNECT_RETRIES_HEADER.
+#[derive(
+    Default, Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConnectPolicy {
+    /// How long each connection attempt may take. If unset, the proxy's own timeout applies.
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+    /// How many times a failed connection attempt may be retried.
+    #[serde(default)]
+    pub max_retries: u32,
+}
+
+impl From<Option<&xds::istio::workload::ConnectPolicy>> for ConnectPolicy {
+    fn from(value: Option<&xds::istio::workload::ConnectPolicy>) -> Self {
+        let Some(p) = value else {
+            return ConnectPolicy::default();
+        };
+        ConnectPolicy {
+            timeout: (p.timeout_ms > 0).then(|| Duration::from_millis(p.timeout_ms.into())),
+            max_retries: p.max_retries,
+        }
+    }
+}
+
+/// Points each endpoint has on the ring. More points spread keys more evenly across endpoints, but
+/// make the ring larger and slower to rebuild when the endpoints change.
+const RING_REPLICAS: usize = 64;
+
+/// RingHash consistently maps keys to endpoints: when an endpoint is added or removed, only the
+/// keys that map to it move.
+#[derive(Debug, Default)]
+pub struct RingHash {
+    /// Points on the ring and the endpoint UID they belong to, sorted.
+    ring: Vec<(u64, String)>,
+}
+
+impl RingHash {
+    pub fn new<'a>(endpoint_uids: impl IntoIterator<Item = &'a String>) -> RingHash {
+        let mut ring: Vec<_> = endpoint_uids
+            .into_iter()
+            .flat_map(|uid| (0..RING_REPLICAS).map(move |i| (hash(&(uid, i)), uid.clone())))
+            .collect();
+        ring.sort_unstable();
+        RingHash { ring }
+    }
+
+    /// Returns the endpoint UIDs for `key`, in order of preference. The first is the endpoint `key`
+    /// maps to, and the ones after it take over, in turn, if it cannot be used.
+    pub fn lookup(&self, key: impl Hash) -> impl Iterator<Item = &str> {
+        let key = hash(&key);
+        let start = self.ring.partition_point(|(point, _)| *point < key);
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .map(|(_, uid)| uid.as_str())
+    }
+}
+
+fn hash(key: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Debug, Hash, Eq, PartialEq, Clone, serde::Serialize)]
 pub struct ServiceDescription {
     pub hostname: String,
@@ -114,6 +211,10 @@ impl TryFrom<&XdsService> for Service {
                 .into(),
             endpoints: Default::default(), // Will be populated once inserted into the store.
             subject_alt_names: s.subject_alt_names.clone(),
+            session_affinity: SessionAffinity::try_from(
+                xds::istio::workload::SessionAffinity::from_i32(s.session_affinity),
+            )?,
+            connect_policy: s.connect_policy.as_ref().into(),
         };
         Ok(svc)
     }
@@ -138,6 +239,11 @@ pub struct ServiceStore {
     /// service for a given hostname. However, `ServiceEntry` allows hostnames to be overridden
     /// on a per-namespace basis.
     by_host: HashMap<String, Vec<Arc<Service>>>,
+
+    /// The endpoint rings of services with [SessionAffinity::ClientIp]. Each is only built on the
+    /// first lookup after the service changes, as endpoints are often added one by one.
+    #[serde(skip)]
+    rings: HashMap<NamespacedHostname, OnceCell<RingHash>>,
 }
 
 impl ServiceStore {
@@ -186,6 +292,19 @@ impl ServiceStore {
         }
     }
 
+    /// Returns the endpoint ring of the service, if it has session affinity.
+    pub fn ring(&self, host: &NamespacedHostname) -> Option<&RingHash> {
+        let ring = self.rings.get(host)?;
+        Some(ring.get_or_init(|| {
+            let endpoints = self
+                .by_host
+                .get(&host.hostname)
+                .and_then(|services| services.iter().find(|s| s.namespace == host.namespace))
+                .map(|s| s.endpoints.keys());
+            RingHash::new(endpoints.into_iter().flatten())
+        }))
+    }
+
     /// Adds an endpoint for the service VIP.
     pub fn insert_endpoint(&mut self, ep: Endpoint) {
         let ep_uid = endpoint_uid(&ep.workload_uid, ep.address.as_ref());
@@ -257,6 +376,11 @@ impl ServiceStore {
         // If we're replacing an existing service, remove the old one from all data structures.
         let _ = self.remove(&namespaced_hostname);
 
+        if service.session_affinity == SessionAffinity::ClientIp {
+            self.rings
+                .insert(namespaced_hostname.clone(), OnceCell::new());
+        }
+
         // Save values used for the indexes.
         let vips = service.vips.clone();
         let hostname = service.hostname.clone();
@@ -322,6 +446,7 @@ impl ServiceStore {
                 // Remove the staged service.
                 // TODO(nmittler): no endpoints for this service should be staged at this point.
                 self.staged_services.remove(namespaced_host);
+                self.rings.remove(namespaced_host);
 
                 // Remove mapping from workload to the VIPs for this service.
                 for (ep_ip, _) in prev.endpoints.iter() {
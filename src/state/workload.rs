@@ -159,6 +159,15 @@ pub struct Workload {
 
     #[serde(default)]
     pub cluster_id: String,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub excluded_outbound_ports: Vec<u16>,
+    /// If not empty, only outbound connections to these ports are captured.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub included_outbound_ports: Vec<u16>,
+    /// If not empty, the only ports the workload accepts connections on.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ports: Vec<u16>,
 }
 
 fn is_default<T: Default + PartialEq>(t: &T) -> bool {
@@ -166,6 +175,32 @@ fn is_default<T: Default + PartialEq>(t: &T) -> bool {
 }
 
 impl Workload {
+    /// captures_outbound_port returns whether outbound connections from this workload to `port`
+    /// go through the mesh, rather than being passed through.
+    pub fn captures_outbound_port(&self, port: u16) -> bool {
+        if self.excluded_outbound_ports.contains(&port) {
+            return false;
+        }
+        self.included_outbound_ports.is_empty() || self.included_outbound_ports.contains(&port)
+    }
+
+    /// declares_port returns whether the workload accepts inbound connections on `port`. Workloads
+    /// that declare no ports accept connections on any.
+    pub fn declares_port(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.contains(&port)
+    }
+
+    /// on_node returns whether the workload runs on the node named `node`, whose address is
+    /// `node_ip`. Workloads that do not report their node, such as VMs on the same host, are
+    /// recognized by their tunnel going to the node's address.
+    pub fn on_node(&self, node: Option<&str>, node_ip: Option<IpAddr>) -> bool {
+        if !self.node.is_empty() {
+            return node == Some(self.node.as_str());
+        }
+        let gateway = self.gateway_address.map(|gw| gw.ip());
+        gateway.is_some() && gateway == node_ip
+    }
+
     pub fn identity(&self) -> Identity {
         Identity::Spiffe {
             trust_domain: self.trust_domain.to_string(),
@@ -344,10 +379,21 @@ impl TryFrom<&XdsWorkload> for Workload {
                     result
                 }
             },
+
+            excluded_outbound_ports: ports(&resource.excluded_outbound_ports)?,
+            included_outbound_ports: ports(&resource.included_outbound_ports)?,
+            ports: ports(&resource.ports)?,
         })
     }
 }
 
+fn ports(ports: &[u32]) -> Result<Vec<u16>, WorkloadError> {
+    ports
+        .iter()
+        .map(|&p| u16::try_from(p).map_err(|_| WorkloadError::PortParse(p)))
+        .collect()
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum WaypointError {
@@ -494,20 +540,38 @@ pub struct WorkloadStore {
     by_uid: HashMap<String, Arc<Workload>>,
     /// byHostname maps workload hostname to workloads.
     by_hostname: HashMap<String, Arc<Workload>>,
+    /// The addresses and hostnames of the workloads of remote clusters, which are only used where
+    /// no workload of our own cluster has them.
+    remote_by_addr: HashMap<NetworkAddress, Arc<Workload>>,
+    remote_by_hostname: HashMap<String, Arc<Workload>>,
 }
 
 impl WorkloadStore {
     pub fn insert(&mut self, w: Workload) -> anyhow::Result<()> {
+        self.insert_into(w, false)
+    }
+
+    /// insert_remote inserts a workload of a remote cluster. Workloads of our own cluster take
+    /// precedence over it for the addresses and hostname they share.
+    pub fn insert_remote(&mut self, w: Workload) -> anyhow::Result<()> {
+        self.insert_into(w, true)
+    }
+
+    fn insert_into(&mut self, w: Workload, remote: bool) -> anyhow::Result<()> {
         // First, remove the entry entirely to make sure things are cleaned up properly.
         self.remove(w.uid.as_str());
 
         let w = Arc::new(w);
+        let (by_addr, by_hostname) = if remote {
+            (&mut self.remote_by_addr, &mut self.remote_by_hostname)
+        } else {
+            (&mut self.by_addr, &mut self.by_hostname)
+        };
         for ip in &w.workload_ips {
-            self.by_addr
-                .insert(network_addr(&w.network, *ip), w.clone());
+            by_addr.insert(network_addr(&w.network, *ip), w.clone());
         }
         if !w.hostname.is_empty() {
-            self.by_hostname.insert(w.hostname.clone(), w.clone());
+            by_hostname.insert(w.hostname.clone(), w.clone());
         }
         self.by_uid.insert(w.uid.clone(), w.clone());
         Ok(())
@@ -520,10 +584,22 @@ impl WorkloadStore {
                 None
             }
             Some(prev) => {
+                // Only remove the entries that are this workload's, rather than another's with the
+                // same address or hostname.
+                let own = |wl: &Arc<Workload>| wl.uid == prev.uid;
                 for wip in prev.workload_ips.iter() {
-                    self.by_addr.remove(&network_addr(&prev.network, *wip));
+                    let addr = network_addr(&prev.network, *wip);
+                    for by_addr in [&mut self.by_addr, &mut self.remote_by_addr] {
+                        if by_addr.get(&addr).map_or(false, own) {
+                            by_addr.remove(&addr);
+                        }
+                    }
+                }
+                for by_hostname in [&mut self.by_hostname, &mut self.remote_by_hostname] {
+                    if by_hostname.get(prev.hostname.as_str()).map_or(false, own) {
+                        by_hostname.remove(prev.hostname.as_str());
+                    }
                 }
-                self.by_hostname.remove(prev.hostname.as_str());
                 Some(prev.deref().clone())
             }
         }
@@ -531,13 +607,17 @@ impl WorkloadStore {
 
     /// Finds the workload by address.
     pub fn find_address(&self, addr: &NetworkAddress) -> Option<Workload> {
-        self.by_addr.get(addr).map(|wl| wl.deref().clone())
+        self.by_addr
+            .get(addr)
+            .or_else(|| self.remote_by_addr.get(addr))
+            .map(|wl| wl.deref().clone())
     }
 
     /// Finds the workload by hostname.
     pub fn find_hostname<T: AsRef<str>>(&self, hostname: T) -> Option<Workload> {
         self.by_hostname
             .get(hostname.as_ref())
+            .or_else(|| self.remote_by_hostname.get(hostname.as_ref()))
             .map(|wl| wl.deref().clone())
     }
 
@@ -562,6 +642,8 @@ pub enum WorkloadError {
     EnumParse(String),
     #[error("nonempty gateway address is missing address")]
     MissingGatewayAddress,
+    #[error("invalid port: {0}")]
+    PortParse(u32),
 }
 
 #[cfg(test)]
@@ -662,6 +744,77 @@ mod tests {
         assert_eq!(maybe_loopback_ip.to_string(), "::1");
     }
 
+    #[test]
+    fn outbound_ports() {
+        let xds = XdsWorkload {
+            excluded_outbound_ports: vec![7000],
+            included_outbound_ports: vec![80, 7000],
+            ..Default::default()
+        };
+        let wl = Workload::try_from(&xds).unwrap();
+        assert!(wl.captures_outbound_port(80));
+        // Exclusions win over inclusions.
+        assert!(!wl.captures_outbound_port(7000));
+        assert!(!wl.captures_outbound_port(443));
+        // Without inclusions, every port not excluded is captured.
+        let wl = Workload {
+            included_outbound_ports: vec![],
+            ..wl
+        };
+        assert!(wl.captures_outbound_port(443));
+
+        let xds = XdsWorkload {
+            excluded_outbound_ports: vec![70000],
+            ..Default::default()
+        };
+        assert_eq!(
+            Workload::try_from(&xds),
+            Err(WorkloadError::PortParse(70000))
+        );
+    }
+
+    #[test]
+    fn declared_ports() {
+        let xds = XdsWorkload {
+            ports: vec![8080, 9090],
+            ..Default::default()
+        };
+        let wl = Workload::try_from(&xds).unwrap();
+        assert!(wl.declares_port(9090));
+        assert!(!wl.declares_port(22));
+        // Workloads that declare no ports accept connections on any.
+        let wl = Workload {
+            ports: vec![],
+            ..wl
+        };
+        assert!(wl.declares_port(22));
+    }
+
+    #[test]
+    fn on_node() {
+        let node_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let wl = Workload {
+            node: "node-a".to_string(),
+            ..test_helpers::test_default_workload()
+        };
+        assert!(wl.on_node(Some("node-a"), node_ip));
+        assert!(!wl.on_node(Some("node-b"), node_ip));
+
+        // Without a node, only a tunnel to the node's address counts.
+        let wl = Workload {
+            node: "".to_string(),
+            gateway_address: Some(SocketAddr::new(node_ip.unwrap(), 15008)),
+            ..wl
+        };
+        assert!(wl.on_node(Some("node-a"), node_ip));
+        assert!(!wl.on_node(Some("node-a"), None));
+        let wl = Workload {
+            gateway_address: None,
+            ..wl
+        };
+        assert!(!wl.on_node(Some("node-a"), node_ip));
+    }
+
     #[test]
     fn workload_information() {
         initialize_telemetry();
@@ -779,6 +932,7 @@ mod tests {
                     target_port: 80,
                 }],
                 subject_alt_names: vec![],
+                ..Default::default()
             })
             .unwrap();
         assert_eq!((state.read().unwrap().services.num_vips()), 1);
@@ -806,6 +960,7 @@ mod tests {
                     target_port: 80,
                 }],
                 subject_alt_names: vec![],
+                ..Default::default()
             })
             .unwrap();
 
@@ -856,6 +1011,7 @@ mod tests {
                     target_port: 80,
                 }],
                 subject_alt_names: vec![],
+                ..Default::default()
             })
             .unwrap();
 
@@ -1053,6 +1209,102 @@ mod tests {
         assert_eq!((state.read().unwrap().services.num_staged_services()), 0); // should remove the VIP if no longer needed
     }
 
+    #[test]
+    fn remote_cluster_workloads() {
+        initialize_telemetry();
+        let state = Arc::new(RwLock::new(ProxyState::default()));
+        let updater = ProxyStateUpdater::new_no_fetch(state.clone());
+        let remote = updater.clone().for_cluster("east".to_string());
+        let svc = NamespacedHostname {
+            namespace: "ns".to_string(),
+            hostname: "svc1.ns.svc.cluster.local".to_string(),
+        };
+        let service = XdsService {
+            name: "svc1".to_string(),
+            namespace: "ns".to_string(),
+            hostname: svc.hostname.clone(),
+            ports: vec![XdsPort {
+                service_port: 80,
+                target_port: 8080,
+            }],
+            ..Default::default()
+        };
+        let workload = |ip: [u8; 4]| XdsWorkload {
+            uid: "ns/my-pod".to_string(),
+            addresses: vec![Bytes::copy_from_slice(&ip)],
+            name: "my-pod".to_string(),
+            services: HashMap::from([(
+                "ns/svc1.ns.svc.cluster.local".to_string(),
+                XdsPortList {
+                    ports: vec![XdsPort {
+                        service_port: 80,
+                        target_port: 8080,
+                    }],
+                },
+            )]),
+            ..Default::default()
+        };
+        let endpoints = || {
+            state
+                .read()
+                .unwrap()
+                .services
+                .get_by_namespaced_host(&svc)
+                .map(|s| s.endpoints.len())
+        };
+
+        updater.insert_service(service.clone()).unwrap();
+        updater.insert_workload(workload([127, 0, 0, 1])).unwrap();
+        // The same name in another cluster is another workload, which joins our service.
+        remote.insert_workload(workload([127, 0, 0, 2])).unwrap();
+        {
+            let state = state.read().unwrap();
+            assert_eq!(state.workloads.by_uid.len(), 2);
+            let wl = state.workloads.find_uid("east/ns/my-pod").unwrap();
+            assert_eq!(wl.cluster_id, "east");
+        }
+        assert_eq!(endpoints(), Some(2));
+
+        // Services belong to our own control plane.
+        remote
+            .insert_service(XdsService {
+                ports: vec![],
+                ..service
+            })
+            .unwrap();
+        remote.remove(&"ns/svc1.ns.svc.cluster.local".to_string());
+        assert_eq!(endpoints(), Some(2));
+
+        remote.remove(&"ns/my-pod".to_string());
+        {
+            let state = state.read().unwrap();
+            assert_eq!(state.workloads.by_uid.len(), 1);
+            assert!(state.workloads.find_uid("ns/my-pod").is_some());
+        }
+        assert_eq!(endpoints(), Some(1));
+
+        // Where a remote workload has the address of one of ours, ours wins.
+        let local_addr = network_addr("", "127.0.0.1".parse().unwrap());
+        remote
+            .insert_workload(XdsWorkload {
+                uid: "ns/other-pod".to_string(),
+                name: "other-pod".to_string(),
+                ..workload([127, 0, 0, 1])
+            })
+            .unwrap();
+        let found = |state: &Arc<RwLock<ProxyState>>| {
+            state
+                .read()
+                .unwrap()
+                .workloads
+                .find_address(&local_addr)
+                .map(|w| w.uid)
+        };
+        assert_eq!(found(&state), Some("ns/my-pod".to_string()));
+        updater.remove(&"ns/my-pod".to_string());
+        assert_eq!(found(&state), Some("east/ns/other-pod".to_string()));
+    }
+
     #[track_caller]
     fn assert_vips(state: &DemandProxyState, want: Vec<&str>) {
         let mut wants: HashSet<String> = HashSet::from_iter(want.iter().map(|x| x.to_string()));
@@ -1060,11 +1312,12 @@ mod tests {
         // VIP has randomness. We will try to fetch the VIP 1k times and assert the we got the expected results
         // at least once, and no unexpected results
         for _ in 0..1000 {
-            if let Some(us) = state
-                .state
-                .read()
-                .unwrap()
-                .find_upstream("", "127.0.1.1:80".parse().unwrap())
+            if let Some(us) =
+                state
+                    .state
+                    .read()
+                    .unwrap()
+                    .find_upstream("", None, "127.0.1.1:80".parse().unwrap())
             {
                 let n = &us.workload.name; // borrow name instead of cloning
                 found.insert(n.to_owned()); // insert an owned copy of the borrowed n
@@ -1108,11 +1361,12 @@ mod tests {
         // Make sure we get a valid workload
         assert!(wl.is_some());
         assert_eq!(wl.unwrap().service_account, "default");
-        let us = demand
-            .state
-            .read()
-            .unwrap()
-            .find_upstream("", "127.10.0.1:80".parse().unwrap());
+        let us =
+            demand
+                .state
+                .read()
+                .unwrap()
+                .find_upstream("", None, "127.10.0.1:80".parse().unwrap());
         // Make sure we get a valid VIP
         assert!(us.is_some());
         assert_eq!(us.clone().unwrap().port, 8080);
@@ -1122,11 +1376,11 @@ mod tests {
         );
 
         // test that we can have a service in another network than workloads it selects
-        let us = demand
-            .state
-            .read()
-            .unwrap()
-            .find_upstream("remote", "127.10.0.2:80".parse().unwrap());
+        let us = demand.state.read().unwrap().find_upstream(
+            "remote",
+            None,
+            "127.10.0.2:80".parse().unwrap(),
+        );
         // Make sure we get a valid VIP
         assert!(us.is_some());
         assert_eq!(us.unwrap().port, 8080);
@@ -1,3 +1,4 @@
+use std::str::FromStr;
 use std::time::Instant;
 
 // Copyright Istio Authors
@@ -16,13 +17,24 @@ use std::time::Instant;
 use atty::Stream;
 use once_cell::sync::Lazy;
 use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
 use thiserror::Error;
-use tracing::{error, info, warn};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, span, warn, Event, Subscriber};
+use tracing_subscriber::fmt::format::{self, Format, Full, Writer};
+use tracing_subscriber::fmt::time::SystemTime;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{filter, filter::EnvFilter, fmt, prelude::*, reload, Layer, Registry};
 
 pub static APPLICATION_START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
 static LOG_HANDLE: OnceCell<LogHandle> = OnceCell::new();
 
+/// LOG_FORMAT selects how logs are written: `plain` (the default) for humans, or `json` for one
+/// JSON object per line, for log pipelines such as Loki or Elasticsearch.
+const LOG_FORMAT: &str = "LOG_FORMAT";
+
 #[cfg(feature = "console")]
 pub fn setup_logging() {
     Lazy::force(&APPLICATION_START_TIME);
@@ -45,8 +57,16 @@ fn fmt_layer() -> impl Layer<Registry> + Sized {
     } else {
         format
     };
+    let (fields, format) = match std::env::var(LOG_FORMAT).as_deref() {
+        Ok("json") => (LogFields::Json, LogFormat::Json),
+        _ => (
+            LogFields::Plain(format::DefaultFields::new()),
+            LogFormat::Plain(format),
+        ),
+    };
     let (filter_layer, reload_handle) = reload::Layer::new(
         tracing_subscriber::fmt::layer()
+            .fmt_fields(fields)
             .event_format(format)
             .with_filter(default_env_filter()),
     );
@@ -56,6 +76,142 @@ fn fmt_layer() -> impl Layer<Registry> + Sized {
     filter_layer
 }
 
+/// LogFormat formats events either as plain text or as a flat JSON object. JSON objects always
+/// carry `ts`, `level`, `target` and `message`, along with the fields of the event and of the
+/// spans it is in, so connection logs also carry `connection_id`, `src.identity` and
+/// `dst.identity` when known.
+enum LogFormat {
+    Plain(Format<Full, SystemTime>),
+    Json,
+}
+
+impl<S> FormatEvent<S, LogFields> for LogFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, LogFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if let LogFormat::Plain(format) = self {
+            return format.format_event(ctx, writer, event);
+        }
+        let meta = event.metadata();
+        let mut obj = Map::new();
+        // Outer spans first, so that inner spans and the event itself override their fields.
+        for span in ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            if let Some(fields) = span.extensions().get::<FormattedFields<LogFields>>() {
+                if let Ok(Value::Object(fields)) = serde_json::from_str(&fields.fields) {
+                    obj.extend(fields);
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut obj));
+        obj.insert(
+            "ts".to_string(),
+            chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true)
+                .into(),
+        );
+        obj.insert("level".to_string(), meta.level().as_str().into());
+        obj.insert("target".to_string(), meta.target().into());
+        writeln!(writer, "{}", Value::Object(obj))
+    }
+}
+
+/// LogFields formats span fields to match [LogFormat]; for JSON, a span's fields are kept as a
+/// JSON object so they can be merged into each event in the span.
+enum LogFields {
+    Plain(format::DefaultFields),
+    Json,
+}
+
+impl<'w> FormatFields<'w> for LogFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(
+        &self,
+        writer: Writer<'w>,
+        fields: R,
+    ) -> std::fmt::Result {
+        match self {
+            LogFields::Plain(f) => f.format_fields(writer, fields),
+            LogFields::Json => {
+                let mut obj = Map::new();
+                fields.record(&mut JsonVisitor(&mut obj));
+                write_json(writer, obj)
+            }
+        }
+    }
+
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &span::Record<'_>,
+    ) -> std::fmt::Result {
+        match self {
+            LogFields::Plain(f) => {
+                if !current.fields.is_empty() {
+                    current.fields.push(' ');
+                }
+                f.format_fields(current.as_writer(), fields)
+            }
+            LogFields::Json => {
+                let mut obj = match serde_json::from_str(&current.fields) {
+                    Ok(Value::Object(obj)) => obj,
+                    _ => Map::new(),
+                };
+                fields.record(&mut JsonVisitor(&mut obj));
+                current.fields.clear();
+                write_json(current.as_writer(), obj)
+            }
+        }
+    }
+}
+
+fn write_json(mut writer: Writer<'_>, obj: Map<String, Value>) -> std::fmt::Result {
+    write!(writer, "{}", Value::Object(obj))
+}
+
+/// JsonVisitor records fields into a JSON object.
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        self.0.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into())
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into())
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into())
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into())
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.into())
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, format!("{value:?}").into())
+    }
+}
+
 fn default_env_filter() -> EnvFilter {
     EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new("info"))
@@ -63,7 +219,7 @@ fn default_env_filter() -> EnvFilter {
 }
 
 // a handle to get and set the log level
-type BoxLayer = fmt::Layer<Registry>;
+type BoxLayer = fmt::Layer<Registry, LogFields, LogFormat>;
 type FilteredLayer = filter::Filtered<BoxLayer, EnvFilter, Registry>;
 type LogHandle = reload::Handle<FilteredLayer, Registry>;
 
@@ -97,6 +253,35 @@ pub fn set_level(reset: bool, level: &str) -> Result<(), Error> {
     }
 }
 
+/// parse_level converts the admin API level syntax into [EnvFilter] directives. Each comma
+/// separated entry is either a bare level, which applies globally, or a per-target override
+/// written as `target:level` (matching Envoy) or `target=level` (matching `RUST_LOG`).
+pub fn parse_level(level: &str) -> Result<String, Error> {
+    level
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| {
+            let (target, lvl) = match d.split_once('=') {
+                Some((target, lvl)) => (Some(target), lvl),
+                // Targets are module paths, so only a single trailing ':' separates the level.
+                None => match d.rsplit_once(':') {
+                    Some((target, lvl)) if !target.ends_with(':') => (Some(target), lvl),
+                    _ => (None, d),
+                },
+            };
+            if LevelFilter::from_str(lvl).is_err() || target.map_or(false, str::is_empty) {
+                return Err(Error::InvalidLevel(d.to_string()));
+            }
+            Ok(match target {
+                Some(target) => format!("{target}={lvl}"),
+                None => lvl.to_string(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|directives| directives.join(","))
+}
+
 pub fn get_current_loglevel() -> Result<String, Error> {
     if let Some(handle) = LOG_HANDLE.get() {
         Ok(handle.with_current(|f| f.filter().to_string())?)
@@ -111,6 +296,70 @@ pub enum Error {
     InvalidFilter(#[from] filter::ParseError),
     #[error("reload failure: {0}")]
     Reload(#[from] reload::Error),
+    #[error("invalid level: {0}")]
+    InvalidLevel(String),
     #[error("logging is not initialized")]
     Uninitialized,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_format() {
+        let buf = Buffer::default();
+        let writer = buf.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            fmt::layer()
+                .fmt_fields(LogFields::Json)
+                .event_format(LogFormat::Json)
+                .with_writer(move || writer.clone()),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "outbound",
+                connection_id = "abc",
+                src.identity = tracing::field::Empty
+            );
+            span.record("src.identity", "spiffe://cluster.local/ns/default/sa/a");
+            let _guard = span.enter();
+            info!(
+                dst.identity = "spiffe://cluster.local/ns/default/sa/b",
+                "proxying"
+            );
+        });
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let log: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(log["level"], "INFO");
+        assert_eq!(log["target"], module_path!());
+        assert_eq!(log["message"], "proxying");
+        assert_eq!(log["connection_id"], "abc");
+        assert_eq!(
+            log["src.identity"],
+            "spiffe://cluster.local/ns/default/sa/a"
+        );
+        assert_eq!(
+            log["dst.identity"],
+            "spiffe://cluster.local/ns/default/sa/b"
+        );
+        assert!(log["ts"].is_string());
+    }
+}
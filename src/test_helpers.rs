@@ -14,6 +14,8 @@
 
 use crate::config::ConfigSource;
 use crate::config::{self, RootCert};
+use crate::proxy::metrics::{ConnectionOpen, Reporter, SecurityPolicy};
+use crate::proxy::ConnectionId;
 use crate::state::service::{Endpoint, Service};
 use crate::state::workload::Protocol;
 use crate::state::workload::Protocol::{HBONE, TCP};
@@ -87,6 +89,7 @@ pub fn test_config_with_port_xds_addr_and_root_cert(
         // inbound_addr cannot do localhost since we abuse that its listening on all of 127.0.0.0/8 range.
         inbound_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
         socks5_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+        http_connect_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
         admin_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
         readiness_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
         stats_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
@@ -114,6 +117,7 @@ pub async fn add_nip_io_nameserver(mut cfg: config::Config) -> config::Config {
             protocol: trust_dns_resolver::config::Protocol::Udp,
             tls_dns_name: None,
             trust_nx_responses: false,
+            tls_config: None,
             bind_addr: None,
         };
         cfg.dns_resolver_cfg.add_name_server(name_server);
@@ -174,6 +178,8 @@ pub fn mock_default_service() -> Service {
         ports,
         endpoints,
         subject_alt_names: vec![],
+        session_affinity: Default::default(),
+        connect_policy: Default::default(),
     }
 }
 
@@ -201,6 +207,27 @@ pub fn test_default_workload() -> Workload {
 
         authorization_policies: Vec::new(),
         native_tunnel: false,
+        excluded_outbound_ports: Vec::new(),
+        included_outbound_ports: Vec::new(),
+        ports: Vec::new(),
+    }
+}
+
+/// test_connection_open returns the open of a connection reported by `reporter`, between peers
+/// nothing is known of, over mTLS.
+pub fn test_connection_open(reporter: Reporter) -> ConnectionOpen {
+    ConnectionOpen {
+        reporter,
+        source: None,
+        derived_source: None,
+        destination: None,
+        derived_destination: None,
+        destination_service: None,
+        connection_security_policy: SecurityPolicy::mutual_tls,
+        trace_id: None,
+        connection_id: ConnectionId::new(),
+        source_ip: None,
+        destination_addr: None,
     }
 }
 
@@ -276,6 +303,8 @@ fn test_custom_svc(
             },
         )]),
         subject_alt_names: vec!["spiffe://cluster.local/ns/default/sa/default".to_string()],
+        session_affinity: Default::default(),
+        connect_policy: Default::default(),
     })
 }
 
@@ -33,6 +33,7 @@ use tokio::net::{TcpSocket, TcpStream};
 
 use crate::app::Bound;
 use crate::identity::SecretManager;
+use crate::signal::ShutdownTrigger;
 use crate::test_helpers::{localhost_error_message, TEST_WORKLOAD_SOURCE};
 use crate::*;
 
@@ -46,6 +47,8 @@ pub struct TestApp {
     pub proxy_addresses: proxy::Addresses,
     pub dns_proxy_address: Option<SocketAddr>,
     pub cert_manager: Arc<SecretManager>,
+    /// Starts a graceful shutdown of the app, as the admin server's quitquitquit would.
+    pub shutdown: ShutdownTrigger,
 }
 
 impl From<(&Bound, Arc<SecretManager>)> for TestApp {
@@ -57,6 +60,7 @@ impl From<(&Bound, Arc<SecretManager>)> for TestApp {
             readiness_address: app.readiness_address,
             dns_proxy_address: app.dns_proxy_address,
             cert_manager,
+            shutdown: app.shutdown.trigger(),
         }
     }
 }
@@ -172,6 +176,39 @@ impl TestApp {
         socks5_connect(stream, addr).await.unwrap()
     }
 
+    /// http_connect opens a tunnel to `authority` through the HTTP CONNECT proxy, as
+    /// TEST_WORKLOAD_SOURCE.
+    pub async fn http_connect(&self, authority: &str) -> anyhow::Result<TcpStream> {
+        let proxy_addr = with_ip(
+            self.proxy_addresses.http_connect,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        );
+        let socket = TcpSocket::new_v4()?;
+        socket
+            .bind(SocketAddr::from((
+                TEST_WORKLOAD_SOURCE.parse::<IpAddr>()?,
+                0,
+            )))
+            .map_err(|e| anyhow!("{:?}. {}", e, localhost_error_message()))?;
+        let mut stream = socket.connect(proxy_addr).await?;
+        stream.set_nodelay(true)?;
+        stream
+            .write_all(
+                format!("CONNECT {authority} HTTP/1.1\r\nhost: {authority}\r\n\r\n").as_bytes(),
+            )
+            .await?;
+        // The proxy responds with nothing but a status line.
+        let mut resp = Vec::new();
+        while !resp.ends_with(b"\r\n\r\n") {
+            resp.push(stream.read_u8().await?);
+        }
+        let resp = String::from_utf8(resp)?;
+        if !resp.starts_with("HTTP/1.1 200") {
+            anyhow::bail!("CONNECT failed: {}", resp.trim_end());
+        }
+        Ok(stream)
+    }
+
     pub async fn dns_request(
         &self,
         hostname: &str,
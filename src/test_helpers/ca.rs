@@ -79,8 +79,10 @@ impl CaServer {
             AuthSource::Token(
                 PathBuf::from(r"src/test_helpers/fake-jwt"),
                 "Kubernetes".to_string(),
+                None,
             ),
             true,
+            None,
         )
         .unwrap();
         (tx, client)
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::config::ConfigSource;
+use crate::rbac::Authorization;
 use crate::state::service::{endpoint_uid, Endpoint, Service};
 use crate::state::workload::{gatewayaddress, Workload};
 use crate::test_helpers::app::TestApp;
@@ -28,12 +29,15 @@ use tracing::info;
 
 /// WorkloadManager provides an interface to deploy "workloads" as part of a test. Each workload
 /// runs in its own isolated network namespace, simulating a real environment. Redirection in the "host network"
-/// namespace is configured, which can redirect traffic to a ztunnel.
-/// Note: at this time, only a single "node" (and therefor, ztunnel), is supported.
+/// namespace of each node is configured, which can redirect traffic to that node's ztunnel.
+/// Every ztunnel is served the same workloads, services and policies, in place of XDS, and issues
+/// certificates from a fake CA.
 pub struct WorkloadManager {
     namespaces: netns::NamespaceManager,
     /// workloads that we have constructed
     workloads: Vec<LocalWorkload>,
+    /// authorization policies that we have constructed
+    policies: Vec<Authorization>,
     /// services that we have constructed. VIP -> SVC
     services: HashMap<NamespacedHostname, Service>,
     /// Node to IPs on the node that are captured
@@ -47,6 +51,7 @@ impl WorkloadManager {
         Ok(Self {
             namespaces: netns::NamespaceManager::new(name)?,
             workloads: vec![],
+            policies: vec![],
             services: HashMap::new(),
             captured_workloads: Default::default(),
             waypoints: vec![],
@@ -66,7 +71,7 @@ impl WorkloadManager {
         let veth = ns.interface();
         let lc = LocalConfig {
             workloads: self.workloads.clone(),
-            policies: vec![],
+            policies: self.policies.clone(),
             services: self.services.values().cloned().collect_vec(),
         };
         let mut b = bytes::BytesMut::new().writer();
@@ -99,9 +104,11 @@ impl WorkloadManager {
                     outbound: helpers::with_ip(proxy_addresses.outbound, ip),
                     inbound: helpers::with_ip(proxy_addresses.inbound, ip),
                     socks5: helpers::with_ip(proxy_addresses.socks5, ip),
+                    http_connect: helpers::with_ip(proxy_addresses.http_connect, ip),
                 },
                 dns_proxy_address: Some(helpers::with_ip(app.dns_proxy_address.unwrap(), ip)),
                 cert_manager,
+                shutdown: app.shutdown.trigger(),
             };
             ta.ready().await;
             info!("ready");
@@ -134,6 +141,11 @@ impl WorkloadManager {
         TestServiceBuilder::new(name, self)
     }
 
+    /// add_policy registers an authorization policy, enforced by every ztunnel deployed afterwards.
+    pub fn add_policy(&mut self, policy: Authorization) {
+        self.policies.push(policy);
+    }
+
     /// register_waypoint builds a new waypoint. This must be used for waypoints, rather than workload_builder,
     /// or the redirection will not work properly
     pub fn register_waypoint(&mut self, name: &str, node: &str) -> anyhow::Result<Namespace> {
@@ -171,6 +183,8 @@ impl<'a> TestServiceBuilder<'a> {
                 ports: Default::default(),
                 endpoints: Default::default(), // populated later when workloads are added
                 subject_alt_names: vec![],
+                session_affinity: Default::default(),
+                connect_policy: Default::default(),
             },
             manager,
         }
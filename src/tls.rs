@@ -13,10 +13,19 @@
 // limitations under the License.
 
 pub mod boring;
+pub mod crl;
+pub mod early_data;
+pub mod groups;
+pub mod keyless;
+pub mod keylog;
+pub mod resolve;
+pub mod secret;
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
 pub use crate::tls::boring::*;
+pub use crate::tls::keyless::KeyProvider;
 use ::boring::error::ErrorStack;
 use hyper::http::uri::InvalidUri;
 
@@ -28,8 +37,26 @@ pub enum Error {
     #[error("invalid root certificate: {0}")]
     InvalidRootCert(ErrorStack),
 
+    #[error("certificate validity period cannot be represented")]
+    InvalidValidity,
+
+    #[error("no certificate found in chain")]
+    EmptyChain,
+
+    #[error("malformed DER certificate list")]
+    InvalidDer,
+
     #[error("invalid uri: {0}")]
     InvalidUri(#[from] Arc<InvalidUri>),
+
+    #[error("key provider: {0}")]
+    KeyProvider(String),
+
+    #[error("invalid certificate revocation lists in {0}: {1}")]
+    InvalidCrl(PathBuf, String),
+
+    #[error("{0} is already configured")]
+    AlreadyConfigured(&'static str),
 }
 
 impl From<InvalidUri> for Error {
@@ -2,6 +2,7 @@ use std::fmt::Debug;
 use std::future::Future;
 use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 // Copyright Istio Authors
 //
 // Licensed under the Apache License, Version 2.0 (the "License");
@@ -15,11 +16,14 @@ use std::pin::Pin;
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+use super::keyless::KeyProvider;
+use super::resolve::Resolution;
+use super::secret::SecretBytes;
 use super::Error;
-use crate::config::RootCert;
+use crate::config::{LiveConfig, RootCert};
 use crate::identity::{self, Identity};
 use crate::state::workload::NetworkAddress;
-use boring::asn1::{Asn1Time, Asn1TimeRef};
+use boring::asn1::{Asn1Integer, Asn1Time, Asn1TimeRef};
 use boring::bn::BigNum;
 use boring::ec::{EcGroup, EcKey};
 use boring::hash::MessageDigest;
@@ -30,6 +34,7 @@ use boring::ssl::{self, SslContextBuilder};
 use boring::stack::Stack;
 use boring::x509::extension::{
     AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
 };
 use boring::x509::verify::X509CheckFlags;
 use boring::x509::{self, X509StoreContext, X509StoreContextRef, X509VerifyResult};
@@ -37,6 +42,7 @@ use bytes::Bytes;
 use http_body_1::{Body, Frame};
 use hyper::body::Incoming;
 use hyper::{Request, Response, Uri};
+use prometheus_client::metrics::counter::Counter;
 use rand::RngCore;
 use std::str::FromStr;
 use std::task::{Context, Poll};
@@ -68,13 +74,100 @@ pub fn cert_from(key: &[u8], cert: &[u8], chain: Vec<&[u8]>) -> Certs {
     Certs {
         cert: ztunnel_cert,
         chain,
-        key,
+        key: PrivateKey::Local(key),
+        trusted: Vec::new(),
     }
 }
 
+/// Loads certificates from PEM encoded data: the private key, the leaf certificate optionally
+/// followed by intermediates, and the root certificate(s) ending the chain.
+pub fn load_certs(key: &[u8], cert_chain: &[u8], roots: &[u8]) -> Result<Certs, Error> {
+    let key = pkey::PKey::private_key_from_pem(key)?;
+    let mut certs = x509::X509::stack_from_pem(cert_chain)?.into_iter();
+    let cert = certs.next().ok_or(Error::EmptyChain)?;
+    let chain = certs
+        .chain(x509::X509::stack_from_pem(roots)?)
+        .map(ZtunnelCert::new)
+        .collect();
+    Ok(Certs {
+        cert: ZtunnelCert::new(cert),
+        chain,
+        key: PrivateKey::Local(key),
+        trusted: Vec::new(),
+    })
+}
+
+/// Loads PEM encoded certificates like [load_certs], for a private key that is only available
+/// through `provider`.
+pub fn load_keyless_certs(
+    provider: Arc<dyn KeyProvider>,
+    cert_chain: &[u8],
+    roots: &[u8],
+) -> Result<Certs, Error> {
+    let mut certs = x509::X509::stack_from_pem(cert_chain)?.into_iter();
+    let cert = certs.next().ok_or(Error::EmptyChain)?;
+    let chain = certs
+        .chain(x509::X509::stack_from_pem(roots)?)
+        .map(ZtunnelCert::new)
+        .collect();
+    Ok(Certs {
+        cert: ZtunnelCert::new(cert),
+        chain,
+        key: PrivateKey::Provider(provider),
+        trusted: Vec::new(),
+    })
+}
+
+/// Loads certificates from DER data in the layout used by the SPIFFE Workload API: a PKCS#8 key,
+/// and the leaf certificate, along with any intermediates and the roots, as concatenated
+/// certificates.
+pub fn load_der_certs(key: &[u8], cert_chain: &[u8], roots: &[u8]) -> Result<Certs, Error> {
+    let key = pkey::PKey::private_key_from_der(key)?;
+    let mut certs = split_der(cert_chain)?.into_iter();
+    let cert = certs.next().ok_or(Error::EmptyChain)?;
+    let chain = certs
+        .chain(split_der(roots)?)
+        .map(ZtunnelCert::new)
+        .collect();
+    Ok(Certs {
+        cert: ZtunnelCert::new(cert),
+        chain,
+        key: PrivateKey::Local(key),
+        trusted: Vec::new(),
+    })
+}
+
+/// Parses a sequence of concatenated DER encoded certificates.
+pub fn split_der(mut data: &[u8]) -> Result<Vec<x509::X509>, Error> {
+    let mut certs = Vec::new();
+    while !data.is_empty() {
+        // Each certificate is a SEQUENCE; its header tells how long it is.
+        let (header, len) = match data {
+            [0x30, l, ..] if *l < 0x80 => (2, *l as usize),
+            [0x30, l, rest @ ..]
+                if (0x81..=0x84).contains(l) && rest.len() >= (*l & 0x7f) as usize =>
+            {
+                let n = (*l & 0x7f) as usize;
+                let len = rest[..n]
+                    .iter()
+                    .fold(0usize, |acc, b| acc << 8 | *b as usize);
+                (2 + n, len)
+            }
+            _ => return Err(Error::InvalidDer),
+        };
+        let end = header
+            .checked_add(len)
+            .filter(|&end| end <= data.len())
+            .ok_or(Error::InvalidDer)?;
+        certs.push(x509::X509::from_der(&data[..end])?);
+        data = &data[end..];
+    }
+    Ok(certs)
+}
+
 pub struct CertSign {
     pub csr: Vec<u8>,
-    pub pkey: Vec<u8>,
+    pub pkey: SecretBytes,
 }
 
 pub struct CsrOptions {
@@ -104,7 +197,7 @@ impl CsrOptions {
         let csr_pem = csr.to_pem()?;
         Ok(CertSign {
             csr: csr_pem,
-            pkey: pkey_pem,
+            pkey: SecretBytes::new(pkey_pem),
         })
     }
 }
@@ -134,7 +227,29 @@ pub struct Certs {
     cert: ZtunnelCert,
     // the remainder of the chain, not including the leaf cert
     chain: Vec<ZtunnelCert>,
-    key: pkey::PKey<pkey::Private>,
+    key: PrivateKey,
+    // additional roots trusted when verifying peers, but never sent to them
+    trusted: Vec<x509::X509>,
+}
+
+#[derive(Clone, Debug)]
+enum PrivateKey {
+    Local(pkey::PKey<pkey::Private>),
+    // the key is held elsewhere, and only used through the provider
+    Provider(Arc<dyn KeyProvider>),
+}
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PrivateKey::Local(a), PrivateKey::Local(b)) => a
+                .private_key_to_der()
+                .iter()
+                .eq(b.private_key_to_der().iter()),
+            (PrivateKey::Provider(a), PrivateKey::Provider(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 impl PartialEq for Certs {
@@ -144,11 +259,7 @@ impl PartialEq for Certs {
             .to_der()
             .iter()
             .eq(other.cert.x509.to_der().iter())
-            && self
-                .key
-                .private_key_to_der()
-                .iter()
-                .eq(other.key.private_key_to_der().iter())
+            && self.key == other.key
             && self.cert.not_after == other.cert.not_after
             && self.cert.not_before == other.cert.not_before
     }
@@ -195,22 +306,56 @@ impl Certs {
     pub fn x509(&self) -> &x509::X509 {
         &self.cert.x509
     }
+
+    /// with_trusted_roots adds roots to verify peers against without presenting them, such as
+    /// the bundles of federated trust domains.
+    pub fn with_trusted_roots(mut self, roots: impl IntoIterator<Item = x509::X509>) -> Certs {
+        self.trusted.extend(roots);
+        self
+    }
 }
 
+type GrpcClient = hyper_util::client::legacy::Client<
+    hyper_boring::HttpsConnector<hyper_util::client::connect::HttpConnector>,
+    BoxBody1,
+>;
+
 #[derive(Clone, Debug)]
 pub struct TlsGrpcChannel {
     uri: Uri,
-    client: hyper_util::client::legacy::Client<
-        hyper_boring::HttpsConnector<hyper_util::client::connect::HttpConnector>,
-        BoxBody1,
-    >,
+    root_cert: RootCert,
+    client: Arc<Mutex<GrpcClient>>,
+    /// If set, the client is replaced, along with its connection, once the addresses of the host
+    /// change.
+    resolution: Option<Arc<tokio::sync::Mutex<Resolution>>>,
 }
 
 /// grpc_connector provides a client TLS channel for gRPC requests.
 pub fn grpc_connector(uri: String, root_cert: RootCert) -> Result<TlsGrpcChannel, Error> {
+    let uri = Uri::try_from(uri)?;
+    let client = grpc_client(&uri, root_cert.clone())?;
+    Ok(TlsGrpcChannel {
+        uri,
+        root_cert,
+        client: Arc::new(Mutex::new(client)),
+        resolution: None,
+    })
+}
+
+impl TlsGrpcChannel {
+    /// with_reresolution re-resolves the host about every `interval`, and moves requests to a new
+    /// connection once the address they are sent to is no longer among its addresses.
+    pub fn with_reresolution(mut self, interval: Duration) -> TlsGrpcChannel {
+        let resolution = Resolution::new(self.uri.clone(), interval);
+        self.resolution = Some(Arc::new(tokio::sync::Mutex::new(resolution)));
+        self
+    }
+}
+
+fn grpc_client(uri: &Uri, root_cert: RootCert) -> Result<GrpcClient, Error> {
     let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
+    super::keylog::install(&mut conn);
 
-    let uri = Uri::try_from(uri)?;
     let is_localhost_call = uri.host() == Some("localhost");
     conn.set_verify(ssl::SslVerifyMode::PEER);
     conn.set_alpn_protos(Alpn::H2.encode())?;
@@ -250,7 +395,7 @@ pub fn grpc_connector(uri: String, root_cert: RootCert) -> Result<TlsGrpcChannel
         .timer(crate::hyper_util::TokioTimer)
         .build(https);
 
-    Ok(TlsGrpcChannel { uri, client })
+    Ok(client)
 }
 
 type BoxBody1 = HttpBody04ToHttpBody1<BoxBody>;
@@ -299,9 +444,28 @@ impl tower::Service<Request<BoxBody>> for TlsGrpcChannel {
             .build()
             .unwrap();
         *req.uri_mut() = uri;
-        let future = self.client.request(req);
+        let channel = self.clone();
         Box::pin(async move {
-            let res = future.await?;
+            if let Some(resolution) = &channel.resolution {
+                if resolution.lock().await.changed().await {
+                    match grpc_client(&channel.uri, channel.root_cert.clone()) {
+                        Ok(client) => *channel.client.lock().unwrap() = client,
+                        Err(e) => error!("failed to reconnect to {}: {e}", channel.uri),
+                    }
+                }
+            }
+            let client = channel.client.lock().unwrap().clone();
+            let res = client.request(req).await?;
+            if let (Some(resolution), Some(info)) = (
+                &channel.resolution,
+                res.extensions()
+                    .get::<hyper_util::client::connect::HttpInfo>(),
+            ) {
+                resolution
+                    .lock()
+                    .await
+                    .connected_to(info.remote_addr().ip());
+            }
             Ok(res
                 .map(DefaultIncoming::Some)
                 .map(HttpBody1ToHttpBody04::new))
@@ -315,6 +479,22 @@ impl Certs {
     }
 
     pub fn mtls_acceptor(&self, dest_id: Option<&Identity>) -> Result<ssl::SslAcceptor, Error> {
+        Ok(self.mtls_acceptor_builder(dest_id)?.build())
+    }
+
+    /// hbone_acceptor is [Certs::mtls_acceptor] for the inbound HBONE listener, which also accepts
+    /// early data, if enabled. Its early data is only ever the CONNECT preamble, which is safe to
+    /// replay, so no other listener accepts it.
+    pub fn hbone_acceptor(&self, dest_id: Option<&Identity>) -> Result<ssl::SslAcceptor, Error> {
+        let mut conn = self.mtls_acceptor_builder(dest_id)?;
+        super::early_data::install_server(&mut conn)?;
+        Ok(conn.build())
+    }
+
+    fn mtls_acceptor_builder(
+        &self,
+        dest_id: Option<&Identity>,
+    ) -> Result<ssl::SslAcceptorBuilder, Error> {
         let _ctx = ssl::SslContext::builder(ssl::SslMethod::tls_server())?;
         // mozilla_intermediate_v5 is the only variant that enables TLSv1.3, so we use that.
         let mut conn = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls_server())?;
@@ -328,7 +508,7 @@ impl Certs {
             );
         }
 
-        Ok(conn.build())
+        Ok(conn)
     }
 
     pub fn acceptor(&self) -> Result<ssl::SslAcceptor, Error> {
@@ -344,6 +524,7 @@ impl Certs {
     pub fn connector(&self, dest_id: Vec<Identity>) -> Result<ssl::SslConnector, Error> {
         let mut conn = ssl::SslConnector::builder(ssl::SslMethod::tls_client())?;
         self.setup_ctx(&mut conn)?;
+        super::early_data::install_client(&mut conn);
 
         // client verifies SAN
         conn.set_verify_callback(Self::verify_mode(), Verifier::San(dest_id).callback());
@@ -353,12 +534,18 @@ impl Certs {
 
     fn setup_ctx(&self, conn: &mut SslContextBuilder) -> Result<(), Error> {
         // general TLS options
+        super::keylog::install(conn);
+        super::crl::install(conn)?;
+        super::groups::install(conn);
         conn.set_alpn_protos(Alpn::H2.encode())?;
         conn.set_min_proto_version(Some(ssl::SslVersion::TLS1_3))?;
         conn.set_max_proto_version(Some(ssl::SslVersion::TLS1_3))?;
 
         // key and certs
-        conn.set_private_key(&self.key)?;
+        match &self.key {
+            PrivateKey::Local(key) => conn.set_private_key(key)?,
+            PrivateKey::Provider(provider) => super::keyless::install(conn, provider.clone())?,
+        }
         conn.set_certificate(&self.cert.x509)?;
         for (i, chain_cert) in self.chain.iter().enumerate() {
             // Only include intermediate certs in the chain.
@@ -369,7 +556,12 @@ impl Certs {
             }
             conn.cert_store_mut().add_cert(chain_cert.x509.clone())?;
         }
-        conn.check_private_key()?;
+        for root in &self.trusted {
+            conn.cert_store_mut().add_cert(root.clone())?;
+        }
+        if let PrivateKey::Local(_) = self.key {
+            conn.check_private_key()?;
+        }
 
         // by default, allow boringssl to do standard validation
         conn.set_verify_callback(Self::verify_mode(), Verifier::None.callback());
@@ -392,6 +584,7 @@ enum Verifier {
 impl Verifier {
     fn base_verifier(verified: bool, ctx: &mut X509StoreContextRef) -> Result<(), TlsError> {
         if !verified {
+            super::crl::record_failure(ctx);
             return Err(TlsError::Verification(ctx.error()));
         };
         Ok(())
@@ -533,6 +726,9 @@ pub struct BoringTlsAcceptor<F: CertProvider> {
     /// Acceptor is a function that determines the TLS context to use. As input, the FD of the client
     /// connection is provided.
     pub acceptor: F,
+    /// If set, handshakes that take longer than the configured handshake timeout, as of when the
+    /// connection is accepted, are aborted and counted.
+    pub handshake_timeout: Option<(LiveConfig, Counter)>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -551,6 +747,8 @@ pub enum TlsError {
         "san verification error: remote did not present the expected trustdomain ({0}), got {1:?}"
     )]
     SanTrustDomainError(String, Vec<Identity>),
+    #[error("tls handshake timed out")]
+    HandshakeTimeout,
     #[error("failed getting ex data")]
     ExDataError,
     #[error("failed getting peer cert")]
@@ -569,11 +767,23 @@ where
 
     fn accept(&self, conn: TcpStream) -> Self::AcceptFuture {
         let mut acceptor = self.acceptor.clone();
+        let handshake_timeout = self.handshake_timeout.clone();
         Box::pin(async move {
-            let tls = acceptor.fetch_cert(&conn).await?;
-            tokio_boring::accept(&tls, conn)
+            let handshake = async move {
+                let tls = acceptor.fetch_cert(&conn).await?;
+                super::keyless::handshake(tokio_boring::accept(&tls, conn))
+                    .await
+                    .map_err(TlsError::Handshake)
+            };
+            let Some((live_cfg, timeouts)) = handshake_timeout else {
+                return handshake.await;
+            };
+            tokio::time::timeout(live_cfg.current().handshake_timeout, handshake)
                 .await
-                .map_err(TlsError::Handshake)
+                .map_err(|_| {
+                    timeouts.inc();
+                    TlsError::HandshakeTimeout
+                })?
         })
     }
 }
@@ -625,50 +835,90 @@ fn generate_test_certs_at(
 ) -> Certs {
     let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
     let (ca_cert, ca_key) = test_ca().unwrap();
-    let mut builder = x509::X509::builder().unwrap();
-    let not_before_asn = system_time_to_asn1_time(not_before).unwrap();
-    builder.set_not_before(&not_before_asn).unwrap();
-    builder
-        .set_not_after(&system_time_to_asn1_time(not_after).unwrap())
-        .unwrap();
-
-    builder.set_pubkey(&key).unwrap();
-    builder.set_version(2).unwrap();
-    let serial_number = {
-        let mut data = [0u8; 20];
-        match rng {
-            None => rand::thread_rng().fill_bytes(&mut data),
-            Some(rng) => rng.fill_bytes(&mut data),
+    let mut thread_rng;
+    let rng: &mut dyn rand::RngCore = match rng {
+        Some(rng) => rng,
+        None => {
+            thread_rng = rand::thread_rng();
+            &mut thread_rng
         }
-        // Clear the most significant bit to make the resulting bignum effectively 159 bit long.
-        data[0] &= 0x7f;
-        let serial = BigNum::from_slice(&data).unwrap();
-        serial.to_asn1_integer().unwrap()
     };
-    builder.set_serial_number(&serial_number).unwrap();
+    let leaf = sign_leaf(id, &key, &ca_cert, &ca_key, not_before, not_after, rng).unwrap();
+
+    let mut cert = ZtunnelCert::new(leaf);
+    // For sub-second granularity
+    cert.not_before = not_before;
+    cert.not_after = not_after;
+    Certs {
+        cert,
+        key: PrivateKey::Local(key),
+        chain: vec![ZtunnelCert::new(ca_cert)],
+        trusted: Vec::new(),
+    }
+}
+
+pub fn generate_test_certs(
+    id: &TestIdentity,
+    duration_until_valid: Duration,
+    duration_until_expiry: Duration,
+) -> Certs {
+    let not_before = SystemTime::now() + duration_until_valid;
+    generate_test_certs_at(id, not_before, not_before + duration_until_expiry, None)
+}
+
+fn random_serial(rng: &mut dyn rand::RngCore) -> Result<Asn1Integer, Error> {
+    let mut data = [0u8; 20];
+    rng.fill_bytes(&mut data);
+    // Clear the most significant bit to make the resulting bignum effectively 159 bit long.
+    data[0] &= 0x7f;
+    let serial = BigNum::from_slice(&data)?;
+    Ok(serial.to_asn1_integer()?)
+}
 
-    let mut names = boring::x509::X509NameBuilder::new().unwrap();
-    names.append_entry_by_text("O", "cluster.local").unwrap();
-    let names = names.build();
-    builder.set_issuer_name(&names).unwrap();
+fn validity_period(
+    not_before: SystemTime,
+    not_after: SystemTime,
+) -> Result<(Asn1Time, Asn1Time), Error> {
+    Ok((
+        system_time_to_asn1_time(not_before).ok_or(Error::InvalidValidity)?,
+        system_time_to_asn1_time(not_after).ok_or(Error::InvalidValidity)?,
+    ))
+}
 
-    let basic_constraints = BasicConstraints::new().critical().build().unwrap();
+// Signs a workload certificate for `id` with the given CA.
+fn sign_leaf(
+    id: &TestIdentity,
+    key: &PKey<Private>,
+    ca_cert: &x509::X509,
+    ca_key: &PKey<Private>,
+    not_before: SystemTime,
+    not_after: SystemTime,
+    rng: &mut dyn rand::RngCore,
+) -> Result<x509::X509, Error> {
+    let mut builder = x509::X509::builder()?;
+    let (not_before, not_after) = validity_period(not_before, not_after)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+
+    builder.set_pubkey(key)?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&random_serial(rng)?)?;
+    builder.set_issuer_name(ca_cert.subject_name())?;
+
+    let basic_constraints = BasicConstraints::new().critical().build()?;
     let key_usage = KeyUsage::new()
         .critical()
         .digital_signature()
         .key_encipherment()
-        .build()
-        .unwrap();
+        .build()?;
     let ext_key_usage = ExtendedKeyUsage::new()
         .client_auth()
         .server_auth()
-        .build()
-        .unwrap();
+        .build()?;
     let authority_key_identifier = AuthorityKeyIdentifier::new()
         .keyid(false)
         .issuer(false)
-        .build(&builder.x509v3_context(Some(&ca_cert), None))
-        .unwrap();
+        .build(&builder.x509v3_context(Some(ca_cert), None))?;
     let mut san = SubjectAlternativeName::new();
     let subject_alternative_name = match id {
         TestIdentity::Identity(id) => san.uri(&id.to_string()),
@@ -676,34 +926,91 @@ fn generate_test_certs_at(
     };
     let subject_alternative_name = subject_alternative_name
         .critical()
-        .build(&builder.x509v3_context(Some(&ca_cert), None))
-        .unwrap();
-    builder.append_extension(key_usage).unwrap();
-    builder.append_extension(ext_key_usage).unwrap();
-    builder.append_extension(basic_constraints).unwrap();
-    builder.append_extension(authority_key_identifier).unwrap();
-    builder.append_extension(subject_alternative_name).unwrap();
+        .build(&builder.x509v3_context(Some(ca_cert), None))?;
+    builder.append_extension(key_usage)?;
+    builder.append_extension(ext_key_usage)?;
+    builder.append_extension(basic_constraints)?;
+    builder.append_extension(authority_key_identifier)?;
+    builder.append_extension(subject_alternative_name)?;
+
+    builder.sign(ca_key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
 
-    builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+fn generate_key() -> Result<PKey<Private>, Error> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
 
-    let mut cert = ZtunnelCert::new(builder.build());
-    // For sub-second granularity
-    cert.not_before = not_before;
-    cert.not_after = not_after;
-    Certs {
-        cert,
-        key,
-        chain: vec![ZtunnelCert::new(ca_cert)],
-    }
+/// SelfSignedCa is an in-process certificate authority with a freshly generated root. It is meant
+/// for tests and standalone deployments without an external CA; no other ztunnel will trust the
+/// certificates it issues unless it is handed the same root.
+pub struct SelfSignedCa {
+    cert: x509::X509,
+    key: PKey<Private>,
 }
 
-pub fn generate_test_certs(
-    id: &TestIdentity,
-    duration_until_valid: Duration,
-    duration_until_expiry: Duration,
-) -> Certs {
-    let not_before = SystemTime::now() + duration_until_valid;
-    generate_test_certs_at(id, not_before, not_before + duration_until_expiry, None)
+impl SelfSignedCa {
+    /// Generates a new root for `trust_domain`, valid for `lifetime` from now.
+    pub fn new(trust_domain: &str, lifetime: Duration) -> Result<SelfSignedCa, Error> {
+        let key = generate_key()?;
+        let now = SystemTime::now();
+        let (not_before, not_after) = validity_period(now, now + lifetime)?;
+
+        let mut names = x509::X509NameBuilder::new()?;
+        names.append_entry_by_text("O", trust_domain)?;
+        let names = names.build();
+
+        let mut builder = x509::X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_serial_number(&random_serial(&mut rand::thread_rng())?)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        builder.set_subject_name(&names)?;
+        builder.set_issuer_name(&names)?;
+        builder.set_pubkey(&key)?;
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()?,
+        )?;
+        builder.append_extension(
+            SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?,
+        )?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(SelfSignedCa {
+            cert: builder.build(),
+            key,
+        })
+    }
+
+    pub fn root(&self) -> &x509::X509 {
+        &self.cert
+    }
+
+    /// Issues a certificate for `id` with a new key, valid for `lifetime` from now.
+    pub fn issue(&self, id: &Identity, lifetime: Duration) -> Result<Certs, Error> {
+        let key = generate_key()?;
+        let now = SystemTime::now();
+        let leaf = sign_leaf(
+            &TestIdentity::Identity(id.clone()),
+            &key,
+            &self.cert,
+            &self.key,
+            now,
+            now + lifetime,
+            &mut rand::thread_rng(),
+        )?;
+        Ok(Certs {
+            cert: ZtunnelCert::new(leaf),
+            chain: vec![ZtunnelCert::new(self.cert.clone())],
+            key: PrivateKey::Local(key),
+            trusted: Vec::new(),
+        })
+    }
 }
 
 fn test_ca() -> Result<(x509::X509, PKey<Private>), Error> {
@@ -716,7 +1023,12 @@ pub fn test_certs() -> Certs {
     let cert = ZtunnelCert::new(x509::X509::from_pem(TEST_CERT).unwrap());
     let key = pkey::PKey::private_key_from_pem(TEST_PKEY).unwrap();
     let chain = vec![cert.clone()];
-    Certs { cert, key, chain }
+    Certs {
+        cert,
+        key: PrivateKey::Local(key),
+        chain,
+        trusted: Vec::new(),
+    }
 }
 
 pub mod mock {
@@ -760,12 +1072,17 @@ pub mod mock {
 
 #[cfg(test)]
 pub mod tests {
+    use std::sync::Arc;
     use std::time::Duration;
 
+    use boring::hash::MessageDigest;
+    use boring::pkey::{PKey, Private};
+    use boring::sign::Signer;
+
     use crate::identity::Identity;
-    use crate::tls::TestIdentity;
+    use crate::tls::{KeyProvider, TestIdentity};
 
-    use super::generate_test_certs;
+    use super::{generate_test_certs, load_keyless_certs, test_ca, Certs, PrivateKey};
 
     #[test]
     #[cfg(feature = "fips")]
@@ -779,6 +1096,120 @@ pub mod tests {
         assert!(!boring::fips::enabled());
     }
 
+    #[derive(Debug)]
+    struct LocalKey(PKey<Private>);
+
+    impl KeyProvider for LocalKey {
+        fn sign(&self, algorithm: u16, input: &[u8]) -> Result<Vec<u8>, crate::tls::Error> {
+            // ecdsa_secp256r1_sha256, the only scheme for the test key in TLS 1.3.
+            assert_eq!(algorithm, 0x0403);
+            Ok(Signer::new(MessageDigest::sha256(), &self.0)?.sign_oneshot_to_vec(input)?)
+        }
+    }
+
+    #[derive(Debug)]
+    struct PanickingKey;
+
+    impl KeyProvider for PanickingKey {
+        fn sign(&self, _: u16, _: &[u8]) -> Result<Vec<u8>, crate::tls::Error> {
+            panic!("bug");
+        }
+    }
+
+    #[tokio::test]
+    async fn keyless_handshake() {
+        let id: TestIdentity = Identity::default().into();
+        let certs = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(100));
+        let PrivateKey::Local(key) = certs.key.clone() else {
+            unreachable!("test certs hold their key");
+        };
+        let handshake = |provider: Arc<dyn KeyProvider>, in_place: bool| {
+            let keyless = load_keyless_certs(
+                provider,
+                &certs.cert.x509.to_pem().unwrap(),
+                &certs.chain[0].x509.to_pem().unwrap(),
+            )
+            .unwrap();
+            let acceptor = keyless.mtls_acceptor(None).unwrap();
+            let connector = certs
+                .connector(vec![Identity::default()])
+                .unwrap()
+                .configure()
+                .unwrap();
+            async move {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let accept = tokio_boring::accept(&acceptor, server);
+                let (client, server) = if in_place {
+                    tokio::join!(tokio_boring::connect(connector, "", client), accept)
+                } else {
+                    tokio::join!(
+                        tokio_boring::connect(connector, "", client),
+                        crate::tls::keyless::handshake(accept),
+                    )
+                };
+                client.is_ok() && server.is_ok()
+            }
+        };
+
+        let key = Arc::new(LocalKey(key));
+        // Signing on the blocking thread pool, and in place.
+        assert!(handshake(key.clone(), false).await);
+        assert!(handshake(key, true).await);
+        // A provider that panics fails the handshake, rather than unwinding through BoringSSL.
+        assert!(!handshake(Arc::new(PanickingKey), false).await);
+        assert!(!handshake(Arc::new(PanickingKey), true).await);
+    }
+
+    #[tokio::test]
+    async fn revoked_peer() {
+        let id: TestIdentity = Identity::default().into();
+        let server = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(100));
+        let revoked = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(100));
+        let (root, root_key) = test_ca().unwrap();
+        let revocations = Arc::new(crate::tls::crl::revoking(
+            &root,
+            &root_key,
+            &[revoked.cert.x509.serial_number()],
+        ));
+
+        // An mTLS acceptor, checking its peers against the revocations.
+        let mut builder =
+            boring::ssl::SslAcceptor::mozilla_intermediate_v5(boring::ssl::SslMethod::tls_server())
+                .unwrap();
+        server.setup_ctx(&mut builder).unwrap();
+        revocations.install(&mut builder).unwrap();
+        let checked = revocations.clone();
+        builder.set_verify_callback(Certs::verify_mode(), move |verified, ctx| {
+            if !verified {
+                checked.record_failure(ctx);
+            }
+            verified
+        });
+        let acceptor = builder.build();
+
+        let handshake = |client: &Certs| {
+            let connector = client
+                .connector(vec![Identity::default()])
+                .unwrap()
+                .configure()
+                .unwrap();
+            let acceptor = &acceptor;
+            async move {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                let (_, server) = tokio::join!(
+                    tokio_boring::connect(connector, "", client),
+                    tokio_boring::accept(acceptor, server),
+                );
+                server.is_ok()
+            }
+        };
+        assert!(!handshake(&revoked).await);
+        assert_eq!(revocations.rejections(), 1);
+        // The root's CRL covers the whole chain of peers it did not revoke.
+        assert!(handshake(&server).await);
+        assert_eq!(revocations.rejections(), 1);
+    }
+
     #[test]
     fn cert_expiration() {
         let expiry_seconds = 1000;
@@ -0,0 +1,244 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Certificate revocation lists, checked when verifying the peers of mTLS connections in either
+//! direction, for environments that must refuse revoked certificates before they expire. The lists
+//! are read from a file of PEM or DER CRLs, and re-read periodically; connections set up after a
+//! refresh are verified against the new lists. Every certificate of the peer's chain is checked,
+//! not only its leaf, so a revoked intermediate is refused too. Once enabled, peers with a
+//! certificate whose issuer has no CRL in the file are refused as well, as their revocation
+//! cannot be checked.
+
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use boring::ssl::SslContextBuilder;
+use boring::x509::X509StoreContextRef;
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::OnceCell;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use tracing::{debug, info, warn};
+
+use super::Error;
+
+static REVOCATIONS: OnceCell<Revocations> = OnceCell::new();
+
+pub(super) struct Revocations {
+    path: PathBuf,
+    crls: RwLock<Vec<Crl>>,
+    rejections: Counter,
+}
+
+/// Crl owns a parsed CRL.
+struct Crl(*mut boring_sys::X509_CRL);
+
+// Parsed CRLs are never modified, and BoringSSL reference counts them, so they can be shared.
+unsafe impl Send for Crl {}
+unsafe impl Sync for Crl {}
+
+impl Drop for Crl {
+    fn drop(&mut self) {
+        unsafe { boring_sys::X509_CRL_free(self.0) }
+    }
+}
+
+/// configure checks the peers of the TLS sessions set up from here on against the CRLs in `path`,
+/// for the rest of the process' lifetime, re-reading them every `refresh`.
+pub fn configure(path: &Path, refresh: Duration, registry: &mut Registry) -> Result<(), Error> {
+    let crls = load(path)?;
+    info!(path = %path.display(), crls = crls.len(), "loaded certificate revocation lists");
+    let rejections = Counter::default();
+    registry.register(
+        "revoked_certificate_rejections",
+        "The total number of TLS peers refused because their certificate was revoked",
+        rejections.clone(),
+    );
+    let revocations = Revocations {
+        path: path.to_path_buf(),
+        crls: RwLock::new(crls),
+        rejections,
+    };
+    if REVOCATIONS.set(revocations).is_err() {
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(refresh);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let revocations = REVOCATIONS.get().expect("set above");
+            match load(&revocations.path) {
+                Ok(crls) => {
+                    debug!(path = %revocations.path.display(), crls = crls.len(), "reloaded certificate revocation lists");
+                    *revocations.crls.write().unwrap() = crls;
+                }
+                // Keep checking against the lists we have.
+                Err(e) => warn!("{e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// install checks the peers of sessions set up with `ctx` against the CRLs, if there are any.
+pub(super) fn install(ctx: &mut SslContextBuilder) -> Result<(), Error> {
+    match REVOCATIONS.get() {
+        Some(revocations) => revocations.install(ctx),
+        None => Ok(()),
+    }
+}
+
+/// record_failure counts the peer of `ctx` as refused if its verification failed because its
+/// certificate was revoked.
+pub(super) fn record_failure(ctx: &X509StoreContextRef) {
+    if let Some(revocations) = REVOCATIONS.get() {
+        revocations.record_failure(ctx);
+    }
+}
+
+impl Revocations {
+    pub(super) fn install(&self, ctx: &mut SslContextBuilder) -> Result<(), Error> {
+        let store = ctx.cert_store_mut().as_ptr();
+        for crl in self.crls.read().unwrap().iter() {
+            // The store takes its own reference.
+            if unsafe { boring_sys::X509_STORE_add_crl(store, crl.0) } != 1 {
+                return Err(Error::SslError(boring::error::ErrorStack::get()));
+            }
+        }
+        let flags = boring_sys::X509_V_FLAG_CRL_CHECK | boring_sys::X509_V_FLAG_CRL_CHECK_ALL;
+        if unsafe { boring_sys::X509_STORE_set_flags(store, flags as _) } != 1 {
+            return Err(Error::SslError(boring::error::ErrorStack::get()));
+        }
+        Ok(())
+    }
+
+    pub(super) fn record_failure(&self, ctx: &X509StoreContextRef) {
+        if ctx.error().as_raw() == boring_sys::X509_V_ERR_CERT_REVOKED as i32 {
+            self.rejections.inc();
+        }
+    }
+
+    /// rejections returns the number of peers refused as revoked.
+    #[cfg(test)]
+    pub(super) fn rejections(&self) -> u64 {
+        self.rejections.get()
+    }
+}
+
+fn load(path: &Path) -> Result<Vec<Crl>, Error> {
+    let invalid = |reason: &str| Error::InvalidCrl(path.to_path_buf(), reason.to_string());
+    let data = std::fs::read(path).map_err(|e| invalid(&e.to_string()))?;
+    let crls = parse(&data);
+    if crls.is_empty() {
+        return Err(invalid("no CRL found"));
+    }
+    Ok(crls)
+}
+
+/// parse returns the CRLs in `data`, either a single DER CRL or any number of PEM ones.
+fn parse(data: &[u8]) -> Vec<Crl> {
+    let mut crls = Vec::new();
+    if !data.windows(10).any(|w| w == b"-----BEGIN") {
+        let mut der = data.as_ptr();
+        let crl = unsafe { boring_sys::d2i_X509_CRL(ptr::null_mut(), &mut der, data.len() as _) };
+        if !crl.is_null() {
+            crls.push(Crl(crl));
+        }
+        unsafe { boring_sys::ERR_clear_error() };
+        return crls;
+    }
+    unsafe {
+        let bio = boring_sys::BIO_new_mem_buf(data.as_ptr() as *const _, data.len() as _);
+        if bio.is_null() {
+            return crls;
+        }
+        loop {
+            let crl =
+                boring_sys::PEM_read_bio_X509_CRL(bio, ptr::null_mut(), None, ptr::null_mut());
+            if crl.is_null() {
+                break;
+            }
+            crls.push(Crl(crl));
+        }
+        boring_sys::BIO_free(bio);
+        // Reading past the last CRL leaves an error behind.
+        boring_sys::ERR_clear_error();
+    }
+    crls
+}
+
+/// revoking returns revocations holding a CRL of `issuer` that revokes `serials`, for tests.
+#[cfg(test)]
+pub(super) fn revoking(
+    issuer: &boring::x509::X509Ref,
+    key: &boring::pkey::PKeyRef<boring::pkey::Private>,
+    serials: &[&boring::asn1::Asn1IntegerRef],
+) -> Revocations {
+    let now = boring::asn1::Asn1Time::days_from_now(0).unwrap();
+    let next = boring::asn1::Asn1Time::days_from_now(1).unwrap();
+    unsafe {
+        let crl = Crl(boring_sys::X509_CRL_new());
+        assert!(!crl.0.is_null());
+        // Version 2, encoded as 1.
+        assert_eq!(boring_sys::X509_CRL_set_version(crl.0, 1), 1);
+        assert_eq!(
+            boring_sys::X509_CRL_set_issuer_name(crl.0, issuer.subject_name().as_ptr()),
+            1
+        );
+        assert_eq!(boring_sys::X509_CRL_set1_lastUpdate(crl.0, now.as_ptr()), 1);
+        assert_eq!(
+            boring_sys::X509_CRL_set1_nextUpdate(crl.0, next.as_ptr()),
+            1
+        );
+        for serial in serials {
+            let revoked = boring_sys::X509_REVOKED_new();
+            assert!(!revoked.is_null());
+            assert_eq!(
+                boring_sys::X509_REVOKED_set_serialNumber(revoked, serial.as_ptr()),
+                1
+            );
+            assert_eq!(
+                boring_sys::X509_REVOKED_set_revocationDate(revoked, now.as_ptr()),
+                1
+            );
+            // The CRL takes ownership of the entry.
+            assert_eq!(boring_sys::X509_CRL_add0_revoked(crl.0, revoked), 1);
+        }
+        assert_eq!(boring_sys::X509_CRL_sort(crl.0), 1);
+        assert_ne!(
+            boring_sys::X509_CRL_sign(crl.0, key.as_ptr(), boring_sys::EVP_sha256()),
+            0
+        );
+        Revocations {
+            path: PathBuf::new(),
+            crls: RwLock::new(vec![crl]),
+            rejections: Counter::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse(b"").is_empty());
+        assert!(parse(b"not a crl").is_empty());
+        assert!(parse(b"-----BEGIN X509 CRL-----\nAAAA\n-----END X509 CRL-----\n").is_empty());
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS 1.3 early data (0-RTT) for HBONE. A connection to a peer whose last session can be
+//! resumed sends the HTTP/2 preface and its CONNECT requests along with the ClientHello, saving
+//! the round trip of the handshake. An attacker can replay early data, so it is limited to that
+//! preamble: the client's own bytes are only sent once a CONNECT is answered, and no answer can be
+//! read before the handshake completes. If the peer rejects the early data, it is sent again once
+//! the handshake completes, losing only the round trip it would have saved. Only the inbound HBONE
+//! listener accepts early data; other TLS servers, such as the admin API, complete the handshake
+//! first. Disabled unless [crate::config::Config::hbone_early_data] is set.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use boring::ex_data::Index;
+use boring::ssl::{
+    ConnectConfiguration, Ssl, SslContextBuilder, SslRef, SslSession, SslSessionCacheMode,
+};
+use foreign_types::{ForeignType, ForeignTypeRef};
+use once_cell::sync::OnceCell;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_boring::SslStream;
+use tracing::info;
+
+use super::Error;
+
+/// How long the sessions we issue can be resumed, in seconds. Resumed sessions are not verified
+/// again, so this bounds how long a rotated or revoked certificate stays usable through them.
+const SESSION_LIFETIME: u32 = 600;
+
+/// The most sessions kept, one per peer, to bound memory use with many peers.
+const MAX_SESSIONS: usize = 10_000;
+
+/// Sessions are only resumed by contexts with the same id.
+const SESSION_ID_CONTEXT: &[u8] = b"ztunnel-hbone";
+
+static EARLY_DATA: OnceCell<EarlyData> = OnceCell::new();
+
+struct EarlyData {
+    /// The last session of each peer, taken by the next connection to it.
+    sessions: Mutex<HashMap<String, SslSession>>,
+    /// The peer each connection is to, to store the sessions it is issued under.
+    peer_index: Index<Ssl, String>,
+    /// The keys the sessions we issue are encrypted with. Contexts are set up per connection, so
+    /// they share them for sessions issued by one to be resumed with another.
+    ticket_keys: [u8; 48],
+    accepted: Counter,
+    rejected: Counter,
+}
+
+/// configure has the HBONE connections set up from here on send and accept early data, for the rest
+/// of the process' lifetime. It can only be called once.
+pub fn configure(registry: &mut Registry) -> Result<(), Error> {
+    if EARLY_DATA.get().is_some() {
+        return Err(Error::AlreadyConfigured("TLS early data"));
+    }
+    let mut ticket_keys = [0; 48];
+    boring::rand::rand_bytes(&mut ticket_keys)?;
+    let accepted = Counter::default();
+    registry.register(
+        "tls_early_data_accepted",
+        "The total number of outbound HBONE connections whose early data the peer accepted",
+        accepted.clone(),
+    );
+    let rejected = Counter::default();
+    registry.register(
+        "tls_early_data_rejected",
+        "The total number of outbound HBONE connections whose early data the peer rejected, and that were sent again",
+        rejected.clone(),
+    );
+    EARLY_DATA
+        .set(EarlyData {
+            sessions: Default::default(),
+            peer_index: Ssl::new_ex_index()?,
+            ticket_keys,
+            accepted,
+            rejected,
+        })
+        .map_err(|_| Error::AlreadyConfigured("TLS early data"))?;
+    info!("sending HBONE CONNECT requests as TLS early data");
+    Ok(())
+}
+
+/// install_client has client sessions set up with `ctx` keep the sessions they are issued, and
+/// send early data when resuming them, if early data is enabled.
+pub(super) fn install_client(ctx: &mut SslContextBuilder) {
+    if EARLY_DATA.get().is_none() {
+        return;
+    }
+    unsafe { boring_sys::SSL_CTX_set_early_data_enabled(ctx.as_ptr(), 1) };
+    ctx.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+    ctx.set_new_session_callback(store);
+}
+
+/// install_server has server sessions set up with `ctx` issue sessions that any of them can
+/// resume, and accept early data, if early data is enabled.
+pub(super) fn install_server(ctx: &mut SslContextBuilder) -> Result<(), Error> {
+    let Some(early_data) = EARLY_DATA.get() else {
+        return Ok(());
+    };
+    ctx.set_session_id_context(SESSION_ID_CONTEXT)?;
+    let keys = &early_data.ticket_keys;
+    unsafe {
+        boring_sys::SSL_CTX_set_early_data_enabled(ctx.as_ptr(), 1);
+        boring_sys::SSL_CTX_set_session_psk_dhe_timeout(ctx.as_ptr(), SESSION_LIFETIME);
+        if boring_sys::SSL_CTX_set_tlsext_ticket_keys(ctx.as_ptr(), keys.as_ptr() as _, keys.len())
+            != 1
+        {
+            return Err(Error::SslError(boring::error::ErrorStack::get()));
+        }
+    }
+    Ok(())
+}
+
+fn store(ssl: &mut SslRef, session: SslSession) {
+    let Some(early_data) = EARLY_DATA.get() else {
+        return;
+    };
+    // Connections that do not know their peer are not resumed.
+    let Some(peer) = ssl.ex_data(early_data.peer_index) else {
+        return;
+    };
+    let mut sessions = early_data.sessions.lock().unwrap();
+    if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(peer) {
+        if let Some(evicted) = sessions.keys().next().cloned() {
+            sessions.remove(&evicted);
+        }
+    }
+    sessions.insert(peer.clone(), session);
+}
+
+/// resume has `ssl`, a connection to `peer`, resume the last session issued by the peer, if early
+/// data is enabled. A session is only resumed once. Returns whether early data is sent.
+pub fn resume(ssl: &mut ConnectConfiguration, peer: String) -> bool {
+    let Some(early_data) = EARLY_DATA.get() else {
+        return false;
+    };
+    let session = early_data.sessions.lock().unwrap().remove(&peer);
+    ssl.set_ex_data(early_data.peer_index, peer);
+    let Some(session) = session else {
+        return false;
+    };
+    // The session was issued to a connection set up with the same options.
+    if unsafe { ssl.set_session(&session) }.is_err() {
+        return false;
+    }
+    unsafe { boring_sys::SSL_SESSION_early_data_capable(session.as_ptr()) == 1 }
+}
+
+/// Stream is a client TLS stream that may have sent early data. If the peer rejects it, it is sent
+/// again, and once the handshake completes, whether it was accepted is counted.
+pub struct Stream<S> {
+    inner: SslStream<S>,
+    /// What was sent as early data, until the handshake completes.
+    sent: Option<Vec<u8>>,
+    /// What is left to send again after a rejection.
+    replay: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// new wraps `inner`, which sent early data if `offered`.
+    pub fn new(inner: SslStream<S>, offered: bool) -> Stream<S> {
+        Stream {
+            inner,
+            sent: offered.then(Vec::new),
+            replay: Vec::new(),
+        }
+    }
+
+    fn in_early_data(&self) -> bool {
+        unsafe { boring_sys::SSL_in_early_data(self.inner.ssl().as_ptr()) == 1 }
+    }
+
+    /// rejected returns `err`, unless it is the peer rejecting our early data, in which case the
+    /// early data is sent again before anything else.
+    fn rejected(&mut self, err: io::Error) -> Option<io::Error> {
+        let is_rejection = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<boring::ssl::Error>())
+            .map_or(false, |e| {
+                e.code().as_raw() == boring_sys::SSL_ERROR_EARLY_DATA_REJECTED as i32
+            });
+        if !is_rejection {
+            return Some(err);
+        }
+        let Some(sent) = self.sent.take() else {
+            return Some(err);
+        };
+        // Only valid after SSL_ERROR_EARLY_DATA_REJECTED, checked above.
+        unsafe { boring_sys::SSL_reset_early_data_reject(self.inner.ssl().as_ptr()) };
+        self.replay = sent;
+        if let Some(early_data) = EARLY_DATA.get() {
+            early_data.rejected.inc();
+        }
+        None
+    }
+
+    fn poll_replay(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.replay.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.replay))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.replay.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// finish counts our early data as accepted, once the handshake completes without rejecting it.
+    fn finish(&mut self) {
+        if self.sent.is_none() || unsafe { boring_sys::SSL_in_init(self.inner.ssl().as_ptr()) } == 1
+        {
+            return;
+        }
+        self.sent = None;
+        if let Some(early_data) = EARLY_DATA.get() {
+            early_data.accepted.inc();
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_replay(cx))?;
+            match ready!(Pin::new(&mut this.inner).poll_read(cx, buf)) {
+                Ok(()) => {
+                    this.finish();
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) => {
+                    if let Some(e) = this.rejected(e) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_replay(cx))?;
+            let early = this.sent.is_some() && this.in_early_data();
+            match ready!(Pin::new(&mut this.inner).poll_write(cx, buf)) {
+                Ok(n) => {
+                    if let Some(sent) = this.sent.as_mut().filter(|_| early) {
+                        sent.extend_from_slice(&buf[..n]);
+                    }
+                    this.finish();
+                    return Poll::Ready(Ok(n));
+                }
+                Err(e) => {
+                    if let Some(e) = this.rejected(e) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_replay(cx))?;
+            match ready!(Pin::new(&mut this.inner).poll_flush(cx)) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(e) => {
+                    if let Some(e) = this.rejected(e) {
+                        return Poll::Ready(Err(e));
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Accepted is a server TLS stream that tracks whether what it last read was early data, so
+/// requests that an attacker could replay can be refused.
+pub struct Accepted<S> {
+    inner: SslStream<S>,
+    early: Arc<AtomicBool>,
+}
+
+impl<S> Accepted<S> {
+    pub fn new(inner: SslStream<S>) -> Accepted<S> {
+        Accepted {
+            inner,
+            early: Default::default(),
+        }
+    }
+
+    pub fn ssl(&self) -> &SslRef {
+        self.inner.ssl()
+    }
+
+    /// early returns whether the last read returned early data, updated as the stream is read.
+    pub fn early(&self) -> Arc<AtomicBool> {
+        self.early.clone()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Accepted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let early = unsafe { boring_sys::SSL_in_early_data(this.inner.ssl().as_ptr()) == 1 };
+        this.early.store(early, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Accepted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use boring::ssl::SslAcceptor;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::identity::Identity;
+    use crate::tls::{generate_test_certs, TestIdentity};
+
+    /// exchange sends `ping` to `acceptor`, resuming the last session issued to `peer`, and reads
+    /// `pong` back. Returns whether early data was offered.
+    async fn exchange(
+        connector: &boring::ssl::SslConnector,
+        acceptor: &SslAcceptor,
+        peer: &str,
+    ) -> bool {
+        let mut config = connector.configure().unwrap();
+        let offered = resume(&mut config, peer.to_string());
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client = async move {
+            let tls = tokio_boring::connect(config, "", client).await.unwrap();
+            let mut stream = Stream::new(tls, offered);
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut pong = [0; 4];
+            stream.read_exact(&mut pong).await.unwrap();
+            assert_eq!(&pong, b"pong");
+        };
+        let server = async move {
+            let mut stream = tokio_boring::accept(acceptor, server).await.unwrap();
+            let mut ping = [0; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            assert_eq!(&ping, b"ping");
+            stream.write_all(b"pong").await.unwrap();
+            stream.flush().await.unwrap();
+        };
+        tokio::join!(client, server);
+        offered
+    }
+
+    #[tokio::test]
+    async fn early_data() {
+        configure(&mut Registry::default()).unwrap();
+        assert!(matches!(
+            configure(&mut Registry::default()),
+            Err(Error::AlreadyConfigured(_))
+        ));
+        let early_data = EARLY_DATA.get().unwrap();
+
+        let id: TestIdentity = Identity::default().into();
+        let certs = generate_test_certs(&id, Duration::from_secs(0), Duration::from_secs(100));
+        let connector = certs.connector(vec![Identity::default()]).unwrap();
+        let hbone = certs.hbone_acceptor(None).unwrap();
+        let peer = "early-data-test";
+
+        // The first connection has no session to resume, and is issued one.
+        assert!(!exchange(&connector, &hbone, peer).await);
+        assert!(early_data.sessions.lock().unwrap().contains_key(peer));
+
+        // The second sends its ping as early data, which is accepted.
+        assert!(exchange(&connector, &hbone, peer).await);
+        assert_eq!(early_data.accepted.get(), 1);
+        assert_eq!(early_data.rejected.get(), 0);
+
+        // A server that does not accept early data rejects it, and the ping is sent again.
+        let plain = certs.mtls_acceptor(None).unwrap();
+        assert!(exchange(&connector, &plain, peer).await);
+        assert_eq!(early_data.accepted.get(), 1);
+        assert_eq!(early_data.rejected.get(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The key exchange groups offered in mTLS handshakes, so hybrid post-quantum groups such as
+//! `X25519Kyber768Draft00` can be piloted on mesh traffic. Groups are offered in the order
+//! configured, so listing classical groups after the hybrid ones lets peers that do not support
+//! them fall back. Without configuration, the library's defaults are used.
+
+use std::ffi::CString;
+
+use boring::ssl::{SslContextBuilder, SslMethod};
+use once_cell::sync::OnceCell;
+use tracing::{info, warn};
+
+static GROUPS: OnceCell<CString> = OnceCell::new();
+
+/// configure offers `groups` in the TLS sessions set up from here on, for the rest of the
+/// process' lifetime. If the library does not support all of them, the defaults are kept.
+pub fn configure(groups: &[String]) {
+    if groups.is_empty() {
+        return;
+    }
+    let Ok(list) = CString::new(groups.join(":")) else {
+        warn!(
+            ?groups,
+            "invalid TLS key exchange groups, using the defaults"
+        );
+        return;
+    };
+    let supported = SslContextBuilder::new(SslMethod::tls())
+        .map(|mut ctx| set(&mut ctx, &list))
+        .unwrap_or(false);
+    if !supported {
+        warn!(
+            ?groups,
+            "TLS key exchange groups not supported by this build, using the defaults"
+        );
+        return;
+    }
+    info!(?groups, "offering configured TLS key exchange groups");
+    let _ = GROUPS.set(list);
+}
+
+/// install offers the configured groups in sessions set up with `ctx`, if there are any.
+pub(super) fn install(ctx: &mut SslContextBuilder) {
+    if let Some(list) = GROUPS.get() {
+        // Checked to be supported when configured.
+        set(ctx, list);
+    }
+}
+
+fn set(ctx: &mut SslContextBuilder, list: &CString) -> bool {
+    let ok = unsafe { boring_sys::SSL_CTX_set1_curves_list(ctx.as_ptr(), list.as_ptr()) } == 1;
+    if !ok {
+        unsafe { boring_sys::ERR_clear_error() };
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_groups() {
+        let mut ctx = SslContextBuilder::new(SslMethod::tls()).unwrap();
+        assert!(set(&mut ctx, &CString::new("X25519:P-256").unwrap()));
+        assert!(!set(&mut ctx, &CString::new("X25519:not-a-group").unwrap()));
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Private keys that ztunnel does not hold itself. A [KeyProvider] signs the handshakes of
+//! certificates whose key lives elsewhere, such as with an external signing agent, an HSM over
+//! PKCS#11, or the kernel keyring, so the key never enters ztunnel's memory. Certificates are
+//! paired with their provider through [load_keyless_certs](super::load_keyless_certs), or served
+//! from files by a keyless [FileCaClient](crate::identity::FileCaClient), and are then used like
+//! any other for both inbound and outbound mTLS.
+//!
+//! Handshakes driven through [handshake] sign on the blocking thread pool, and carry on once the
+//! signature is ready, so a slow provider holds up only its own handshakes. Others sign in place.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+use boring::ex_data::Index;
+use boring::ssl::{Ssl, SslContext, SslContextBuilder, SslContextRef, SslRef};
+use foreign_types::ForeignTypeRef;
+use once_cell::sync::OnceCell;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use super::Error;
+
+/// KeyProvider performs the private key operations of a certificate on ztunnel's behalf.
+pub trait KeyProvider: Send + Sync + fmt::Debug {
+    /// sign signs `input` with the private key, using the TLS signature scheme `algorithm`, such
+    /// as 0x0804 for rsa_pss_rsae_sha256 or 0x0403 for ecdsa_secp256r1_sha256. The input is not
+    /// hashed yet. It may block, as the proxy's handshakes run it on the blocking thread pool.
+    fn sign(&self, algorithm: u16, input: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+type Signature = Result<Vec<u8>, String>;
+
+/// PendingSignature is the signature a session waits on, if any.
+type PendingSignature = Mutex<Option<oneshot::Receiver<Signature>>>;
+
+/// The contexts' providers, found from the sessions the callbacks are called for.
+static PROVIDER_INDEX: OnceCell<Index<SslContext, Arc<dyn KeyProvider>>> = OnceCell::new();
+/// The sessions' pending signatures.
+static PENDING_INDEX: OnceCell<Index<Ssl, PendingSignature>> = OnceCell::new();
+
+thread_local! {
+    /// The waker of the handshake being polled by [handshake] on this thread.
+    static WAKER: RefCell<Option<Waker>> = RefCell::new(None);
+}
+
+/// handshake drives `fut`, a TLS handshake, so that a keyless certificate's signature is made
+/// without blocking the thread, and the handshake is woken once it is ready.
+pub(crate) async fn handshake<F: Future>(fut: F) -> F::Output {
+    futures::pin_mut!(fut);
+    futures::future::poll_fn(|cx| {
+        let previous = WAKER.with(|w| w.replace(Some(cx.waker().clone())));
+        let res = fut.as_mut().poll(cx);
+        WAKER.with(|w| *w.borrow_mut() = previous);
+        res
+    })
+    .await
+}
+
+static METHOD: boring_sys::SSL_PRIVATE_KEY_METHOD = boring_sys::SSL_PRIVATE_KEY_METHOD {
+    sign: Some(sign),
+    decrypt: Some(decrypt),
+    complete: Some(complete),
+};
+
+/// install has sessions set up with `ctx` delegate their private key operations to `provider`.
+pub(super) fn install(
+    ctx: &mut SslContextBuilder,
+    provider: Arc<dyn KeyProvider>,
+) -> Result<(), Error> {
+    let index = *PROVIDER_INDEX.get_or_try_init(SslContext::new_ex_index)?;
+    PENDING_INDEX.get_or_try_init(Ssl::new_ex_index)?;
+    ctx.set_ex_data(index, provider);
+    unsafe { boring_sys::SSL_CTX_set_private_key_method(ctx.as_ptr(), &METHOD) };
+    Ok(())
+}
+
+unsafe fn provider<'a>(ssl: *mut boring_sys::SSL) -> Option<&'a Arc<dyn KeyProvider>> {
+    let index = PROVIDER_INDEX.get()?;
+    SslContextRef::from_ptr(boring_sys::SSL_get_SSL_CTX(ssl)).ex_data(*index)
+}
+
+unsafe fn pending<'a>(ssl: *mut boring_sys::SSL) -> Option<&'a PendingSignature> {
+    let index = *PENDING_INDEX.get()?;
+    let ssl = SslRef::from_ptr_mut(ssl);
+    if ssl.ex_data(index).is_none() {
+        ssl.set_ex_data(index, Mutex::new(None));
+    }
+    ssl.ex_data(index)
+}
+
+/// sign_with has `provider` sign, turning a panic into an error rather than unwinding into
+/// BoringSSL.
+fn sign_with(provider: &dyn KeyProvider, algorithm: u16, input: &[u8]) -> Signature {
+    match catch_unwind(AssertUnwindSafe(|| provider.sign(algorithm, input))) {
+        Ok(res) => res.map_err(|e| e.to_string()),
+        Err(_) => Err("panicked".to_string()),
+    }
+}
+
+/// guard runs a callback, failing the operation rather than unwinding into BoringSSL.
+fn guard(
+    f: impl FnOnce() -> boring_sys::ssl_private_key_result_t,
+) -> boring_sys::ssl_private_key_result_t {
+    catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or(boring_sys::ssl_private_key_result_t::ssl_private_key_failure)
+}
+
+/// finish hands `signature` to BoringSSL.
+unsafe fn finish(
+    signature: Signature,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+) -> boring_sys::ssl_private_key_result_t {
+    match signature {
+        Ok(signature) if signature.len() <= max_out => {
+            ptr::copy_nonoverlapping(signature.as_ptr(), out, signature.len());
+            *out_len = signature.len();
+            boring_sys::ssl_private_key_result_t::ssl_private_key_success
+        }
+        Ok(signature) => {
+            warn!(
+                len = signature.len(),
+                max_out, "key provider signature too long"
+            );
+            boring_sys::ssl_private_key_result_t::ssl_private_key_failure
+        }
+        Err(e) => {
+            warn!("key provider failed to sign: {e}");
+            boring_sys::ssl_private_key_result_t::ssl_private_key_failure
+        }
+    }
+}
+
+unsafe extern "C" fn sign(
+    ssl: *mut boring_sys::SSL,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+    signature_algorithm: u16,
+    input: *const u8,
+    in_len: usize,
+) -> boring_sys::ssl_private_key_result_t {
+    guard(|| {
+        let failure = boring_sys::ssl_private_key_result_t::ssl_private_key_failure;
+        let (Some(provider), Some(pending)) = (provider(ssl), pending(ssl)) else {
+            return failure;
+        };
+        let input = std::slice::from_raw_parts(input, in_len);
+        let waker = WAKER.with(|w| w.borrow().clone());
+        let (Some(waker), Ok(runtime)) = (waker, tokio::runtime::Handle::try_current()) else {
+            return finish(
+                sign_with(provider.as_ref(), signature_algorithm, input),
+                out,
+                out_len,
+                max_out,
+            );
+        };
+        let (tx, rx) = oneshot::channel();
+        let provider = provider.clone();
+        let input = input.to_vec();
+        runtime.spawn_blocking(move || {
+            let _ = tx.send(sign_with(provider.as_ref(), signature_algorithm, &input));
+            waker.wake();
+        });
+        *pending.lock().unwrap() = Some(rx);
+        boring_sys::ssl_private_key_result_t::ssl_private_key_retry
+    })
+}
+
+// Decryption is only used by RSA key exchange, which TLS 1.3 does not have.
+unsafe extern "C" fn decrypt(
+    _: *mut boring_sys::SSL,
+    _: *mut u8,
+    _: *mut usize,
+    _: usize,
+    _: *const u8,
+    _: usize,
+) -> boring_sys::ssl_private_key_result_t {
+    boring_sys::ssl_private_key_result_t::ssl_private_key_failure
+}
+
+// Called as the handshake is driven again, until the pending signature is ready.
+unsafe extern "C" fn complete(
+    ssl: *mut boring_sys::SSL,
+    out: *mut u8,
+    out_len: *mut usize,
+    max_out: usize,
+) -> boring_sys::ssl_private_key_result_t {
+    guard(|| {
+        let failure = boring_sys::ssl_private_key_result_t::ssl_private_key_failure;
+        let Some(pending) = pending(ssl) else {
+            return failure;
+        };
+        let mut pending = pending.lock().unwrap();
+        let Some(rx) = pending.as_mut() else {
+            return failure;
+        };
+        let signature = match rx.try_recv() {
+            Err(oneshot::error::TryRecvError::Empty) => {
+                return boring_sys::ssl_private_key_result_t::ssl_private_key_retry
+            }
+            Err(oneshot::error::TryRecvError::Closed) => Err("signing was abandoned".to_string()),
+            Ok(signature) => signature,
+        };
+        *pending = None;
+        finish(signature, out, out_len, max_out)
+    })
+}
@@ -0,0 +1,80 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS key logging, in the NSS key log format Wireshark reads, so captured HBONE and control plane
+//! traffic can be decrypted while debugging. Anyone who can read the log can decrypt all of that
+//! traffic, so it is only enabled when explicitly asked for; see [crate::config::Config::tls_key_log].
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use boring::ssl::SslContextBuilder;
+use once_cell::sync::OnceCell;
+use tracing::{error, warn};
+
+static KEY_LOG: OnceCell<KeyLog> = OnceCell::new();
+
+struct KeyLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Whether a key has been written yet, to warn again once traffic is actually exposed.
+    written: AtomicBool,
+}
+
+/// configure appends the keys of every TLS session set up from here on to `path`, for the rest of
+/// the process' lifetime.
+pub fn configure(path: &Path) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    // The mode only applies to a file we create; an existing log may be readable by others.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    warn!(
+        path = %path.display(),
+        "TLS KEY LOGGING IS ENABLED: the session keys of all TLS connections are written to disk, \
+        and anyone who can read them can decrypt all mesh traffic through this ztunnel. \
+        Never enable this outside of development"
+    );
+    let _ = KEY_LOG.set(KeyLog {
+        path: path.to_path_buf(),
+        file: Mutex::new(file),
+        written: AtomicBool::new(false),
+    });
+    Ok(())
+}
+
+/// install logs the keys of sessions set up with `ctx`, if key logging is enabled.
+pub(super) fn install(ctx: &mut SslContextBuilder) {
+    if let Some(log) = KEY_LOG.get() {
+        ctx.set_keylog_callback(move |_, line| log.write(line));
+    }
+}
+
+impl KeyLog {
+    fn write(&self, line: &str) {
+        if !self.written.swap(true, Ordering::Relaxed) {
+            warn!(path = %self.path.display(), "writing TLS session keys");
+        }
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{line}") {
+            error!(path = %self.path.display(), "failed to write TLS key log: {e}");
+        }
+    }
+}
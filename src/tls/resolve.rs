@@ -0,0 +1,153 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The control plane is usually reached through a DNS name, which may move to other addresses,
+//! such as when istiod fails over. A new connection resolves the name again, but a connection is
+//! kept for as long as it works, so we re-resolve the name periodically and move to a new
+//! connection once the address it is connected to is no longer among them.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use hyper::Uri;
+use rand::Rng;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// Resolution tracks the addresses the host of a control plane URI resolves to.
+#[derive(Debug)]
+pub struct Resolution {
+    uri: Uri,
+    interval: Duration,
+    next: Instant,
+    addrs: Option<BTreeSet<IpAddr>>,
+    /// The address the connection to the host is connected to, if known.
+    connected: Option<IpAddr>,
+}
+
+impl Resolution {
+    pub fn new(uri: Uri, interval: Duration) -> Resolution {
+        Resolution {
+            uri,
+            interval,
+            next: Instant::now(),
+            addrs: None,
+            connected: None,
+        }
+    }
+
+    /// connected_to records the address the connection to the host is connected to.
+    pub fn connected_to(&mut self, ip: IpAddr) {
+        self.connected = Some(ip);
+    }
+
+    /// changed re-resolves the host if it is due, and returns whether the connection should move:
+    /// whether the address it is connected to is no longer among the host's addresses or, if that
+    /// is not known, whether they changed since the last time. The first resolution, and those
+    /// that fail, are not changes: connections should only move once we know where to.
+    pub async fn changed(&mut self) -> bool {
+        if Instant::now() < self.next {
+            return false;
+        }
+        // Spread the resolutions of many ztunnels out, rather than having them move at once.
+        self.next = Instant::now()
+            + self
+                .interval
+                .mul_f64(rand::thread_rng().gen_range(0.75..=1.25));
+        let addrs = match resolve(&self.uri).await {
+            Ok(addrs) if !addrs.is_empty() => addrs,
+            Ok(_) => {
+                warn!(uri = %self.uri, "control plane resolved to no addresses");
+                return false;
+            }
+            Err(e) => {
+                warn!(uri = %self.uri, "failed to resolve control plane: {e}");
+                return false;
+            }
+        };
+        let prev = self.addrs.replace(addrs.clone());
+        let moved = match (self.connected, prev) {
+            (_, None) => false,
+            (Some(connected), _) => !addrs.contains(&connected),
+            (None, Some(prev)) => prev != addrs,
+        };
+        if moved {
+            info!(uri = %self.uri, connected = ?self.connected, ?addrs, "control plane addresses changed");
+        } else {
+            debug!(uri = %self.uri, ?addrs, "connected control plane address still current");
+        }
+        moved
+    }
+
+    /// wait_for_change completes once the addresses change.
+    pub async fn wait_for_change(&mut self) {
+        loop {
+            tokio::time::sleep_until(self.next).await;
+            if self.changed().await {
+                return;
+            }
+        }
+    }
+}
+
+async fn resolve(uri: &Uri) -> io::Result<BTreeSet<IpAddr>> {
+    let host = uri
+        .host()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    // The port only matters to the lookup API.
+    let port = uri.port_u16().unwrap_or(443);
+    Ok(tokio::net::lookup_host((host, port))
+        .await?
+        .map(|addr| addr.ip())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolution() {
+        let mut res = Resolution::new(
+            Uri::from_static("https://127.0.0.1:15012"),
+            Duration::from_secs(30),
+        );
+        assert!(!res.changed().await);
+        assert_eq!(
+            res.addrs,
+            Some(BTreeSet::from(["127.0.0.1".parse().unwrap()]))
+        );
+
+        // A change is only noticed once the next resolution is due.
+        res.addrs = Some(BTreeSet::from(["127.0.0.2".parse().unwrap()]));
+        assert!(!res.changed().await);
+        res.next = Instant::now();
+        assert!(res.changed().await);
+        res.next = Instant::now();
+        assert!(!res.changed().await);
+
+        // Once the connected address is known, only its removal counts.
+        res.connected_to("127.0.0.1".parse().unwrap());
+        res.addrs = Some(BTreeSet::from(["127.0.0.2".parse().unwrap()]));
+        res.next = Instant::now();
+        assert!(!res.changed().await);
+        res.connected_to("127.0.0.2".parse().unwrap());
+        res.next = Instant::now();
+        assert!(res.changed().await);
+    }
+}
@@ -0,0 +1,195 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage for encoded private keys on their way into TLS contexts. Keys are always zeroed once
+//! dropped, and never formatted. In hardened mode they are also locked into memory, so they are
+//! never swapped to disk, and excluded from core dumps. If the memory lock limit is too low to
+//! lock a key, it is kept unlocked rather than refused, with a warning. This covers only the
+//! encoded keys ztunnel reads or generates, while it holds them: the parsed keys of certificates,
+//! and their copies in TLS contexts, are held by BoringSSL, and are neither locked nor kept out of
+//! core dumps.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{self, AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+static HARDENED: AtomicBool = AtomicBool::new(false);
+/// Whether a key failed to lock yet, to warn only once.
+static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+/// The number of keys on each locked page, by page address. Keys can share a page, which must stay
+/// locked until the last of them is dropped. Only taken as keys are stored and dropped.
+static LOCKED_PAGES: Lazy<Mutex<HashMap<usize, usize>>> = Lazy::new(Default::default);
+
+/// configure sets whether the keys stored from here on are locked into memory.
+pub fn configure(hardened: bool) {
+    if !hardened {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } == 0 {
+            info!(
+                limit = limit.rlim_cur,
+                "hardened key storage enabled, locking private keys into memory"
+            );
+        }
+    }
+    #[cfg(not(unix))]
+    warn!("hardened key storage requested, but memory locking is not supported on this platform");
+    HARDENED.store(true, Ordering::Relaxed);
+}
+
+/// SecretBytes holds an encoded private key.
+pub struct SecretBytes {
+    buf: Vec<u8>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    pub fn new(buf: Vec<u8>) -> SecretBytes {
+        let locked = HARDENED.load(Ordering::Relaxed) && buf.capacity() > 0 && lock(&buf);
+        SecretBytes { buf, locked }
+    }
+}
+
+/// pages returns the addresses of the pages `buf` spans.
+#[cfg(unix)]
+fn pages(buf: &Vec<u8>) -> impl Iterator<Item = usize> {
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = buf.as_ptr() as usize;
+    (start & !(page - 1)..start + buf.capacity()).step_by(page)
+}
+
+#[cfg(unix)]
+fn lock(buf: &Vec<u8>) -> bool {
+    let (ptr, len) = (buf.as_ptr() as *const libc::c_void, buf.capacity());
+    // Held across the mlock, so a page is not unlocked by a key dropped meanwhile.
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    if unsafe { libc::mlock(ptr, len) } != 0 {
+        if !LOCK_FAILED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "failed to lock a private key into memory, keeping it unlocked; \
+                raise RLIMIT_MEMLOCK to lock keys: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        return false;
+    }
+    #[cfg(target_os = "linux")]
+    unsafe {
+        // Best effort: the key is still locked and zeroed if this fails.
+        let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let start = ptr as usize & !(page - 1);
+        libc::madvise(
+            start as *mut libc::c_void,
+            ptr as usize + len - start,
+            libc::MADV_DONTDUMP,
+        );
+    }
+    for page in pages(buf) {
+        *locked.entry(page).or_default() += 1;
+    }
+    true
+}
+
+/// unlock unlocks the pages of `buf` that no other key is on.
+#[cfg(unix)]
+fn unlock(buf: &Vec<u8>) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let mut locked = LOCKED_PAGES.lock().unwrap();
+    for page in pages(buf) {
+        let Some(keys) = locked.get_mut(&page) else {
+            continue;
+        };
+        *keys -= 1;
+        if *keys == 0 {
+            locked.remove(&page);
+            unsafe { libc::munlock(page as *const libc::c_void, page_size) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_: &Vec<u8>) -> bool {
+    false
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // Volatile writes, so the zeroing is not optimized away as a dead store.
+        let ptr = self.buf.as_mut_ptr();
+        for i in 0..self.buf.capacity() {
+            unsafe { std::ptr::write_volatile(ptr.add(i), 0) };
+        }
+        atomic::compiler_fence(Ordering::SeqCst);
+        #[cfg(unix)]
+        if self.locked {
+            unlock(&self.buf);
+        }
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretBytes(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let secret = SecretBytes::new(b"private key".to_vec());
+        assert_eq!(&*secret, b"private key");
+        assert_eq!(format!("{secret:?}"), "SecretBytes(<redacted>)");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_pages() {
+        let page = |buf: &Vec<u8>| pages(buf).next().unwrap();
+        let count = |page: usize| LOCKED_PAGES.lock().unwrap().get(&page).copied();
+        // Small keys allocated together usually share a page.
+        let (a, b) = (vec![1u8; 16], vec![2u8; 16]);
+        if page(&a) != page(&b) || !lock(&a) {
+            return;
+        }
+        let shared = page(&a);
+        assert!(lock(&b));
+        assert_eq!(count(shared), Some(2));
+        unlock(&a);
+        assert_eq!(count(shared), Some(1));
+        unlock(&b);
+        assert_eq!(count(shared), None);
+    }
+}
@@ -14,26 +14,31 @@
 
 mod client;
 pub mod metrics;
+mod record;
 mod types;
 
 pub use metrics::*;
+pub use record::Recorder;
 
 use self::service::discovery::v3::DeltaDiscoveryRequest;
 use crate::cert_fetcher::{CertFetcher, NoCertFetcher};
 use crate::config::ConfigSource;
 use crate::rbac;
 use crate::rbac::Authorization;
+use crate::state::names::NameTableStore;
 use crate::state::service::{endpoint_uid, Endpoint, Service};
 use crate::state::workload::{network_addr, HealthStatus, NamespacedHostname, Workload};
 use crate::state::ProxyState;
 use crate::xds;
 pub use client::*;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
 use tracing::{debug, info, instrument, trace, warn};
 pub use types::*;
+use xds::istio::networking::nds::v1::NameTable as XdsNameTable;
 use xds::istio::security::Authorization as XdsAuthorization;
 use xds::istio::workload::address::Type as XdsType;
 use xds::istio::workload::Address as XdsAddress;
@@ -52,6 +57,8 @@ pub enum Error {
     RequestFailure(#[from] Box<mpsc::error::SendError<DeltaDiscoveryRequest>>),
     #[error("failed to send on demand resource")]
     OnDemandSend(),
+    #[error("control plane addresses changed")]
+    AddressesChanged,
 }
 
 /// Updates the [ProxyState] from XDS.
@@ -59,6 +66,8 @@ pub enum Error {
 pub struct ProxyStateUpdater {
     state: Arc<RwLock<ProxyState>>,
     cert_fetcher: Arc<dyn CertFetcher>,
+    /// If set, the updates are from the control plane of this remote cluster.
+    cluster: Option<String>,
 }
 
 impl ProxyStateUpdater {
@@ -67,6 +76,25 @@ impl ProxyStateUpdater {
         Self {
             state,
             cert_fetcher,
+            cluster: None,
+        }
+    }
+
+    /// for_cluster applies the workloads of the remote cluster `cluster_id` on top of those of our
+    /// own. Their UIDs are qualified by the cluster, so they cannot collide with ours, and they
+    /// join the services of our own control plane, which alone adds and removes services.
+    pub fn for_cluster(mut self, cluster_id: String) -> Self {
+        self.cluster = Some(cluster_id);
+        self
+    }
+
+    /// qualify returns the UID a workload named `name` by the control plane is stored under.
+    fn qualify<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match &self.cluster {
+            Some(cluster) if !name.starts_with(&format!("{cluster}/")) => {
+                Cow::Owned(format!("{cluster}/{name}"))
+            }
+            _ => Cow::Borrowed(name),
         }
     }
 
@@ -75,7 +103,13 @@ impl ProxyStateUpdater {
         Self::new(state, Arc::new(NoCertFetcher()))
     }
 
-    pub fn insert_workload(&self, w: XdsWorkload) -> anyhow::Result<()> {
+    pub fn insert_workload(&self, mut w: XdsWorkload) -> anyhow::Result<()> {
+        if let Some(cluster) = &self.cluster {
+            w.uid = self.qualify(&w.uid).into_owned();
+            if w.cluster_id.is_empty() {
+                w.cluster_id = cluster.clone();
+            }
+        }
         // Convert the workload.
         let workload = Workload::try_from(&w)?;
 
@@ -95,7 +129,11 @@ impl ProxyStateUpdater {
 
         // Lock and upstate the stores.
         let mut state = self.state.write().unwrap();
-        state.workloads.insert(workload)?;
+        if self.cluster.is_some() {
+            state.workloads.insert_remote(workload)?;
+        } else {
+            state.workloads.insert(workload)?;
+        }
         while let Some(endpoint) = endpoints.pop() {
             state.services.insert_endpoint(endpoint);
         }
@@ -108,7 +146,8 @@ impl ProxyStateUpdater {
         let mut state = self.state.write().unwrap();
 
         // remove workload by UID; if xds_name is a service then this will no-op
-        if let Some(prev) = state.workloads.remove(xds_name) {
+        if let Some(prev) = state.workloads.remove(&self.qualify(xds_name)) {
+            self.cert_fetcher.clear_cert(&prev);
             // Also remove service endpoints for the workload.
             for wip in prev.workload_ips.iter() {
                 let prev_addr = &network_addr(&prev.network, *wip);
@@ -125,6 +164,10 @@ impl ProxyStateUpdater {
             // We removed a workload, no reason to attempt to remove a service with the same name
             return;
         }
+        if self.cluster.is_some() {
+            // Services are only removed by our own control plane.
+            return;
+        }
 
         let Ok(name) = NamespacedHostname::from_str(xds_name) else {
             // we don't have namespace/hostname xds primary key for service
@@ -152,6 +195,16 @@ impl ProxyStateUpdater {
         }
     }
 
+    /// drain closes the connections to the workload with UID `xds_name`, after the grace period,
+    /// once it is gone for good. Removals as part of an update leave connections be.
+    pub fn drain(&self, xds_name: &str) {
+        self.state
+            .read()
+            .unwrap()
+            .connections
+            .drain(&self.qualify(xds_name));
+    }
+
     pub fn insert_address(&self, a: XdsAddress) -> anyhow::Result<()> {
         match a.r#type {
             Some(XdsType::Workload(w)) => self.insert_workload(w),
@@ -161,6 +214,13 @@ impl ProxyStateUpdater {
     }
 
     pub fn insert_service(&self, service: XdsService) -> anyhow::Result<()> {
+        if let Some(cluster) = &self.cluster {
+            debug!(
+                cluster,
+                "ignoring service {} from remote cluster", service.hostname
+            );
+            return Ok(());
+        }
         let mut service = Service::try_from(&service)?;
 
         // Lock the store.
@@ -195,6 +255,20 @@ impl ProxyStateUpdater {
         let mut state = self.state.write().unwrap();
         state.policies.remove(name);
     }
+
+    pub fn insert_name_table(&self, t: XdsNameTable) -> anyhow::Result<()> {
+        info!("handling name table update with {} hosts", t.table.len());
+        let names = NameTableStore::try_from(&t)?;
+        let mut state = self.state.write().unwrap();
+        state.names.replace(names);
+        Ok(())
+    }
+
+    pub fn remove_name_table(&self) {
+        info!("handling name table delete");
+        let mut state = self.state.write().unwrap();
+        state.names.clear();
+    }
 }
 
 impl Handler<XdsWorkload> for ProxyStateUpdater {
@@ -202,7 +276,10 @@ impl Handler<XdsWorkload> for ProxyStateUpdater {
         let handle = |res: XdsUpdate<XdsWorkload>| {
             match res {
                 XdsUpdate::Update(w) => self.insert_workload(w.resource)?,
-                XdsUpdate::Remove(name) => self.remove(&name),
+                XdsUpdate::Remove(name) => {
+                    self.remove(&name);
+                    self.drain(&name);
+                }
             }
             Ok(())
         };
@@ -215,7 +292,10 @@ impl Handler<XdsAddress> for ProxyStateUpdater {
         let handle = |res: XdsUpdate<XdsAddress>| {
             match res {
                 XdsUpdate::Update(w) => self.insert_address(w.resource)?,
-                XdsUpdate::Remove(name) => self.remove(&name),
+                XdsUpdate::Remove(name) => {
+                    self.remove(&name);
+                    self.drain(&name);
+                }
             }
             Ok(())
         };
@@ -276,6 +356,19 @@ impl Handler<XdsAuthorization> for ProxyStateUpdater {
     }
 }
 
+impl Handler<XdsNameTable> for ProxyStateUpdater {
+    fn handle(&self, updates: Vec<XdsUpdate<XdsNameTable>>) -> Result<(), Vec<RejectedConfig>> {
+        let handle = |res: XdsUpdate<XdsNameTable>| {
+            match res {
+                XdsUpdate::Update(w) => self.insert_name_table(w.resource)?,
+                XdsUpdate::Remove(_) => self.remove_name_table(),
+            }
+            Ok(())
+        };
+        handle_single_resource(updates, handle)
+    }
+}
+
 /// LocalClient serves as a local file reader alternative for XDS. This is intended for testing.
 pub struct LocalClient {
     pub cfg: ConfigSource,
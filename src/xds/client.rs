@@ -13,23 +13,33 @@
 // limitations under the License.
 
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{fmt, mem};
 
+use hyper::Uri;
 use prost::{DecodeError, EncodeError};
 use prost_types::value::Kind;
 use prost_types::{Struct, Value};
+use rand::Rng;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use crate::config::RootCert;
 use crate::metrics::IncrementRecorder;
+use crate::tls::resolve::Resolution;
+use crate::xds::istio::networking::nds::v1::NameTable;
 use crate::xds::istio::security::Authorization;
 use crate::xds::istio::workload::Address;
-use crate::xds::metrics::{ConnectionTerminationReason, Metrics};
+use crate::xds::metrics::{
+    ConnectionState, ConnectionTerminationReason, Metrics, ResourceType, Response, ResponseResult,
+};
+use crate::xds::record::{self, Event, Recorder};
 use crate::xds::service::discovery::v3::aggregated_discovery_service_client::AggregatedDiscoveryServiceClient;
 use crate::xds::service::discovery::v3::Resource as ProtoResource;
 use crate::xds::service::discovery::v3::*;
@@ -120,24 +130,103 @@ pub struct Config {
 
     address_handler: Box<dyn Handler<Address>>,
     authorization_handler: Box<dyn Handler<Authorization>>,
+    name_table_handler: Box<dyn Handler<NameTable>>,
     initial_watches: Vec<String>,
+    /// The initial watches readiness does not wait on.
+    optional_watches: HashSet<String>,
     on_demand: bool,
+    on_demand_timeout: Duration,
+    reresolve_interval: Option<Duration>,
+    recorder: Option<Recorder>,
+    sync_status: HashMap<String, SyncStatus>,
+}
+
+/// SyncStatus tracks whether our resources of a type are in sync with the control plane. They are
+/// once a response of the type has been accepted, and stale from when the stream it arrived on
+/// ends until one is accepted on a new stream, as changes made in the meantime are not seen.
+#[derive(Clone, Debug, Default)]
+pub struct SyncStatus(Arc<Mutex<SyncInner>>);
+
+#[derive(Debug, Default)]
+struct SyncInner {
+    synced: bool,
+    stale_since: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    NeverSynced,
+    Current,
+    /// Stale for this long.
+    Stale(Duration),
+}
+
+impl SyncStatus {
+    pub fn freshness(&self) -> Freshness {
+        let inner = self.0.lock().unwrap();
+        match (inner.synced, inner.stale_since) {
+            (false, _) => Freshness::NeverSynced,
+            (true, None) => Freshness::Current,
+            (true, Some(since)) => Freshness::Stale(since.elapsed()),
+        }
+    }
+
+    fn synced(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.synced = true;
+        inner.stale_since = None;
+    }
+
+    fn disconnected(&self) {
+        let mut inner = self.0.lock().unwrap();
+        if inner.stale_since.is_none() {
+            inner.stale_since = Some(Instant::now());
+        }
+    }
 }
 
 impl Config {
     pub fn new(config: crate::config::Config) -> Config {
         Config {
-            address: config.xds_address.clone().unwrap(),
+            // Replaying a recording needs no control plane.
+            address: config.xds_address.clone().unwrap_or_default(),
             root_cert: config.xds_root_cert.clone(),
             auth: config.auth,
             address_handler: Box::new(NopHandler {}),
             authorization_handler: Box::new(NopHandler {}),
+            name_table_handler: Box::new(NopHandler {}),
             initial_watches: Vec::new(),
+            optional_watches: HashSet::new(),
             on_demand: config.xds_on_demand,
+            on_demand_timeout: config.xds_on_demand_timeout,
+            reresolve_interval: config.control_plane_reresolve_interval,
             proxy_metadata: config.proxy_metadata,
+            recorder: None,
+            sync_status: HashMap::new(),
         }
     }
 
+    /// record appends every response received, along with the streams they arrive on, to
+    /// `recorder`, so the control plane state can be replayed later with [AdsClient::replay].
+    pub fn record(mut self, recorder: Recorder) -> Config {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// remote connects to the control plane at `address` in place of our own, which serves the
+    /// whole of its resources rather than those requested on-demand.
+    pub fn remote(mut self, address: String) -> Config {
+        self.address = address;
+        self.on_demand = false;
+        self
+    }
+
+    /// track_sync reports whether the resources of `type_url` are in sync to `status`.
+    pub fn track_sync(mut self, type_url: &str, status: SyncStatus) -> Config {
+        self.sync_status.insert(type_url.to_string(), status);
+        self
+    }
+
     pub fn with_address_handler(mut self, f: impl Handler<Address>) -> Config {
         self.address_handler = Box::new(f);
         self
@@ -148,29 +237,65 @@ impl Config {
         self
     }
 
+    pub fn with_name_table_handler(mut self, f: impl Handler<NameTable>) -> Config {
+        self.name_table_handler = Box::new(f);
+        self
+    }
+
     pub fn watch(mut self, type_url: String) -> Config {
         self.initial_watches.push(type_url);
         self
     }
 
+    /// watch_optional watches `type_url` without holding up readiness until it is synced, for
+    /// types a control plane may not serve at all.
+    pub fn watch_optional(mut self, type_url: String) -> Config {
+        self.optional_watches.insert(type_url.clone());
+        self.watch(type_url)
+    }
+
     pub fn build(self, metrics: Metrics, block_ready: readiness::BlockReady) -> AdsClient {
+        self.build_with(metrics, Some(block_ready))
+    }
+
+    /// build_detached builds a client that does not hold up readiness, for control planes ztunnel
+    /// can do its job without.
+    pub fn build_detached(self, metrics: Metrics) -> AdsClient {
+        self.build_with(metrics, None)
+    }
+
+    fn build_with(self, metrics: Metrics, block_ready: Option<readiness::BlockReady>) -> AdsClient {
         let (tx, rx) = mpsc::channel(100);
         AdsClient {
             config: self,
             known_resources: Default::default(),
+            resyncing: Default::default(),
             pending: Default::default(),
             demand: rx,
             demand_tx: tx,
             metrics,
-            block_ready: Some(block_ready),
+            block_ready,
+            synced: Default::default(),
             connection_id: 0,
         }
     }
 }
+/// addresses_changed completes once the addresses of the control plane change, if they are
+/// followed at all.
+async fn addresses_changed(resolution: &mut Option<Resolution>) {
+    match resolution {
+        Some(resolution) => resolution.wait_for_change().await,
+        None => std::future::pending().await,
+    }
+}
+
 pub struct AdsClient {
     config: Config,
-    /// Stores all known workload resources. Map from type_url to name
-    known_resources: HashMap<String, HashSet<String>>,
+    /// Stores all known workload resources. Map from type_url to name to version
+    known_resources: HashMap<String, HashMap<String, String>>,
+    /// The types whose first response on the current stream has yet to reconcile our known
+    /// resources against.
+    resyncing: HashSet<String>,
 
     /// pending stores a list of all resources that are pending and XDS push
     pending: HashMap<ResourceKey, oneshot::Sender<()>>,
@@ -179,7 +304,10 @@ pub struct AdsClient {
     demand_tx: mpsc::Sender<(oneshot::Sender<()>, ResourceKey)>,
 
     pub(crate) metrics: Metrics,
+    /// Held until the initial sync completes, blocking readiness.
     block_ready: Option<readiness::BlockReady>,
+    /// The types we have accepted a response for while block_ready is held.
+    synced: HashSet<String>,
 
     connection_id: u32,
 }
@@ -187,14 +315,17 @@ pub struct AdsClient {
 /// Demanded allows awaiting for an on-demand XDS resource
 pub struct Demanded {
     b: oneshot::Receiver<()>,
+    timeout: Duration,
 }
 
 impl Demanded {
-    /// recv awaits for the requested resource
+    /// recv awaits for the requested resource, for at most the configured on-demand timeout.
+    /// It returns false if the XDS server did not respond in time, or if the client dropped the
+    /// request, such as when its connection is lost.
     /// Note: the actual resource is not directly returned. Instead, callers are notified that the event
     /// has been handled through the configured resource handler.
-    pub async fn recv(self) {
-        let _ = self.b.await;
+    pub async fn recv(self) -> bool {
+        matches!(tokio::time::timeout(self.timeout, self.b).await, Ok(Ok(())))
     }
 }
 
@@ -202,6 +333,7 @@ impl Demanded {
 #[derive(Debug, Clone)]
 pub struct Demander {
     demand: mpsc::Sender<(oneshot::Sender<()>, ResourceKey)>,
+    timeout: Duration,
 }
 
 #[derive(Debug)]
@@ -235,7 +367,10 @@ impl Demander {
             ))
             .await
             .unwrap();
-        Demanded { b: rx }
+        Demanded {
+            b: rx,
+            timeout: self.timeout,
+        }
     }
 }
 
@@ -245,15 +380,27 @@ impl AdsClient {
         if self.config.on_demand {
             Some(Demander {
                 demand: self.demand_tx.clone(),
+                timeout: self.config.on_demand_timeout,
             })
         } else {
             None
         }
     }
 
+    /// jittered spreads out reconnects of many clients backing off from the same failure, such as
+    /// a control plane restart, by waiting between half and all of `backoff`.
+    fn jittered(backoff: Duration) -> Duration {
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
     async fn run_loop(&mut self, backoff: Duration) -> Duration {
         const MAX_BACKOFF: Duration = Duration::from_secs(15);
-        match self.run_internal().await {
+        let res = self.run_internal().await;
+        self.metrics.increment(&ConnectionState::Disconnected);
+        for status in self.config.sync_status.values() {
+            status.disconnected();
+        }
+        match res {
             Err(e @ Error::Connection(_)) => {
                 // For connection errors, we add backoff
                 let backoff = std::cmp::min(MAX_BACKOFF, backoff * 2);
@@ -263,7 +410,7 @@ impl AdsClient {
                 );
                 self.metrics
                     .increment(&ConnectionTerminationReason::ConnectionError);
-                tokio::time::sleep(backoff).await;
+                tokio::time::sleep(Self::jittered(backoff)).await;
                 backoff
             }
             Err(ref e @ Error::GrpcStatus(ref status)) => {
@@ -291,9 +438,15 @@ impl AdsClient {
                     );
                     self.metrics.increment(&ConnectionTerminationReason::Error);
                 }
-                tokio::time::sleep(backoff).await;
+                tokio::time::sleep(Self::jittered(backoff)).await;
                 backoff
             }
+            Err(Error::AddressesChanged) => {
+                info!("XDS client reconnecting, as the control plane addresses changed");
+                self.metrics
+                    .increment(&ConnectionTerminationReason::Reconnect);
+                Duration::from_millis(10)
+            }
             Err(e) => {
                 // For other errors, we connect immediately
                 // TODO: we may need more nuance here; if we fail due to invalid initial request we may overload
@@ -313,6 +466,33 @@ impl AdsClient {
         }
     }
 
+    /// replay applies the events of a recording made with [Config::record] in order, in place of
+    /// a control plane, and returns once all of them are applied. Responses are handled just as
+    /// when they were received, so the resulting state matches the recorded one.
+    pub async fn replay(mut self, path: &Path) -> anyhow::Result<()> {
+        let records = record::read(path).await?;
+        // Acks have nowhere to go. Each response sends one, which is dropped right after.
+        let (send, mut acks) = mpsc::channel(1);
+        let mut responses = 0;
+        for record in records {
+            match record.event {
+                Event::Connected => {
+                    debug!(timestamp = record.timestamp, "replaying new stream");
+                    self.start_resync();
+                }
+                Event::Response { type_url, response } => {
+                    debug!(timestamp = record.timestamp, type_url, "replaying response");
+                    let response = Event::decode_response(&response)?;
+                    self.handle_stream_event(Some(response), &send).await?;
+                    let _ = acks.try_recv();
+                    responses += 1;
+                }
+            }
+        }
+        info!(responses, path = %path.display(), "replayed xds recording");
+        Ok(())
+    }
+
     pub async fn run(mut self) -> Result<(), Error> {
         let mut backoff = Duration::from_millis(10);
         loop {
@@ -364,13 +544,24 @@ impl AdsClient {
     }
 
     async fn run_internal(&mut self) -> Result<(), Error> {
+        self.metrics.increment(&ConnectionState::Connecting);
         let address = self.config.address.clone();
+        // Move to another of the control plane's addresses once the one we stream from is no
+        // longer among them. The new stream resolves them when connecting.
+        let mut resolution = match (self.config.reresolve_interval, Uri::try_from(&address)) {
+            (Some(interval), Ok(uri)) => Some(Resolution::new(uri, interval)),
+            _ => None,
+        };
         let svc = tls::grpc_connector(address, self.config.root_cert.clone()).unwrap();
         let mut client =
             AggregatedDiscoveryServiceClient::with_interceptor(svc, self.config.auth.clone());
         let (discovery_req_tx, mut discovery_req_rx) = mpsc::channel::<DeltaDiscoveryRequest>(100);
         // For each type in initial_watches we will send a request on connection to subscribe
         let initial_requests = self.construct_initial_requests();
+        self.start_resync();
+        if let Some(recorder) = &self.config.recorder {
+            recorder.connected();
+        }
         let outbound = async_stream::stream! {
             for initial in initial_requests {
                 info!(resources=initial.initial_resource_versions.len(), type_url=initial.type_url, "sending initial request");
@@ -383,26 +574,23 @@ impl AdsClient {
             warn!("outbound stream complete");
         };
 
-        let mut response_stream = client
+        let response = client
             .delta_aggregated_resources(tonic::Request::new(outbound))
             .await
-            .map_err(Error::Connection)?
-            .into_inner();
+            .map_err(Error::Connection)?;
+        if let (Some(resolution), Some(info)) = (
+            &mut resolution,
+            response
+                .extensions()
+                .get::<hyper_util::client::connect::HttpInfo>(),
+        ) {
+            resolution.connected_to(info.remote_addr().ip());
+        }
+        let mut response_stream = response.into_inner();
         debug!("connected established");
 
         info!("Stream established");
-        // Create a oneshot channel to be notified as soon as we ACK the first XDS response
-        let (tx, initial_xds_rx) = oneshot::channel();
-        let mut initial_xds_tx = Some(tx);
-        let ready = mem::take(&mut self.block_ready);
-        tokio::spawn(async move {
-            match initial_xds_rx.await {
-                Ok(_) => drop(ready),
-                Err(_) => {
-                    debug!("sender was dropped before initial xds sync event was received");
-                }
-            }
-        });
+        self.metrics.increment(&ConnectionState::Connected);
 
         loop {
             tokio::select! {
@@ -410,21 +598,47 @@ impl AdsClient {
                     self.handle_demand_event(_demand_event, &discovery_req_tx).await?;
                 }
                 msg = response_stream.message() => {
-                    // TODO: If we have responses of different types (e.g. RBAC), we'll want to wait for
-                    // each type to receive a response before marking ready
-                    if let XdsSignal::Ack = self.handle_stream_event(msg?, &discovery_req_tx).await? {
-                        let val = mem::take(&mut initial_xds_tx);
-                        if let Some(tx) = val {
-                            if let Err(err) = tx.send(()) {
-                                warn!("initial xds sync signal send failed: {:?}", err)
-                            }
-                        }
-                    };
+                    self.handle_stream_event(msg?, &discovery_req_tx).await?;
+                }
+                _ = addresses_changed(&mut resolution) => {
+                    return Err(Error::AddressesChanged);
                 }
             }
         }
     }
 
+    /// start_resync is called for each new stream. Resources may have been removed while we were
+    /// disconnected, or, if the control plane restarted, forgotten without it ever telling us. We
+    /// watch every resource of the initial types without versions, so the response to the initial
+    /// request holds their full state, and anything missing from it is pruned. On demand, we only
+    /// hold what we asked for, and resume from its versions, so there is nothing to reconcile.
+    fn start_resync(&mut self) {
+        self.resyncing = if self.config.on_demand {
+            HashSet::new()
+        } else {
+            self.config.initial_watches.iter().cloned().collect()
+        };
+    }
+
+    /// mark_synced records that a response of `type_url` was accepted. Once one has been for every
+    /// type we initially watch, other than the optional ones, we are ready.
+    fn mark_synced(&mut self, type_url: &str) {
+        if self.block_ready.is_none() {
+            return;
+        }
+        self.synced.insert(type_url.to_string());
+        let config = &self.config;
+        if config
+            .initial_watches
+            .iter()
+            .filter(|t| !config.optional_watches.contains(*t))
+            .all(|t| self.synced.contains(t))
+        {
+            info!("initial xds sync complete");
+            self.block_ready = None;
+        }
+    }
+
     fn construct_initial_requests(&mut self) -> Vec<DeltaDiscoveryRequest> {
         let node = self.node();
         let initial_requests: Vec<DeltaDiscoveryRequest> = self
@@ -432,15 +646,17 @@ impl AdsClient {
             .initial_watches
             .iter()
             .map(|request_type| {
-                let irv: HashMap<String, String> = self
-                    .known_resources
-                    .get(request_type)
-                    .map(|hs| {
-                        hs.iter()
-                            .map(|n| (n.to_owned(), "".to_string())) // Proto expects Name -> Version. We don't care about version
-                            .collect()
-                    })
-                    .unwrap_or_default();
+                // On demand, we resume from the versions we hold, so the control plane only sends
+                // what changed while we were disconnected. Otherwise we ask for the full state,
+                // to prune what it no longer has; see start_resync.
+                let irv: HashMap<String, String> = if self.config.on_demand {
+                    self.known_resources
+                        .get(request_type)
+                        .cloned()
+                        .unwrap_or_default()
+                } else {
+                    HashMap::new()
+                };
                 let (sub, unsub) = if self.config.on_demand {
                     // XDS doesn't have a way to subscribe to zero resources. We workaround this by subscribing and unsubscribing
                     // in one event, effectively giving us "subscribe to nothing".
@@ -469,6 +685,9 @@ impl AdsClient {
         let Some(response) = stream_event else {
             return Ok( XdsSignal::None);
         };
+        if let Some(recorder) = &self.config.recorder {
+            recorder.response(&response);
+        }
         let type_url = response.type_url.clone();
         let nonce = response.nonce.clone();
         info!(
@@ -486,6 +705,9 @@ impl AdsClient {
                 |a| &a.config.authorization_handler,
                 response,
             ),
+            xds::NAME_TABLE_TYPE => {
+                self.decode_and_handle::<NameTable, _>(|a| &a.config.name_table_handler, response)
+            }
             _ => {
                 error!("unknown type");
                 Ok(())
@@ -504,6 +726,27 @@ impl AdsClient {
             _ => (XdsSignal::Ack, None),
         };
 
+        self.metrics.increment(&Response {
+            type_url: type_url.clone(),
+            result: match response_type {
+                XdsSignal::Nack => ResponseResult::Nack,
+                _ => ResponseResult::Ack,
+            },
+        });
+        let known = self.known_resources.get(&type_url).map_or(0, HashMap::len);
+        self.metrics
+            .resources
+            .get_or_create(&ResourceType {
+                type_url: type_url.clone(),
+            })
+            .set(known as i64);
+        if let XdsSignal::Ack = response_type {
+            self.mark_synced(&type_url);
+            if let Some(status) = self.config.sync_status.get(&type_url) {
+                status.synced();
+            }
+        }
+
         debug!(
             type_url=type_url,
             nonce,
@@ -538,7 +781,8 @@ impl AdsClient {
         self.known_resources
             .entry(type_url.clone())
             .or_default()
-            .insert(name.clone());
+            .entry(name.clone())
+            .or_default();
         send.send(DeltaDiscoveryRequest {
             type_url,
             resource_names_subscribe: vec![name],
@@ -565,13 +809,48 @@ impl AdsClient {
                     type_url: resp.type_url.clone(),
                 };
                 debug!("received delete resource {k}");
-                self.known_resources.remove(res);
+                if let Some(known) = self.known_resources.get_mut(&k.type_url) {
+                    known.remove(res);
+                }
                 self.notify_on_demand(&k);
                 k.name
             })
             .collect()
     }
 
+    /// handle_resync returns the resources to prune, if `resp` is the first of its type on this
+    /// stream: those we know of that it neither updates nor removes.
+    fn handle_resync(&mut self, resp: &DeltaDiscoveryResponse) -> Vec<String> {
+        if !self.resyncing.remove(&resp.type_url) {
+            return Vec::new();
+        }
+        let Some(known) = self.known_resources.get_mut(&resp.type_url) else {
+            return Vec::new();
+        };
+        let present: HashSet<&str> = resp
+            .resources
+            .iter()
+            .map(|r| r.name.as_str())
+            .chain(resp.removed_resources.iter().map(String::as_str))
+            .collect();
+        let stale: Vec<String> = known
+            .keys()
+            .filter(|name| !present.contains(name.as_str()))
+            .cloned()
+            .collect();
+        for name in &stale {
+            known.remove(name);
+        }
+        if !stale.is_empty() {
+            info!(
+                type_url = resp.type_url,
+                pruned = stale.len(),
+                "pruning resources missing after reconnect"
+            );
+        }
+        stale
+    }
+
     fn decode_and_handle<
         T: prost::Message + Default + 'static,
         F: FnOnce(&AdsClient) -> &Box<dyn Handler<T>>,
@@ -581,7 +860,8 @@ impl AdsClient {
         response: DeltaDiscoveryResponse,
     ) -> Result<(), Vec<RejectedConfig>> {
         let type_url = response.type_url.clone();
-        let removes = self.handle_removes(&response);
+        let mut removes = self.handle_removes(&response);
+        removes.extend(self.handle_resync(&response));
         let updates: Vec<XdsUpdate<T>> = response
             .resources
             .into_iter()
@@ -594,7 +874,7 @@ impl AdsClient {
                 self.known_resources
                     .entry(key.type_url)
                     .or_default()
-                    .insert(key.name);
+                    .insert(key.name, r.version.clone());
                 r
             })
             .map(|raw| decode_proto::<T>(raw).unwrap())
@@ -705,6 +985,117 @@ mod tests {
         }
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn demanded_timeout() {
+        let (_tx, rx) = oneshot::channel();
+        let demanded = Demanded {
+            b: rx,
+            timeout: Duration::from_secs(1),
+        };
+        // Nothing answers, so we give up once the timeout elapses
+        assert!(!demanded.recv().await);
+
+        // The request is dropped without being answered
+        let (tx, rx) = oneshot::channel::<()>();
+        drop(tx);
+        let demanded = Demanded {
+            b: rx,
+            timeout: Duration::from_secs(1),
+        };
+        assert!(!demanded.recv().await);
+
+        let (tx, rx) = oneshot::channel();
+        tx.send(()).unwrap();
+        let demanded = Demanded {
+            b: rx,
+            timeout: Duration::from_secs(1),
+        };
+        assert!(demanded.recv().await);
+    }
+
+    fn test_client(ready: &readiness::Ready) -> AdsClient {
+        let cfg = crate::test_helpers::test_config_with_port_xds_addr_and_root_cert(
+            80,
+            Some("https://127.0.0.1:15010".to_string()),
+            None,
+            None,
+        );
+        let mut registry = prometheus_client::registry::Registry::default();
+        Config::new(cfg)
+            .watch(xds::ADDRESS_TYPE.into())
+            .watch(xds::AUTHORIZATION_TYPE.into())
+            .watch_optional(xds::NAME_TABLE_TYPE.into())
+            .build(
+                Metrics::new(&mut registry),
+                ready.register_task("ads client"),
+            )
+    }
+
+    #[tokio::test]
+    async fn ready_after_initial_sync() {
+        let ready = readiness::Ready::new();
+        let mut client = test_client(&ready);
+
+        client.mark_synced(xds::ADDRESS_TYPE);
+        assert!(!ready.pending().is_empty());
+        // The name table is not waited on.
+        client.mark_synced(xds::AUTHORIZATION_TYPE);
+        assert!(ready.pending().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_status() {
+        let status = SyncStatus::default();
+        assert_eq!(status.freshness(), Freshness::NeverSynced);
+        status.synced();
+        assert_eq!(status.freshness(), Freshness::Current);
+
+        status.disconnected();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        // Failing to reconnect keeps it stale since the stream first ended.
+        status.disconnected();
+        assert_eq!(status.freshness(), Freshness::Stale(Duration::from_secs(5)));
+        status.synced();
+        assert_eq!(status.freshness(), Freshness::Current);
+    }
+
+    #[tokio::test]
+    async fn resync_prunes_missing() {
+        let mut client = test_client(&readiness::Ready::new());
+        client.known_resources.insert(
+            ADDRESS_TYPE.to_string(),
+            HashMap::from([
+                ("kept".to_string(), "1".to_string()),
+                ("removed".to_string(), "1".to_string()),
+                ("forgotten".to_string(), "1".to_string()),
+            ]),
+        );
+        // A new stream asks for the full state, rather than resuming from our versions.
+        let initial = client.construct_initial_requests();
+        assert!(initial
+            .iter()
+            .all(|r| r.initial_resource_versions.is_empty()));
+        client.start_resync();
+        let resp = DeltaDiscoveryResponse {
+            type_url: ADDRESS_TYPE.to_string(),
+            resources: vec![ProtoResource {
+                name: "kept".to_string(),
+                ..Default::default()
+            }],
+            removed_resources: vec!["removed".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(client.handle_resync(&resp), vec!["forgotten".to_string()]);
+        assert!(!client.known_resources[ADDRESS_TYPE].contains_key("forgotten"));
+        // Only the first response after connecting holds the full state.
+        client
+            .known_resources
+            .get_mut(ADDRESS_TYPE)
+            .unwrap()
+            .insert("other".to_string(), "1".to_string());
+        assert!(client.handle_resync(&resp).is_empty());
+    }
+
     #[tokio::test]
     async fn test_add_abort_remove() {
         helpers::initialize_telemetry();
@@ -784,4 +1175,64 @@ mod tests {
             .expect("failed to send server response");
         verify_address(IpAddr::V4(ip), None, &state).await;
     }
+
+    /// Updates collects the address updates handled, as `update <name>` or `remove <name>`.
+    #[derive(Clone, Default)]
+    struct Updates(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Handler<XdsAddress> for Updates {
+        fn handle(&self, res: Vec<XdsUpdate<XdsAddress>>) -> Result<(), Vec<RejectedConfig>> {
+            self.0.lock().unwrap().extend(res.iter().map(|u| match u {
+                XdsUpdate::Update(r) => format!("update {}", r.name),
+                XdsUpdate::Remove(name) => format!("remove {name}"),
+            }));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_recording() {
+        let path = std::env::temp_dir().join(format!("xds-replay-{}", rand::random::<u64>()));
+        let resource = |name: &str| ProtoResource {
+            name: name.to_string(),
+            resource: Some(Any {
+                type_url: ADDRESS_TYPE.to_string(),
+                value: XdsAddress::default().encode_to_vec(),
+            }),
+            ..Default::default()
+        };
+        let response = |resources, removed_resources| DeltaDiscoveryResponse {
+            type_url: ADDRESS_TYPE.to_string(),
+            resources,
+            removed_resources,
+            ..Default::default()
+        };
+        let recorder = Recorder::create(&path).unwrap();
+        recorder.connected();
+        recorder.response(&response(vec![resource("a"), resource("b")], vec![]));
+        // After reconnecting, the control plane removes b, which it no longer has.
+        recorder.connected();
+        recorder.response(&response(vec![resource("a")], vec!["b".to_string()]));
+        drop(recorder);
+
+        let cfg =
+            crate::test_helpers::test_config_with_port_xds_addr_and_root_cert(80, None, None, None);
+        let updates = Updates::default();
+        let mut registry = prometheus_client::registry::Registry::default();
+        Config::new(cfg)
+            .with_address_handler(updates.clone())
+            .watch(ADDRESS_TYPE.into())
+            .build(
+                Metrics::new(&mut registry),
+                readiness::Ready::new().register_task("ads client"),
+            )
+            .replay(&path)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            *updates.0.lock().unwrap(),
+            vec!["update a", "update b", "update a", "remove b"]
+        );
+    }
 }
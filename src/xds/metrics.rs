@@ -12,15 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::SystemTime;
+
 use prometheus_client::encoding::{EncodeLabelSet, EncodeLabelValue};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Registry;
 
 use crate::metrics::Recorder;
 
 pub struct Metrics {
     pub connection_terminations: Family<ConnectionTermination, Counter>,
+    pub connection_state_changes: Family<ConnectionStateChange, Counter>,
+    pub connected: Gauge,
+    pub last_update: Gauge,
+    pub resources: Family<ResourceType, Gauge>,
+    pub responses: Family<Response, Counter>,
 }
 
 #[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
@@ -36,6 +44,35 @@ pub enum ConnectionTerminationReason {
     Complete,
 }
 
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConnectionStateChange {
+    pub state: ConnectionState,
+}
+
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct ResourceType {
+    pub type_url: String,
+}
+
+#[derive(Clone, Hash, Debug, PartialEq, Eq, EncodeLabelSet)]
+pub struct Response {
+    pub type_url: String,
+    pub result: ResponseResult,
+}
+
+#[derive(Copy, Clone, Hash, Debug, PartialEq, Eq, EncodeLabelValue)]
+pub enum ResponseResult {
+    Ack,
+    Nack,
+}
+
 impl Metrics {
     pub fn new(registry: &mut Registry) -> Self {
         let connection_terminations = Family::default();
@@ -44,9 +81,44 @@ impl Metrics {
             "The total number of completed connections to xds server",
             connection_terminations.clone(),
         );
+        let connection_state_changes = Family::default();
+        registry.register(
+            "xds_connection_state_changes",
+            "The total number of times the connection to the xds server changed to each state",
+            connection_state_changes.clone(),
+        );
+        let connected = Gauge::default();
+        registry.register(
+            "xds_connected",
+            "Whether a stream to the xds server is currently established",
+            connected.clone(),
+        );
+        let last_update = Gauge::default();
+        registry.register(
+            "xds_last_update_timestamp",
+            "The time of the last update accepted from the xds server (unit: seconds since the epoch)",
+            last_update.clone(),
+        );
+        let resources = Family::default();
+        registry.register(
+            "xds_resources",
+            "The number of resources of each type known from the xds server",
+            resources.clone(),
+        );
+        let responses = Family::default();
+        registry.register(
+            "xds_responses",
+            "The total number of responses from the xds server, by whether they were accepted",
+            responses.clone(),
+        );
 
         Self {
             connection_terminations,
+            connection_state_changes,
+            connected,
+            last_update,
+            resources,
+            responses,
         }
     }
 }
@@ -58,3 +130,25 @@ impl Recorder<ConnectionTerminationReason, u64> for Metrics {
             .inc_by(count);
     }
 }
+
+impl Recorder<ConnectionState, u64> for Metrics {
+    fn record(&self, state: &ConnectionState, count: u64) {
+        self.connection_state_changes
+            .get_or_create(&ConnectionStateChange { state: *state })
+            .inc_by(count);
+        self.connected
+            .set(i64::from(*state == ConnectionState::Connected));
+    }
+}
+
+impl Recorder<Response, u64> for Metrics {
+    fn record(&self, response: &Response, count: u64) {
+        self.responses.get_or_create(response).inc_by(count);
+        if response.result == ResponseResult::Ack {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            self.last_update.set(now.as_secs() as i64);
+        }
+    }
+}
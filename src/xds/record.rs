@@ -0,0 +1,173 @@
+// Copyright Istio Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recordings of the xDS responses ztunnel receives, which can be replayed into a ztunnel running
+//! without a control plane to reproduce the state it was in. A recording has one JSON object per
+//! line, each holding a timestamp and either a `connected` event, marking a new stream, or a
+//! `response` event with the base64 encoded DeltaDiscoveryResponse.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+
+use anyhow::Context;
+use base64::Engine;
+use prost::Message;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::xds::service::discovery::v3::DeltaDiscoveryResponse;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+pub(super) struct Record {
+    /// When the event happened, in RFC 3339.
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(super) enum Event {
+    /// A new stream was established, so the first response of each type holds its full state.
+    Connected,
+    Response {
+        type_url: String,
+        response: String,
+    },
+}
+
+/// Recorder appends the events of an xDS client to a recording. The writes happen on a thread of
+/// their own, in order, so a slow disk does not hold up the client.
+pub struct Recorder {
+    lines: Option<mpsc::UnboundedSender<String>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, mut rx) = mpsc::unbounded_channel::<String>();
+        let writer = thread::Builder::new()
+            .name("xds-recorder".to_string())
+            .spawn(move || {
+                while let Some(line) = rx.blocking_recv() {
+                    // A failed write leaves the recording incomplete, but should not affect the
+                    // client.
+                    if let Err(e) = file.write_all(line.as_bytes()) {
+                        warn!("failed to record xds event: {e}");
+                    }
+                }
+            })?;
+        Ok(Recorder {
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    pub(super) fn connected(&self) {
+        self.write(Event::Connected);
+    }
+
+    pub(super) fn response(&self, response: &DeltaDiscoveryResponse) {
+        self.write(Event::Response {
+            type_url: response.type_url.clone(),
+            response: base64::engine::general_purpose::STANDARD.encode(response.encode_to_vec()),
+        });
+    }
+
+    fn write(&self, event: Event) {
+        let record = Record {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            event,
+        };
+        let mut line = serde_json::to_string(&record).expect("records serialize");
+        line.push('\n');
+        if let Some(lines) = &self.lines {
+            let _ = lines.send(line);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /// drop waits for the events recorded so far to be written.
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// read returns the records of the recording at `path`, in order.
+pub(super) async fn read(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read xds recording {}", path.display()))?;
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("invalid record on line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+impl Event {
+    pub(super) fn decode_response(response: &str) -> anyhow::Result<DeltaDiscoveryResponse> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(response)?;
+        Ok(DeltaDiscoveryResponse::decode(bytes.as_slice())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xds::service::discovery::v3::Resource;
+
+    #[tokio::test]
+    async fn record_round_trip() {
+        let path = std::env::temp_dir().join(format!("xds-record-{}", rand::random::<u64>()));
+        let recorder = Recorder::create(&path).unwrap();
+        let response = DeltaDiscoveryResponse {
+            type_url: crate::xds::ADDRESS_TYPE.to_string(),
+            resources: vec![Resource {
+                name: "default/example".to_string(),
+                version: "1".to_string(),
+                ..Default::default()
+            }],
+            removed_resources: vec!["default/removed".to_string()],
+            ..Default::default()
+        };
+        recorder.connected();
+        recorder.response(&response);
+        drop(recorder);
+
+        let records = read(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].event, Event::Connected);
+        let Event::Response {
+            type_url,
+            response: encoded,
+        } = &records[1].event
+        else {
+            panic!("expected a response, got {:?}", records[1].event);
+        };
+        assert_eq!(type_url, crate::xds::ADDRESS_TYPE);
+        assert_eq!(Event::decode_response(encoded).unwrap(), response);
+    }
+}
@@ -22,6 +22,30 @@ pub mod service {
             tonic::include_proto!("envoy.service.discovery.v3");
         }
     }
+    pub mod secret {
+        pub mod v3 {
+            tonic::include_proto!("envoy.service.secret.v3");
+        }
+    }
+}
+
+// The SPIFFE Workload API protos have no package.
+#[allow(warnings)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod spiffe {
+    tonic::include_proto!("_");
+}
+
+#[allow(warnings)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+pub mod extensions {
+    pub mod transport_sockets {
+        pub mod tls {
+            pub mod v3 {
+                tonic::include_proto!("envoy.extensions.transport_sockets.tls.v3");
+            }
+        }
+    }
 }
 
 #[allow(warnings)]
@@ -36,6 +60,20 @@ pub mod istio {
     pub mod ca {
         tonic::include_proto!("istio.v1.auth");
     }
+    pub mod networking {
+        pub mod nds {
+            pub mod v1 {
+                tonic::include_proto!("istio.networking.nds.v1");
+            }
+        }
+    }
+    pub mod telemetry {
+        pub mod traffic {
+            pub mod v1 {
+                tonic::include_proto!("istio.telemetry.traffic.v1");
+            }
+        }
+    }
 }
 
 pub const WORKLOAD_TYPE: &str = "type.googleapis.com/istio.workload.Workload";
@@ -43,3 +81,6 @@ pub const SERVICE_TYPE: &str = "type.googleapis.com/istio.workload.Service";
 pub const GATEWAY_ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.GatewayAddress";
 pub const ADDRESS_TYPE: &str = "type.googleapis.com/istio.workload.Address";
 pub const AUTHORIZATION_TYPE: &str = "type.googleapis.com/istio.security.Authorization";
+pub const NAME_TABLE_TYPE: &str = "type.googleapis.com/istio.networking.nds.v1.NameTable";
+pub const SECRET_TYPE: &str =
+    "type.googleapis.com/envoy.extensions.transport_sockets.tls.v3.Secret";
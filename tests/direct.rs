@@ -17,7 +17,7 @@ use std::str::FromStr;
 use std::time::Duration;
 
 use bytes::Bytes;
-use http_body_util::Empty;
+use http_body_util::{BodyExt, Empty};
 use hyper::{Method, Request};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
@@ -78,6 +78,11 @@ async fn test_conflicting_bind_error_socks5() {
     test_bind_conflict(|c| &mut c.socks5_addr).await;
 }
 
+#[tokio::test]
+async fn test_conflicting_bind_error_http_connect() {
+    test_bind_conflict(|c| &mut c.http_connect_addr).await;
+}
+
 #[tokio::test]
 async fn test_conflicting_bind_error_admin() {
     test_bind_conflict(|c| &mut c.admin_addr).await;
@@ -213,6 +218,45 @@ async fn test_hbone_request() {
     run_request_test(TEST_WORKLOAD_HBONE, "").await;
 }
 
+#[tokio::test]
+async fn test_hbone_concurrent_streams() {
+    let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let echo_addr = echo.address();
+    let cfg = test_config_with_port(echo_addr.port());
+    tokio::spawn(echo.run());
+    testapp::with_app(cfg, |app| async move {
+        let dst = helpers::with_ip(echo_addr, TEST_WORKLOAD_HBONE.parse().unwrap());
+        let open = || async {
+            let mut stream = app.socks5_connect(dst).await;
+            read_write_stream(&mut stream).await;
+            stream
+        };
+        // The first stream sets up the pooled connection, which the rest then share. There are
+        // more of them than the 100 streams HTTP/2 peers commonly allow, which is not a limit by
+        // default.
+        let mut streams = vec![open().await];
+        streams.extend(futures::future::join_all((0..149).map(|_| open())).await);
+
+        let body = app
+            .admin_request("hbone_peers")
+            .await
+            .unwrap()
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes();
+        let peers: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(peers.len(), 1, "{peers:?}");
+        assert_eq!(peers[0]["active_streams"], 150);
+        assert_eq!(peers[0]["total_streams"], 150);
+        for mut stream in streams {
+            read_write_stream(&mut stream).await;
+        }
+    })
+    .await;
+}
+
 #[tokio::test]
 async fn test_tcp_request() {
     run_request_test(TEST_WORKLOAD_TCP, "").await;
@@ -223,6 +267,33 @@ async fn test_vip_request() {
     run_request_test(&format!("{TEST_VIP}:80"), "").await;
 }
 
+#[tokio::test]
+async fn test_http_connect_request() {
+    let echo = tcp::TestServer::new(tcp::Mode::ReadWrite, 0).await;
+    let echo_addr = echo.address();
+    let cfg = test_config_with_port(echo_addr.port());
+    tokio::spawn(echo.run());
+    testapp::with_app(cfg, |app| async move {
+        // The service hostname is resolved by ztunnel, to its VIP.
+        let mut stream = app
+            .http_connect(&format!("{TEST_SERVICE_HOST}:80"))
+            .await
+            .unwrap();
+        read_write_stream(&mut stream).await;
+
+        let workload = helpers::with_ip(echo_addr, TEST_WORKLOAD_HBONE.parse().unwrap());
+        let mut stream = app.http_connect(&workload.to_string()).await.unwrap();
+        read_write_stream(&mut stream).await;
+
+        let err = app
+            .http_connect("unknown.default.svc.cluster.local:80")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("502"), "{err}");
+    })
+    .await;
+}
+
 fn on_demand_dns_assertions(metrics: ParsedMetrics) {
     for metric in &[
         ("istio_on_demand_dns_total"),
@@ -286,6 +357,7 @@ async fn test_stats_exist() {
         for metric in &[
             ("istio_build"),
             ("istio_connection_terminations"),
+            ("istio_xds_connected"),
             ("istio_tcp_connections_opened"),
             ("istio_tcp_connections_closed"),
             // DNS.
@@ -28,7 +28,9 @@ mod namespaced {
     use tokio::time::timeout;
     use tracing::{error, info};
 
+    use ipnet::IpNet;
     use ztunnel::identity;
+    use ztunnel::rbac::{Authorization, RbacAction, RbacMatch, RbacScope};
     use ztunnel::state::workload::NetworkAddress;
     use ztunnel::test_helpers::app::ParsedMetrics;
     use ztunnel::test_helpers::app::TestApp;
@@ -294,6 +296,117 @@ mod namespaced {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_hbone_policy_denied() -> anyhow::Result<()> {
+        let mut manager = setup_netns_test!();
+        run_tcp_server(
+            manager
+                .workload_builder("server", REMOTE_NODE)
+                .hbone()
+                .register()?,
+        )?;
+        let client = manager
+            .workload_builder("client", DEFAULT_NODE)
+            .register()?;
+        manager.add_policy(Authorization {
+            name: "deny-client".to_string(),
+            namespace: "default".to_string(),
+            scope: RbacScope::Global,
+            action: RbacAction::Deny,
+            rules: vec![vec![vec![RbacMatch {
+                source_ips: vec![IpNet::new(client.ip(), 32)?],
+                ..Default::default()
+            }]]],
+            audit: false,
+        });
+        let _ = manager.deploy_ztunnel(REMOTE_NODE)?;
+        let _ = manager.deploy_ztunnel(DEFAULT_NODE)?;
+
+        let srv = resolve_target(manager.resolver(), "server");
+        client
+            .run(move || async move {
+                let mut tcp_stream = TcpStream::connect(srv).await?;
+                tcp_stream.write_all(b"hello world!").await?;
+                let mut buf = [0; 10];
+                let mut buf = ReadBuf::new(&mut buf);
+
+                let result = poll_fn(|cx| tcp_stream.poll_peek(cx, &mut buf)).await;
+                // The remote ztunnel rejects the tunnel, so ours resets the connection.
+                assert_eq!(
+                    result.err().map(|e| e.kind()),
+                    Some(std::io::ErrorKind::ConnectionReset)
+                );
+                Ok(())
+            })?
+            .join()
+            .unwrap()?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_keeps_connections() -> anyhow::Result<()> {
+        let mut manager = setup_netns_test!();
+        run_tcp_server(
+            manager
+                .workload_builder("server", REMOTE_NODE)
+                .hbone()
+                .register()?,
+        )?;
+        let remote = manager.deploy_ztunnel(REMOTE_NODE)?;
+        let client = manager
+            .workload_builder("client", DEFAULT_NODE)
+            .register()?;
+        let _ = manager.deploy_ztunnel(DEFAULT_NODE)?;
+
+        let srv = resolve_target(manager.resolver(), "server");
+        client
+            .run(move || async move {
+                let mut stream = timeout(Duration::from_secs(5), TcpStream::connect(srv)).await??;
+                timeout(
+                    Duration::from_secs(5),
+                    double_read_write_stream(&mut stream),
+                )
+                .await?;
+                // Once the remote ztunnel has started draining, it refuses new connections...
+                remote.shutdown.shutdown_now().await;
+                timeout(Duration::from_secs(5), async {
+                    while !refused(srv).await {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                })
+                .await?;
+                // ...but serves those already established until they close.
+                timeout(
+                    Duration::from_secs(5),
+                    double_read_write_stream(&mut stream),
+                )
+                .await?;
+                stream.shutdown().await?;
+                let mut rest = Vec::new();
+                timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await??;
+                assert!(rest.is_empty(), "{rest:?}");
+                Ok(())
+            })?
+            .join()
+            .unwrap()?;
+        Ok(())
+    }
+
+    /// refused returns whether a new connection to `srv` is closed rather than echoed back.
+    async fn refused(srv: SocketAddr) -> bool {
+        let Ok(mut stream) = TcpStream::connect(srv).await else {
+            return true;
+        };
+        if stream.write_all(b"hello world").await.is_err() {
+            return true;
+        }
+        let mut buf = [0; 1];
+        matches!(
+            timeout(Duration::from_secs(1), stream.read(&mut buf)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
     fn destination_labels() -> HashMap<String, String> {
         HashMap::from([("reporter".to_string(), "destination".to_string())])
     }
@@ -685,6 +798,7 @@ mod namespaced {
                     (15006, Request),    // Inbound: should be blocked due to recursive call
                     (15008, Request),    // HBONE: expected TLS, reject
                     (15080, Connection), // Socks5: only localhost
+                    (15081, Connection), // HTTP CONNECT: only localhost
                     (15000, Connection), // Admin: only localhost
                     (15020, Http),       // Stats: accept connection and returns a HTTP error
                     (15021, Http),       // Readiness: accept connection and returns a HTTP error